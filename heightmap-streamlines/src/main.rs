@@ -67,6 +67,7 @@ fn main() {
         line_width,
         &white,
         &gradient,
+        None,
         |uv_domain, t_domain, t_screen| {
             // let exp_decay = f32::exp(-t_domain.1);
             // let noise_scale = 0.2 * exp_decay.max(0.0) * exp_decay;
@@ -81,6 +82,6 @@ fn main() {
     );
 
     // canvas.display_in_window("waves");
-    canvas.save_png(&std::path::Path::new("waves.png"));
+    canvas.save_png(std::path::Path::new("waves.png")).expect("failed to save waves.png");
 
 }
@@ -6,6 +6,7 @@ use std::time::Instant;
 
 use rusty_sdfs_lib::render_edges;
 use rusty_sdfs_lib::render_hatch_lines;
+use rusty_sdfs_lib::LineCap;
 use rusty_sdfs_lib::Canvas;
 use rusty_sdfs_lib::PixelPropertyCanvas;
 use rusty_sdfs_lib::SkiaCanvas;
@@ -30,11 +31,11 @@ fn main() {
     let separation = 5.0;
     let line_color = [0, 0, 0];
     let line_width = 1.5;
-    render_hatch_lines(&pp_canvas, &mut output_canvas, 0.85, step_size, &line_color, line_width, 0.2*PI, separation);
-    render_hatch_lines(&pp_canvas, &mut output_canvas, 0.5, step_size, &line_color, line_width, 0.55*PI, 0.75 * separation);
-    render_hatch_lines(&pp_canvas, &mut output_canvas, 0.25, step_size, &line_color, line_width, 0.85*PI, 0.3 * separation);
+    render_hatch_lines(&pp_canvas, &mut output_canvas, 0.85, step_size, &line_color, line_width, 0.2*PI, separation, LineCap::Butt, None, None, true);
+    render_hatch_lines(&pp_canvas, &mut output_canvas, 0.5, step_size, &line_color, line_width, 0.55*PI, 0.75 * separation, LineCap::Butt, None, None, true);
+    render_hatch_lines(&pp_canvas, &mut output_canvas, 0.25, step_size, &line_color, line_width, 0.85*PI, 0.3 * separation, LineCap::Butt, None, None, true);
 
-    render_edges(&pp_canvas, &mut output_canvas, &[0, 0, 0], line_width);
+    render_edges(&pp_canvas, &mut output_canvas, &[0, 0, 0], line_width, None, None);
 
     let duraction_hatching = start_instant.elapsed();
     println!(
@@ -43,7 +44,7 @@ fn main() {
     );
 
     println!("Outputting image(s) to disk/display...");
-    // output_canvas.save_png(Path::new("output.png"));
+    // output_canvas.save_png(Path::new("output.png")).expect("failed to save output.png");
     output_canvas.display_in_window("ppc hatching");
     println!("Done");
 }
@@ -1,5 +1,6 @@
 use std::time::Duration;
 
+#[cfg(feature = "window")]
 use minifb::{Key, Window, WindowOptions};
 
 
@@ -9,6 +10,7 @@ pub trait Animation {
     fn frame_duration(&self) -> Duration;
     fn render_frame(&mut self) -> Vec<u32>;
 
+    #[cfg(feature = "window")]
     fn play(&mut self, title: &str, window_options: WindowOptions) {
         let mut window = Window::new(
             title,
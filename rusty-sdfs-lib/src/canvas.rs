@@ -1,13 +1,16 @@
+use std::collections::HashMap;
 use std::fmt;
 use std::fs::File;
-use std::io::{self, BufReader, BufWriter};
+use std::io::{self, BufReader, BufWriter, Write};
 
-use crate::ray_marcher::RayMarcher;
-use crate::scene::Scene;
+use crate::noise::{generate_blue_noise_mask, rand_3d};
+use crate::ray_marcher::{HitRecord, QualitySettings, RayMarcher};
+use crate::scene::{AnimatedScene, Scene};
 use crate::vector::{vec2, vec3, Vec2, Vec3, VecFloat};
-use crate::Material;
+use crate::{LinearGradient, Material};
 
 use bincode;
+#[cfg(feature = "window")]
 use minifb::{Key, Window, WindowOptions};
 use rayon::prelude::*;
 use serde::{Serialize, Deserialize};
@@ -15,6 +18,74 @@ use tiny_skia::{
     Color, FillRule, IntSize, LineCap, LineJoin, Paint, Path, PathBuilder, Pixmap, PremultipliedColorU8, Rect, Stroke, Transform
 };
 
+#[cfg(feature = "text")]
+struct GlyphPathBuilder<'a> {
+    pb: &'a mut PathBuilder,
+    x: f32,
+    y: f32,
+    scale: f32,
+}
+
+#[cfg(feature = "text")]
+impl<'a> ttf_parser::OutlineBuilder for GlyphPathBuilder<'a> {
+    // Font units have their y-axis pointing up, canvas coordinates have it pointing down
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.pb.move_to(self.x + self.scale * x, self.y - self.scale * y);
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.pb.line_to(self.x + self.scale * x, self.y - self.scale * y);
+    }
+
+    fn quad_to(&mut self, x1: f32, y1: f32, x: f32, y: f32) {
+        self.pb.quad_to(
+            self.x + self.scale * x1, self.y - self.scale * y1,
+            self.x + self.scale * x, self.y - self.scale * y,
+        );
+    }
+
+    fn curve_to(&mut self, x1: f32, y1: f32, x2: f32, y2: f32, x: f32, y: f32) {
+        self.pb.cubic_to(
+            self.x + self.scale * x1, self.y - self.scale * y1,
+            self.x + self.scale * x2, self.y - self.scale * y2,
+            self.x + self.scale * x, self.y - self.scale * y,
+        );
+    }
+
+    fn close(&mut self) {
+        self.pb.close();
+    }
+}
+
+// Integer pixel coordinates; pixel (x, y) covers the canvas coordinates [x, x + 1) x [y, y + 1)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PixelCoord {
+    pub x: u32,
+    pub y: u32,
+}
+
+impl PixelCoord {
+    pub fn new(x: u32, y: u32) -> PixelCoord {
+        PixelCoord { x, y }
+    }
+
+    // Linear index into row-major pixel data of a canvas with the given width
+    pub fn from_index(index: usize, width: u32) -> PixelCoord {
+        PixelCoord {
+            x: (index % (width as usize)) as u32,
+            y: (index / (width as usize)) as u32,
+        }
+    }
+
+    pub fn to_index(&self, width: u32) -> usize {
+        (width as usize) * (self.y as usize) + (self.x as usize)
+    }
+
+    pub fn center(&self) -> Vec2 {
+        vec2::from_values(self.x as f32 + 0.5, self.y as f32 + 0.5)
+    }
+}
+
 pub trait Canvas {
     fn width(&self) -> u32;
     fn height(&self) -> u32;
@@ -23,6 +94,12 @@ pub trait Canvas {
         (self.width() as f32) / (self.height() as f32)
     }
 
+    // Aspect ratio of the printed/displayed image for devices with different horizontal and vertical resolution;
+    // pass this to RayMarcher::new to keep circles circular on the device
+    fn physical_aspect_ratio(&self, dpi_x: f32, dpi_y: f32) -> f32 {
+        self.aspect_ratio() * dpi_y / dpi_x
+    }
+
     fn to_screen_coordinates_wh(width: u32, height: u32, x: f32, y: f32) -> Vec2 {
         vec2::from_values(
             2.0 * (x / (width as f32) - 0.5),
@@ -50,6 +127,10 @@ pub trait Canvas {
 pub enum CanvasError {
     Io(io::Error),
     Serialization(bincode::Error),
+    Png(String),
+    RegionOutOfBounds,
+    Font(String),
+    UnsupportedVersion(u32),
 }
 
 impl fmt::Display for CanvasError {
@@ -57,6 +138,15 @@ impl fmt::Display for CanvasError {
         match self {
             CanvasError::Io(err) => write!(f, "I/O error: {}", err),
             CanvasError::Serialization(err) => write!(f, "Serialization error: {}", err),
+            CanvasError::Png(err) => write!(f, "PNG encoding error: {}", err),
+            CanvasError::RegionOutOfBounds => write!(f, "Region is empty or exceeds the canvas bounds"),
+            CanvasError::Font(msg) => write!(f, "Font error: {}", msg),
+            CanvasError::UnsupportedVersion(version) => write!(
+                f,
+                "Unsupported .ppc format version {} (expected {})",
+                version,
+                PixelPropertyCanvas::FILE_FORMAT_VERSION
+            ),
         }
     }
 }
@@ -73,25 +163,222 @@ impl From<bincode::Error> for CanvasError {
     }
 }
 
+fn check_region(canvas: &impl Canvas, x: u32, y: u32, width: u32, height: u32) -> Result<(), CanvasError> {
+    let x_end = x.checked_add(width).ok_or(CanvasError::RegionOutOfBounds)?;
+    let y_end = y.checked_add(height).ok_or(CanvasError::RegionOutOfBounds)?;
+    if width == 0 || height == 0 || x_end > canvas.width() || y_end > canvas.height() {
+        Err(CanvasError::RegionOutOfBounds)
+    } else {
+        Ok(())
+    }
+}
+
 #[derive(Clone, Copy, Serialize, Deserialize)]
 pub struct PixelProperties {
     pub lightness: f32,
-    pub direction: f32,
+    pub direction_vec: Vec2,
     pub depth: f32,
     pub bg_hsl: Vec3,
     pub is_shaded: bool,
     pub is_hatched: bool,
+    pub is_streamlined: bool,
+    pub specular: f32, // specular part of lightness
+    pub specular_hsl: Option<Vec3>,
+    pub coverage: f32, // 1 for hits; for misses next to a silhouette, an estimate of the fraction covered by the scene
+    pub normal: Vec3, // unit surface normal in camera coordinates (x to the right, y up, z into the screen)
+    pub ao: f32, // ambient visibility baked during marching (cf. RayMarcher::ambient_occlusion); 1 means unoccluded
+    pub material_id: Option<u32>, // id of the material hit; None for misses
 }
 
 impl PixelProperties {
     fn default() -> PixelProperties {
         PixelProperties {
             lightness: f32::NAN,
-            direction: f32::NAN,
+            direction_vec: vec2::from_values(f32::NAN, f32::NAN),
             depth: f32::NAN,
             bg_hsl: vec3::from_values(0.0, 0.0, 1.0),
             is_shaded: false,
             is_hatched: false,
+            is_streamlined: false,
+            specular: 0.0,
+            specular_hsl: None,
+            coverage: 0.0,
+            normal: vec3::from_values(f32::NAN, f32::NAN, f32::NAN),
+            ao: 1.0,
+            material_id: None,
+        }
+    }
+
+    // Polar angle of direction_vec; prefer direction_vec, which does not wrap around at +/- pi
+    pub fn direction(&self) -> f32 {
+        vec2::polar_angle(&self.direction_vec)
+    }
+
+    pub fn set_direction(&mut self, angle: f32) {
+        self.direction_vec = vec2::polar_angle_to_unit_vector(angle);
+    }
+}
+
+// What the flow direction of a surface point is oriented against: the light source of its material, or a fixed
+// world-space vector (e.g., gravity) that keeps the strokes in place when the lighting changes
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum DirectionSource {
+    Light,
+    Field(Vec3),
+}
+
+// Placement of the samples within a pixel for supersampling. Sample counts that are not perfect squares have no
+// n x n grid; Grid, Jittered, and Rotated then place the samples in the cells of an n-rooks pattern instead, i.e., one
+// per row and column of an n x n subdivision of the pixel.
+#[derive(Clone, Copy)]
+pub enum SamplePattern {
+    Grid,      // centers of a regular n x n grid
+    Jittered,  // one random point per cell of the grid (stratified)
+    Rotated,   // the grid rotated by atan(1/2), e.g., the classic rotated grid for 4 samples
+    BlueNoise, // the first samples of a blue-noise mask, randomly shifted (toroidally) per pixel
+}
+
+impl SamplePattern {
+    const BLUE_NOISE_SEED: u64 = 1009;
+    const JITTER_SEED: u64 = 1013;
+
+    // Sample offsets in [0, 1)^2 relative to the top-left pixel corner, before any per-pixel randomization
+    pub fn offsets(&self, sample_count: u32) -> Vec<Vec2> {
+        let sample_count = sample_count.max(1);
+        let side = (sample_count as f32).sqrt().ceil() as u32;
+        if side * side != sample_count && !matches!(self, SamplePattern::BlueNoise) {
+            return Self::n_rooks_offsets(sample_count);
+        }
+        let grid = (0..side * side).map(|i| {
+            vec2::from_values(((i % side) as f32 + 0.5) / side as f32, ((i / side) as f32 + 0.5) / side as f32)
+        });
+        let mut offsets: Vec<Vec2> = match self {
+            SamplePattern::Grid | SamplePattern::Jittered => grid.collect(),
+            SamplePattern::Rotated => grid
+                .map(|g| {
+                    // Rotate the centered grid by atan(1/2) and scale it by sqrt(5)/2 so that no two samples share a
+                    // row or column; wrap around to stay inside the pixel
+                    let (x, y) = (g.0 - 0.5, g.1 - 0.5);
+                    vec2::from_values((x - 0.5 * y + 0.5).rem_euclid(1.0), (0.5 * x + y + 0.5).rem_euclid(1.0))
+                })
+                .collect(),
+            SamplePattern::BlueNoise => {
+                let mask_side = 4 * side;
+                let mask = generate_blue_noise_mask(mask_side, mask_side, Self::BLUE_NOISE_SEED);
+                let mut indices: Vec<usize> = (0..mask.len()).collect();
+                indices.sort_by(|&a, &b| mask[a].total_cmp(&mask[b]));
+                indices
+                    .iter()
+                    .map(|&i| {
+                        vec2::from_values(
+                            ((i as u32 % mask_side) as f32 + 0.5) / mask_side as f32,
+                            ((i as u32 / mask_side) as f32 + 0.5) / mask_side as f32,
+                        )
+                    })
+                    .collect()
+            }
+        };
+        // Only the blue-noise mask has more points than samples; its first points by rank are evenly spread
+        offsets.truncate(sample_count as usize);
+        offsets
+    }
+
+    // Sample k lies in column k and row (k * step) mod n, where the step is coprime to n (so that every row is taken)
+    // and close to sqrt(n) (so that consecutive samples are spread across the pixel). As every row and column has
+    // one sample, the samples are centered in the pixel.
+    fn n_rooks_offsets(sample_count: u32) -> Vec<Vec2> {
+        let n = sample_count;
+        let gcd = |mut a: u32, mut b: u32| {
+            while b != 0 {
+                (a, b) = (b, a % b);
+            }
+            a
+        };
+        let step = (1..n.max(2))
+            .filter(|&k| gcd(k, n) == 1)
+            .min_by(|&a, &b| (a as f32 - (n as f32).sqrt()).abs().total_cmp(&(b as f32 - (n as f32).sqrt()).abs()))
+            .unwrap();
+        (0..n)
+            .map(|k| vec2::from_values((k as f32 + 0.5) / n as f32, ((k * step % n) as f32 + 0.5) / n as f32))
+            .collect()
+    }
+
+    // Width of the cell around each of the offsets of the given pattern that contains only that offset
+    fn cell_size(sample_count: usize) -> f32 {
+        let side = (sample_count as f32).sqrt().ceil();
+        if side * side == sample_count as f32 { 1.0 / side } else { 1.0 / sample_count as f32 }
+    }
+
+    fn offsets_for_pixel(&self, offsets: &[Vec2], pixel_coord: &PixelCoord) -> Vec<Vec2> {
+        let (x, y) = (pixel_coord.x as f32, pixel_coord.y as f32);
+        let unit_rand = |z: f32, seed: u64| 0.5 * (rand_3d(x, y, z, seed) + 1.0);
+        match self {
+            SamplePattern::Grid | SamplePattern::Rotated => offsets.to_vec(),
+            SamplePattern::Jittered => {
+                let cell_size = Self::cell_size(offsets.len());
+                offsets
+                    .iter()
+                    .enumerate()
+                    .map(|(k, o)| {
+                        let jitter_x = (unit_rand(2.0 * k as f32, Self::JITTER_SEED) - 0.5) * cell_size;
+                        let jitter_y = (unit_rand(2.0 * k as f32 + 1.0, Self::JITTER_SEED) - 0.5) * cell_size;
+                        vec2::from_values(o.0 + jitter_x, o.1 + jitter_y)
+                    })
+                    .collect()
+            }
+            SamplePattern::BlueNoise => {
+                let shift = vec2::from_values(unit_rand(0.0, Self::BLUE_NOISE_SEED), unit_rand(1.0, Self::BLUE_NOISE_SEED));
+                offsets
+                    .iter()
+                    .map(|o| vec2::from_values((o.0 + shift.0).rem_euclid(1.0), (o.1 + shift.1).rem_euclid(1.0)))
+                    .collect()
+            }
+        }
+    }
+}
+
+// Background (HSL) of the pixels that miss the scene; Direction cannot be serialized
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum BackgroundSpec {
+    Flat(Vec3),
+    VerticalGradient { top_hsl: Vec3, bottom_hsl: Vec3 }, // by the elevation of the view ray along the up axis of the ray marcher
+    #[serde(skip)]
+    Direction(fn(&Vec3) -> Vec3),                       // any function of the normalized view ray direction
+    Sky { top_hsl: Vec3, horizon_hsl: Vec3 },           // see sky_gradient
+}
+
+impl BackgroundSpec {
+    // Blends from the horizon (and below) to the top color as the view ray turns upward
+    pub fn sky_gradient(top_hsl: &Vec3, horizon_hsl: &Vec3) -> BackgroundSpec {
+        BackgroundSpec::Sky {
+            top_hsl: *top_hsl,
+            horizon_hsl: *horizon_hsl,
+        }
+    }
+}
+
+impl Default for BackgroundSpec {
+    fn default() -> BackgroundSpec {
+        BackgroundSpec::Flat(PixelProperties::default().bg_hsl)
+    }
+}
+
+// Options shared by the PixelPropertyCanvas::from_scene* constructors
+#[derive(Clone, Copy)]
+pub struct SceneRenderOptions {
+    pub angle_in_tangent_plane: VecFloat,
+    pub normal_finite_diff_h: Option<VecFloat>, // defaults to the finite difference step of the ray marcher
+    pub direction_source: DirectionSource,
+    pub background: BackgroundSpec,
+}
+
+impl Default for SceneRenderOptions {
+    fn default() -> SceneRenderOptions {
+        SceneRenderOptions {
+            angle_in_tangent_plane: 0.0,
+            normal_finite_diff_h: None,
+            direction_source: DirectionSource::Light,
+            background: BackgroundSpec::default(),
         }
     }
 }
@@ -107,6 +394,21 @@ impl Kernel {
         Kernel { size, data }
     }
 
+    pub fn gaussian(sigma: f32) -> Kernel {
+        let hk = (3.0 * sigma).ceil().max(1.0) as i32;
+        let size = (2 * hk + 1) as u32;
+        let mut data = Vec::with_capacity((size * size) as usize);
+        for ky in -hk..=hk {
+            for kx in -hk..=hk {
+                let r_squared = (kx * kx + ky * ky) as f32;
+                data.push((-r_squared / (2.0 * sigma * sigma)).exp());
+            }
+        }
+        let sum: f32 = data.iter().sum();
+        data.iter_mut().for_each(|v| *v /= sum);
+        Kernel { size, data }
+    }
+
     pub fn size(&self) -> u32 {
         self.size
     }
@@ -135,6 +437,9 @@ impl Canvas for PixelPropertyCanvas {
 
 impl PixelPropertyCanvas {
     const NAN_RGBA_VALUE: [u8; 4] = [255, 0, 255, 255];
+    // Written ahead of the bincode data by to_file; bump whenever the layout of PixelProperties changes
+    // (version 2 dropped the redundant direction angle in favor of direction_vec; unversioned files predate it)
+    const FILE_FORMAT_VERSION: u32 = 2;
 
     pub fn new(width: u32, height: u32) -> PixelPropertyCanvas {
         let data_length = (width as usize) * (height as usize);
@@ -148,71 +453,458 @@ impl PixelPropertyCanvas {
 
     pub fn to_file(&self, filename: &str) -> Result<(), CanvasError> {
         let file = File::create(filename)?;
-        let writer = BufWriter::new(file);
+        let mut writer = BufWriter::new(file);
+        bincode::serialize_into(&mut writer, &Self::FILE_FORMAT_VERSION)?;
         Ok(bincode::serialize_into(writer, self)?)
     }
 
     pub fn from_file(filename: &str) -> Result<Self, CanvasError> {
         let file = File::open(filename)?;
-        let reader = BufReader::new(file);
+        let mut reader = BufReader::new(file);
+        let version: u32 = bincode::deserialize_from(&mut reader)?;
+        if version != Self::FILE_FORMAT_VERSION {
+            return Err(CanvasError::UnsupportedVersion(version));
+        }
         Ok(bincode::deserialize_from(reader)?)
     }
 
+    // Writes lightness.npy, depth.npy, and direction.npy (float32 arrays of shape (height, width)) into dir
+    pub fn save_npy(&self, dir: &std::path::Path) -> Result<(), CanvasError> {
+        self.write_npy(&dir.join("lightness.npy"), |pixel| pixel.lightness)?;
+        self.write_npy(&dir.join("depth.npy"), |pixel| pixel.depth)?;
+        self.write_npy(&dir.join("direction.npy"), |pixel| pixel.direction())
+    }
+
+    fn write_npy(&self, path: &std::path::Path, f: impl Fn(&PixelProperties) -> f32) -> Result<(), CanvasError> {
+        // NPY format version 1.0: magic string, version, header length (u16, little endian), and a header dict padded
+        // with spaces and terminated by a newline such that the data starts at a multiple of 64 bytes
+        const PREAMBLE_LEN: usize = 10;
+        let mut header = format!(
+            "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+            self.height, self.width
+        );
+        let padding = (64 - (PREAMBLE_LEN + header.len() + 1) % 64) % 64;
+        header.push_str(&" ".repeat(padding));
+        header.push('\n');
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(b"\x93NUMPY\x01\x00")?;
+        writer.write_all(&(header.len() as u16).to_le_bytes())?;
+        writer.write_all(header.as_bytes())?;
+        for pixel in self.data.iter() {
+            writer.write_all(&f(pixel).to_le_bytes())?;
+        }
+        Ok(writer.flush()?)
+    }
+
     pub fn from_scene<S>(
         ray_marcher: &RayMarcher,
         scene: &S,
         width: u32,
         height: u32,
-        angle_in_tangent_plane: VecFloat,
+        options: &SceneRenderOptions,
+    ) -> PixelPropertyCanvas
+    where
+        S: Scene + Sync,
+    {
+        Self::from_scene_multi_angle(ray_marcher, scene, width, height, &[options.angle_in_tangent_plane], options)
+            .pop()
+            .unwrap()
+    }
+
+    // Motion blur: march the scene at each of the given times and average the lightness of the sub-frames; each
+    // pixel averages only over the sub-frames in which it hits the scene
+    pub fn from_scene_motion_blur<S>(
+        ray_marcher: &RayMarcher,
+        scene: &mut S,
+        width: u32,
+        height: u32,
+        options: &SceneRenderOptions,
+        times: &[VecFloat],
+    ) -> PixelPropertyCanvas
+    where
+        S: AnimatedScene + Sync,
+    {
+        let mut canvas = PixelPropertyCanvas::new(width, height);
+        let mut hit_counts = vec![0; canvas.data.len()];
+        for t in times.iter() {
+            scene.set_time(*t);
+            let frame = Self::from_scene(ray_marcher, scene, width, height, options);
+            canvas.accumulate_mean(&frame, &mut hit_counts);
+        }
+        canvas.fill_background(ray_marcher, &options.background);
+        canvas
+    }
+
+    // Anti-aliased still: render the complete from_scene path passes times with the projection jittered by sub-pixel
+    // offsets (cf. SamplePattern::Rotated) and average the resolved colors (cf. bg_to_skia_canvas) in a float buffer
+    pub fn from_scene_accumulated<S>(
+        ray_marcher: &RayMarcher,
+        scene: &S,
+        width: u32,
+        height: u32,
+        options: &SceneRenderOptions,
+        passes: u32,
+    ) -> SkiaCanvas
+    where
+        S: Scene + Sync,
+    {
+        let offsets = SamplePattern::Rotated.offsets(passes);
+        let mut accumulation = FloatCanvas::new(width, height, 3);
+        let mut jittered_ray_marcher = ray_marcher.clone();
+        for offset in offsets.iter() {
+            // Pixel offsets relative to the pixel center in screen coordinates, whose y axis points up
+            jittered_ray_marcher.screen_offset = vec2::add(
+                &ray_marcher.screen_offset,
+                &vec2::from_values(2.0 * (offset.0 - 0.5) / width as f32, -2.0 * (offset.1 - 0.5) / height as f32),
+            );
+            let pass = Self::from_scene(&jittered_ray_marcher, scene, width, height, options);
+            let rgb = pass.bg_to_skia_canvas(None).to_u32_rgb();
+            accumulation.for_each_pixel_mut(|x, y, values| {
+                let pixel_rgb = rgb[(y * width + x) as usize];
+                values[0] += ((pixel_rgb >> 16) & 0xFF) as f32;
+                values[1] += ((pixel_rgb >> 8) & 0xFF) as f32;
+                values[2] += (pixel_rgb & 0xFF) as f32;
+            });
+        }
+        let pass_count = offsets.len() as f32;
+        accumulation.to_skia_canvas(|values| {
+            let mean = |value: f32| (value / pass_count).round().clamp(0.0, 255.0) as u8;
+            [mean(values[0]), mean(values[1]), mean(values[2]), 255]
+        })
+    }
+
+    // Blend the lightness of other into this canvas with the given weight. Where only one of the canvases has a
+    // hit, that pixel is taken as is, so that misses (NaN) do not spread into the accumulated result.
+    pub fn accumulate(&mut self, other: &Self, weight: f32) {
+        assert!(self.width == other.width && self.height == other.height);
+        for (pixel, other_pixel) in self.data.iter_mut().zip(other.data.iter()) {
+            if other_pixel.lightness.is_nan() {
+                continue;
+            }
+            if pixel.lightness.is_nan() {
+                *pixel = *other_pixel;
+            } else {
+                pixel.lightness += weight * (other_pixel.lightness - pixel.lightness);
+            }
+        }
+    }
+
+    // Running mean over the hits of the canvases accumulated so far, where hit_counts holds the number of hits of
+    // each pixel (initially 0)
+    fn accumulate_mean(&mut self, other: &Self, hit_counts: &mut [u32]) {
+        assert!(self.width == other.width && self.height == other.height && hit_counts.len() == self.data.len());
+        for ((pixel, other_pixel), hit_count) in self.data.iter_mut().zip(other.data.iter()).zip(hit_counts.iter_mut()) {
+            if other_pixel.lightness.is_nan() {
+                continue;
+            }
+            *hit_count += 1;
+            if *hit_count == 1 {
+                *pixel = *other_pixel;
+            } else {
+                pixel.lightness += (other_pixel.lightness - pixel.lightness) / *hit_count as f32;
+            }
+        }
+    }
+
+    // Fast framing: march at a fraction of the resolution without AO and shadows, then upscale (nearest)
+    pub fn from_scene_preview<S>(
+        ray_marcher: &RayMarcher,
+        scene: &S,
+        width: u32,
+        height: u32,
+        options: &SceneRenderOptions,
+        downscale: u32,
     ) -> PixelPropertyCanvas
     where
         S: Scene + Sync,
     {
+        assert!(downscale > 0);
+        let preview_width = width.div_ceil(downscale);
+        let preview_height = height.div_ceil(downscale);
+        let preview = Self::from_scene_multi_angle_with_quality(
+            ray_marcher,
+            scene,
+            preview_width,
+            preview_height,
+            &[options.angle_in_tangent_plane],
+            options,
+            &QualitySettings::preview(),
+        )
+        .pop()
+        .unwrap();
+
         let mut canvas = Self::new(width, height);
-        let offset_angle_vector = vec2::from_values(
-            angle_in_tangent_plane.cos(),
-            angle_in_tangent_plane.sin()
-        );
+        for (index, pixel) in canvas.data.iter_mut().enumerate() {
+            let coord = PixelCoord::from_index(index, width);
+            *pixel = preview.data[preview.pixel_index(coord.x / downscale, coord.y / downscale)];
+        }
+        canvas
+    }
+
+    // One canvas per angle; the angles take the place of options.angle_in_tangent_plane
+    pub fn from_scene_multi_angle<S>(
+        ray_marcher: &RayMarcher,
+        scene: &S,
+        width: u32,
+        height: u32,
+        angles_in_tangent_plane: &[VecFloat],
+        options: &SceneRenderOptions,
+    ) -> Vec<PixelPropertyCanvas>
+    where
+        S: Scene + Sync,
+    {
+        Self::from_scene_multi_angle_with_quality(
+            ray_marcher,
+            scene,
+            width,
+            height,
+            angles_in_tangent_plane,
+            options,
+            &QualitySettings::default(),
+        )
+    }
+
+    // Sets the background color of the pixels that miss the scene
+    pub fn fill_background(&mut self, ray_marcher: &RayMarcher, background: &BackgroundSpec) {
+        let width = self.width;
+        let height = self.height;
+        self.data.par_iter_mut().enumerate().filter(|(_, pixel)| pixel.depth.is_nan()).for_each(|(index, pixel)| {
+            pixel.bg_hsl = match background {
+                BackgroundSpec::Flat(hsl) => *hsl,
+                BackgroundSpec::VerticalGradient { top_hsl, bottom_hsl } => {
+                    let pixel_center = PixelCoord::from_index(index, width).center();
+                    let screen_coordinates = Self::to_screen_coordinates_wh(width, height, pixel_center.0, pixel_center.1);
+                    let elevation = ray_marcher.up_axis().height(&ray_marcher.screen_direction(&screen_coordinates));
+                    vec3::lerp_hsl(bottom_hsl, top_hsl, 0.5 * (elevation + 1.0))
+                }
+                BackgroundSpec::Direction(f) => {
+                    let pixel_center = PixelCoord::from_index(index, width).center();
+                    let screen_coordinates = Self::to_screen_coordinates_wh(width, height, pixel_center.0, pixel_center.1);
+                    f(&ray_marcher.screen_direction(&screen_coordinates))
+                }
+                BackgroundSpec::Sky { top_hsl, horizon_hsl } => {
+                    let pixel_center = PixelCoord::from_index(index, width).center();
+                    let screen_coordinates = Self::to_screen_coordinates_wh(width, height, pixel_center.0, pixel_center.1);
+                    let elevation = ray_marcher.up_axis().height(&ray_marcher.screen_direction(&screen_coordinates));
+                    vec3::lerp_hsl(horizon_hsl, top_hsl, elevation.max(0.0))
+                }
+            };
+        });
+    }
+
+    pub fn from_scene_multi_angle_with_quality<S>(
+        ray_marcher: &RayMarcher,
+        scene: &S,
+        width: u32,
+        height: u32,
+        angles_in_tangent_plane: &[VecFloat],
+        options: &SceneRenderOptions,
+        quality: &QualitySettings,
+    ) -> Vec<PixelPropertyCanvas>
+    where
+        S: Scene + Sync,
+    {
+        // March the scene only once and keep the surface geometry (point, normal, direction reference) of each pixel;
+        // only the direction needs to be recomputed for each angle
+        let mut canvas = Self::new(width, height);
+        let mut geometry: Vec<Option<(Vec3, Vec3, Vec3)>> = vec![None; canvas.data.len()];
+        let normal_finite_diff_h = options.normal_finite_diff_h.unwrap_or(ray_marcher.finite_diff_h());
         canvas
             .pixels_mut()
             .par_iter_mut()
+            .zip(geometry.par_iter_mut())
             .enumerate()
-            .for_each(|(index, pixel)| {
-                let (i_x, i_y) = Self::pixel_coordinates_wh(width, index);
-                let screen_coordinates = Self::to_screen_coordinates_wh(
+            .for_each(|(index, (pixel, pixel_geometry))| {
+                let pixel_center = PixelCoord::from_index(index, width).center();
+                match Self::march_sample(
+                    ray_marcher,
+                    scene,
                     width,
                     height,
-                    i_x as f32 + 0.5,
-                    i_y as f32 + 0.5,
-                );
-                let intersection = ray_marcher.intersection_with_scene(scene, &screen_coordinates);
-                if intersection.is_some() {
-                    let (p, depth, material) = intersection.unwrap();
-                    let normal = ray_marcher.scene_normal(scene, &p);
-                    let lightness = ray_marcher.light_intensity(
-                        scene,
-                        &material.reflective_properties,
-                        &p,
-                        &normal,
-                        &material.light_source,
-                    );
-                    let direction = Self::world_to_canvas_direction(
+                    &pixel_center,
+                    normal_finite_diff_h,
+                    options.direction_source,
+                    quality,
+                ) {
+                    Ok((sample, sample_geometry)) => {
+                        *pixel = sample;
+                        *pixel_geometry = Some(sample_geometry);
+                    }
+                    Err(coverage) => pixel.coverage = coverage,
+                }
+            });
+        canvas.fill_background(ray_marcher, &options.background);
+
+        angles_in_tangent_plane.iter().map(|angle| {
+            let offset_angle_vector = vec2::from_values(angle.cos(), angle.sin());
+            let mut angle_canvas = PixelPropertyCanvas {
+                data: canvas.data.clone(),
+                width,
+                height,
+            };
+            angle_canvas
+                .pixels_mut()
+                .par_iter_mut()
+                .zip(geometry.par_iter())
+                .for_each(|(pixel, pixel_geometry)| {
+                    if let Some((p, normal, light_source)) = pixel_geometry {
+                        pixel.direction_vec = Self::world_to_canvas_direction(
+                            ray_marcher,
+                            width,
+                            height,
+                            p,
+                            normal,
+                            light_source,
+                            &offset_angle_vector
+                        );
+                    }
+                });
+            angle_canvas
+        }).collect()
+    }
+
+    // Shade the scene at a point on the canvas; also returns the surface geometry (point, normal, direction reference).
+    // A miss yields the estimated coverage of the pixel (cf. RayMarcher::silhouette_coverage).
+    #[allow(clippy::too_many_arguments)]
+    fn march_sample<S>(
+        ray_marcher: &RayMarcher,
+        scene: &S,
+        width: u32,
+        height: u32,
+        canvas_point: &Vec2,
+        normal_finite_diff_h: VecFloat,
+        direction_source: DirectionSource,
+        quality: &QualitySettings,
+    ) -> Result<(PixelProperties, (Vec3, Vec3, Vec3)), f32>
+    where
+        S: Scene + Sync,
+    {
+        let screen_coordinates = Self::to_screen_coordinates_wh(width, height, canvas_point.0, canvas_point.1);
+        // The material is the one the scene returns at the hit point; in the seams of smooth CSG operations,
+        // it is already blended (cf. SdfOutput::smooth_union), so the colors transition as smoothly as the geometry
+        let hit = ray_marcher
+            .march_or_closest_approach(scene, &screen_coordinates, normal_finite_diff_h)
+            .map_err(|min_ratio| {
+                // Approximation: the pixel is a cone around the ray and the scene a half-space that the ray passed at
+                // the narrowest angle; a ray that grazes the surface is half covered. Silhouettes thus grow outward
+                // by up to half a pixel, as the hits next to them remain fully covered.
+                if ray_marcher.silhouette_coverage {
+                    (0.5 * (1.0 - min_ratio / ray_marcher.pixel_half_angle_tan(height))).clamp(0.0, 1.0)
+                } else {
+                    0.0
+                }
+            })?;
+        let HitRecord { point: p, depth, material, normal, .. } = hit;
+        let ao = ray_marcher.ambient_occlusion(scene, &material.reflective_properties, &p, &normal, quality);
+        let (lightness, specular) = ray_marcher.light_intensity_with_ambient_visibility(
+            scene,
+            &material.reflective_properties,
+            &p,
+            &normal,
+            &material.light_source,
+            ao,
+            quality,
+        );
+        let mut pixel = PixelProperties::default();
+        pixel.ao = ao;
+        pixel.lightness = lightness;
+        pixel.specular = specular;
+        pixel.specular_hsl = material.specular_hsl;
+        pixel.depth = depth;
+        pixel.bg_hsl = match &material.water {
+            Some(water) => ray_marcher.water_hsl(&p, &normal, water),
+            None => material.bg_hsl,
+        };
+        pixel.is_shaded = material.is_shaded;
+        pixel.is_hatched = material.is_hatched;
+        pixel.is_streamlined = material.is_streamlined;
+        pixel.material_id = Some(material.id);
+        pixel.coverage = 1.0;
+        pixel.normal = ray_marcher.to_camera_direction(&normal);
+        let direction_reference = match direction_source {
+            DirectionSource::Light => material.light_source,
+            DirectionSource::Field(field) => vec3::add(&p, &field),
+        };
+        Ok((pixel, (p, normal, direction_reference)))
+    }
+
+    // Anti-aliased variant of from_scene that marches sample_count rays per pixel, placed according to the pattern.
+    // A pixel counts as a hit if at least half of its samples hit the scene. Lightness, specular, and direction are
+    // averaged over the hits; all other properties are taken from the hit closest to the pixel center.
+    pub fn from_scene_supersampled<S>(
+        ray_marcher: &RayMarcher,
+        scene: &S,
+        width: u32,
+        height: u32,
+        options: &SceneRenderOptions,
+        sample_count: u32,
+        pattern: SamplePattern,
+    ) -> PixelPropertyCanvas
+    where
+        S: Scene + Sync,
+    {
+        let mut canvas = Self::new(width, height);
+        let normal_finite_diff_h = options.normal_finite_diff_h.unwrap_or(ray_marcher.finite_diff_h());
+        let angle_in_tangent_plane = options.angle_in_tangent_plane;
+        let offset_angle_vector = vec2::from_values(angle_in_tangent_plane.cos(), angle_in_tangent_plane.sin());
+        let base_offsets = pattern.offsets(sample_count);
+        let pixel_center_offset = vec2::from_values(0.5, 0.5);
+        canvas.pixels_mut().par_iter_mut().enumerate().for_each(|(index, pixel)| {
+            let pixel_coord = PixelCoord::from_index(index, width);
+            let offsets = pattern.offsets_for_pixel(&base_offsets, &pixel_coord);
+            let hits: Vec<(Vec2, PixelProperties, Vec2)> = offsets
+                .iter()
+                .filter_map(|offset| {
+                    let canvas_point = vec2::from_values(pixel_coord.x as f32 + offset.0, pixel_coord.y as f32 + offset.1);
+                    Self::march_sample(
                         ray_marcher,
+                        scene,
                         width,
                         height,
-                        &p,
-                        &normal,
-                        &material.light_source,
-                        &offset_angle_vector
-                    );
-                    pixel.lightness = lightness;
-                    pixel.direction = direction;
-                    pixel.depth = depth;
-                    pixel.bg_hsl = material.bg_hsl;
-                    pixel.is_shaded = material.is_shaded;
-                    pixel.is_hatched = material.is_hatched;
-                }
-            });
+                        &canvas_point,
+                        normal_finite_diff_h,
+                        options.direction_source,
+                        &QualitySettings::default(),
+                    )
+                    .ok()
+                    .map(|(sample, (p, normal, direction_reference))| {
+                        let direction_vec = Self::world_to_canvas_direction(
+                            ray_marcher,
+                            width,
+                            height,
+                            &p,
+                            &normal,
+                            &direction_reference,
+                            &offset_angle_vector,
+                        );
+                        (*offset, sample, direction_vec)
+                    })
+                })
+                .collect();
+            if hits.is_empty() || 2 * hits.len() < offsets.len() {
+                return;
+            }
+
+            let (_, closest, _) = hits
+                .iter()
+                .min_by(|a, b| vec2::dist(&a.0, &pixel_center_offset).total_cmp(&vec2::dist(&b.0, &pixel_center_offset)))
+                .unwrap();
+            *pixel = *closest;
+            let hit_count = hits.len() as f32;
+            pixel.lightness = hits.iter().map(|(_, sample, _)| sample.lightness).sum::<f32>() / hit_count;
+            pixel.specular = hits.iter().map(|(_, sample, _)| sample.specular).sum::<f32>() / hit_count;
+            let direction_sum = hits
+                .iter()
+                .filter(|(_, _, direction_vec)| !direction_vec.0.is_nan())
+                .fold(vec2::from_values(0.0, 0.0), |sum, (_, _, direction_vec)| vec2::add(&sum, direction_vec));
+            pixel.direction_vec = if vec2::len(&direction_sum) > 0.0 {
+                vec2::normalize_inplace(direction_sum)
+            } else {
+                vec2::from_values(f32::NAN, f32::NAN)
+            };
+        });
+        canvas.fill_background(ray_marcher, &options.background);
         canvas
     }
 
@@ -237,12 +929,12 @@ impl PixelPropertyCanvas {
             .par_iter_mut()
             .enumerate()
             .for_each(|(index, pixel)| {
-                let (i_x, i_y) = Self::pixel_coordinates_wh(width, index);
+                let pixel_center = PixelCoord::from_index(index, width).center();
                 let screen_coordinates = Self::to_screen_coordinates_wh(
                     width,
                     height,
-                    i_x as f32 + 0.5,
-                    i_y as f32 + 0.5,
+                    pixel_center.0,
+                    pixel_center.1,
                 );
                 let intersection = ray_marcher.intersection_with_heightmap(heightmap, &screen_coordinates);
                 if intersection.is_some() {
@@ -265,16 +957,103 @@ impl PixelPropertyCanvas {
                         &offset_angle_vector
                     );
                     pixel.lightness = lightness;
-                    pixel.direction = direction;
+                    pixel.direction_vec = direction;
                     pixel.depth = depth;
                     pixel.bg_hsl = material.bg_hsl;
                     pixel.is_shaded = material.is_shaded;
                     pixel.is_hatched = material.is_hatched;
+                    pixel.is_streamlined = material.is_streamlined;
+                    pixel.material_id = Some(material.id);
+                    pixel.coverage = 1.0;
+                    pixel.normal = ray_marcher.to_camera_direction(&normal);
                 }
             });
         canvas
     }
 
+    pub fn apply_horizon_ambient_occlusion(
+        &mut self,
+        radius: f32,
+        azimuth_count: u32,
+        depth_scale: f32,
+        strength: f32,
+    ) {
+        // Screen-space horizon-based ambient occlusion: treat the negated depth as a height field and, for each pixel,
+        // walk along azimuth_count directions up to radius pixels to find the highest horizon. depth_scale converts
+        // depth differences to pixels. The lightness is scaled by 1 - strength * (mean sine of the horizon angles).
+        let width = self.width;
+        let height = self.height;
+        let depth_at = |data: &[PixelProperties], x: i32, y: i32| {
+            if x < 0 || y < 0 || x >= width as i32 || y >= height as i32 {
+                f32::NAN
+            } else {
+                data[PixelCoord::new(x as u32, y as u32).to_index(width)].depth
+            }
+        };
+        let step_count = radius.ceil().max(1.0) as u32;
+        let occlusion: Vec<f32> = (0..self.data.len()).into_par_iter().map(|index| {
+            let depth = self.data[index].depth;
+            if depth.is_nan() {
+                return 0.0;
+            }
+            let pixel_center = PixelCoord::from_index(index, width).center();
+            let sum_of_horizon_sines: f32 = (0..azimuth_count).map(|i| {
+                let dir = vec2::polar_angle_to_unit_vector(2.0 * std::f32::consts::PI * (i as f32) / (azimuth_count as f32));
+                (1..=step_count).fold(0.0f32, |max_sine, step| {
+                    let dist = radius * (step as f32) / (step_count as f32);
+                    let p = vec2::scale_and_add(&pixel_center, &dir, dist);
+                    let sample_depth = depth_at(&self.data, p.0.floor() as i32, p.1.floor() as i32);
+                    if sample_depth.is_nan() {
+                        return max_sine;
+                    }
+                    let elevation = depth_scale * (depth - sample_depth);
+                    max_sine.max(elevation / (elevation * elevation + dist * dist).sqrt())
+                })
+            }).sum();
+            sum_of_horizon_sines / azimuth_count.max(1) as f32
+        }).collect();
+        self.data.iter_mut().zip(occlusion.iter()).for_each(|(pixel, occlusion)| {
+            pixel.lightness *= 1.0 - strength * occlusion;
+        });
+    }
+
+    pub fn bilateral_smooth_lightness(&mut self, spatial_sigma: f32, range_sigma: f32) {
+        // Each pixel becomes the mean of its neighbors weighted by both their distance and their difference in
+        // lightness, so noise in flat regions averages out while steps much larger than range_sigma stay sharp.
+        // Pixels without a hit neither receive nor contribute lightness.
+        let mut float_canvas = FloatCanvas::new(self.width, self.height, 1);
+        self.to_float_canvas_layer(&mut float_canvas, 0, |pixel| pixel.lightness);
+        let radius = (3.0 * spatial_sigma).ceil() as i32;
+        let spatial_factor = -0.5 / (spatial_sigma * spatial_sigma);
+        let range_factor = -0.5 / (range_sigma * range_sigma);
+        let width = self.width;
+        let smoothed: Vec<f32> = (0..self.data.len()).into_par_iter().map(|index| {
+            let PixelCoord { x, y } = PixelCoord::from_index(index, width);
+            let center = float_canvas.value(x, y, 0);
+            if center.is_nan() {
+                return center;
+            }
+            let mut weighted_sum = 0.0;
+            let mut weight_sum = 0.0;
+            for ky in -radius..=radius {
+                for kx in -radius..=radius {
+                    let neighbor = float_canvas.value_at_reflected(x as i32 + kx, y as i32 + ky, 0);
+                    if neighbor.is_nan() {
+                        continue;
+                    }
+                    let diff = neighbor - center;
+                    let weight = (spatial_factor * (kx * kx + ky * ky) as f32 + range_factor * diff * diff).exp();
+                    weighted_sum += weight * neighbor;
+                    weight_sum += weight;
+                }
+            }
+            weighted_sum / weight_sum
+        }).collect();
+        self.data.iter_mut().zip(smoothed.iter()).for_each(|(pixel, lightness)| {
+            pixel.lightness = *lightness;
+        });
+    }
+
     fn world_to_canvas_direction(
         ray_marcher: &RayMarcher,
         canvas_width: u32,
@@ -283,7 +1062,7 @@ impl PixelPropertyCanvas {
         normal: &Vec3,
         light_source: &Vec3,
         offset_angle_vector: &Vec2
-    ) -> f32 {
+    ) -> Vec2 {
         let tangent_plane_basis = vec3::orthonormal_basis_of_plane(
             normal,
             &vec3::sub(light_source, p),
@@ -296,7 +1075,7 @@ impl PixelPropertyCanvas {
                     offset_angle_vector.1,
                 );
 
-                // Project p +/- h * dir_in_plane onto the canvas, take the polar angle of their difference as the direction
+                // Project p +/- h * dir_in_plane onto the canvas, take their normalized difference as the direction
                 const H: VecFloat = 0.01;
                 let p_plus_dir = vec3::scale_and_add(p, &dir_in_plane, H);
                 let p_plus_dir = ray_marcher.to_screen_coordinates(&p_plus_dir);
@@ -307,26 +1086,18 @@ impl PixelPropertyCanvas {
                 let p_minus_dir =
                     Self::to_canvas_coordinates_wh(canvas_width, canvas_height, &p_minus_dir);
 
-                let dir_vec = vec2::sub(&p_plus_dir, &p_minus_dir);
-                vec2::polar_angle(&dir_vec)
+                vec2::normalize_inplace(vec2::sub(&p_plus_dir, &p_minus_dir))
             }
-            None => f32::NAN,
+            None => vec2::from_values(f32::NAN, f32::NAN),
         }
     }
 
     fn pixel_index(&self, x: u32, y: u32) -> usize {
-        (self.width as usize) * (y as usize) + (x as usize)
-    }
-
-    fn pixel_coordinates_wh(width: u32, index: usize) -> (u32, u32) {
-        (
-            (index % (width as usize)) as u32,
-            (index / (width as usize)) as u32,
-        )
+        PixelCoord::new(x, y).to_index(self.width)
     }
 
-    fn pixel_coordinates(&self, index: usize) -> (u32, u32) {
-        Self::pixel_coordinates_wh(self.width, index)
+    fn pixel_coordinates(&self, index: usize) -> PixelCoord {
+        PixelCoord::from_index(index, self.width)
     }
 
     pub fn pixel_value(&self, x: f32, y: f32) -> Option<PixelProperties> {
@@ -335,13 +1106,40 @@ impl PixelPropertyCanvas {
         }
         let idx = self.pixel_index(x as u32, y as u32);
         let pixel = self.data.get(idx).unwrap();
-        if pixel.lightness.is_nan() || pixel.direction.is_nan() || pixel.depth.is_nan() {
+        if pixel.lightness.is_nan() || pixel.direction().is_nan() || pixel.depth.is_nan() {
             None
         } else {
             Some(*pixel)
         }
     }
 
+    pub fn direction_vec_bilinear(&self, x: f32, y: f32) -> Option<Vec2> {
+        // Interpolate the direction vectors of the pixels surrounding (x, y) (in pixel index coordinates, like
+        // SkiaCanvas::sample_bilinear); unlike interpolating angles, this is continuous across the +/- pi boundary
+        // At the last row or column, the neighbors beyond the border are the border pixels themselves
+        const EPSILON: f32  = 1.0 / 256.0;
+        let x_clamp = x.clamp(0.0, (self.width - 1) as f32);
+        let y_clamp = y.clamp(0.0, (self.height - 1) as f32);
+        let xi = x_clamp as u32;
+        let yi = y_clamp as u32;
+        let xi_next = (xi + 1).min(self.width - 1);
+        let yi_next = (yi + 1).min(self.height - 1);
+        let xf = x_clamp.fract();
+        let yf = y_clamp.fract();
+
+        let d00 = self.data[self.pixel_index(xi, yi)].direction_vec;
+        let d01 = self.data[self.pixel_index(xi_next, yi)].direction_vec;
+        let d10 = self.data[self.pixel_index(xi, yi_next)].direction_vec;
+        let d11 = self.data[self.pixel_index(xi_next, yi_next)].direction_vec;
+
+        let d = vec2::lerp(&vec2::lerp(&d00, &d01, xf), &vec2::lerp(&d10, &d11, xf), yf);
+        if d.0.is_nan() || d.1.is_nan() || vec2::len_squared(&d) < EPSILON * EPSILON {
+            None
+        } else {
+            Some(vec2::normalize_inplace(d))
+        }
+    }
+
     pub fn pixel_at_reflected(&self, x: i32, y: i32) -> &PixelProperties {
         let w = self.width as i32;
         let h = self.height as i32;
@@ -354,6 +1152,17 @@ impl PixelPropertyCanvas {
         &mut self.data
     }
 
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> Result<PixelPropertyCanvas, CanvasError> {
+        check_region(self, x, y, width, height)?;
+        let data = (y..y + height)
+            .flat_map(|py| {
+                let start = self.pixel_index(x, py);
+                self.data[start..start + width as usize].iter().copied()
+            })
+            .collect();
+        Ok(PixelPropertyCanvas { data, width, height })
+    }
+
     pub fn to_float_canvas_layer(&self, float_canvas: &mut FloatCanvas, layer_index: u32, mut f: impl FnMut(&PixelProperties) -> f32) {
         let li = layer_index as usize;
         float_canvas.for_each_pixel_mut(|x, y, pixel_data| {
@@ -380,23 +1189,62 @@ impl PixelPropertyCanvas {
         });
     }
 
-    pub fn bg_to_skia_canvas(&self) -> SkiaCanvas {
-        let rgba_data = self
-            .data
-            .iter()
-            .map(|pixel| {
-                let hsl = if pixel.is_shaded && !pixel.lightness.is_nan() {
-                    vec3::from_values(
+    // If far_hsl is given, blend each pixel's color towards it with increasing depth. Misses that are partially
+    // covered by the scene (cf. PixelProperties::coverage) are blended towards the color of their nearest hit neighbor.
+    pub fn bg_to_skia_canvas(&self, far_hsl: Option<&Vec3>) -> SkiaCanvas {
+        let (min_depth, max_depth) = self.depth_range();
+        let pixel_rgba = |pixel: &PixelProperties| {
+            let hsl = match pixel.specular_hsl {
+                Some(specular_hsl) if pixel.is_shaded && !pixel.lightness.is_nan() => {
+                    // Shade with the non-specular light only and blend the highlight toward the specular color
+                    let surface_hsl = vec3::from_values(
                         pixel.bg_hsl.0,
                         pixel.bg_hsl.1,
-                        (pixel.bg_hsl.2 * pixel.lightness).clamp(0.0, 1.0),
-                    )
-                } else {
-                    pixel.bg_hsl
-                };
-                vec3::hsl_to_rgba_u8(&hsl)
+                        (pixel.bg_hsl.2 * (pixel.lightness - pixel.specular)).clamp(0.0, 1.0),
+                    );
+                    vec3::lerp_hsl(&surface_hsl, &specular_hsl, pixel.specular.clamp(0.0, 1.0))
+                }
+                _ if pixel.is_shaded && !pixel.lightness.is_nan() => vec3::from_values(
+                    pixel.bg_hsl.0,
+                    pixel.bg_hsl.1,
+                    (pixel.bg_hsl.2 * pixel.lightness).clamp(0.0, 1.0),
+                ),
+                _ => pixel.bg_hsl,
+            };
+            let hsl = match far_hsl {
+                Some(far_hsl) if !pixel.depth.is_nan() && max_depth > min_depth => {
+                    let normalized_depth = (pixel.depth - min_depth) / (max_depth - min_depth);
+                    vec3::lerp_hsl(&hsl, far_hsl, normalized_depth)
+                }
+                _ => hsl,
+            };
+            vec3::hsl_to_rgba_u8(&hsl)
+        };
+        let rgba_data = self
+            .data
+            .iter()
+            .enumerate()
+            .flat_map(|(index, pixel)| {
+                let rgba = pixel_rgba(pixel);
+                if !pixel.depth.is_nan() || pixel.coverage <= 0.0 {
+                    return rgba;
+                }
+                let PixelCoord { x, y } = self.pixel_coordinates(index);
+                let nearest_hit = (-1..=1)
+                    .flat_map(|dy| (-1..=1).map(move |dx| (x as i32 + dx, y as i32 + dy)))
+                    .filter(|&(nx, ny)| nx >= 0 && ny >= 0 && nx < self.width as i32 && ny < self.height as i32)
+                    .map(|(nx, ny)| &self.data[self.pixel_index(nx as u32, ny as u32)])
+                    .filter(|neighbor| !neighbor.depth.is_nan())
+                    .min_by(|a, b| a.depth.total_cmp(&b.depth));
+                match nearest_hit {
+                    Some(neighbor) => {
+                        let neighbor_rgba = pixel_rgba(neighbor);
+                        let coverage = pixel.coverage.min(1.0);
+                        std::array::from_fn(|i| (rgba[i] as f32 + coverage * (neighbor_rgba[i] as f32 - rgba[i] as f32)).round() as u8)
+                    }
+                    None => rgba,
+                }
             })
-            .flatten()
             .collect();
         SkiaCanvas::from_rgba(rgba_data, self.width, self.height)
     }
@@ -418,16 +1266,33 @@ impl PixelPropertyCanvas {
         SkiaCanvas::from_rgba(rgba_data, self.width, self.height)
     }
 
+    // False-color/duotone variant of lightness_to_skia_canvas: lightness in [0, 1] is mapped through the gradient
+    pub fn lightness_to_skia_canvas_gradient(&self, gradient: &LinearGradient) -> SkiaCanvas {
+        let rgba_data = self
+            .data
+            .iter()
+            .flat_map(|pixel| {
+                if pixel.lightness.is_nan() {
+                    Self::NAN_RGBA_VALUE
+                } else {
+                    let [r, g, b] = gradient.rgb(pixel.lightness.clamp(0.0, 1.0));
+                    [r, g, b, 255]
+                }
+            })
+            .collect();
+        SkiaCanvas::from_rgba(rgba_data, self.width, self.height)
+    }
+
     pub fn direction_to_skia_canvas(&self) -> SkiaCanvas {
         let rgba_data = self
             .data
             .iter()
             .map(|pixel| {
-                if pixel.direction.is_nan() {
+                if pixel.direction().is_nan() {
                     Self::NAN_RGBA_VALUE
                 } else {
                     const PI2: f32 = 2.0 * std::f32::consts::PI;
-                    let mut normalized_dir = pixel.direction % PI2;
+                    let mut normalized_dir = pixel.direction() % PI2;
                     if normalized_dir < 0.0 {
                         normalized_dir += PI2;
                     }
@@ -440,8 +1305,55 @@ impl PixelPropertyCanvas {
         SkiaCanvas::from_rgba(rgba_data, self.width, self.height)
     }
 
-    pub fn depth_to_skia_canvas(&self) -> SkiaCanvas {
-        let (min_depth, max_depth) = self.data.iter().fold(
+    // Light-independent shading by looking up the color of each hit in a matcap ("material capture"), i.e., an image
+    // of a lit sphere seen head-on: the normal's x and y in camera coordinates span the inscribed circle of the image
+    pub fn shade_matcap(&self, matcap: &SkiaCanvas) -> SkiaCanvas {
+        let max_x = (matcap.width() - 1) as f32;
+        let max_y = (matcap.height() - 1) as f32;
+        let rgba_data = self
+            .data
+            .iter()
+            .flat_map(|pixel| {
+                if pixel.normal.0.is_nan() {
+                    Self::NAN_RGBA_VALUE
+                } else {
+                    let color = matcap.sample_bilinear(
+                        (0.5 + 0.5 * pixel.normal.0) * max_x,
+                        (0.5 - 0.5 * pixel.normal.1) * max_y,
+                    ).demultiply();
+                    [color.red(), color.green(), color.blue(), 255]
+                }
+            })
+            .collect();
+        SkiaCanvas::from_rgba(rgba_data, self.width, self.height)
+    }
+
+    // Number of pixels that show each material (by Material::id), e.g., to balance a composition or to estimate how
+    // much of the page each pen covers
+    pub fn material_histogram(&self) -> HashMap<u32, usize> {
+        let mut histogram = HashMap::new();
+        for material_id in self.data.iter().filter_map(|pixel| pixel.material_id) {
+            *histogram.entry(material_id).or_insert(0) += 1;
+        }
+        histogram
+    }
+
+    // Number of hit pixels per lightness bin, with bin_count bins of equal width covering [0, 1]; lightnesses
+    // outside of [0, 1] count toward the first or last bin
+    pub fn lightness_histogram(&self, bin_count: u32) -> Vec<usize> {
+        let mut histogram = vec![0; bin_count as usize];
+        if bin_count == 0 {
+            return histogram;
+        }
+        for lightness in self.data.iter().map(|pixel| pixel.lightness).filter(|lightness| !lightness.is_nan()) {
+            let bin = ((lightness * bin_count as f32) as i64).clamp(0, bin_count as i64 - 1);
+            histogram[bin as usize] += 1;
+        }
+        histogram
+    }
+
+    pub(crate) fn depth_range(&self) -> (f32, f32) {
+        self.data.iter().fold(
             (std::f32::INFINITY, std::f32::NEG_INFINITY),
             |(min_acc, max_acc), pixel| {
                 if pixel.depth.is_nan() {
@@ -450,7 +1362,11 @@ impl PixelPropertyCanvas {
                     (min_acc.min(pixel.depth), max_acc.max(pixel.depth))
                 }
             },
-        );
+        )
+    }
+
+    pub fn depth_to_skia_canvas(&self) -> SkiaCanvas {
+        let (min_depth, max_depth) = self.depth_range();
         let rgba_data = self
             .data
             .iter()
@@ -469,6 +1385,9 @@ impl PixelPropertyCanvas {
     }
 }
 
+// Stack of layer_count float images of the same size. The values of all layers of a pixel are stored contiguously,
+// pixels are stored in row-major order. Layers typically hold the inputs and intermediate results of an image
+// operator, e.g., a property of a PixelPropertyCanvas (cf. to_float_canvas_layer) and its convolutions.
 pub struct FloatCanvas {
     width: u32,
     height: u32,
@@ -499,7 +1418,20 @@ impl FloatCanvas {
     }
 
     fn value_index(&self, x: u32, y: u32, layer_index: u32) -> usize {
-        ((self.width as usize) * (y as usize) + (x as usize)) * (self.layer_count as usize) + (layer_index as usize)
+        PixelCoord::new(x, y).to_index(self.width) * (self.layer_count as usize) + (layer_index as usize)
+    }
+
+    pub fn layer_count(&self) -> u32 {
+        self.layer_count
+    }
+
+    pub fn value(&self, x: u32, y: u32, layer_index: u32) -> f32 {
+        self.data[self.value_index(x, y, layer_index)]
+    }
+
+    pub fn set_value(&mut self, x: u32, y: u32, layer_index: u32, value: f32) {
+        let index = self.value_index(x, y, layer_index);
+        self.data[index] = value;
     }
 
     pub fn for_each_pixel(&self, mut f: impl FnMut(u32, u32, &[f32])) {
@@ -534,6 +1466,7 @@ impl FloatCanvas {
         self.data[self.value_index(x, y, layer_index)]
     }
 
+    // Adds the convolution of the source layer to the target layer; borders are handled by reflection
     pub fn convolve_layer(&mut self, source_layer_index: u32, target_layer_index: u32, kernel: &Kernel) {
         assert!(kernel.size() % 2 == 1, "Kernel size must be odd");
         let hk = (kernel.size() / 2) as i32; // == (kernel_size - 1) / 2
@@ -555,6 +1488,37 @@ impl FloatCanvas {
         }
     }
 
+    pub fn gaussian_blur_layer(&mut self, source_layer_index: u32, target_layer_index: u32, sigma: f32) {
+        self.convolve_layer(source_layer_index, target_layer_index, &Kernel::gaussian(sigma));
+    }
+
+    // Debug view of a layer: its values are normalized to [0, 1] (by their finite min and max) and shown in grayscale or
+    // through the colormap; non-finite values are shown in magenta
+    pub fn layer_to_skia_canvas(&self, layer_index: u32, colormap: Option<&LinearGradient>) -> SkiaCanvas {
+        let values = || self.data.iter().skip(layer_index as usize).step_by(self.layer_count as usize);
+        let (min_value, max_value) = values()
+            .filter(|v| v.is_finite())
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), v| (lo.min(*v), hi.max(*v)));
+        let range = max_value - min_value;
+        let rgba_data: Vec<u8> = values()
+            .flat_map(|v| {
+                if !v.is_finite() {
+                    return PixelPropertyCanvas::NAN_RGBA_VALUE;
+                }
+                let t = if range > 0.0 { (v - min_value) / range } else { 0.0 };
+                let [r, g, b] = match colormap {
+                    Some(gradient) => gradient.rgb(t),
+                    None => {
+                        let l = (t * 255.0).round() as u8;
+                        [l, l, l]
+                    }
+                };
+                [r, g, b, 255]
+            })
+            .collect();
+        SkiaCanvas::from_rgba(rgba_data, self.width, self.height)
+    }
+
     pub fn to_skia_canvas(&self, mut f: impl FnMut(&[f32]) -> [u8; 4]) -> SkiaCanvas {
         let rgba_data: Vec<u8> = self.data
             .chunks(self.layer_count as usize)
@@ -567,6 +1531,7 @@ impl FloatCanvas {
 
 pub struct SkiaCanvas {
     pixmap: Pixmap,
+    vertical_stretch: f32, // = dpi_y / dpi_x
 }
 
 impl Canvas for SkiaCanvas {
@@ -581,15 +1546,38 @@ impl Canvas for SkiaCanvas {
 
 impl SkiaCanvas {
     pub fn new(width: u32, height: u32) -> SkiaCanvas {
+        Self::new_filled(width, height, &[255, 255, 255, 255])
+    }
+
+    pub fn new_filled(width: u32, height: u32, rgba: &[u8; 4]) -> SkiaCanvas {
+        let mut pixmap = Pixmap::new(width, height).unwrap();
+        pixmap.fill(Color::from_rgba8(rgba[0], rgba[1], rgba[2], rgba[3]));
+        SkiaCanvas { pixmap, vertical_stretch: 1.0 }
+    }
+
+    pub fn new_transparent(width: u32, height: u32) -> SkiaCanvas {
         let pixmap = Pixmap::new(width, height).unwrap();
-        let mut canvas = SkiaCanvas { pixmap };
-        canvas.fill(&[255, 255, 255]);
-        canvas
+        SkiaCanvas { pixmap, vertical_stretch: 1.0 }
     }
 
     pub fn from_rgba(rgba_data: Vec<u8>, width: u32, height: u32) -> SkiaCanvas {
         let pixmap = Pixmap::from_vec(rgba_data, IntSize::from_wh(width, height).unwrap()).unwrap();
-        SkiaCanvas { pixmap }
+        SkiaCanvas { pixmap, vertical_stretch: 1.0 }
+    }
+
+    // For devices with non-square pixels: stroke widths and point radii are then given in horizontal pixels
+    // and stretched vertically so that they stay round on the device
+    pub fn set_dpi(&mut self, dpi_x: f32, dpi_y: f32) {
+        self.vertical_stretch = dpi_y / dpi_x;
+    }
+
+    // Map a path into a space with square pixels, along with the transform that maps it back onto the pixmap
+    fn to_isotropic(&self, path: &Path) -> (Path, Transform) {
+        if self.vertical_stretch == 1.0 {
+            return (path.clone(), Transform::identity());
+        }
+        let path = path.clone().transform(Transform::from_scale(1.0, 1.0 / self.vertical_stretch)).unwrap();
+        (path, Transform::from_scale(1.0, self.vertical_stretch))
     }
 
     pub fn to_u32_rgb(&self) -> Vec<u32> {
@@ -643,6 +1631,149 @@ impl SkiaCanvas {
         }
     }
 
+    pub fn unsharp_mask(&mut self, sigma: f32, amount: f32) {
+        // layers 0-2: rgb, layers 3-5: blurred rgb
+        let mut float_canvas = FloatCanvas::new(self.width(), self.height(), 6);
+        let rgba_data = self.pixmap.data();
+        float_canvas.for_each_pixel_mut(|x, y, pixel_data| {
+            let base_index = 4 * (y as usize * self.pixmap.width() as usize + x as usize);
+            for c in 0..3 {
+                pixel_data[c] = rgba_data[base_index + c] as f32;
+            }
+        });
+        for c in 0..3 {
+            float_canvas.gaussian_blur_layer(c, 3 + c, sigma);
+        }
+        self.iter_mut_rgba_with_coordinates(|x, y, rgba| {
+            for c in 0..3 {
+                let value = float_canvas.data[float_canvas.value_index(x, y, c)];
+                let blurred = float_canvas.data[float_canvas.value_index(x, y, 3 + c)];
+                rgba[c as usize] = (value + amount * (value - blurred)).round().clamp(0.0, 255.0) as u8;
+            }
+        });
+    }
+
+    fn nearest_palette_color(palette: &[[u8; 3]], rgb: &[f32; 3]) -> [u8; 3] {
+        *palette.iter().min_by(|a, b| {
+            let dist_squared = |c: &[u8; 3]| (0..3).map(|i| (c[i] as f32 - rgb[i]).powi(2)).sum::<f32>();
+            dist_squared(a).total_cmp(&dist_squared(b))
+        }).unwrap()
+    }
+
+    pub fn quantize_to_palette(&mut self, palette: &[[u8; 3]], dither: bool) {
+        if palette.is_empty() {
+            return;
+        }
+        if !dither {
+            self.iter_mut_rgba_with_coordinates(|_, _, rgba| {
+                let nearest = Self::nearest_palette_color(palette, &[rgba[0] as f32, rgba[1] as f32, rgba[2] as f32]);
+                rgba[..3].copy_from_slice(&nearest);
+            });
+            return;
+        }
+
+        // Floyd-Steinberg dithering: distribute the quantization error of each pixel onto its unvisited neighbors
+        let w = self.width() as usize;
+        let h = self.height() as usize;
+        let mut rgb_data: Vec<f32> = self.pixmap.data()
+            .chunks_exact(4)
+            .flat_map(|rgba| [rgba[0] as f32, rgba[1] as f32, rgba[2] as f32])
+            .collect();
+        let diffuse_error = |rgb_data: &mut [f32], x: isize, y: usize, error: &[f32; 3], weight: f32| {
+            if x >= 0 && (x as usize) < w && y < h {
+                let base_index = 3 * (y * w + x as usize);
+                for c in 0..3 {
+                    rgb_data[base_index + c] += weight * error[c];
+                }
+            }
+        };
+        for iy in 0..h {
+            for ix in 0..w {
+                let base_index = 3 * (iy * w + ix);
+                let rgb = [rgb_data[base_index], rgb_data[base_index + 1], rgb_data[base_index + 2]];
+                let nearest = Self::nearest_palette_color(palette, &rgb);
+                let error = [
+                    rgb[0] - nearest[0] as f32,
+                    rgb[1] - nearest[1] as f32,
+                    rgb[2] - nearest[2] as f32,
+                ];
+                for c in 0..3 {
+                    rgb_data[base_index + c] = nearest[c] as f32;
+                }
+                let x = ix as isize;
+                diffuse_error(&mut rgb_data, x + 1, iy, &error, 7.0 / 16.0);
+                diffuse_error(&mut rgb_data, x - 1, iy + 1, &error, 3.0 / 16.0);
+                diffuse_error(&mut rgb_data, x, iy + 1, &error, 5.0 / 16.0);
+                diffuse_error(&mut rgb_data, x + 1, iy + 1, &error, 1.0 / 16.0);
+            }
+        }
+        self.iter_mut_rgba_with_coordinates(|x, y, rgba| {
+            let base_index = 3 * (y as usize * w + x as usize);
+            for c in 0..3 {
+                rgba[c] = rgb_data[base_index + c] as u8;
+            }
+        });
+    }
+
+    pub fn separate_by_color(&self, palette: &[[u8; 3]]) -> Vec<SkiaCanvas> {
+        // One layer per pen: opaque in the pen's color where the pixel matches it exactly, transparent elsewhere
+        palette.iter().map(|rgb| {
+            let mut layer = SkiaCanvas::new_transparent(self.width(), self.height());
+            let source_data = self.pixmap.data();
+            layer.iter_mut_rgba_with_coordinates(|x, y, rgba| {
+                let base_index = 4 * (y as usize * self.pixmap.width() as usize + x as usize);
+                let source_rgba = &source_data[base_index..base_index + 4];
+                if source_rgba[3] > 0 && source_rgba[..3] == rgb[..] {
+                    rgba.copy_from_slice(&[rgb[0], rgb[1], rgb[2], 255]);
+                }
+            });
+            layer
+        }).collect()
+    }
+
+    // Build a canvas of the given size whose pixel (x, y) is copied from the source pixel source_coord(x, y)
+    fn remapped(&self, width: u32, height: u32, vertical_stretch: f32, source_coord: impl Fn(u32, u32) -> (u32, u32)) -> SkiaCanvas {
+        let source_data = self.pixmap.data();
+        let source_width = self.pixmap.width() as usize;
+        let rgba_data: Vec<u8> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                let (sx, sy) = source_coord(x, y);
+                let base_index = 4 * (sy as usize * source_width + sx as usize);
+                [source_data[base_index], source_data[base_index + 1], source_data[base_index + 2], source_data[base_index + 3]]
+            })
+            .collect();
+        let mut canvas = SkiaCanvas::from_rgba(rgba_data, width, height);
+        canvas.vertical_stretch = vertical_stretch;
+        canvas
+    }
+
+    // Rotate clockwise by times * 90 degrees, e.g., to match the orientation of the paper on a plotter bed
+    pub fn rotate90(&self, times: u32) -> SkiaCanvas {
+        let (w, h) = (self.width(), self.height());
+        match times % 4 {
+            0 => self.remapped(w, h, self.vertical_stretch, |x, y| (x, y)),
+            1 => self.remapped(h, w, 1.0 / self.vertical_stretch, |x, y| (y, h - 1 - x)),
+            2 => self.remapped(w, h, self.vertical_stretch, |x, y| (w - 1 - x, h - 1 - y)),
+            _ => self.remapped(h, w, 1.0 / self.vertical_stretch, |x, y| (w - 1 - y, x)),
+        }
+    }
+
+    pub fn flip_horizontal(&self) -> SkiaCanvas {
+        let w = self.width();
+        self.remapped(w, self.height(), self.vertical_stretch, |x, y| (w - 1 - x, y))
+    }
+
+    pub fn flip_vertical(&self) -> SkiaCanvas {
+        let h = self.height();
+        self.remapped(self.width(), h, self.vertical_stretch, |x, y| (x, h - 1 - y))
+    }
+
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> Result<SkiaCanvas, CanvasError> {
+        check_region(self, x, y, width, height)?;
+        Ok(self.remapped(width, height, self.vertical_stretch, |cx, cy| (x + cx, y + cy)))
+    }
+
     pub fn fill(&mut self, rgb: &[u8; 3]) {
         self.pixmap.fill(Color::from_rgba8(rgb[0], rgb[1], rgb[2], 255));
     }
@@ -660,14 +1791,14 @@ impl SkiaCanvas {
 
     pub fn fill_point(&mut self, x: f32, y: f32, radius: f32, rgb: &[u8; 3]) {
         let mut pb = PathBuilder::new();
-        pb.push_circle(x, y, radius);
+        pb.push_circle(0.0, 0.0, radius);
         let path = pb.finish().unwrap();
 
         let mut paint = Paint::default();
         paint.set_color_rgba8(rgb[0], rgb[1], rgb[2], 255);
         paint.anti_alias = true;
 
-        let transform = Transform::identity();
+        let transform = Transform::from_row(1.0, 0.0, 0.0, self.vertical_stretch, x, y);
         self.pixmap.fill_path(&path, &paint, FillRule::Winding, transform, None);
     }
 
@@ -678,7 +1809,7 @@ impl SkiaCanvas {
 
         let mut pb = PathBuilder::new();
         for p in points {
-            pb.push_circle(p.0, p.1, radius);
+            pb.push_circle(p.0, p.1 / self.vertical_stretch, radius);
         }
         let path = pb.finish().unwrap();
 
@@ -686,7 +1817,7 @@ impl SkiaCanvas {
         paint.set_color_rgba8(rgb[0], rgb[1], rgb[2], 255);
         paint.anti_alias = true;
 
-        let transform = Transform::identity();
+        let transform = Transform::from_scale(1.0, self.vertical_stretch);
         self.pixmap.fill_path(&path, &paint, FillRule::Winding, transform, None);
     }
 
@@ -740,26 +1871,31 @@ impl SkiaCanvas {
     }
 
     pub fn stroke_path(&mut self, path: &Path, width: f32, rgb: &[u8; 3]) {
+        self.stroke_path_with_cap(path, width, rgb, LineCap::Round);
+    }
+
+    pub fn stroke_path_with_cap(&mut self, path: &Path, width: f32, rgb: &[u8; 3], line_cap: LineCap) {
         let mut paint = Paint::default();
         paint.set_color_rgba8(rgb[0], rgb[1], rgb[2], 255);
         paint.anti_alias = true;
 
         let mut stroke = Stroke::default();
         stroke.width = width;
-        stroke.line_cap = LineCap::Round;
+        stroke.line_cap = line_cap;
         stroke.line_join = LineJoin::Round;
 
-        let transform = Transform::identity();
-        self.pixmap.stroke_path(path, &paint, &stroke, transform, None);
+        let (path, transform) = self.to_isotropic(path);
+        self.pixmap.stroke_path(&path, &paint, &stroke, transform, None);
     }
 
-    pub fn fill_path(&mut self, path: &Path, rgb: &[u8; 3]) {
+    // With FillRule::EvenOdd, regions that a self-intersecting path covers twice stay empty
+    pub fn fill_path(&mut self, path: &Path, rgb: &[u8; 3], fill_rule: FillRule) {
         let mut paint = Paint::default();
         paint.set_color_rgba8(rgb[0], rgb[1], rgb[2], 255);
         paint.anti_alias = true;
 
         let transform = Transform::identity();
-        self.pixmap.fill_path(path, &paint, FillRule::Winding, transform, None);
+        self.pixmap.fill_path(path, &paint, fill_rule, transform, None);
     }
 
     pub fn stroke_line(&mut self, x0: f32, y0: f32, x1: f32, y1: f32, width: f32, rgb: &[u8; 3]) {
@@ -770,10 +1906,59 @@ impl SkiaCanvas {
         self.stroke_path(&path, width, rgb);
     }
 
-    pub fn save_png(&self, path: &std::path::Path) {
-        self.pixmap.save_png(path).unwrap();
+    // Stroke a polyline whose width and color vary along it: each segment is split into pieces of about one pixel
+    // that interpolate between the values at the segment's endpoints
+    pub fn stroke_polyline_colored(&mut self, points: &[Vec2], widths: &[f32], colors: &[[u8; 3]]) {
+        assert!(
+            points.len() == widths.len() && points.len() == colors.len(),
+            "points, widths, and colors must have the same length"
+        );
+        let to_vec3 = |rgb: &[u8; 3]| vec3::from_values(rgb[0] as VecFloat, rgb[1] as VecFloat, rgb[2] as VecFloat);
+        for i in 1..points.len() {
+            let (p0, p1) = (&points[i - 1], &points[i]);
+            let (c0, c1) = (to_vec3(&colors[i - 1]), to_vec3(&colors[i]));
+            let piece_count = vec2::dist(p0, p1).ceil().max(1.0) as u32;
+            for piece in 0..piece_count {
+                let t0 = piece as f32 / piece_count as f32;
+                let t1 = (piece + 1) as f32 / piece_count as f32;
+                let t_mid = 0.5 * (t0 + t1);
+                let q0 = vec2::lerp(p0, p1, t0);
+                let q1 = vec2::lerp(p0, p1, t1);
+                let width = widths[i - 1] + t_mid * (widths[i] - widths[i - 1]);
+                let c = vec3::lerp(&c0, &c1, t_mid);
+                let rgb = [c.0.round() as u8, c.1.round() as u8, c.2.round() as u8];
+                self.stroke_line(q0.0, q0.1, q1.0, q1.1, width, &rgb);
+            }
+        }
     }
 
+    #[cfg(feature = "text")]
+    pub fn draw_text(&mut self, font_data: &[u8], text: &str, x: f32, y: f32, size: f32, rgb: &[u8; 3]) -> Result<(), CanvasError> {
+        // (x, y) is the start of the baseline; size is the font size in pixels (em height)
+        let face = ttf_parser::Face::parse(font_data, 0).map_err(|err| CanvasError::Font(err.to_string()))?;
+        let scale = size / face.units_per_em() as f32;
+
+        let mut pb = PathBuilder::new();
+        let mut pen_x = x;
+        for c in text.chars() {
+            let glyph_id = face.glyph_index(c).unwrap_or(ttf_parser::GlyphId(0));
+            let mut builder = GlyphPathBuilder { pb: &mut pb, x: pen_x, y, scale };
+            face.outline_glyph(glyph_id, &mut builder);
+            pen_x += scale * face.glyph_hor_advance(glyph_id).unwrap_or(0) as f32;
+        }
+
+        // Whitespace-only text yields no path
+        if let Some(path) = pb.finish() {
+            self.fill_path(&path, rgb, FillRule::Winding);
+        }
+        Ok(())
+    }
+
+    pub fn save_png(&self, path: &std::path::Path) -> Result<(), CanvasError> {
+        self.pixmap.save_png(path).map_err(|err| CanvasError::Png(err.to_string()))
+    }
+
+    #[cfg(feature = "window")]
     pub fn display_in_window(&self, title: &str) {
         let mut window = Window::new(
             title,
@@ -791,3 +1976,1113 @@ impl SkiaCanvas {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ray_marcher::{EnvironmentGradient, UpAxis};
+    use crate::test_fixtures::{scene_sphere, sphere_ray_marcher, SceneSphere};
+    use assert_approx_eq::assert_approx_eq;
+    use std::f32::consts::PI;
+
+    #[test]
+    fn test_pixel_coord() {
+        let width = 7;
+        let height = 5;
+        for index in 0..(width * height) as usize {
+            let pixel_coord = PixelCoord::from_index(index, width);
+            assert!(pixel_coord.x < width && pixel_coord.y < height);
+            assert_eq!(index, pixel_coord.to_index(width));
+        }
+        assert_eq!(PixelCoord::new(6, 0), PixelCoord::from_index(6, width));
+        assert_eq!(PixelCoord::new(0, 1), PixelCoord::from_index(7, width));
+        assert_eq!(vec2::from_values(6.5, 0.5), PixelCoord::new(6, 0).center());
+
+        let canvas = PixelPropertyCanvas::new(width, height);
+        assert_eq!(PixelCoord::new(3, 2), canvas.pixel_coordinates(canvas.pixel_index(3, 2)));
+    }
+
+    #[test]
+    fn test_float_canvas_custom_kernel() {
+        // A user-defined 3x3 box blur applied to the lightness of a single bright pixel
+        let mut pp_canvas = PixelPropertyCanvas::new(5, 5);
+        for (index, pixel) in pp_canvas.pixels_mut().iter_mut().enumerate() {
+            pixel.lightness = if index == 12 { 9.0 } else { 0.0 };
+        }
+        let box_blur = Kernel::new(3, vec![1.0 / 9.0; 9]);
+        let mut float_canvas = FloatCanvas::new(5, 5, 2);
+        pp_canvas.to_float_canvas_layer(&mut float_canvas, 0, |pp| pp.lightness);
+        float_canvas.convolve_layer(0, 1, &box_blur);
+
+        assert_eq!(2, float_canvas.layer_count());
+        assert_eq!(9.0, float_canvas.value(2, 2, 0));
+        for y in 0..5 {
+            for x in 0..5 {
+                let is_neighbor = (x as i32 - 2).abs() <= 1 && (y as i32 - 2).abs() <= 1;
+                assert_approx_eq!(if is_neighbor { 1.0 } else { 0.0 }, float_canvas.value(x, y, 1));
+            }
+        }
+
+        float_canvas.set_value(0, 0, 1, -1.0);
+        assert_eq!(-1.0, float_canvas.value(0, 0, 1));
+    }
+
+    #[test]
+    fn test_apply_horizon_ambient_occlusion() {
+        let mut canvas = PixelPropertyCanvas::new(30, 15);
+        for (index, pixel) in canvas.pixels_mut().iter_mut().enumerate() {
+            // A plateau with a square depression around (7, 7)
+            let pixel_coord = PixelCoord::from_index(index, 30);
+            let is_in_depression = (pixel_coord.x as i32 - 7).abs() <= 2 && (pixel_coord.y as i32 - 7).abs() <= 2;
+            pixel.lightness = 0.8;
+            pixel.set_direction(0.0);
+            pixel.depth = if is_in_depression { 11.0 } else { 10.0 };
+        }
+        canvas.apply_horizon_ambient_occlusion(4.0, 8, 2.0, 1.0);
+
+        let lightness_at = |x: u32, y: u32| canvas.pixel_value(x as f32, y as f32).unwrap().lightness;
+        assert!(lightness_at(7, 7) < 0.7);
+        assert_approx_eq!(0.8, lightness_at(24, 7));
+    }
+
+    #[test]
+    fn test_float_canvas_layer_to_skia_canvas() {
+        let mut float_canvas = FloatCanvas::new(8, 2, 2);
+        float_canvas.for_each_pixel_mut(|x, y, values| {
+            values[0] = -3.0;
+            values[1] = if y == 1 && x == 0 { f32::NAN } else { 10.0 + 2.0 * x as f32 };
+        });
+
+        let rgb = float_canvas.layer_to_skia_canvas(1, None).to_u32_rgb();
+        let row: Vec<u32> = rgb[..8].iter().map(|rgb| rgb & 0xFF).collect();
+        assert!(row.windows(2).all(|pair| pair[0] < pair[1]));
+        assert_eq!((0x000000, 0xFFFFFF), (rgb[0], rgb[7]));
+        assert!(rgb[0..8].iter().all(|rgb| rgb >> 16 == rgb & 0xFF));
+        assert_eq!(0xFF00FF, rgb[8]);
+
+        let gradient = LinearGradient::new(&[0, 0, 255], &[255, 0, 0]);
+        let rgb = float_canvas.layer_to_skia_canvas(1, Some(&gradient)).to_u32_rgb();
+        assert_eq!((0x0000FF, 0xFF0000), (rgb[0], rgb[7]));
+
+        let constant = float_canvas.layer_to_skia_canvas(0, None).to_u32_rgb();
+        assert!(constant.iter().all(|rgb| *rgb == 0x000000));
+    }
+
+    #[test]
+    fn test_bilateral_smooth_lightness() {
+        // A step from 0.2 to 0.8 at x = 20, both sides overlaid with a +/- 0.05 checkerboard noise
+        let width = 40;
+        let mut canvas = PixelPropertyCanvas::new(width, 20);
+        for (index, pixel) in canvas.pixels_mut().iter_mut().enumerate() {
+            let pixel_coord = PixelCoord::from_index(index, width);
+            let noise = if (pixel_coord.x + pixel_coord.y).is_multiple_of(2) { 0.05 } else { -0.05 };
+            pixel.lightness = if pixel_coord.x < 20 { 0.2 } else { 0.8 } + noise;
+            pixel.set_direction(0.0);
+            pixel.depth = 1.0;
+        }
+        canvas.bilateral_smooth_lightness(2.0, 0.15);
+
+        let lightness_at = |x: u32, y: u32| canvas.pixel_value(x as f32, y as f32).unwrap().lightness;
+        for (x, expected) in [(5, 0.2), (10, 0.2), (30, 0.8), (35, 0.8)] {
+            for y in 8..10 {
+                assert_approx_eq!(expected, lightness_at(x, y), 0.01);
+            }
+        }
+        let step = lightness_at(20, 10) - lightness_at(19, 10);
+        assert_approx_eq!(0.6, step, 0.1);
+        assert!(lightness_at(19, 10) < 0.3 && lightness_at(20, 10) > 0.7);
+    }
+
+    #[test]
+    fn test_bg_to_skia_canvas_depth_ramp() {
+        let mut canvas = PixelPropertyCanvas::new(2, 1);
+        for (index, pixel) in canvas.pixels_mut().iter_mut().enumerate() {
+            pixel.lightness = 1.0;
+            pixel.set_direction(0.0);
+            pixel.depth = if index == 0 { 1.0 } else { 10.0 };
+            pixel.bg_hsl = vec3::from_values(0.0, 1.0, 0.5);
+        }
+
+        let rgb = canvas.bg_to_skia_canvas(None).to_u32_rgb();
+        assert_eq!(rgb[0], rgb[1]);
+
+        let far_hsl = vec3::from_values(0.0, 0.0, 1.0);
+        let rgb = canvas.bg_to_skia_canvas(Some(&far_hsl)).to_u32_rgb();
+        assert_eq!(0xFF0000, rgb[0]);
+        assert_eq!(0xFFFFFF, rgb[1]);
+    }
+
+    #[test]
+    fn test_baked_ambient_occlusion() {
+        struct SceneSphereOnFloor {
+            material: Material,
+        }
+
+        impl Scene for SceneSphereOnFloor {
+            fn eval(&self, p: &Vec3) -> crate::SdfOutput {
+                let sphere = crate::sdf_op::sd_sphere(p, 1.0);
+                let floor = crate::sdf_op::sd_plane(p, &vec3::from_values(0.0, 1.0, 0.0), -1.0);
+                crate::SdfOutput::new(sphere.min(floor), self.material)
+            }
+        }
+
+        let ray_marcher = RayMarcher::new(
+            1.0,
+            &vec3::from_values(0.0, 2.0, 5.0),
+            &vec3::from_values(0.0, -0.5, 0.0),
+            &vec3::from_values(0.0, 1.0, 0.0),
+            60.0,
+            1.0,
+        );
+        // An AO radius large enough to see the crease between sphere and floor
+        let properties = crate::ReflectiveProperties::new(0.1, 0.5, 0.0, 0.8, 1.0, None, None, None, None).with_ao_radius(0.5);
+        let scene = SceneSphereOnFloor {
+            material: Material::new(&vec3::from_values(5.0, 5.0, 5.0), Some(&properties), None, true, true),
+        };
+        let (width, height) = (24, 24);
+        let canvas = PixelPropertyCanvas::from_scene(&ray_marcher, &scene, width, height, &SceneRenderOptions::default());
+        let occluded: Vec<usize> = (0..canvas.data.len()).filter(|&index| canvas.data[index].ao < 0.9).collect();
+        assert!(!occluded.is_empty());
+
+        let properties = &scene.material.reflective_properties;
+        let quality = QualitySettings::default();
+        let other_light = vec3::from_values(-3.0, 4.0, 2.0);
+        for index in occluded {
+            let pixel = &canvas.data[index];
+            let pixel_center = PixelCoord::from_index(index, width).center();
+            let screen_coordinates = PixelPropertyCanvas::to_screen_coordinates_wh(width, height, pixel_center.0, pixel_center.1);
+            let (p, _, _) = ray_marcher.intersection_with_scene(&scene, &screen_coordinates).unwrap();
+            let normal = ray_marcher.scene_normal(&scene, &p);
+            assert_eq!(pixel.ao, ray_marcher.ambient_occlusion(&scene, properties, &p, &normal, &quality));
+
+            // Re-shading with the baked value matches a full evaluation, also for a different light
+            let (lightness, _) = ray_marcher.light_intensity_with_ambient_visibility(&scene, properties, &p, &normal, &scene.material.light_source, pixel.ao, &quality);
+            assert_eq!(pixel.lightness, lightness);
+            let (reshaded, _) = ray_marcher.light_intensity_with_ambient_visibility(&scene, properties, &p, &normal, &other_light, pixel.ao, &quality);
+            let (fresh, _) = ray_marcher.light_intensity_with_quality(&scene, properties, &p, &normal, &other_light, &quality);
+            assert_eq!(fresh, reshaded);
+        }
+        assert!(canvas.data.iter().all(|pixel| !pixel.depth.is_nan() || pixel.ao == 1.0));
+    }
+
+    #[test]
+    fn test_material_histogram() {
+        // A wall facing the camera, made of material 1 on the left and material 2 on the right
+        struct SceneSplitWall {
+            material: Material,
+        }
+
+        impl Scene for SceneSplitWall {
+            fn eval(&self, p: &Vec3) -> crate::SdfOutput {
+                let mut material = self.material;
+                material.id = if p.0 < 0.0 { 1 } else { 2 };
+                crate::SdfOutput::new(p.2, material)
+            }
+        }
+
+        let ray_marcher = sphere_ray_marcher(2.0);
+        let scene = SceneSplitWall {
+            material: Material::new(&vec3::from_values(0.0, 0.0, 5.0), None, None, true, true),
+        };
+        let (width, height) = (20, 10);
+        let mut canvas = PixelPropertyCanvas::from_scene(&ray_marcher, &scene, width, height, &SceneRenderOptions::default());
+        assert_eq!(HashMap::from([(1, 100), (2, 100)]), canvas.material_histogram());
+        assert_eq!(200, canvas.lightness_histogram(10).iter().sum::<usize>());
+
+        // Misses count toward no material
+        for pixel in canvas.pixels_mut().iter_mut().take(5) {
+            *pixel = PixelProperties::default();
+        }
+        assert_eq!(HashMap::from([(1, 95), (2, 100)]), canvas.material_histogram());
+
+        for (pixel, lightness) in canvas.pixels_mut().iter_mut().zip([0.0, 0.25, 0.5, 1.0, 1.2]) {
+            pixel.lightness = lightness;
+        }
+        let histogram = canvas.lightness_histogram(4);
+        assert_eq!((1, 1, 1), (histogram[0], histogram[1], histogram[2]));
+        assert_eq!(197, histogram[3]);
+    }
+
+    #[test]
+    fn test_from_scene_accumulated() {
+        // A black, unshaded sphere on the white background: each single pass is strictly black and white
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let scene = SceneSphere {
+            material: Material::new(&vec3::from_values(5.0, 5.0, 5.0), None, Some(&vec3::from_values(0.0, 0.0, 0.0)), false, true),
+        };
+        let gray_levels = |passes: u32| {
+            let rgb = PixelPropertyCanvas::from_scene_accumulated(&ray_marcher, &scene, 24, 24, &SceneRenderOptions::default(), passes).to_u32_rgb();
+            assert!(rgb.iter().all(|rgb| (rgb >> 16) == (rgb & 0xFF)));
+            rgb.iter().map(|rgb| rgb & 0xFF).collect::<Vec<u32>>()
+        };
+
+        assert!(gray_levels(1).iter().all(|&l| l == 0 || l == 255));
+        let accumulated = gray_levels(4);
+        assert_eq!(0, accumulated[12 * 24 + 12]);
+        assert_eq!(255, accumulated[0]);
+        let boundary: Vec<&u32> = accumulated.iter().filter(|&&l| l > 0 && l < 255).collect();
+        assert!(!boundary.is_empty());
+        // The coverage of a pixel is a multiple of 1 / 4
+        assert!(boundary.iter().all(|&&l| [64, 128, 191].contains(&l)));
+    }
+
+    #[test]
+    fn test_from_scene_multi_angle() {
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let scene = scene_sphere();
+        let angles = [0.0, 0.5 * PI];
+        let canvases = PixelPropertyCanvas::from_scene_multi_angle(&ray_marcher, &scene, 16, 16, &angles, &SceneRenderOptions::default());
+        assert_eq!(2, canvases.len());
+
+        let single = PixelPropertyCanvas::from_scene(
+            &ray_marcher,
+            &scene,
+            16,
+            16,
+            &SceneRenderOptions { angle_in_tangent_plane: angles[1], ..SceneRenderOptions::default() },
+        );
+        let mut differing_direction_count = 0;
+        for ((p0, p1), p_single) in canvases[0].data.iter().zip(canvases[1].data.iter()).zip(single.data.iter()) {
+            assert!(p0.depth == p1.depth || (p0.depth.is_nan() && p1.depth.is_nan()));
+            assert!(p0.lightness == p1.lightness || (p0.lightness.is_nan() && p1.lightness.is_nan()));
+            assert!(p1.direction() == p_single.direction() || (p1.direction().is_nan() && p_single.direction().is_nan()));
+            if !p0.depth.is_nan() && (p0.direction() - p1.direction()).abs() > 0.1 {
+                differing_direction_count += 1;
+            }
+        }
+        assert!(differing_direction_count > 0);
+    }
+
+    #[test]
+    fn test_direction_source_field() {
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let scene_with_light = |light: Vec3| SceneSphere {
+            material: Material::new(&light, None, None, true, true),
+        };
+        let scene_a = scene_with_light(vec3::from_values(5.0, 5.0, 5.0));
+        let scene_b = scene_with_light(vec3::from_values(-5.0, 2.0, 3.0));
+        let render = |scene: &SceneSphere, direction_source: DirectionSource| {
+            PixelPropertyCanvas::from_scene(&ray_marcher, scene, 16, 16, &SceneRenderOptions { direction_source, ..SceneRenderOptions::default() })
+        };
+        let hit_directions = |canvas: &PixelPropertyCanvas| -> Vec<f32> {
+            canvas.data.iter().filter(|p| !p.direction().is_nan()).map(|p| p.direction()).collect()
+        };
+
+        let gravity = DirectionSource::Field(vec3::from_values(0.0, -1.0, 0.0));
+        let field_a = hit_directions(&render(&scene_a, gravity));
+        let field_b = hit_directions(&render(&scene_b, gravity));
+        assert!(!field_a.is_empty());
+        assert_eq!(field_a, field_b);
+
+        let light_a = hit_directions(&render(&scene_a, DirectionSource::Light));
+        let light_b = hit_directions(&render(&scene_b, DirectionSource::Light));
+        assert_ne!(light_a, light_b);
+    }
+
+    #[test]
+    fn test_sample_pattern_rotated_grid() {
+        let offsets = SamplePattern::Rotated.offsets(4);
+        let expected = [(0.375, 0.125), (0.875, 0.375), (0.125, 0.625), (0.625, 0.875)];
+        assert_eq!(4, offsets.len());
+        for (offset, (x, y)) in offsets.iter().zip(expected) {
+            assert_approx_eq!(x, offset.0);
+            assert_approx_eq!(y, offset.1);
+        }
+    }
+
+    #[test]
+    fn test_sample_patterns_stay_in_pixel() {
+        let pixel_coord = PixelCoord::new(3, 7);
+        for pattern in [SamplePattern::Grid, SamplePattern::Jittered, SamplePattern::Rotated, SamplePattern::BlueNoise] {
+            for sample_count in [3, 9] {
+                let offsets = pattern.offsets_for_pixel(&pattern.offsets(sample_count), &pixel_coord);
+                assert_eq!(sample_count as usize, offsets.len());
+                assert!(offsets.iter().all(|o| (0.0..1.0).contains(&o.0) && (0.0..1.0).contains(&o.1)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_sample_pattern_non_square_counts() {
+        for pattern in [SamplePattern::Grid, SamplePattern::Jittered, SamplePattern::Rotated] {
+            for sample_count in [2, 3, 5, 6] {
+                let offsets = pattern.offsets(sample_count);
+                assert_eq!(sample_count as usize, offsets.len());
+                let mean = vec2::scale(&offsets.iter().fold(vec2::from_values(0.0, 0.0), |sum, o| vec2::add(&sum, o)), 1.0 / sample_count as f32);
+                assert_approx_eq!(0.5, mean.0);
+                assert_approx_eq!(0.5, mean.1);
+                // One sample per row and column
+                for coordinate in [|o: &Vec2| o.0, |o: &Vec2| o.1] {
+                    let mut cells: Vec<u32> = offsets.iter().map(|o| (coordinate(o) * sample_count as f32) as u32).collect();
+                    cells.sort();
+                    assert_eq!((0..sample_count).collect::<Vec<u32>>(), cells);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_scene_supersampled() {
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let scene = scene_sphere();
+        // A single grid sample is the pixel center, just like in from_scene
+        let single = PixelPropertyCanvas::from_scene(&ray_marcher, &scene, 16, 16, &SceneRenderOptions::default());
+        let supersampled = PixelPropertyCanvas::from_scene_supersampled(
+            &ray_marcher, &scene, 16, 16, &SceneRenderOptions::default(), 1, SamplePattern::Grid,
+        );
+        for (p_single, p_super) in single.data.iter().zip(supersampled.data.iter()) {
+            assert!(p_single.depth == p_super.depth || (p_single.depth.is_nan() && p_super.depth.is_nan()));
+            assert!(p_single.lightness == p_super.lightness || (p_single.lightness.is_nan() && p_super.lightness.is_nan()));
+        }
+
+        let rotated = PixelPropertyCanvas::from_scene_supersampled(
+            &ray_marcher, &scene, 16, 16, &SceneRenderOptions::default(), 4, SamplePattern::Rotated,
+        );
+        assert!(rotated.data.iter().any(|p| !p.depth.is_nan() && !p.direction().is_nan()));
+    }
+
+    #[test]
+    fn test_from_scene_smooth_union_material() {
+        let ray_marcher = sphere_ray_marcher(2.0);
+        let light_source = vec3::from_values(5.0, 5.0, 5.0);
+        let material_with_hue = |hue: VecFloat| {
+            Material::new(&light_source, None, Some(&vec3::from_values(hue, 0.8, 0.5)), true, true)
+        };
+        let left = SceneMovingSphere { material: material_with_hue(0.0), offset_x: -0.9 };
+        let right = SceneMovingSphere { material: material_with_hue(2.0), offset_x: 0.9 };
+        let scene = crate::SmoothUnionScene::new(&left, &right, 0.5);
+
+        let width = 64;
+        let canvas = PixelPropertyCanvas::from_scene(&ray_marcher, &scene, width, 32, &SceneRenderOptions::default());
+        let hues: Vec<VecFloat> = (0..width)
+            .map(|x| &canvas.data[canvas.pixel_index(x, 16)])
+            .filter(|pixel| !pixel.depth.is_nan())
+            .map(|pixel| pixel.bg_hsl.0)
+            .collect();
+        assert_approx_eq!(0.0, hues[0], 1.0e-3);
+        assert_approx_eq!(2.0, hues[hues.len() - 1], 1.0e-3);
+        // The seam between the spheres is colored in between, changing monotonically from left to right
+        assert!(hues.iter().any(|hue| *hue > 0.5 && *hue < 1.5));
+        assert!(hues.windows(2).all(|pair| pair[0] <= pair[1] + 1.0e-6));
+    }
+
+    #[test]
+    fn test_from_scene_silhouette_coverage() {
+        let mut ray_marcher = sphere_ray_marcher(1.0);
+        let scene = SceneSphere {
+            material: Material::new(&vec3::from_values(5.0, 5.0, 5.0), None, Some(&vec3::from_values(0.0, 1.0, 0.5)), false, true),
+        };
+        let size = 32;
+        let render = |ray_marcher: &RayMarcher| {
+            PixelPropertyCanvas::from_scene(ray_marcher, &scene, size, size, &SceneRenderOptions::default())
+        };
+
+        let without_coverage = render(&ray_marcher);
+        assert!(without_coverage.data.iter().all(|p| p.coverage == if p.depth.is_nan() { 0.0 } else { 1.0 }));
+
+        ray_marcher.silhouette_coverage = true;
+        let canvas = render(&ray_marcher);
+        let center = canvas.pixel_index(size / 2, size / 2);
+        assert_eq!(1.0, canvas.data[center].coverage);
+        assert_eq!(0.0, canvas.data[0].coverage);
+        let partial: Vec<usize> = (0..canvas.data.len())
+            .filter(|&index| canvas.data[index].coverage > 0.0 && canvas.data[index].coverage < 1.0)
+            .collect();
+        assert!(!partial.is_empty());
+        // Partially covered pixels are misses next to the silhouette
+        let is_next_to_hit = |index: usize| {
+            let PixelCoord { x, y } = canvas.pixel_coordinates(index);
+            (-1..=1).any(|dy| (-1..=1).any(|dx| !canvas.pixel_at_reflected(x as i32 + dx, y as i32 + dy).depth.is_nan()))
+        };
+        assert!(partial.iter().all(|&index| canvas.data[index].depth.is_nan() && is_next_to_hit(index)));
+
+        // Their color lies between the sphere's and the background's
+        let rgb = canvas.bg_to_skia_canvas(None).to_u32_rgb();
+        let green = (rgb[partial[0]] >> 8) & 0xFF;
+        assert!(green > 0 && green < 255);
+        assert_eq!(0xFFFFFF, rgb[0]);
+    }
+
+    #[test]
+    fn test_shade_matcap() {
+        // Red increases to the right, green downward
+        let mut matcap = SkiaCanvas::new(64, 64);
+        matcap.iter_mut_rgba_with_coordinates(|x, y, rgba| {
+            rgba.copy_from_slice(&[(4 * x) as u8, (4 * y) as u8, 0, 255]);
+        });
+        let mut canvas = PixelPropertyCanvas::new(4, 1);
+        let facing_camera = vec3::from_values(0.0, 0.0, -1.0);
+        let facing_up_right = vec3::normalize(&vec3::from_values(1.0, 1.0, -1.0));
+        canvas.pixels_mut()[0].normal = facing_camera;
+        canvas.pixels_mut()[1].normal = facing_camera;
+        canvas.pixels_mut()[2].normal = facing_up_right;
+
+        let rgb = canvas.shade_matcap(&matcap).to_u32_rgb();
+        assert_eq!(rgb[0], rgb[1]);
+        assert_ne!(rgb[0], rgb[2]);
+        // Facing up and to the right looks up the upper right of the matcap
+        assert!((rgb[2] >> 16) > (rgb[0] >> 16));
+        assert!(((rgb[2] >> 8) & 0xFF) < ((rgb[0] >> 8) & 0xFF));
+        let nan_rgba = PixelPropertyCanvas::NAN_RGBA_VALUE;
+        assert_eq!(((nan_rgba[0] as u32) << 16) | ((nan_rgba[1] as u32) << 8) | nan_rgba[2] as u32, rgb[3]);
+
+        // The normal buffer of a marched sphere faces the camera at the center
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let scene = scene_sphere();
+        let sphere = PixelPropertyCanvas::from_scene(&ray_marcher, &scene, 16, 16, &SceneRenderOptions::default());
+        let center_normal = sphere.data[sphere.pixel_index(8, 8)].normal;
+        assert_approx_eq!(-1.0, center_normal.2, 0.05);
+        assert!(sphere.data[0].normal.0.is_nan());
+    }
+
+    #[test]
+    fn test_from_heightmap_up_axis() {
+        use crate::ray_marcher::UpAxis;
+        // The rotation (x, y, z) -> (x, -z, y) takes the Y-up world to a Z-up one
+        let to_z_up = |p: &Vec3| vec3::from_values(p.0, -p.2, p.1);
+        let heightmap_y_up = |x: f32, z: f32| 0.3 * (2.0 * x).sin() * (3.0 * z).cos();
+        let heightmap_z_up = |x: f32, y: f32| heightmap_y_up(x, -y);
+        let camera = vec3::from_values(0.5, 2.0, -3.0);
+        let look_at = vec3::from_values(0.0, 0.0, 1.0);
+        let up = vec3::from_values(0.0, 1.0, 0.0);
+        let light = vec3::from_values(1.0, 5.0, -1.0);
+        let material_y_up = Material::new(&light, None, None, true, true);
+        let material_z_up = Material::new(&to_z_up(&light), None, None, true, true);
+
+        let ray_marcher_y_up = RayMarcher::new(0.5, &camera, &look_at, &up, 45.0, 1.0);
+        let ray_marcher_z_up = RayMarcher::new_with_up_axis(0.5, &to_z_up(&camera), &to_z_up(&look_at), &to_z_up(&up), UpAxis::Z, 45.0, 1.0);
+        assert_eq!(UpAxis::Z, ray_marcher_z_up.up_axis());
+        let y_up = PixelPropertyCanvas::from_heightmap(&ray_marcher_y_up, &heightmap_y_up, &material_y_up, 24, 24, 0.0);
+        let z_up = PixelPropertyCanvas::from_heightmap(&ray_marcher_z_up, &heightmap_z_up, &material_z_up, 24, 24, 0.0);
+
+        assert!(y_up.data.iter().all(|p| !p.depth.is_nan()));
+        for (p_y_up, p_z_up) in y_up.data.iter().zip(z_up.data.iter()) {
+            assert_approx_eq!(p_y_up.depth, p_z_up.depth, 1.0e-3);
+            assert_approx_eq!(p_y_up.lightness, p_z_up.lightness, 1.0e-3);
+            assert_approx_eq!(p_y_up.direction_vec.0, p_z_up.direction_vec.0, 1.0e-2);
+            assert_approx_eq!(p_y_up.direction_vec.1, p_z_up.direction_vec.1, 1.0e-2);
+        }
+    }
+
+    #[test]
+    fn test_from_scene_background() {
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let scene = scene_sphere();
+        let sky = vec3::from_values(200.0f32.to_radians(), 0.5, 0.7);
+        let render = |background: BackgroundSpec| {
+            PixelPropertyCanvas::from_scene(&ray_marcher, &scene, 16, 16, &SceneRenderOptions { background, ..SceneRenderOptions::default() })
+        };
+
+        let flat = render(BackgroundSpec::Flat(sky));
+        let (misses, hits): (Vec<&PixelProperties>, Vec<&PixelProperties>) = flat.data.iter().partition(|p| p.depth.is_nan());
+        assert!(!misses.is_empty() && !hits.is_empty());
+        assert!(misses.iter().all(|p| p.bg_hsl == sky));
+        assert!(hits.iter().all(|p| p.bg_hsl == scene.material.bg_hsl));
+        let rgb = flat.bg_to_skia_canvas(None).to_u32_rgb();
+        let sky_rgb = vec3::hsl_to_rgb_u8(&sky);
+        assert_eq!(((sky_rgb[0] as u32) << 16) | ((sky_rgb[1] as u32) << 8) | sky_rgb[2] as u32, rgb[0]);
+
+        let gradient = render(BackgroundSpec::VerticalGradient {
+            top_hsl: vec3::from_values(0.0, 0.0, 1.0),
+            bottom_hsl: vec3::from_values(0.0, 0.0, 0.0),
+        });
+        let lightness_at = |x: u32, y: u32| gradient.data[gradient.pixel_index(x, y)].bg_hsl.2;
+        assert!(lightness_at(0, 0) > 0.5 && lightness_at(0, 15) < 0.5);
+
+        let by_direction = render(BackgroundSpec::Direction(|dir| vec3::from_values(0.0, 0.0, 0.5 * (dir.0 + 1.0))));
+        assert!(by_direction.data[0].bg_hsl.2 < by_direction.data[15].bg_hsl.2);
+
+        // The other constructors fill the background of the misses just the same
+        let options = SceneRenderOptions { background: BackgroundSpec::Flat(sky), ..SceneRenderOptions::default() };
+        let preview = PixelPropertyCanvas::from_scene_preview(&ray_marcher, &scene, 16, 16, &options, 2);
+        let supersampled = PixelPropertyCanvas::from_scene_supersampled(&ray_marcher, &scene, 16, 16, &options, 4, SamplePattern::Grid);
+        let mut moving_scene = SceneMovingSphere { material: scene.material, offset_x: 0.0 };
+        let blurred = PixelPropertyCanvas::from_scene_motion_blur(&ray_marcher, &mut moving_scene, 16, 16, &options, &[-0.5, 0.5]);
+        for canvas in [preview, supersampled, blurred] {
+            let misses: Vec<&PixelProperties> = canvas.data.iter().filter(|p| p.depth.is_nan()).collect();
+            assert!(!misses.is_empty() && misses.iter().all(|p| p.bg_hsl == sky));
+        }
+    }
+
+    #[test]
+    fn test_sky_gradient_background() {
+        // The sphere is behind the camera in both views, so every pixel misses the scene
+        let scene = scene_sphere();
+        let top = vec3::from_values(220.0f32.to_radians(), 0.6, 0.4);
+        let horizon = vec3::from_values(40.0f32.to_radians(), 0.3, 0.9);
+        let center_bg_hsl = |look_at: Vec3| {
+            let ray_marcher = RayMarcher::new(
+                1.0,
+                &vec3::from_values(0.0, 0.0, 5.0),
+                &look_at,
+                &vec3::from_values(0.0, 1.0, 0.0),
+                45.0,
+                1.0,
+            );
+            let canvas = PixelPropertyCanvas::from_scene(
+                &ray_marcher,
+                &scene,
+                15,
+                15,
+                &SceneRenderOptions { background: BackgroundSpec::sky_gradient(&top, &horizon), ..SceneRenderOptions::default() },
+            );
+            assert!(canvas.data.iter().all(|p| p.depth.is_nan()));
+            canvas.data[canvas.pixel_index(7, 7)].bg_hsl
+        };
+
+        let looking_up = center_bg_hsl(vec3::from_values(0.0, 10.0, 5.0));
+        let looking_at_horizon = center_bg_hsl(vec3::from_values(0.0, 0.0, 10.0));
+        for (expected, actual) in [(top, looking_up), (horizon, looking_at_horizon)] {
+            assert_approx_eq!(expected.0, actual.0, 1.0e-4);
+            assert_approx_eq!(expected.1, actual.1, 1.0e-4);
+            assert_approx_eq!(expected.2, actual.2, 1.0e-4);
+        }
+    }
+
+    #[test]
+    fn test_sky_gradient_background_z_up() {
+        let scene = scene_sphere();
+        let top = vec3::from_values(220.0f32.to_radians(), 0.6, 0.4);
+        let horizon = vec3::from_values(40.0f32.to_radians(), 0.3, 0.9);
+        // With Z up, looking along +z is looking up, and looking along +y is looking at the horizon
+        let center_bg_hsl = |look_at: Vec3, background: BackgroundSpec| {
+            let ray_marcher = RayMarcher::new_with_up_axis(
+                1.0,
+                &vec3::from_values(0.0, 0.0, 5.0),
+                &look_at,
+                &vec3::from_values(1.0, 0.0, 0.0),
+                UpAxis::Z,
+                45.0,
+                1.0,
+            );
+            let canvas = PixelPropertyCanvas::from_scene(
+                &ray_marcher,
+                &scene,
+                15,
+                15,
+                &SceneRenderOptions { background, ..SceneRenderOptions::default() },
+            );
+            assert!(canvas.data.iter().all(|p| p.depth.is_nan()));
+            (canvas.data[canvas.pixel_index(7, 7)].bg_hsl, ray_marcher.sample_environment(&vec3::sub(&look_at, &ray_marcher.camera)))
+        };
+
+        let sky = BackgroundSpec::sky_gradient(&top, &horizon);
+        let (looking_up, environment_up) = center_bg_hsl(vec3::from_values(0.0, 0.0, 10.0), sky);
+        let (looking_at_horizon, environment_horizon) = center_bg_hsl(vec3::from_values(0.0, 10.0, 5.0), sky);
+        for (expected, actual) in [(top, looking_up), (horizon, looking_at_horizon)] {
+            assert_approx_eq!(expected.0, actual.0, 1.0e-4);
+            assert_approx_eq!(expected.1, actual.1, 1.0e-4);
+            assert_approx_eq!(expected.2, actual.2, 1.0e-4);
+        }
+        // The environment lighting agrees on what is up
+        let environment = EnvironmentGradient::default();
+        for (expected, actual) in [(environment.zenith_hsl, environment_up), (environment.horizon_hsl, environment_horizon)] {
+            assert_approx_eq!(expected.0, actual.0, 1.0e-4);
+            assert_approx_eq!(expected.1, actual.1, 1.0e-4);
+            assert_approx_eq!(expected.2, actual.2, 1.0e-4);
+        }
+
+        let gradient = BackgroundSpec::VerticalGradient {
+            top_hsl: vec3::from_values(0.0, 0.0, 1.0),
+            bottom_hsl: vec3::from_values(0.0, 0.0, 0.0),
+        };
+        assert_approx_eq!(1.0, center_bg_hsl(vec3::from_values(0.0, 0.0, 10.0), gradient).0.2, 1.0e-4);
+        assert_approx_eq!(0.5, center_bg_hsl(vec3::from_values(0.0, 10.0, 5.0), gradient).0.2, 1.0e-4);
+    }
+
+    #[test]
+    fn test_from_scene_preview() {
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let scene = scene_sphere();
+        let downscale = 4;
+        let canvas = PixelPropertyCanvas::from_scene_preview(&ray_marcher, &scene, 32, 32, &SceneRenderOptions::default(), downscale);
+        assert_eq!(32, canvas.width());
+        assert_eq!(32, canvas.height());
+
+        // Every 4x4 block is a copy of a single marched pixel, i.e., the internal resolution is 8x8
+        let mut hit_count = 0;
+        for y in 0..32 {
+            for x in 0..32 {
+                let pixel = &canvas.data[canvas.pixel_index(x, y)];
+                let block_pixel = &canvas.data[canvas.pixel_index(x - x % downscale, y - y % downscale)];
+                assert!(pixel.depth == block_pixel.depth || (pixel.depth.is_nan() && block_pixel.depth.is_nan()));
+                if !pixel.depth.is_nan() {
+                    hit_count += 1;
+                }
+            }
+        }
+        assert!(hit_count > 0 && hit_count < 32 * 32);
+        assert_eq!(0, hit_count % (downscale * downscale));
+    }
+
+    #[test]
+    fn test_anisotropic_dpi() {
+        // Horizontal resolution twice the vertical one: the 64x32 canvas is physically square
+        let (dpi_x, dpi_y) = (200.0, 100.0);
+        let (width, height) = (64, 32);
+        let mut output_canvas = SkiaCanvas::new(width, height);
+        assert_approx_eq!(1.0, output_canvas.physical_aspect_ratio(dpi_x, dpi_y));
+
+        let ray_marcher = sphere_ray_marcher(output_canvas.physical_aspect_ratio(dpi_x, dpi_y));
+        let scene = scene_sphere();
+        let pp_canvas = PixelPropertyCanvas::from_scene(&ray_marcher, &scene, width, height, &SceneRenderOptions::default());
+        let is_hit = |x: u32, y: u32| !pp_canvas.data[PixelCoord::new(x, y).to_index(width)].depth.is_nan();
+        let hit_columns = (0..width).filter(|&x| (0..height).any(|y| is_hit(x, y))).count() as f32;
+        let hit_rows = (0..height).filter(|&y| (0..width).any(|x| is_hit(x, y))).count() as f32;
+        assert!((hit_columns / hit_rows - dpi_x / dpi_y).abs() < 0.15);
+
+        // A point of radius 8 (horizontal) pixels covers only 4 pixels vertically
+        output_canvas.set_dpi(dpi_x, dpi_y);
+        output_canvas.fill_point(32.0, 16.0, 8.0, &[0, 0, 0]);
+        let rgb = output_canvas.to_u32_rgb();
+        let is_inked = |x: u32, y: u32| rgb[PixelCoord::new(x, y).to_index(width)] & 0xFF < 128;
+        let inked_columns = (0..width).filter(|&x| (0..height).any(|y| is_inked(x, y))).count();
+        let inked_rows = (0..height).filter(|&y| (0..width).any(|x| is_inked(x, y))).count();
+        assert_eq!(16, inked_columns);
+        assert_eq!(8, inked_rows);
+    }
+
+    #[test]
+    fn test_pixel_property_canvas_crop() {
+        let width = 5;
+        let mut canvas = PixelPropertyCanvas::new(width, 4);
+        for (index, pixel) in canvas.data.iter_mut().enumerate() {
+            pixel.lightness = index as f32;
+            pixel.depth = 2.0 * index as f32;
+            pixel.direction_vec = vec2::from_values(1.0, index as f32);
+        }
+
+        let cropped = canvas.crop(1, 2, 2, 2).unwrap();
+        assert_eq!((2, 2), (cropped.width(), cropped.height()));
+        let expected_indices = [11, 12, 16, 17];
+        for (pixel, index) in cropped.data.iter().zip(expected_indices) {
+            assert_eq!(index as f32, pixel.lightness);
+            assert_eq!(2.0 * index as f32, pixel.depth);
+            assert_eq!(index as f32, pixel.direction_vec.1);
+        }
+
+        assert!(matches!(canvas.crop(4, 0, 2, 1), Err(CanvasError::RegionOutOfBounds)));
+        assert!(matches!(canvas.crop(u32::MAX, 0, 2, 1), Err(CanvasError::RegionOutOfBounds)));
+    }
+
+    #[test]
+    fn test_accumulate() {
+        let width = 20;
+        let height = 4;
+        // A bright band moving to the right by 5 pixels between the two frames
+        let frame = |band_start: u32| {
+            let mut canvas = PixelPropertyCanvas::new(width, height);
+            for (index, pixel) in canvas.data.iter_mut().enumerate() {
+                let x = PixelCoord::from_index(index, width).x;
+                pixel.lightness = if x >= band_start && x < band_start + 10 { 1.0 } else { 0.0 };
+                pixel.depth = 1.0;
+            }
+            canvas
+        };
+        let lightness_at = |canvas: &PixelPropertyCanvas, x: u32| canvas.data[PixelCoord::new(x, 0).to_index(width)].lightness;
+
+        let mut blurred = frame(0);
+        blurred.accumulate(&frame(5), 0.5);
+        assert_approx_eq!(0.5, lightness_at(&blurred, 2));
+        assert_approx_eq!(1.0, lightness_at(&blurred, 7));
+        assert_approx_eq!(0.5, lightness_at(&blurred, 12));
+        assert_approx_eq!(0.0, lightness_at(&blurred, 17));
+
+        // Misses neither darken nor erase hits
+        let mut partial = PixelPropertyCanvas::new(width, height);
+        partial.accumulate(&blurred, 0.5);
+        assert_approx_eq!(0.5, lightness_at(&partial, 2));
+        blurred.accumulate(&PixelPropertyCanvas::new(width, height), 0.5);
+        assert_approx_eq!(1.0, lightness_at(&blurred, 7));
+    }
+
+    #[test]
+    fn test_accumulate_mean() {
+        // The first pixel misses the first two of four sub-frames, the second one always hits
+        let frame = |lightnesses: [f32; 2]| {
+            let mut canvas = PixelPropertyCanvas::new(2, 1);
+            for (pixel, lightness) in canvas.data.iter_mut().zip(lightnesses) {
+                pixel.lightness = lightness;
+            }
+            canvas
+        };
+        let mut mean = PixelPropertyCanvas::new(2, 1);
+        let mut hit_counts = vec![0; 2];
+        for lightnesses in [[f32::NAN, 0.1], [f32::NAN, 0.3], [0.2, 0.5], [0.6, 0.7]] {
+            mean.accumulate_mean(&frame(lightnesses), &mut hit_counts);
+        }
+        assert_eq!(vec![2, 4], hit_counts);
+        assert_approx_eq!(0.4, mean.data[0].lightness);
+        assert_approx_eq!(0.4, mean.data[1].lightness);
+    }
+
+    struct SceneMovingSphere {
+        material: Material,
+        offset_x: VecFloat,
+    }
+
+    impl Scene for SceneMovingSphere {
+        fn eval(&self, p: &Vec3) -> crate::SdfOutput {
+            let q = vec3::sub(p, &vec3::from_values(self.offset_x, 0.0, 0.0));
+            crate::SdfOutput::new(crate::sdf_op::sd_sphere(&q, 1.0), self.material)
+        }
+    }
+
+    impl AnimatedScene for SceneMovingSphere {
+        fn set_time(&mut self, t: VecFloat) {
+            self.offset_x = t;
+        }
+    }
+
+    #[test]
+    fn test_from_scene_motion_blur() {
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let mut scene = SceneMovingSphere {
+            material: Material::new(&vec3::from_values(5.0, 5.0, 5.0), None, None, true, true),
+            offset_x: 0.0,
+        };
+        let hit_count = |canvas: &PixelPropertyCanvas| canvas.data.iter().filter(|p| !p.depth.is_nan()).count();
+
+        let still = PixelPropertyCanvas::from_scene(&ray_marcher, &scene, 32, 32, &SceneRenderOptions::default());
+        let blurred = PixelPropertyCanvas::from_scene_motion_blur(&ray_marcher, &mut scene, 32, 32, &SceneRenderOptions::default(), &[-0.5, 0.0, 0.5]);
+        assert!(hit_count(&blurred) > hit_count(&still));
+        assert_eq!(0.5, scene.offset_x);
+    }
+
+    #[test]
+    fn test_lightness_to_skia_canvas_gradient() {
+        let mut canvas = PixelPropertyCanvas::new(3, 1);
+        for (pixel, lightness) in canvas.data.iter_mut().zip([0.5, 1.0, f32::NAN]) {
+            pixel.lightness = lightness;
+        }
+        let gradient = LinearGradient::new(&[200, 100, 0], &[0, 100, 200]);
+        let rgb = canvas.lightness_to_skia_canvas_gradient(&gradient).to_u32_rgb();
+        assert_eq!(0x646464, rgb[0]);
+        assert_eq!(0x0064C8, rgb[1]);
+        assert_eq!(0xFF00FF, rgb[2]);
+    }
+
+    #[test]
+    fn test_bg_to_skia_canvas_specular_hsl() {
+        let mut canvas = PixelPropertyCanvas::new(2, 1);
+        let blue = vec3::from_values(240.0f32.to_radians(), 1.0, 0.5);
+        let white = vec3::from_values(0.0, 0.0, 1.0);
+        for (pixel, specular) in canvas.data.iter_mut().zip([0.8, 0.0]) {
+            pixel.lightness = 1.0;
+            pixel.specular = specular;
+            pixel.depth = 1.0;
+            pixel.bg_hsl = blue;
+            pixel.is_shaded = true;
+            pixel.specular_hsl = Some(white);
+        }
+        let rgb = canvas.bg_to_skia_canvas(None).to_u32_rgb();
+        let channels = |c: u32| [(c >> 16) & 0xFF, (c >> 8) & 0xFF, c & 0xFF];
+
+        // The highlight shifts toward white; the diffuse-only pixel keeps the surface color
+        let [r, g, b] = channels(rgb[0]);
+        assert!(r > 150 && g > 150 && b > 200);
+        assert_eq!(vec3::hsl_to_rgb_u8(&blue), channels(rgb[1]).map(|c| c as u8));
+
+        // Without a specular color, highlights only brighten the surface color
+        canvas.data[0].specular_hsl = None;
+        let [r, g, b] = channels(canvas.bg_to_skia_canvas(None).to_u32_rgb()[0]);
+        assert_eq!((0, 0, 255), (r, g, b));
+    }
+
+    #[test]
+    fn test_stroke_polyline_colored() {
+        let mut canvas = SkiaCanvas::new(40, 10);
+        let points = [vec2::from_values(2.0, 5.0), vec2::from_values(38.0, 5.0)];
+        canvas.stroke_polyline_colored(&points, &[2.0, 4.0], &[[255, 0, 0], [0, 0, 255]]);
+        let rgb = canvas.to_u32_rgb();
+        let at = |x: u32, y: u32| rgb[PixelCoord::new(x, y).to_index(40)];
+
+        let near_start = at(4, 5);
+        let near_end = at(36, 5);
+        assert!((near_start >> 16) & 0xFF > 200 && near_start & 0xFF < 50);
+        assert!(near_end & 0xFF > 200 && (near_end >> 16) & 0xFF < 50);
+        // The stroke widens toward the end
+        assert_eq!(0xFFFFFF, at(4, 3));
+        assert_ne!(0xFFFFFF, at(36, 3));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_stroke_polyline_colored_length_mismatch() {
+        let mut canvas = SkiaCanvas::new(10, 10);
+        let points = [vec2::from_values(2.0, 5.0), vec2::from_values(8.0, 5.0)];
+        canvas.stroke_polyline_colored(&points, &[1.0], &[[0, 0, 0], [0, 0, 0]]);
+    }
+
+    #[test]
+    fn test_save_npy() {
+        let mut canvas = PixelPropertyCanvas::new(3, 2);
+        let lightness = [0.0, 0.25, f32::NAN, 0.5, 1.0, -2.0];
+        for (pixel, l) in canvas.data.iter_mut().zip(lightness) {
+            pixel.lightness = l;
+        }
+        let dir = std::env::temp_dir().join(format!("rusty-sdfs-npy-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        canvas.save_npy(&dir).unwrap();
+        let bytes = std::fs::read(dir.join("lightness.npy")).unwrap();
+        assert!(dir.join("depth.npy").exists() && dir.join("direction.npy").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(b"\x93NUMPY\x01\x00", &bytes[..8]);
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        assert_eq!(0, (10 + header_len) % 64);
+        let header = std::str::from_utf8(&bytes[10..10 + header_len]).unwrap();
+        assert!(header.contains("'descr': '<f4'"));
+        assert!(header.contains("'fortran_order': False"));
+        assert!(header.contains("'shape': (2, 3)"));
+        assert!(header.ends_with('\n'));
+
+        let values: Vec<f32> = bytes[10 + header_len..]
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect();
+        assert_eq!(lightness.len(), values.len());
+        for (expected, value) in lightness.iter().zip(values.iter()) {
+            assert!(expected == value || (expected.is_nan() && value.is_nan()));
+        }
+    }
+
+    #[test]
+    fn test_file_round_trip() {
+        let mut canvas = PixelPropertyCanvas::new(3, 2);
+        for (index, pixel) in canvas.data.iter_mut().enumerate() {
+            pixel.lightness = 0.1 * index as f32;
+            pixel.set_direction(0.5);
+        }
+        let dir = std::env::temp_dir().join(format!("rusty-sdfs-ppc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("canvas.ppc");
+        let path = path.to_str().unwrap();
+        canvas.to_file(path).unwrap();
+        let loaded = PixelPropertyCanvas::from_file(path).unwrap();
+
+        std::fs::write(path, bincode::serialize(&1u32).unwrap()).unwrap();
+        let outdated = PixelPropertyCanvas::from_file(path);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!((3, 2), (loaded.width(), loaded.height()));
+        for (expected, pixel) in canvas.data.iter().zip(loaded.data.iter()) {
+            assert_approx_eq!(expected.lightness, pixel.lightness);
+            assert_approx_eq!(0.5, pixel.direction());
+        }
+        assert!(matches!(outdated, Err(CanvasError::UnsupportedVersion(1))));
+    }
+
+    #[test]
+    fn test_save_png_invalid_directory() {
+        let canvas = SkiaCanvas::new(4, 4);
+        let result = canvas.save_png(std::path::Path::new("/nonexistent-directory/output.png"));
+        assert!(matches!(result, Err(CanvasError::Png(_))));
+    }
+
+    #[test]
+    fn test_direction_vec_bilinear() {
+        let mut canvas = PixelPropertyCanvas::new(2, 2);
+        for (index, pixel) in canvas.pixels_mut().iter_mut().enumerate() {
+            // Left column points just above -x, right column just below -x
+            pixel.set_direction(if index % 2 == 0 { PI - 0.1 } else { -PI + 0.1 });
+        }
+
+        let mid = canvas.direction_vec_bilinear(0.5, 0.5).unwrap();
+        assert_approx_eq!(-1.0, mid.0);
+        assert_approx_eq!(0.0, mid.1);
+        // Averaging the angles instead would point along +x
+        assert!(vec2::polar_angle(&mid).abs() > PI - 1.0e-3);
+
+        let left = canvas.direction_vec_bilinear(0.0, 0.0).unwrap();
+        assert_approx_eq!(PI - 0.1, vec2::polar_angle(&left));
+        let quarter = canvas.direction_vec_bilinear(0.25, 0.0).unwrap();
+        assert_approx_eq!(PI - 0.05, vec2::polar_angle(&quarter), 1.0e-3);
+
+        assert!(PixelPropertyCanvas::new(2, 2).direction_vec_bilinear(0.5, 0.5).is_none());
+
+        // A single column interpolates only along y
+        let mut column = PixelPropertyCanvas::new(1, 3);
+        for (index, pixel) in column.pixels_mut().iter_mut().enumerate() {
+            pixel.direction_vec = vec2::polar_angle_to_unit_vector(0.2 * index as f32);
+        }
+        assert_approx_eq!(0.1, vec2::polar_angle(&column.direction_vec_bilinear(0.0, 0.5).unwrap()), 1.0e-3);
+        assert_approx_eq!(0.4, vec2::polar_angle(&column.direction_vec_bilinear(5.0, 2.0).unwrap()), 1.0e-3);
+        assert_approx_eq!(0.4, vec2::polar_angle(&column.direction_vec_bilinear(-1.0, 7.0).unwrap()), 1.0e-3);
+        let row = PixelPropertyCanvas::new(3, 1);
+        assert!(row.direction_vec_bilinear(2.5, 0.5).is_none());
+    }
+
+    #[test]
+    fn test_new_filled_and_transparent() {
+        let canvas = SkiaCanvas::new_transparent(4, 3);
+        assert!(canvas.pixmap.pixels().iter().all(|p| p.alpha() == 0));
+
+        let canvas = SkiaCanvas::new_filled(4, 3, &[10, 20, 30, 255]);
+        assert!(canvas.pixmap.pixels().iter().all(|p| p.red() == 10 && p.green() == 20 && p.blue() == 30 && p.alpha() == 255));
+    }
+
+    #[test]
+    fn test_quantize_to_palette() {
+        let width = 16;
+        let height = 4;
+        let mut canvas = SkiaCanvas::new(width, height);
+        for x in 0..width {
+            let value = (x * 255 / (width - 1)) as u8;
+            canvas.fill_rect(x as f32, 0.0, 1.0, height as f32, &[value, value, value]);
+        }
+        let palette = [[0, 0, 0], [255, 255, 255]];
+        let is_in_palette = |p: &PremultipliedColorU8| palette.contains(&[p.red(), p.green(), p.blue()]);
+
+        let mut quantized = SkiaCanvas::from_rgba(canvas.pixmap.data().to_vec(), width, height);
+        quantized.quantize_to_palette(&palette, false);
+        assert!(quantized.pixmap.pixels().iter().all(is_in_palette));
+        assert_eq!(0, quantized.pixmap.pixel(width / 2 - 1, 0).unwrap().red());
+        assert_eq!(255, quantized.pixmap.pixel(width / 2, 0).unwrap().red());
+
+        // With dithering, the share of white pixels in a region follows its mean gray value
+        canvas.fill(&[64, 64, 64]);
+        canvas.quantize_to_palette(&palette, true);
+        assert!(canvas.pixmap.pixels().iter().all(is_in_palette));
+        let white_count = canvas.pixmap.pixels().iter().filter(|p| p.red() == 255).count();
+        assert!(white_count > 0 && white_count < (width * height / 2) as usize);
+    }
+
+    #[test]
+    fn test_fill_path_fill_rule() {
+        // A pentagram: its pentagon in the center is enclosed twice, its tips once
+        let center = vec2::from_values(20.0, 20.0);
+        let vertices: Vec<Vec2> = (0..5)
+            .map(|i| {
+                let angle = -0.5 * PI + 4.0 * PI * i as f32 / 5.0;
+                vec2::scale_and_add(&center, &vec2::polar_angle_to_unit_vector(angle), 18.0)
+            })
+            .collect();
+        let path = SkiaCanvas::closed_linear_path(&vertices).unwrap();
+        let filled_at = |fill_rule: FillRule, x: u32, y: u32| {
+            let mut canvas = SkiaCanvas::new(40, 40);
+            canvas.fill_path(&path, &[0, 0, 0], fill_rule);
+            canvas.to_u32_rgb()[(y * 40 + x) as usize] == 0x000000
+        };
+
+        assert!(filled_at(FillRule::Winding, 20, 20));
+        assert!(!filled_at(FillRule::EvenOdd, 20, 20));
+        // Top tip
+        assert!(filled_at(FillRule::Winding, 20, 6));
+        assert!(filled_at(FillRule::EvenOdd, 20, 6));
+    }
+
+    #[test]
+    fn test_separate_by_color() {
+        let red = [255, 0, 0];
+        let blue = [0, 0, 255];
+        let mut canvas = SkiaCanvas::new(8, 4);
+        canvas.fill_rect(0.0, 0.0, 3.0, 4.0, &red);
+        canvas.fill_rect(5.0, 0.0, 3.0, 4.0, &blue);
+
+        let layers = canvas.separate_by_color(&[red, blue]);
+        assert_eq!(2, layers.len());
+        for y in 0..4 {
+            for x in 0..8 {
+                let source = canvas.pixmap.pixel(x, y).unwrap();
+                let source_rgb = [source.red(), source.green(), source.blue()];
+                let is_colored = source_rgb == red || source_rgb == blue;
+                let covering_layer_count = layers.iter().filter(|layer| layer.pixmap.pixel(x, y).unwrap().alpha() > 0).count();
+                assert_eq!(if is_colored { 1 } else { 0 }, covering_layer_count);
+            }
+        }
+        let red_layer_pixel = layers[0].pixmap.pixel(1, 1).unwrap();
+        assert_eq!(red, [red_layer_pixel.red(), red_layer_pixel.green(), red_layer_pixel.blue()]);
+    }
+
+    #[test]
+    fn test_rotate90() {
+        let mut canvas = SkiaCanvas::new(6, 4);
+        canvas.fill_rect(0.0, 0.0, 1.0, 1.0, &[255, 0, 0]);
+
+        let rotated = canvas.rotate90(1);
+        assert_eq!((4, 6), (rotated.width(), rotated.height()));
+        let rgb = rotated.to_u32_rgb();
+        assert_eq!(0xFF0000, rgb[3]);
+        assert_eq!(1, rgb.iter().filter(|rgb| **rgb == 0xFF0000).count());
+
+        let rgb = canvas.rotate90(2).to_u32_rgb();
+        assert_eq!(0xFF0000, rgb[6 * 4 - 1]);
+        assert_eq!(canvas.to_u32_rgb(), canvas.rotate90(1).rotate90(3).to_u32_rgb());
+        assert_eq!(canvas.to_u32_rgb(), canvas.rotate90(4).to_u32_rgb());
+    }
+
+    #[test]
+    fn test_flip() {
+        let mut canvas = SkiaCanvas::new(5, 3);
+        canvas.fill_rect(0.0, 0.0, 2.0, 1.0, &[0, 0, 255]);
+        canvas.fill_rect(4.0, 2.0, 1.0, 1.0, &[255, 0, 0]);
+
+        let rgb = canvas.flip_horizontal().to_u32_rgb();
+        assert_eq!((0x0000FF, 0x0000FF, 0xFFFFFF), (rgb[4], rgb[3], rgb[0]));
+        let rgb = canvas.flip_vertical().to_u32_rgb();
+        assert_eq!((0x0000FF, 0xFF0000), (rgb[10], rgb[4]));
+        assert_eq!(canvas.to_u32_rgb(), canvas.flip_horizontal().flip_horizontal().to_u32_rgb());
+        assert_eq!(canvas.to_u32_rgb(), canvas.flip_vertical().flip_vertical().to_u32_rgb());
+        assert_eq!(canvas.rotate90(2).to_u32_rgb(), canvas.flip_horizontal().flip_vertical().to_u32_rgb());
+    }
+
+    #[test]
+    fn test_skia_canvas_crop() {
+        let mut canvas = SkiaCanvas::new(6, 5);
+        canvas.fill_rect(2.0, 1.0, 1.0, 1.0, &[255, 0, 0]);
+        canvas.fill_rect(3.0, 1.0, 1.0, 1.0, &[0, 255, 0]);
+        canvas.fill_rect(2.0, 2.0, 1.0, 1.0, &[0, 0, 255]);
+
+        let cropped = canvas.crop(2, 1, 2, 2).unwrap();
+        assert_eq!((2, 2), (cropped.width(), cropped.height()));
+        assert_eq!(vec![0xFF0000, 0x00FF00, 0x0000FF, 0xFFFFFF], cropped.to_u32_rgb());
+
+        assert!(matches!(canvas.crop(5, 0, 2, 1), Err(CanvasError::RegionOutOfBounds)));
+        assert!(matches!(canvas.crop(0, 4, 1, 2), Err(CanvasError::RegionOutOfBounds)));
+        assert!(matches!(canvas.crop(0, 0, 0, 1), Err(CanvasError::RegionOutOfBounds)));
+        assert!(canvas.crop(0, 0, 6, 5).is_ok());
+    }
+
+    #[test]
+    fn test_unsharp_mask() {
+        let width = 16;
+        let height = 8;
+        let mut canvas = SkiaCanvas::new(width, height);
+        canvas.fill_rect(0.0, 0.0, 0.5 * width as f32, height as f32, &[64, 64, 64]);
+        canvas.fill_rect(0.5 * width as f32, 0.0, 0.5 * width as f32, height as f32, &[192, 192, 192]);
+        canvas.unsharp_mask(1.0, 1.0);
+
+        let red = |x: u32| canvas.pixmap.pixel(x, height / 2).unwrap().red();
+        assert!(red(width / 2 - 1) < 64);
+        assert!(red(width / 2) > 192);
+        assert!((red(1) as i32 - 64).abs() <= 1);
+        assert!((red(width - 2) as i32 - 192).abs() <= 1);
+    }
+
+    #[cfg(feature = "text")]
+    #[test]
+    fn test_draw_text() {
+        // Minimal font with a single glyph: "I" is the rectangle (100, 0)-(300, 700) in a 1000 unit em
+        let font_data = include_bytes!("../test-data/i-glyph.ttf");
+        let mut canvas = SkiaCanvas::new(64, 64);
+        canvas.draw_text(font_data, "I", 20.0, 40.0, 32.0, &[0, 0, 0]).unwrap();
+        assert!(matches!(canvas.draw_text(&[0; 16], "I", 20.0, 40.0, 32.0, &[0, 0, 0]), Err(CanvasError::Font(_))));
+
+        let mut marked_count = 0;
+        for y in 0..64 {
+            for x in 0..64 {
+                let pixel = canvas.pixmap.pixel(x, y).unwrap();
+                let is_blank = pixel.red() == 255 && pixel.green() == 255 && pixel.blue() == 255;
+                // The glyph spans x + 0.1 em to x + 0.3 em and rises 0.7 em above the baseline
+                let is_in_glyph_box = (23..30).contains(&x) && (17..=40).contains(&y);
+                if is_in_glyph_box {
+                    marked_count += if is_blank { 0 } else { 1 };
+                } else {
+                    assert!(is_blank);
+                }
+            }
+        }
+        assert!(marked_count > 0);
+    }
+}
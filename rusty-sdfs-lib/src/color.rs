@@ -1,4 +1,26 @@
-use crate::{vec3, VecFloat};
+use crate::{vec3, Vec3, VecFloat};
+
+// Named palette colors, for use with hsl_from_hex
+pub mod palette {
+    pub const PAPER_WHITE: &str = "#F4F1EA";
+    pub const INK_BLACK: &str = "#1B1B1F";
+    pub const INDIGO: &str = "#2E3A87";
+    pub const PRUSSIAN_BLUE: &str = "#003153";
+    pub const VERMILION: &str = "#E34234";
+    pub const OCHRE: &str = "#CC7722";
+    pub const SAGE_GREEN: &str = "#87A96B";
+}
+
+// Parse a "#RRGGBB" string into HSL (hue in radians); panics on malformed input
+pub fn hsl_from_hex(hex: &str) -> Vec3 {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    assert!(digits.len() == 6 && digits.is_ascii(), "expected a color of the form #RRGGBB, got {:?}", hex);
+    let channel = |i: usize| {
+        u8::from_str_radix(&digits[2 * i..2 * i + 2], 16)
+            .unwrap_or_else(|_| panic!("expected a color of the form #RRGGBB, got {:?}", hex))
+    };
+    vec3::rgb_u8_to_hsl(&[channel(0), channel(1), channel(2)])
+}
 
 pub struct LinearGradient {
     stops: Vec<(f32, [u8; 3])>
@@ -14,6 +36,15 @@ impl LinearGradient {
         }
     }
 
+    // Evenly spaced stops from t = 0 (first color) to t = 1 (last color); panics if fewer than two colors are given
+    pub fn from_colors(colors: &[[u8; 3]]) -> Self {
+        assert!(colors.len() >= 2, "a gradient needs at least two colors, got {}", colors.len());
+        let last_index = (colors.len() - 1) as f32;
+        Self {
+            stops: colors.iter().enumerate().map(|(i, rgb)| (i as f32 / last_index, *rgb)).collect(),
+        }
+    }
+
     pub fn add_stop(&mut self, t: f32, rgb: &[u8; 3]) {
         if t <= 0.0 || t >= 1.0 {
             return;
@@ -50,3 +81,51 @@ impl LinearGradient {
         self.stops.last().unwrap().1
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_hsl_from_hex() {
+        let red = hsl_from_hex("#FF0000");
+        assert_approx_eq!(0.0, red.0);
+        assert_approx_eq!(1.0, red.1);
+        assert_approx_eq!(0.5, red.2);
+        assert_eq!([255, 0, 0], vec3::hsl_to_rgb_u8(&red));
+
+        let blue = hsl_from_hex("#0000ff");
+        assert_approx_eq!(240.0f32.to_radians(), blue.0);
+        assert_eq!([0, 0, 255], vec3::hsl_to_rgb_u8(&blue));
+
+        use palette::*;
+        for hex in [PAPER_WHITE, INK_BLACK, INDIGO, PRUSSIAN_BLUE, VERMILION, OCHRE, SAGE_GREEN] {
+            let hsl = hsl_from_hex(hex);
+            assert!(hsl.0 >= 0.0 && hsl.0 < 2.0 * std::f32::consts::PI);
+            assert!((0.0..=1.0).contains(&hsl.1));
+            assert!((0.0..=1.0).contains(&hsl.2));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_hsl_from_hex_malformed() {
+        hsl_from_hex("#12345");
+    }
+
+    #[test]
+    fn test_linear_gradient_from_colors() {
+        let gradient = LinearGradient::from_colors(&[[0, 0, 0], [200, 100, 0], [200, 200, 200]]);
+        assert_eq!([0, 0, 0], gradient.rgb(0.0));
+        assert_eq!([200, 100, 0], gradient.rgb(0.5));
+        assert_eq!([100, 50, 0], gradient.rgb(0.25));
+        assert_eq!([200, 200, 200], gradient.rgb(1.0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_linear_gradient_from_single_color() {
+        LinearGradient::from_colors(&[[0, 0, 0]]);
+    }
+}
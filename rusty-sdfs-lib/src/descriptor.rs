@@ -0,0 +1,154 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::canvas::SkiaCanvas;
+use crate::ray_marcher::RayMarcher;
+use crate::render::{render_scene_illustration, IllustrationConfig};
+use crate::scene::Scene;
+use crate::vector::{Vec3, VecFloat};
+
+#[derive(Debug)]
+pub enum DescriptorError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    UnknownScene(String),
+}
+
+impl fmt::Display for DescriptorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DescriptorError::Io(err) => write!(f, "I/O error: {}", err),
+            DescriptorError::Json(err) => write!(f, "JSON error: {}", err),
+            DescriptorError::UnknownScene(scene_id) => write!(f, "Unknown scene: {}", scene_id),
+        }
+    }
+}
+
+impl From<io::Error> for DescriptorError {
+    fn from(err: io::Error) -> DescriptorError {
+        DescriptorError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for DescriptorError {
+    fn from(err: serde_json::Error) -> DescriptorError {
+        DescriptorError::Json(err)
+    }
+}
+
+// Everything needed to reproduce a render, stored as JSON. Scenes are code, so the descriptor only names the scene;
+// the caller of render_from_descriptor resolves the name. The canvas size is taken from the illustration config.
+#[derive(Serialize, Deserialize)]
+pub struct RenderScene {
+    pub scene_id: String,
+    pub camera: Vec3,
+    pub look_at: Vec3,
+    pub up: Vec3,
+    pub fov_y_degrees: VecFloat,
+    pub dpi: f32,
+    pub illustration: IllustrationConfig,
+}
+
+impl RenderScene {
+    pub fn to_file(&self, path: &Path) -> Result<(), DescriptorError> {
+        let writer = BufWriter::new(File::create(path)?);
+        Ok(serde_json::to_writer_pretty(writer, self)?)
+    }
+
+    pub fn from_file(path: &Path) -> Result<RenderScene, DescriptorError> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+
+    // The ray marcher's step size factor follows from the scene (cf. RayMarcher::from_scene)
+    pub fn render<S>(&self, scene: &S) -> SkiaCanvas
+    where
+        S: Scene + Sync,
+    {
+        let aspect_ratio = self.illustration.width as VecFloat / self.illustration.height as VecFloat;
+        let ray_marcher = RayMarcher::from_scene(scene, &self.camera, &self.look_at, &self.up, self.fov_y_degrees, aspect_ratio);
+        let mut canvas = render_scene_illustration(&ray_marcher, scene, &self.illustration);
+        canvas.set_dpi(self.dpi, self.dpi);
+        canvas
+    }
+}
+
+// Loads the descriptor at path and renders the scene that scene_for_id returns for its scene id
+pub fn render_from_descriptor<S>(path: &Path, scene_for_id: impl Fn(&str) -> Option<S>) -> Result<SkiaCanvas, DescriptorError>
+where
+    S: Scene + Sync,
+{
+    let descriptor = RenderScene::from_file(path)?;
+    let scene = scene_for_id(&descriptor.scene_id).ok_or_else(|| DescriptorError::UnknownScene(descriptor.scene_id.clone()))?;
+    Ok(descriptor.render(&scene))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::{BackgroundSpec, Canvas, DirectionSource};
+    use crate::render::{EdgeConfig, HatchConfig, HatchDirection};
+    use crate::test_fixtures::scene_sphere;
+    use crate::vector::vec3;
+    use tiny_skia::LineCap;
+
+    #[test]
+    fn test_render_from_descriptor() {
+        let descriptor = RenderScene {
+            scene_id: "sphere".to_string(),
+            camera: vec3::from_values(0.0, 0.0, 5.0),
+            look_at: vec3::from_values(0.0, 0.0, 0.0),
+            up: vec3::from_values(0.0, 1.0, 0.0),
+            fov_y_degrees: 45.0,
+            dpi: 300.0,
+            illustration: IllustrationConfig {
+                width: 48,
+                height: 32,
+                angle_in_tangent_plane: 0.0,
+                direction_source: DirectionSource::Light,
+                background: BackgroundSpec::Flat(vec3::from_values(0.0, 0.0, 1.0)),
+                is_background_shaded: true,
+                streamlines: None,
+                hatching: vec![HatchConfig {
+                    lightness_threshold: 0.5,
+                    step_size: 0.5,
+                    line_color: [0, 0, 0],
+                    stroke_width: 1.0,
+                    direction: HatchDirection::Angle(0.25),
+                    line_sep: 3.0,
+                    line_cap: LineCap::Round,
+                    pressure: None,
+                    dry_brush: Some(0.3),
+                }],
+                edges: Some(EdgeConfig {
+                    color: [0, 0, 0],
+                    width: 1.0,
+                    depth_weight: None,
+                    direction_weight: Some(0.5),
+                }),
+            },
+        };
+        let dir = std::env::temp_dir().join(format!("rusty-sdfs-descriptor-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sphere.json");
+        descriptor.to_file(&path).unwrap();
+
+        let loaded = RenderScene::from_file(&path).unwrap();
+        assert_eq!(serde_json::to_string(&descriptor).unwrap(), serde_json::to_string(&loaded).unwrap());
+        assert!(matches!(loaded.illustration.hatching[0].line_cap, LineCap::Round));
+
+        let scene_for_id = |scene_id: &str| (scene_id == "sphere").then(scene_sphere);
+        let canvas = render_from_descriptor(&path, scene_for_id).unwrap();
+        assert_eq!((48, 32), (canvas.width(), canvas.height()));
+
+        let mut unknown = loaded;
+        unknown.scene_id = "cube".to_string();
+        unknown.to_file(&path).unwrap();
+        assert!(matches!(render_from_descriptor(&path, scene_for_id), Err(DescriptorError::UnknownScene(_))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -20,6 +20,7 @@ pub fn on_jittered_grid<F>(
     height: f32,
     cell_count_x: u32,
     cell_count_y: u32,
+    jitter: f32, // in [0, 1]: 0 yields the cell centers, 1 yields uniformly distributed points within each cell
     rng: &mut dyn RngCore,
     mut f: F,
 ) where
@@ -27,11 +28,46 @@ pub fn on_jittered_grid<F>(
 {
     let cell_width = width / (cell_count_x as f32);
     let cell_height = height / (cell_count_y as f32);
+    let center_offset = 0.5 * (1.0 - jitter);
     for i_y in 0..cell_count_y {
         for i_x in 0..cell_count_x {
-            let x = cell_width * ((i_x as f32) + rng.gen::<f32>());
-            let y = cell_height * ((i_y as f32) + rng.gen::<f32>());
+            let x = cell_width * ((i_x as f32) + center_offset + jitter * rng.gen::<f32>());
+            let y = cell_height * ((i_y as f32) + center_offset + jitter * rng.gen::<f32>());
             f(x, y);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_on_jittered_grid_no_jitter() {
+        let mut centers = Vec::new();
+        on_grid(10.0, 6.0, 5, 3, |x, y, w, h| centers.push((x + 0.5 * w, y + 0.5 * h)));
+        let mut points = Vec::new();
+        let mut rng = StdRng::seed_from_u64(7);
+        on_jittered_grid(10.0, 6.0, 5, 3, 0.0, &mut rng, |x, y| points.push((x, y)));
+        assert_eq!(centers, points);
+    }
+
+    #[test]
+    fn test_on_jittered_grid_full_jitter() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut expected = Vec::new();
+        for i_y in 0..3 {
+            for i_x in 0..5 {
+                let x = 2.0 * ((i_x as f32) + rng.gen::<f32>());
+                let y = 2.0 * ((i_y as f32) + rng.gen::<f32>());
+                expected.push((x, y));
+            }
+        }
+        let mut points = Vec::new();
+        let mut rng = StdRng::seed_from_u64(7);
+        on_jittered_grid(10.0, 6.0, 5, 3, 1.0, &mut rng, |x, y| points.push((x, y)));
+        assert_eq!(expected, points);
+    }
+}
@@ -3,29 +3,42 @@
 mod animation;
 mod canvas;
 mod color;
+mod descriptor;
+#[cfg(feature = "sdf-text")]
+mod font;
 mod grid;
 mod noise;
 mod ray_marcher;
+mod remapping;
 mod render;
 mod scene;
 mod sdf;
 mod streamline;
+#[cfg(test)]
+pub(crate) mod test_fixtures;
 mod vector;
 
 pub use animation::Animation;
 
-pub use canvas::{Canvas, PixelPropertyCanvas, SkiaCanvas};
+pub use canvas::{BackgroundSpec, Canvas, CanvasError, DirectionSource, FloatCanvas, Kernel, PixelCoord, PixelProperties, PixelPropertyCanvas, SamplePattern, SceneRenderOptions, SkiaCanvas};
+pub use tiny_skia::{FillRule, LineCap, LineJoin};
 
-pub use color::LinearGradient;
+pub use color::{hsl_from_hex, palette, LinearGradient};
 
-pub use noise::{noise_2d, noisy_waves_heightmap, smoothstep};
+pub use descriptor::{render_from_descriptor, DescriptorError, RenderScene};
 
-pub use ray_marcher::RayMarcher;
+pub use noise::{advect_field, generate_blue_noise_mask, noise_2d, noisy_waves_heightmap, smoothstep};
 
-pub use render::{render_flow_field_streamlines, DomainRegion, render_heightmap_streamlines, render_hatch_lines, render_edges};
+pub use ray_marcher::{EnvironmentGradient, HitRecord, QualitySettings, RayMarcher, UpAxis};
 
-pub use scene::Scene;
+pub use remapping::{smooth_abs, smooth_sign};
 
-pub use sdf::{sdf_op, Material, ReflectiveProperties, SdfOutput};
+pub use render::{render_flow_field_streamlines, render_flow_field_streamlines_progressive, DomainRegion, render_heightmap_streamlines, outline_stroke, render_hatch_lines, render_tone_matched_hatch_lines, render_direction_tiled_hatch_lines, render_cross_slope_hatch_lines, hatch_separation_for_coverage, render_edges, extract_edges_vector, render_direction_quiver, render_dog_edges, render_halftone, extract_silhouette, render_depth_contours, render_scene_illustration, EdgeConfig, HatchConfig, HatchDirection, IllustrationConfig, StreamlineParams, StreamlineParamsError};
+
+pub use scene::{AnimatedScene, CachedScene, CountingScene, HeightmapScene, Scene, SmoothUnionScene, TransformScene, UnionScene};
+
+pub use sdf::{sdf_op, Material, ReflectiveProperties, SdfOutput, ShadowMode, WaterProperties};
+
+pub use streamline::{prune_streamlines, SeedOrder, StreamlineConfig, StrokePressure};
 
 pub use vector::{vec2, vec3, vec4, Vec2, Vec3, Vec4, VecFloat};
@@ -1,6 +1,6 @@
 use wyhash::wyhash;
 
-use crate::vector::{vec2, VecFloat};
+use crate::vector::{vec2, Vec2, VecFloat};
 
 const WYHASH_DEFAULT_SEED1: u64 = 14678021983192906369;
 const WYHASH_DEFAULT_SEED2: u64 = 601104623970451784;
@@ -45,6 +45,15 @@ pub fn rand_3d(x: f32, y: f32, z: f32, seed: u64) -> VecFloat {
     2.0 * ((wyhash(&bytes, seed) as VecFloat) / (u64::MAX as VecFloat)) - 1.0
 }
 
+// Random values at the integers, interpolated smoothly in between; in [-1, 1]
+pub fn value_noise_1d(x: VecFloat, seed: u64) -> VecFloat {
+    let idx = x.floor();
+    let u = smoothstep(x - idx);
+    let v0 = rand_1d(idx, seed);
+    let v1 = rand_1d(idx + 1.0, seed);
+    v0 + u * (v1 - v0)
+}
+
 fn noise_1d_octave(x: VecFloat) -> VecFloat {
     let idx = x.floor();
     let t = x - idx;
@@ -140,6 +149,115 @@ pub fn noisy_waves_heightmap(x: VecFloat, y: VecFloat) -> VecFloat {
     accum
 }
 
+// Tileable blue-noise threshold mask with values in (0, 1), generated with the void-and-cluster method, cf.
+// R. Ulichney, "The void-and-cluster method for dither array generation", 1993.
+// Generation takes O((width * height)^2) time, so precompute the mask once and tile it (e.g., index it by
+// (x % width, y % height)) instead of generating canvas-sized masks.
+pub fn generate_blue_noise_mask(width: u32, height: u32, seed: u64) -> Vec<f32> {
+    const SIGMA: f32 = 1.5;
+    const INITIAL_DENSITY: f32 = 0.1;
+    let w = width as usize;
+    let h = height as usize;
+    let n = w * h;
+    if n == 0 {
+        return Vec::new();
+    }
+
+    // Gaussian energy of a point at each (toroidal) offset
+    let gaussian: Vec<f32> = (0..n).map(|i| {
+        let dx = (i % w).min(w - i % w) as f32;
+        let dy = (i / w).min(h - i / w) as f32;
+        (-(dx * dx + dy * dy) / (2.0 * SIGMA * SIGMA)).exp()
+    }).collect();
+    let update_energy = |energy: &mut [f32], index: usize, sign: f32| {
+        let (x0, y0) = (index % w, index / w);
+        for (i, e) in energy.iter_mut().enumerate() {
+            let dx = (i % w + w - x0) % w;
+            let dy = (i / w + h - y0) % h;
+            *e += sign * gaussian[dy * w + dx];
+        }
+    };
+    let tightest_cluster = |pattern: &[bool], energy: &[f32]| {
+        (0..n).filter(|&i| pattern[i]).max_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+    let largest_void = |pattern: &[bool], energy: &[f32]| {
+        (0..n).filter(|&i| !pattern[i]).min_by(|&a, &b| energy[a].total_cmp(&energy[b])).unwrap()
+    };
+
+    // Initial binary pattern: white noise
+    let mut pattern: Vec<bool> = (0..n)
+        .map(|i| 0.5 * (rand_2d((i % w) as f32, (i / w) as f32, seed) + 1.0) < INITIAL_DENSITY)
+        .collect();
+    if !pattern.contains(&true) {
+        pattern[0] = true;
+    }
+    if !pattern.contains(&false) {
+        pattern[n - 1] = false;
+    }
+    let mut energy = vec![0.0; n];
+    for i in (0..n).filter(|&i| pattern[i]) {
+        update_energy(&mut energy, i, 1.0);
+    }
+
+    // Relax the initial pattern by moving points from the tightest cluster to the largest void until stable
+    for _ in 0..n {
+        let cluster = tightest_cluster(&pattern, &energy);
+        pattern[cluster] = false;
+        update_energy(&mut energy, cluster, -1.0);
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        update_energy(&mut energy, void, 1.0);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; n];
+    let initial_count = pattern.iter().filter(|&&is_set| is_set).count();
+
+    // Rank the points of the initial pattern by successively removing the tightest cluster
+    let mut phase_pattern = pattern.clone();
+    let mut phase_energy = energy.clone();
+    for rank in (0..initial_count).rev() {
+        let cluster = tightest_cluster(&phase_pattern, &phase_energy);
+        phase_pattern[cluster] = false;
+        update_energy(&mut phase_energy, cluster, -1.0);
+        ranks[cluster] = rank;
+    }
+
+    // Rank the remaining pixels by successively filling the largest void
+    for rank in initial_count..n {
+        let void = largest_void(&pattern, &energy);
+        pattern[void] = true;
+        update_energy(&mut energy, void, 1.0);
+        ranks[void] = rank;
+    }
+
+    ranks.iter().map(|&rank| (rank as f32 + 0.5) / n as f32).collect()
+}
+
+// Semi-Lagrangian advection of a row-major width x height field by a flow in pixels per unit of time: every pixel takes
+// the bilinearly interpolated value of base at the point p - dt * flow(p) that the flow carries onto it. The field
+// wraps around at the borders, so tileable noise stays tileable.
+pub fn advect_field(base: &[f32], flow: &[Vec2], width: u32, height: u32, dt: f32) -> Vec<f32> {
+    let w = width as usize;
+    let h = height as usize;
+    assert!(base.len() == w * h && flow.len() == w * h, "base and flow must have width * height entries");
+    let value_at = |x: i64, y: i64| base[y.rem_euclid(h as i64) as usize * w + x.rem_euclid(w as i64) as usize];
+    (0..w * h)
+        .map(|i| {
+            let p = vec2::from_values((i % w) as f32, (i / w) as f32);
+            let q = vec2::scale_and_add(&p, &flow[i], -dt);
+            let (x0, y0) = (q.0.floor(), q.1.floor());
+            let (tx, ty) = (q.0 - x0, q.1 - y0);
+            let (x0, y0) = (x0 as i64, y0 as i64);
+            let top = (1.0 - tx) * value_at(x0, y0) + tx * value_at(x0 + 1, y0);
+            let bottom = (1.0 - tx) * value_at(x0, y0 + 1) + tx * value_at(x0 + 1, y0 + 1);
+            (1.0 - ty) * top + ty * bottom
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -270,4 +388,67 @@ mod tests {
         println!("Info for rand_3d: collision share for different seeds = {collision_share_seed}");
         assert!(collision_share_seed <= MAX_COLLISION_SHARE);
     }
+
+    #[test]
+    fn test_generate_blue_noise_mask() {
+        const SIZE: u32 = 32;
+        const THRESHOLD: f32 = 0.1;
+        let mask = generate_blue_noise_mask(SIZE, SIZE, WYHASH_DEFAULT_SEED3);
+        assert_eq!((SIZE * SIZE) as usize, mask.len());
+        // Every threshold value occurs exactly once
+        let mut sorted_mask = mask.clone();
+        sorted_mask.sort_by(|a, b| a.total_cmp(b));
+        assert!(sorted_mask.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(sorted_mask[0] > 0.0 && sorted_mask[sorted_mask.len() - 1] < 1.0);
+
+        // Coarse proxy for suppressed low frequencies: points selected by thresholding the mask are spread out more
+        // evenly than white noise of the same density, i.e., their mean nearest-neighbor distance is larger
+        let mean_nearest_neighbor_dist = |points: &[(i32, i32)]| {
+            let s = SIZE as i32;
+            let toroidal_delta = |a: i32, b: i32| { let d = (a - b).rem_euclid(s); d.min(s - d) as f32 };
+            points.iter().map(|p| {
+                points.iter()
+                    .filter(|q| *q != p)
+                    .map(|q| (toroidal_delta(p.0, q.0).powi(2) + toroidal_delta(p.1, q.1).powi(2)).sqrt())
+                    .fold(f32::INFINITY, f32::min)
+            }).sum::<f32>() / points.len() as f32
+        };
+        let coordinates = |i: usize| ((i as u32 % SIZE) as i32, (i as u32 / SIZE) as i32);
+        let blue_points: Vec<(i32, i32)> = (0..mask.len()).filter(|&i| mask[i] < THRESHOLD).map(coordinates).collect();
+        let white_points: Vec<(i32, i32)> = (0..mask.len())
+            .filter(|&i| {
+                let (x, y) = coordinates(i);
+                0.5 * (rand_2d(x as f32, y as f32, WYHASH_DEFAULT_SEED2) + 1.0) < THRESHOLD
+            })
+            .map(coordinates)
+            .collect();
+        let blue_dist = mean_nearest_neighbor_dist(&blue_points);
+        let white_dist = mean_nearest_neighbor_dist(&white_points);
+        println!("Info for generate_blue_noise_mask: mean nearest-neighbor distance = {blue_dist} (white noise: {white_dist})");
+        assert!(blue_dist > 1.3 * white_dist);
+    }
+
+    #[test]
+    fn test_advect_field() {
+        let width = 16;
+        let height = 12;
+        let bump = |x: usize, y: usize| {
+            let mut field = vec![0.0; (width * height) as usize];
+            field[y * width as usize + x] = 1.0;
+            field
+        };
+        let constant_flow = |v: Vec2| vec![v; (width * height) as usize];
+
+        let advected = advect_field(&bump(5, 5), &constant_flow(vec2::from_values(4.0, 2.0)), width, height, 0.5);
+        assert_eq!(bump(7, 6), advected);
+
+        // Features leaving the field enter it on the opposite side
+        let advected = advect_field(&bump(15, 0), &constant_flow(vec2::from_values(1.0, -1.0)), width, height, 1.0);
+        assert_eq!(bump(0, 11), advected);
+
+        let advected = advect_field(&bump(5, 5), &constant_flow(vec2::from_values(0.25, 0.0)), width, height, 1.0);
+        assert_eq!(0.75, advected[5 * width as usize + 5]);
+        assert_eq!(0.25, advected[5 * width as usize + 6]);
+        assert_eq!(1.0, advected.iter().sum::<f32>());
+    }
 }
@@ -1,17 +1,131 @@
 use crate::scene::Scene;
-use crate::sdf::{Material, ReflectiveProperties};
+use crate::sdf::{sdf_op, Material, ReflectiveProperties, SdfOutput, ShadowMode, WaterProperties};
 use crate::vector::{vec2, vec3, Vec2, Vec3, VecFloat};
 
+// World axis pointing up. Heightmaps h(a, b) are sampled in the ground plane spanned by the other two axes, i.e.,
+// (x, z) for Y-up and (x, y) for Z-up as is common for CAD and GIS data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UpAxis {
+    Y,
+    Z,
+}
+
+impl UpAxis {
+    pub fn vector(&self) -> Vec3 {
+        match self {
+            UpAxis::Y => vec3::from_values(0.0, 1.0, 0.0),
+            UpAxis::Z => vec3::from_values(0.0, 0.0, 1.0),
+        }
+    }
+
+    pub fn height(&self, p: &Vec3) -> VecFloat {
+        match self {
+            UpAxis::Y => p.1,
+            UpAxis::Z => p.2,
+        }
+    }
+
+    pub fn ground(&self, p: &Vec3) -> Vec2 {
+        match self {
+            UpAxis::Y => vec2::from_values(p.0, p.2),
+            UpAxis::Z => vec2::from_values(p.0, p.1),
+        }
+    }
+
+    pub fn from_ground(&self, ground: &Vec2, height: VecFloat) -> Vec3 {
+        match self {
+            UpAxis::Y => vec3::from_values(ground.0, height, ground.1),
+            UpAxis::Z => vec3::from_values(ground.0, ground.1, height),
+        }
+    }
+}
+
+// Sky gradient (HSL) for cheap environment lookups; the elevation is measured along the ray marcher's up axis
+#[derive(Clone, Copy)]
+pub struct EnvironmentGradient {
+    pub zenith_hsl: Vec3,
+    pub horizon_hsl: Vec3,
+    pub nadir_hsl: Vec3,
+}
+
+impl EnvironmentGradient {
+    pub fn new(zenith_hsl: &Vec3, horizon_hsl: &Vec3, nadir_hsl: &Vec3) -> EnvironmentGradient {
+        EnvironmentGradient {
+            zenith_hsl: *zenith_hsl,
+            horizon_hsl: *horizon_hsl,
+            nadir_hsl: *nadir_hsl,
+        }
+    }
+}
+
+impl Default for EnvironmentGradient {
+    fn default() -> EnvironmentGradient {
+        Self::new(
+            &vec3::from_values(210.0f32.to_radians(), 0.6, 0.65),
+            &vec3::from_values(200.0f32.to_radians(), 0.3, 0.95),
+            &vec3::from_values(200.0f32.to_radians(), 0.2, 0.2),
+        )
+    }
+}
+
+// Which of the expensive lighting terms to evaluate; disabling them is meant for fast previews
+#[derive(Clone, Copy)]
+pub struct QualitySettings {
+    pub ambient_occlusion: bool, // if false, surfaces are treated as fully unoccluded
+    pub shadows: bool,           // if false, every surface facing the light is lit
+}
+
+impl QualitySettings {
+    pub fn preview() -> QualitySettings {
+        QualitySettings {
+            ambient_occlusion: false,
+            shadows: false,
+        }
+    }
+}
+
+impl Default for QualitySettings {
+    fn default() -> QualitySettings {
+        QualitySettings {
+            ambient_occlusion: true,
+            shadows: true,
+        }
+    }
+}
+
+// Diffuse term with wrap lighting: max((cos + wrap) / (1 + wrap), 0), all of which is subject to shadowing
+fn wrapped_diffuse(cos_to_light: VecFloat, wrap: VecFloat, visibility_factor: VecFloat) -> VecFloat {
+    visibility_factor * ((cos_to_light + wrap) / (1.0 + wrap)).max(0.0)
+}
+
+// Everything known about where a camera ray hits the scene, cf. RayMarcher::march
+#[derive(Clone, Copy)]
+pub struct HitRecord {
+    pub point: Vec3,
+    pub depth: VecFloat, // distance from the camera along the ray
+    pub material: Material,
+    pub normal: Vec3,
+    pub steps: u32, // number of scene evaluations until the hit
+}
+
+#[derive(Clone)]
 pub struct RayMarcher {
     max_ray_iter_steps: u32,
     min_scene_dist: VecFloat,
     max_scene_dist: VecFloat,
-    initial_scene_dist: VecFloat,
+    pub initial_scene_dist: VecFloat, // distance that secondary rays skip before the first scene evaluation
+    pub ray_origin_epsilon: VecFloat, // secondary rays start this far off the surface along its normal
+    pub shadow_max_distance: Option<VecFloat>, // shadow rays give up (unoccluded) beyond this distance
+    pub silhouette_coverage: bool, // let PixelPropertyCanvas::from_scene estimate the coverage of missed pixels at silhouettes
+    pub screen_offset: Vec2, // shifts the projection in screen coordinates, e.g., to jitter it by sub-pixel offsets
     finite_diff_h: VecFloat,
     step_size_factor: VecFloat, // set to 1 / sqrt(max_x(dh(x)/dx)^2 + 1) so safely raymarch heightmap h(x)
     pub camera: Vec3,
+    pub clip_plane: Option<(Vec3, VecFloat, Material)>, // (unit normal, offset, cap material); the side the normal points to is cut away
+    pub environment: EnvironmentGradient,
     look_at: Vec3,
     up: Vec3,
+    up_axis: UpAxis,
     fov_y: VecFloat,
     aspect_ratio: VecFloat,
     half_screen_length_y: VecFloat, // assuming half_screen_length_x = 1
@@ -29,11 +143,81 @@ impl RayMarcher {
         up: &Vec3,
         fov_y_degrees: VecFloat,
         aspect_ratio: VecFloat,
+    ) -> RayMarcher {
+        Self::new_with_up_axis(step_size_factor, camera, look_at, up, UpAxis::Y, fov_y_degrees, aspect_ratio)
+    }
+
+    // The step size factor is derived from the scene's Lipschitz bound L: steps of distance / L never overshoot the
+    // surface. Bounds below 1 still yield full steps.
+    pub fn from_scene(
+        scene: &impl Scene,
+        camera: &Vec3,
+        look_at: &Vec3,
+        up: &Vec3,
+        fov_y_degrees: VecFloat,
+        aspect_ratio: VecFloat,
+    ) -> RayMarcher {
+        let step_size_factor = 1.0 / scene.lipschitz_bound().max(1.0);
+        Self::new(step_size_factor, camera, look_at, up, fov_y_degrees, aspect_ratio)
+    }
+
+    // Looks at the center of the axis-aligned box [bounds_min, bounds_max] from direction (pointing from the box
+    // towards the camera), from just far enough away that all of the box is on screen
+    #[allow(clippy::too_many_arguments)]
+    pub fn frame_bounds(
+        step_size_factor: VecFloat,
+        bounds_min: &Vec3,
+        bounds_max: &Vec3,
+        direction: &Vec3,
+        up: &Vec3,
+        fov_y_degrees: VecFloat,
+        aspect_ratio: VecFloat,
+    ) -> RayMarcher {
+        let center = vec3::scale(&vec3::add(bounds_min, bounds_max), 0.5);
+        let direction = vec3::normalize(direction);
+        // The camera basis does not depend on the distance, so measure the corners from a camera at distance 1
+        let unit_distance = Self::new(step_size_factor, &vec3::add(&center, &direction), &center, up, fov_y_degrees, aspect_ratio);
+        let half_screen_length_x = unit_distance.aspect_ratio * unit_distance.half_screen_length_y;
+        let distance = (0..8)
+            .map(|i| {
+                let corner = vec3::from_values(
+                    if i & 1 == 0 { bounds_min.0 } else { bounds_max.0 },
+                    if i & 2 == 0 { bounds_min.1 } else { bounds_max.1 },
+                    if i & 4 == 0 { bounds_min.2 } else { bounds_max.2 },
+                );
+                // At distance d, the corner lies at depth z + d - 1 and has to satisfy |x| / depth <= half_screen_length_x
+                // and |y| / depth <= half_screen_length_y
+                let q = unit_distance.to_camera_coordinates(&corner);
+                let depth_needed = (q.0.abs() / half_screen_length_x)
+                    .max(q.1.abs() / unit_distance.half_screen_length_y)
+                    .max(1.0e-3);
+                depth_needed - q.2 + 1.0
+            })
+            .fold(0.0, VecFloat::max);
+        Self::new(step_size_factor, &vec3::scale_and_add(&center, &direction, distance), &center, up, fov_y_degrees, aspect_ratio)
+    }
+
+    // up orients the screen, whereas up_axis determines the world's vertical for heightmaps and the environment
+    pub fn new_with_up_axis(
+        step_size_factor: VecFloat,
+        camera: &Vec3,
+        look_at: &Vec3,
+        up: &Vec3,
+        up_axis: UpAxis,
+        fov_y_degrees: VecFloat,
+        aspect_ratio: VecFloat,
     ) -> RayMarcher {
         let fov_y = fov_y_degrees.to_radians();
         let half_screen_length_y = (0.5 * fov_y).tan();
         let w = vec3::normalize(&vec3::sub(look_at, camera)); // w = normalize(lookAt - camera)
-        let v = vec3::normalize(&vec3::scale_and_add(up, &w, -vec3::dot(up, &w))); // v = normalize(up - dot(up, w) * w)
+        // When looking along the up axis (e.g., a top-down view), up carries no information about the screen's
+        // vertical; fall back to the world axis that is least aligned with the view direction
+        let up = if vec3::len_squared(&vec3::cross(up, &w)) < 1.0e-6 * vec3::len_squared(up) {
+            if w.2.abs() < 0.9 { vec3::from_values(0.0, 0.0, 1.0) } else { vec3::from_values(1.0, 0.0, 0.0) }
+        } else {
+            *up
+        };
+        let v = vec3::normalize(&vec3::scale_and_add(&up, &w, -vec3::dot(&up, &w))); // v = normalize(up - dot(up, w) * w)
         let u = vec3::cross(&w, &v); // u = cross(w, v)
 
         RayMarcher {
@@ -41,11 +225,18 @@ impl RayMarcher {
             min_scene_dist: 0.001,
             max_scene_dist: 1.0e4,
             initial_scene_dist: 25.0 * 0.001,
+            ray_origin_epsilon: 2.0 * 0.001,
+            shadow_max_distance: None,
+            silhouette_coverage: false,
+            screen_offset: vec2::from_values(0.0, 0.0),
             finite_diff_h: 0.005 * step_size_factor,
             step_size_factor,
             camera: *camera,
+            clip_plane: None,
+            environment: EnvironmentGradient::default(),
             look_at: *look_at,
-            up: *up,
+            up,
+            up_axis,
             fov_y,
             aspect_ratio,
             half_screen_length_y,
@@ -55,25 +246,136 @@ impl RayMarcher {
         }
     }
 
+    fn eval_scene(&self, scene: &impl Scene, p: &Vec3) -> SdfOutput {
+        // Intersect the scene with the half-space behind the clip plane. Where the plane cuts through an object,
+        // the plane itself becomes the surface (a flat cap), which is shaded with the cap material.
+        let out = scene.eval(p);
+        match &self.clip_plane {
+            Some((normal, offset, cap_material)) => {
+                let plane_distance = sdf_op::sd_plane(p, normal, *offset);
+                if plane_distance > out.distance {
+                    SdfOutput::new(plane_distance, *cap_material)
+                } else {
+                    out
+                }
+            }
+            None => out,
+        }
+    }
+
     // screen_coordinates \in [-1, 1]^2
     pub fn intersection_with_scene(
         &self,
         scene: &impl Scene,
         screen_coordinates: &Vec2,
     ) -> Option<(Vec3, VecFloat, Material)> {
+        self.intersection_or_closest_approach(scene, screen_coordinates).ok()
+    }
+
+    // screen_coordinates \in [-1, 1]^2
+    // Like intersection_with_scene, but a miss yields the smallest ratio of scene distance to ray length along the ray,
+    // i.e., approximately the tangent of the narrowest angle at which the ray passed the scene
+    pub fn intersection_or_closest_approach(
+        &self,
+        scene: &impl Scene,
+        screen_coordinates: &Vec2,
+    ) -> Result<(Vec3, VecFloat, Material), VecFloat> {
+        self.march_steps(scene, screen_coordinates).map(|(p, len, material, _)| (p, len, material))
+    }
+
+    // screen_coordinates \in [-1, 1]^2
+    // Like intersection_with_scene, but also evaluates the normal at the hit point
+    pub fn march(&self, scene: &impl Scene, screen_coordinates: &Vec2) -> Option<HitRecord> {
+        self.march_or_closest_approach(scene, screen_coordinates, self.finite_diff_h).ok()
+    }
+
+    // screen_coordinates \in [-1, 1]^2
+    // Like march, with the normal evaluated with the given finite difference h (cf. scene_normal_with_h); a miss yields
+    // the same ratio as intersection_or_closest_approach
+    pub fn march_or_closest_approach(
+        &self,
+        scene: &impl Scene,
+        screen_coordinates: &Vec2,
+        normal_finite_diff_h: VecFloat,
+    ) -> Result<HitRecord, VecFloat> {
+        self.march_steps(scene, screen_coordinates).map(|(point, depth, material, steps)| HitRecord {
+            point,
+            depth,
+            material,
+            normal: self.scene_normal_with_h(scene, &point, normal_finite_diff_h),
+            steps,
+        })
+    }
+
+    fn march_steps(
+        &self,
+        scene: &impl Scene,
+        screen_coordinates: &Vec2,
+    ) -> Result<(Vec3, VecFloat, Material, u32), VecFloat> {
         let dir = self.screen_direction(screen_coordinates);
         let mut len: VecFloat = 0.0;
-        for _ in 0..self.max_ray_iter_steps {
+        let mut min_ratio = VecFloat::INFINITY;
+        for step in 1..=self.max_ray_iter_steps {
             let p = vec3::scale_and_add(&self.camera, &dir, len); // p = camera + len * dir
-            let out = scene.eval(&p);
+            let out = self.eval_scene(scene, &p);
             if out.distance < self.min_scene_dist {
-                return Some((p, len, out.material));
+                return Ok((p, len, out.material, step));
             } else if out.distance > self.max_scene_dist {
-                return None;
+                return Err(min_ratio);
+            }
+            if len > 0.0 {
+                min_ratio = min_ratio.min(out.distance / len);
             }
             len += self.step_size_factor * out.distance;
         }
-        None
+        Err(min_ratio)
+    }
+
+    // Tangent of half the angle that a pixel of a canvas of the given height spans (at the center of the screen)
+    pub fn pixel_half_angle_tan(&self, canvas_height: u32) -> VecFloat {
+        self.half_screen_length_y / canvas_height as VecFloat
+    }
+
+    // screen_coordinates \in [-1, 1]^2
+    // Returns up to max_hits surface crossings along the ray, ordered by depth
+    pub fn all_intersections(
+        &self,
+        scene: &impl Scene,
+        screen_coordinates: &Vec2,
+        max_hits: usize,
+    ) -> Vec<(Vec3, VecFloat, Material)> {
+        let dir = self.screen_direction(screen_coordinates);
+        let mut hits = Vec::new();
+        let mut len: VecFloat = 0.0;
+        let mut previous_sign: Option<VecFloat> = None;
+        let mut is_in_surface_band = false;
+        for _ in 0..self.max_ray_iter_steps {
+            if hits.len() >= max_hits {
+                break;
+            }
+            let p = vec3::scale_and_add(&self.camera, &dir, len); // p = camera + len * dir
+            let out = self.eval_scene(scene, &p);
+            let dist = out.distance.abs();
+            if dist > self.max_scene_dist {
+                break;
+            }
+            // A sign change without a preceding near-surface sample means we stepped across a thin shell
+            let is_sign_changed = previous_sign.is_some_and(|sign| sign != out.distance.signum());
+            if dist < self.min_scene_dist || is_sign_changed {
+                if !is_in_surface_band {
+                    hits.push((p, len, out.material));
+                }
+                // Step through the surface with small, fixed steps until we leave its vicinity
+                is_in_surface_band = true;
+                len += 2.0 * self.min_scene_dist;
+            } else {
+                // Inside of objects, march on the unsigned distance to reach the surface from the back
+                is_in_surface_band = false;
+                len += self.step_size_factor * dist;
+            }
+            previous_sign = Some(out.distance.signum());
+        }
+        hits
     }
 
     // screen_coordinates \in [-1, 1]^2
@@ -86,19 +388,19 @@ impl RayMarcher {
         F: Fn(f32, f32) -> f32,
     {
         let dir = self.screen_direction(screen_coordinates);
-        // To find the closest intersection of r(t) = camera + t * dir and the xz-surface described by heightmap h,
+        // To find the closest intersection of r(t) = camera + t * dir and the ground surface described by heightmap h,
         // employ a combination of a safe search strategy and an unsafe method. With the safe method, take steps
-        // until you walk through the surface (i.e., the sign r.y - h changes). Then, the intersection should be
+        // until you walk through the surface (i.e., the sign of the height of r above h changes). Then, the intersection should be
         // in the interval of the last and second to last step. Employ the unsafe method to quickly narrow in on
         // the intersection.
         // Combine relaxed cone stepping and the false position method.
         let mut ta: VecFloat = 0.0;
-        let mut ha = self.camera.1 - heightmap(self.camera.0, self.camera.2);
+        let mut ha = self.heightmap_gap(heightmap, &self.camera);
         let mut tb = self.step_size_factor * ha;
         let mut hb;
         for _ in 0..self.max_ray_iter_steps {
             let pb = vec3::scale_and_add(&self.camera, &dir, tb); // p = camera + tb * dir
-            hb = pb.1 - heightmap(pb.0, pb.2);
+            hb = self.heightmap_gap(heightmap, &pb);
             if hb < self.min_scene_dist {
                 return Some((pb, tb));
             }
@@ -108,7 +410,7 @@ impl RayMarcher {
                 loop {
                     let tc = tb - hb * ((tb - ta) / (hb - ha));
                     let pc = vec3::scale_and_add(&self.camera, &dir, tc);
-                    let hc = pc.1 - heightmap(pc.0, pc.2);
+                    let hc = self.heightmap_gap(heightmap, &pc);
                     if hc < self.min_scene_dist {
                         return Some((pc, tc));
                     }
@@ -128,11 +430,33 @@ impl RayMarcher {
         None
     }
 
+    pub fn up_axis(&self) -> UpAxis {
+        self.up_axis
+    }
+
+    // Vertical distance of p above the heightmap surface
+    fn heightmap_gap<F>(&self, heightmap: &F, p: &Vec3) -> VecFloat
+    where
+        F: Fn(f32, f32) -> f32,
+    {
+        let ground = self.up_axis.ground(p);
+        self.up_axis.height(p) - heightmap(ground.0, ground.1)
+    }
+
     pub fn to_screen_coordinates(&self, p_scene: &Vec3) -> Vec2 {
         let camera_coord = self.to_camera_coordinates(p_scene);
         vec2::from_values(
-            (camera_coord.0 / camera_coord.2) / (self.aspect_ratio * self.half_screen_length_y),
-            (camera_coord.1 / camera_coord.2) / self.half_screen_length_y,
+            (camera_coord.0 / camera_coord.2) / (self.aspect_ratio * self.half_screen_length_y) - self.screen_offset.0,
+            (camera_coord.1 / camera_coord.2) / self.half_screen_length_y - self.screen_offset.1,
+        )
+    }
+
+    // Direction (e.g., a normal) expressed in the camera's basis: x to the right, y up, z towards the scene
+    pub fn to_camera_direction(&self, dir: &Vec3) -> Vec3 {
+        vec3::from_values(
+            vec3::dot(dir, &self.u),
+            vec3::dot(dir, &self.v),
+            vec3::dot(dir, &self.w),
         )
     }
 
@@ -145,10 +469,19 @@ impl RayMarcher {
         )
     }
 
+    pub fn finite_diff_h(&self) -> VecFloat {
+        self.finite_diff_h
+    }
+
     pub fn scene_normal(&self, scene: &impl Scene, p: &Vec3) -> Vec3 {
-        let d_x = vec3::from_values(self.finite_diff_h, 0.0, 0.0);
-        let d_y = vec3::from_values(0.0, self.finite_diff_h, 0.0);
-        let d_z = vec3::from_values(0.0, 0.0, self.finite_diff_h);
+        self.scene_normal_with_h(scene, p, self.finite_diff_h)
+    }
+
+    // Smaller h resolves thin features more accurately, larger h yields smoother normals
+    pub fn scene_normal_with_h(&self, scene: &impl Scene, p: &Vec3, h: VecFloat) -> Vec3 {
+        let d_x = vec3::from_values(h, 0.0, 0.0);
+        let d_y = vec3::from_values(0.0, h, 0.0);
+        let d_z = vec3::from_values(0.0, 0.0, h);
 
         let ppd_x = vec3::add(p, &d_x);
         let pmd_x = vec3::sub(p, &d_x);
@@ -158,9 +491,9 @@ impl RayMarcher {
         let pmd_z = vec3::sub(p, &d_z);
 
         vec3::normalize_inplace(vec3::from_values(
-            scene.eval(&ppd_x).distance - scene.eval(&pmd_x).distance,
-            scene.eval(&ppd_y).distance - scene.eval(&pmd_y).distance,
-            scene.eval(&ppd_z).distance - scene.eval(&pmd_z).distance,
+            self.eval_scene(scene, &ppd_x).distance - self.eval_scene(scene, &pmd_x).distance,
+            self.eval_scene(scene, &ppd_y).distance - self.eval_scene(scene, &pmd_y).distance,
+            self.eval_scene(scene, &ppd_z).distance - self.eval_scene(scene, &pmd_z).distance,
         ))
     }
 
@@ -168,37 +501,38 @@ impl RayMarcher {
     where
         F: Fn(f32, f32) -> f32
     {
-        let p_xz = vec2::from_values(p.0, p.2);
-        let d_x = vec2::from_values(self.finite_diff_h, 0.0);
-        let d_z = vec2::from_values(0.0, self.finite_diff_h);
+        // a and b are the ground coordinates, i.e., (x, z) for Y-up and (x, y) for Z-up
+        let p_ab = self.up_axis.ground(p);
+        let d_a = vec2::from_values(self.finite_diff_h, 0.0);
+        let d_b = vec2::from_values(0.0, self.finite_diff_h);
 
-        let ppd_x = vec2::add(&p_xz, &d_x);
-        let pmd_x = vec2::sub(&p_xz, &d_x);
-        let ppd_z = vec2::add(&p_xz, &d_z);
-        let pmd_z = vec2::sub(&p_xz, &d_z);
+        let ppd_a = vec2::add(&p_ab, &d_a);
+        let pmd_a = vec2::sub(&p_ab, &d_a);
+        let ppd_b = vec2::add(&p_ab, &d_b);
+        let pmd_b = vec2::sub(&p_ab, &d_b);
 
-        vec3::normalize_inplace(vec3::from_values(
-            heightmap(pmd_x.0, pmd_x.1) - heightmap(ppd_x.0, ppd_x.1), // = (p.1 - heightmap(ppd_x.0, ppd_x.1)) - (p.1 - heightmap(pmd_x.0, pmd_x.1))
-            2.0 * self.finite_diff_h,
-            heightmap(pmd_z.0, pmd_z.1) - heightmap(ppd_z.0, ppd_z.1),
-        ))
+        let ground_normal = vec2::from_values(
+            heightmap(pmd_a.0, pmd_a.1) - heightmap(ppd_a.0, ppd_a.1), // = (height - heightmap(ppd_a.0, ppd_a.1)) - (height - heightmap(pmd_a.0, pmd_a.1))
+            heightmap(pmd_b.0, pmd_b.1) - heightmap(ppd_b.0, ppd_b.1),
+        );
+        vec3::normalize_inplace(self.up_axis.from_ground(&ground_normal, 2.0 * self.finite_diff_h))
     }
 
     pub fn scene_normal_tetrahedron_diff(&self, scene: &impl Scene, p: &Vec3) -> Vec3 {
         // See tetrahedron technique from https://iquilezles.org/articles/normalsSDF/
         // k0 = [1,-1,-1], k1 = [-1,-1,1], k2 = [-1,1,-1], k3 = [1,1,1]
         let h = self.finite_diff_h;
-        let f0 = scene
-            .eval(&vec3::from_values(p.0 + h, p.1 - h, p.2 - h))
+        let f0 = self
+            .eval_scene(scene, &vec3::from_values(p.0 + h, p.1 - h, p.2 - h))
             .distance;
-        let f1 = scene
-            .eval(&vec3::from_values(p.0 - h, p.1 - h, p.2 + h))
+        let f1 = self
+            .eval_scene(scene, &vec3::from_values(p.0 - h, p.1 - h, p.2 + h))
             .distance;
-        let f2 = scene
-            .eval(&vec3::from_values(p.0 - h, p.1 + h, p.2 - h))
+        let f2 = self
+            .eval_scene(scene, &vec3::from_values(p.0 - h, p.1 + h, p.2 - h))
             .distance;
-        let f3 = scene
-            .eval(&vec3::from_values(p.0 + h, p.1 + h, p.2 + h))
+        let f3 = self
+            .eval_scene(scene, &vec3::from_values(p.0 + h, p.1 + h, p.2 + h))
             .distance;
 
         vec3::normalize_inplace(vec3::from_values(
@@ -216,42 +550,133 @@ impl RayMarcher {
         normal: &Vec3,
         light: &Vec3,
     ) -> VecFloat {
+        self.light_intensity_with_specular(scene, properties, p, normal, light).0
+    }
+
+    // Returns the total light intensity along with its specular contribution
+    pub fn light_intensity_with_specular(
+        &self,
+        scene: &impl Scene,
+        properties: &ReflectiveProperties,
+        p: &Vec3,
+        normal: &Vec3,
+        light: &Vec3,
+    ) -> (VecFloat, VecFloat) {
+        self.light_intensity_with_quality(scene, properties, p, normal, light, &QualitySettings::default())
+    }
+
+    pub fn light_intensity_with_quality(
+        &self,
+        scene: &impl Scene,
+        properties: &ReflectiveProperties,
+        p: &Vec3,
+        normal: &Vec3,
+        light: &Vec3,
+        quality: &QualitySettings,
+    ) -> (VecFloat, VecFloat) {
+        let ambient_visibility = self.ambient_occlusion(scene, properties, p, normal, quality);
+        self.light_intensity_with_ambient_visibility(scene, properties, p, normal, light, ambient_visibility, quality)
+    }
+
+    // Fraction of the ambient light that reaches p, from 0 (fully occluded) to 1 (unoccluded). It depends on the
+    // geometry only, so it can be computed once per point and reused across re-shades with different lights.
+    pub fn ambient_occlusion(
+        &self,
+        scene: &impl Scene,
+        properties: &ReflectiveProperties,
+        p: &Vec3,
+        normal: &Vec3,
+        quality: &QualitySettings,
+    ) -> VecFloat {
+        if !quality.ambient_occlusion || properties.ao_weight <= 0.0 {
+            return 1.0;
+        }
+        self.ambient_visibility(
+            scene,
+            p,
+            normal,
+            properties.ao_steps,
+            properties.ao_step_size,
+            properties.ao_falloff_exponent,
+        )
+    }
+
+    // Same as light_intensity_with_quality, but with a precomputed ambient_occlusion, e.g., PixelProperties::ao
+    #[allow(clippy::too_many_arguments)]
+    pub fn light_intensity_with_ambient_visibility(
+        &self,
+        scene: &impl Scene,
+        properties: &ReflectiveProperties,
+        p: &Vec3,
+        normal: &Vec3,
+        light: &Vec3,
+        ambient_visibility: VecFloat,
+        quality: &QualitySettings,
+    ) -> (VecFloat, VecFloat) {
         let ambient = properties.ambient_weight;
-        let ao = if properties.ao_weight > 0.0 {
-            properties.ao_weight
-                * Self::ambient_visibility(
-                    scene,
-                    p,
-                    normal,
-                    properties.ao_steps,
-                    properties.ao_step_size,
-                )
-        } else {
+        let ao = properties.ao_weight.max(0.0) * ambient_visibility;
+        let penumbra = match properties.shadow_mode {
+            ShadowMode::Soft => properties.penumbra,
+            ShadowMode::Hard => VecFloat::INFINITY, // every miss ratio saturates at 1
+        };
+        let visibility_factor = if quality.shadows {
+            self.visibility_factor(scene, light, p, Some(normal), penumbra)
+        } else if vec3::dot(&vec3::sub(light, p), normal) < 0.0 {
             0.0
+        } else {
+            1.0
         };
-        let visibility_factor =
-            self.visibility_factor(scene, light, p, Some(normal), properties.penumbra);
         let visibility = properties.visibility_weight * visibility_factor;
-        let (diffuse, specular) = if visibility_factor > 0.0 {
-            let to_light = vec3::normalize_inplace(vec3::sub(light, p));
-            let diffuse = properties.diffuse_weight
-                * visibility_factor
-                * vec3::dot(&to_light, normal).max(0.0); // = max(dot(normalize(light - p), n), 0.0)
-
+        let to_light = vec3::normalize_inplace(vec3::sub(light, p));
+        let cos_to_light = vec3::dot(&to_light, normal);
+        // Wrap lighting reaches beyond the terminator, where the shadow ray towards the light would pass through the
+        // surface itself; there, cast shadows are tested towards the light moved into the tangent plane instead
+        let diffuse_visibility_factor = if properties.diffuse_wrap <= 0.0 || cos_to_light >= 0.0 {
+            visibility_factor
+        } else if quality.shadows {
+            let tangent_light = vec3::scale_and_add(light, normal, -vec3::dot(&vec3::sub(light, p), normal));
+            self.visibility_factor(scene, &tangent_light, p, Some(normal), penumbra)
+        } else {
+            1.0
+        };
+        let diffuse = properties.diffuse_weight
+            * wrapped_diffuse(cos_to_light, properties.diffuse_wrap, diffuse_visibility_factor);
+        let specular = if visibility_factor > 0.0 {
             let from_light = vec3::scale(&to_light, -1.0);
             let to_camera = vec3::normalize_inplace(vec3::sub(&self.camera, p));
-            let specular = properties.specular_weight
+            properties.specular_weight
                 * visibility_factor
                 * vec3::dot(&vec3::reflect(&from_light, normal), &to_camera)
                     .max(0.0)
-                    .powf(properties.specular_exponent);
-
-            (diffuse, specular)
+                    .powf(properties.specular_exponent)
         } else {
-            (0.0, 0.0)
+            0.0
         };
 
-        ambient + ao + visibility + diffuse + specular
+        (ambient + ao + visibility + diffuse + specular, specular)
+    }
+
+    pub fn sample_environment(&self, dir: &Vec3) -> Vec3 {
+        let elevation = self.up_axis.height(&vec3::normalize(dir));
+        if elevation >= 0.0 {
+            vec3::lerp_hsl(&self.environment.horizon_hsl, &self.environment.zenith_hsl, elevation)
+        } else {
+            vec3::lerp_hsl(&self.environment.horizon_hsl, &self.environment.nadir_hsl, -elevation)
+        }
+    }
+
+    // Fake water color without a second march: mix the reflected environment with a tinted lookup along the
+    // refracted direction using Schlick's approximation of the Fresnel term
+    pub fn water_hsl(&self, p: &Vec3, normal: &Vec3, water: &WaterProperties) -> Vec3 {
+        let view_dir = vec3::normalize_inplace(vec3::sub(p, &self.camera));
+        let cos_view = (-vec3::dot(&view_dir, normal)).clamp(0.0, 1.0);
+        let r0 = ((1.0 - water.index_of_refraction) / (1.0 + water.index_of_refraction)).powi(2);
+        let fresnel = r0 + (1.0 - r0) * (1.0 - cos_view).powi(5);
+
+        let reflected_hsl = self.sample_environment(&vec3::reflect(&view_dir, normal));
+        let refracted_dir = vec3::refract(&view_dir, normal, 1.0 / water.index_of_refraction);
+        let refracted_hsl = vec3::lerp_hsl(&self.sample_environment(&refracted_dir), &water.tint_hsl, water.tint_strength);
+        vec3::lerp_hsl(&refracted_hsl, &reflected_hsl, fresnel)
     }
 
     pub fn heightmap_light_intensity<F>(
@@ -269,45 +694,47 @@ impl RayMarcher {
         let ao = 0.0;
         let visibility_factor = 1.0;
         let visibility = properties.visibility_weight * visibility_factor;
-        let (diffuse, specular) = if visibility_factor > 0.0 {
-            let to_light = vec3::normalize_inplace(vec3::sub(light, p));
-            let diffuse = properties.diffuse_weight
-                * visibility_factor
-                * vec3::dot(&to_light, normal).max(0.0); // = max(dot(normalize(light - p), n), 0.0)
-
+        let to_light = vec3::normalize_inplace(vec3::sub(light, p));
+        let diffuse = properties.diffuse_weight
+            * wrapped_diffuse(vec3::dot(&to_light, normal), properties.diffuse_wrap, visibility_factor);
+        let specular = if visibility_factor > 0.0 {
             let from_light = vec3::scale(&to_light, -1.0);
             let to_camera = vec3::normalize_inplace(vec3::sub(&self.camera, p));
-            let specular = properties.specular_weight
+            properties.specular_weight
                 * visibility_factor
                 * vec3::dot(&vec3::reflect(&from_light, normal), &to_camera)
                     .max(0.0)
-                    .powf(properties.specular_exponent);
-
-            (diffuse, specular)
+                    .powf(properties.specular_exponent)
         } else {
-            (0.0, 0.0)
+            0.0
         };
 
         ambient + ao + visibility + diffuse + specular
     }
 
     fn ambient_visibility(
+        &self,
         scene: &impl Scene,
         p: &Vec3,
         normal: &Vec3,
         step_count: u32,
         step_size: VecFloat,
+        falloff_exponent: VecFloat,
     ) -> VecFloat {
         let mut acc_occlusion: VecFloat = 0.0;
+        let mut max_acc_occlusion: VecFloat = 0.0;
         for step in 1..=step_count {
             let dist_step = step as VecFloat * step_size;
             let p_step = vec3::scale_and_add(p, normal, dist_step);
-            let dist_sdf = scene.eval(&p_step).distance;
+            let dist_sdf = self.eval_scene(scene, &p_step).distance;
             let occlusion = (dist_step - dist_sdf.clamp(0.0, dist_step)) / dist_step;
-            let weight = 0.5f32.powi(step as i32);
+            let weight = (-falloff_exponent * step as VecFloat).exp2(); // = 0.5^step for an exponent of 1
             acc_occlusion += weight * occlusion;
+            max_acc_occlusion += weight;
+        }
+        if max_acc_occlusion <= 0.0 {
+            return 1.0;
         }
-        let max_acc_occlusion: VecFloat = 1.0 - 0.5f32.powi(step_count as i32); // cf. partial geometric series
         let occlusion = acc_occlusion / max_acc_occlusion;
         1.0 - occlusion
     }
@@ -327,19 +754,27 @@ impl RayMarcher {
         }
 
         // if we walk from p towards eye, do we reach eye or hit the scene before?
+        // Starting off the surface keeps the ray from reporting its own origin as an occluder
+        let p = match point_normal {
+            Some(n) => vec3::scale_and_add(p, n, self.ray_origin_epsilon),
+            None => *p,
+        };
+        let to_eye = vec3::sub(eye, &p);
         let dist_to_eye = vec3::len(&to_eye);
         let to_eye = vec3::normalize_inplace(to_eye);
+        // Far geometry rarely occludes; for distant lights, stopping early saves most of the march
+        let max_len = self.shadow_max_distance.map_or(dist_to_eye, |d| d.min(dist_to_eye));
 
         let mut len = self.initial_scene_dist;
         let mut closest_miss_ratio: VecFloat = 1.0;
         for _ in 0..self.max_ray_iter_steps {
-            if len >= dist_to_eye {
+            if len >= max_len {
                 return closest_miss_ratio;
             }
 
-            let q = vec3::scale_and_add(p, &to_eye, len); // q = p + len * dir
+            let q = vec3::scale_and_add(&p, &to_eye, len); // q = p + len * dir
 
-            let dist_to_scene = scene.eval(&q).distance;
+            let dist_to_scene = self.eval_scene(scene, &q).distance;
             if dist_to_scene < self.min_scene_dist {
                 return 0.0;
             }
@@ -351,9 +786,9 @@ impl RayMarcher {
     }
 
     // screen_coordinates \in [-1, 1]^2
-    fn screen_direction(&self, screen_coordinates: &Vec2) -> Vec3 {
-        let p_u = screen_coordinates.0 * self.aspect_ratio * self.half_screen_length_y;
-        let p_v = screen_coordinates.1 * self.half_screen_length_y;
+    pub(crate) fn screen_direction(&self, screen_coordinates: &Vec2) -> Vec3 {
+        let p_u = (screen_coordinates.0 + self.screen_offset.0) * self.aspect_ratio * self.half_screen_length_y;
+        let p_v = (screen_coordinates.1 + self.screen_offset.1) * self.half_screen_length_y;
         vec3::normalize_inplace(vec3::scale_and_add_inplace(
             vec3::scale_and_add(&self.w, &self.v, p_v),
             &self.u,
@@ -361,3 +796,422 @@ impl RayMarcher {
         )) // screen_direction = normalize(screen_coordinates.x * u + screen_coordinates.y * v + w)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{sphere_ray_marcher, SceneSphere};
+    use assert_approx_eq::assert_approx_eq;
+
+    struct SceneRidge {
+        material: Material,
+    }
+
+    impl SceneRidge {
+        fn new() -> SceneRidge {
+            SceneRidge {
+                material: Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, None, true, true),
+            }
+        }
+    }
+
+    impl Scene for SceneRidge {
+        fn eval(&self, p: &Vec3) -> SdfOutput {
+            // A thin ridge of width 0.02 resting on the plane y = -0.01
+            let ridge = sdf_op::sd_box(p, &vec3::from_values(0.01, 0.01, 1.0));
+            let floor = sdf_op::sd_plane(p, &vec3::from_values(0.0, 1.0, 0.0), -0.01);
+            SdfOutput::new(ridge.min(floor), self.material)
+        }
+    }
+
+    struct SceneOnionSphere {
+        material: Material,
+    }
+
+    impl Scene for SceneOnionSphere {
+        fn eval(&self, p: &Vec3) -> SdfOutput {
+            // A hollow sphere whose shell spans radii 0.9 to 1.1
+            SdfOutput::new(sdf_op::op_onion(sdf_op::sd_sphere(p, 1.0), 0.1), self.material)
+        }
+    }
+
+    struct SceneSphereOnFloor {
+        material: Material,
+    }
+
+    impl Scene for SceneSphereOnFloor {
+        fn eval(&self, p: &Vec3) -> SdfOutput {
+            let sphere = sdf_op::sd_sphere(p, 1.0);
+            let floor = sdf_op::sd_plane(p, &vec3::from_values(0.0, 1.0, 0.0), -1.0);
+            SdfOutput::new(sphere.min(floor), self.material)
+        }
+    }
+
+    struct SceneFloorWithWall {
+        material: Material,
+    }
+
+    impl Scene for SceneFloorWithWall {
+        fn eval(&self, p: &Vec3) -> SdfOutput {
+            // The floor y = 0 and a tall wall occupying x > 0.5
+            let floor = sdf_op::sd_plane(p, &vec3::from_values(0.0, 1.0, 0.0), 0.0);
+            let wall = sdf_op::sd_plane(p, &vec3::from_values(-1.0, 0.0, 0.0), -0.5);
+            SdfOutput::new(floor.min(wall), self.material)
+        }
+    }
+
+    fn ray_marcher() -> RayMarcher {
+        RayMarcher::new(
+            1.0,
+            &vec3::from_values(0.0, 5.0, 5.0),
+            &vec3::from_values(0.0, 0.0, 0.0),
+            &vec3::from_values(0.0, 1.0, 0.0),
+            45.0,
+            1.0,
+        )
+    }
+
+    #[test]
+    fn test_scene_normal_with_h() {
+        let ray_marcher = ray_marcher();
+        let scene = SceneRidge::new();
+        let p_top = vec3::from_values(0.0, 0.01, 0.0);
+        let p_side = vec3::from_values(0.01, 0.005, 0.0);
+
+        let n_top_fine = ray_marcher.scene_normal_with_h(&scene, &p_top, 0.001);
+        let n_side_fine = ray_marcher.scene_normal_with_h(&scene, &p_side, 0.001);
+        let n_top_coarse = ray_marcher.scene_normal_with_h(&scene, &p_top, 0.05);
+        let n_side_coarse = ray_marcher.scene_normal_with_h(&scene, &p_side, 0.05);
+
+        let variation_fine = 1.0 - vec3::dot(&n_top_fine, &n_side_fine);
+        let variation_coarse = 1.0 - vec3::dot(&n_top_coarse, &n_side_coarse);
+        assert!(variation_fine > 0.9);
+        assert!(variation_fine > variation_coarse);
+    }
+
+    #[test]
+    fn test_all_intersections() {
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let scene = SceneOnionSphere {
+            material: Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, None, true, true),
+        };
+        let screen_center = vec2::from_values(0.0, 0.0);
+
+        // Entering and leaving the front shell, then entering and leaving the back shell
+        let hits = ray_marcher.all_intersections(&scene, &screen_center, 10);
+        assert_eq!(4, hits.len());
+        for (hit, expected_depth) in hits.iter().zip([3.9, 4.1, 5.9, 6.1]) {
+            assert!((hit.1 - expected_depth).abs() < 0.01);
+            assert!(hit.0.0.abs() < 1.0e-3 && hit.0.1.abs() < 1.0e-3);
+        }
+
+        let hits = ray_marcher.all_intersections(&scene, &screen_center, 2);
+        assert_eq!(2, hits.len());
+        let first_hit = ray_marcher.intersection_with_scene(&scene, &screen_center).unwrap();
+        assert_eq!(first_hit.1, hits[0].1);
+
+        assert!(ray_marcher.all_intersections(&scene, &vec2::from_values(1.0, 1.0), 10).is_empty());
+    }
+
+    #[test]
+    fn test_march() {
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let scene = SceneSphere {
+            material: Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, None, true, true),
+        };
+        for screen_coordinates in [vec2::from_values(0.0, 0.0), vec2::from_values(0.2, -0.1), vec2::from_values(-0.3, 0.25)] {
+            let hit = ray_marcher.march(&scene, &screen_coordinates).unwrap();
+            let (p, depth, _) = ray_marcher.intersection_with_scene(&scene, &screen_coordinates).unwrap();
+            assert_eq!((p, depth), (hit.point, hit.depth));
+            assert_eq!(ray_marcher.scene_normal(&scene, &p), hit.normal);
+            // The normal points away from the center of the sphere
+            assert!(vec3::dot(&hit.normal, &vec3::normalize(&hit.point)) > 0.999);
+            assert!(hit.steps > 1);
+        }
+        assert!(ray_marcher.march(&scene, &vec2::from_values(1.0, 1.0)).is_none());
+    }
+
+    #[test]
+    fn test_clip_plane() {
+        let mut ray_marcher = ray_marcher();
+        let material = Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, None, true, true);
+        let cap_material = Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, Some(&vec3::from_values(0.0, 1.0, 0.5)), true, false);
+        let scene = SceneSphere { material };
+        let screen_center = vec2::from_values(0.0, 0.0);
+        let camera_distance = vec3::len(&ray_marcher.camera);
+        let (_, depth, hit_material) = ray_marcher.intersection_with_scene(&scene, &screen_center).unwrap();
+        assert!((depth - (camera_distance - 1.0)).abs() < 0.01);
+        assert_eq!(material.bg_hsl, hit_material.bg_hsl);
+
+        // Cut away the half of the sphere that faces the camera: the center pixel hits the flat cap at the plane depth
+        let plane_normal = vec3::normalize(&ray_marcher.camera);
+        ray_marcher.clip_plane = Some((plane_normal, 0.0, cap_material));
+        let (p, depth, hit_material) = ray_marcher.intersection_with_scene(&scene, &screen_center).unwrap();
+        assert!((depth - camera_distance).abs() < 0.01);
+        assert!(vec3::dot(&p, &plane_normal).abs() < 0.01);
+        assert_eq!(cap_material.bg_hsl, hit_material.bg_hsl);
+        assert!(!hit_material.is_hatched);
+        let normal = ray_marcher.scene_normal(&scene, &p);
+        assert!(vec3::dot(&normal, &plane_normal) > 0.99);
+        let normal = ray_marcher.scene_normal_tetrahedron_diff(&scene, &p);
+        assert!(vec3::dot(&normal, &plane_normal) > 0.99);
+
+        let scene = SceneOnionSphere { material };
+        // In a hollow sphere, only pixels that hit the shell where it is cut land on the cap
+        let (p, _, hit_material) = ray_marcher.intersection_with_scene(&scene, &screen_center).unwrap();
+        assert!(vec3::dot(&p, &plane_normal) < -0.8);
+        assert_eq!(material.bg_hsl, hit_material.bg_hsl);
+        let screen_cap = ray_marcher.to_screen_coordinates(&vec3::from_values(1.0, 0.0, 0.0));
+        let (p, _, hit_material) = ray_marcher.intersection_with_scene(&scene, &screen_cap).unwrap();
+        assert!(vec3::dot(&p, &plane_normal).abs() < 0.01);
+        assert_eq!(cap_material.bg_hsl, hit_material.bg_hsl);
+        let normal = ray_marcher.scene_normal(&scene, &p);
+        assert!(vec3::dot(&normal, &plane_normal) > 0.99);
+    }
+
+    #[test]
+    fn test_shadow_mode() {
+        let ray_marcher = ray_marcher();
+        let scene = SceneSphereOnFloor {
+            material: Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, None, true, true),
+        };
+        let light = vec3::from_values(0.0, 10.0, 0.0);
+        let normal = vec3::from_values(0.0, 1.0, 0.0);
+        let soft = ReflectiveProperties::new(0.0, 0.0, 1.0, 0.0, 0.0, None, None, None, None);
+        let hard = ReflectiveProperties { shadow_mode: ShadowMode::Hard, ..soft };
+
+        // Just outside the geometric shadow of the sphere, within the soft penumbra
+        let p_penumbra = vec3::from_values(1.12, -1.0, 0.0);
+        let soft_penumbra = ray_marcher.light_intensity(&scene, &soft, &p_penumbra, &normal, &light);
+        let hard_penumbra = ray_marcher.light_intensity(&scene, &hard, &p_penumbra, &normal, &light);
+        assert!(soft_penumbra > 0.0 && soft_penumbra < 1.0);
+        assert_eq!(1.0, hard_penumbra);
+
+        let p_umbra = vec3::from_values(0.0, -1.0, 0.0);
+        assert_eq!(0.0, ray_marcher.light_intensity(&scene, &hard, &p_umbra, &normal, &light));
+
+        let p_lit = vec3::from_values(4.0, -1.0, 0.0);
+        assert_eq!(1.0, ray_marcher.light_intensity(&scene, &hard, &p_lit, &normal, &light));
+    }
+
+    #[test]
+    fn test_shadow_ray_origin_epsilon() {
+        let mut ray_marcher = ray_marcher();
+        let scene = SceneSphereOnFloor {
+            material: Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, None, true, true),
+        };
+        // A lit floor point with the light at a grazing angle; no distance is skipped along the shadow ray
+        let p = vec3::from_values(3.0, -1.0, 0.0);
+        let normal = vec3::from_values(0.0, 1.0, 0.0);
+        let light = vec3::from_values(30.0, 0.0, 0.0);
+        ray_marcher.initial_scene_dist = 0.0;
+        assert!(ray_marcher.visibility_factor(&scene, &light, &p, Some(&normal), 48.0) > 0.0);
+
+        ray_marcher.ray_origin_epsilon = 0.0;
+        assert_eq!(0.0, ray_marcher.visibility_factor(&scene, &light, &p, Some(&normal), 48.0));
+    }
+
+    #[test]
+    fn test_shadow_max_distance() {
+        let mut ray_marcher = ray_marcher();
+        let scene = SceneSphereOnFloor {
+            material: Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, None, true, true),
+        };
+        // The sphere occludes the distant light from about 0.13 to 1.87 units above the floor point
+        let p = vec3::from_values(0.5, -1.0, 0.0);
+        let normal = vec3::from_values(0.0, 1.0, 0.0);
+        let light = vec3::from_values(0.0, 1.0e5, 0.0);
+        assert_eq!(0.0, ray_marcher.visibility_factor(&scene, &light, &p, Some(&normal), 48.0));
+
+        ray_marcher.shadow_max_distance = Some(0.1);
+        assert_eq!(1.0, ray_marcher.visibility_factor(&scene, &light, &p, Some(&normal), 48.0));
+        ray_marcher.shadow_max_distance = Some(5.0);
+        assert_eq!(0.0, ray_marcher.visibility_factor(&scene, &light, &p, Some(&normal), 48.0));
+    }
+
+    #[test]
+    fn test_diffuse_wrap() {
+        let ray_marcher = ray_marcher();
+        let scene = SceneSphere {
+            material: Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, None, true, true),
+        };
+        // The top of the sphere with the light in its tangent plane
+        let p = vec3::from_values(0.0, 1.0, 0.0);
+        let normal = vec3::from_values(0.0, 1.0, 0.0);
+        let light = vec3::from_values(10.0, 1.0, 0.0);
+        let lambert = ReflectiveProperties::new(0.0, 0.0, 0.0, 1.0, 0.0, None, None, None, None);
+        let half_lambert = ReflectiveProperties { diffuse_wrap: 1.0, ..lambert };
+        assert_eq!(0.0, ray_marcher.light_intensity(&scene, &lambert, &p, &normal, &light));
+        assert_approx_eq!(0.5, ray_marcher.light_intensity(&scene, &half_lambert, &p, &normal, &light), 1.0e-5);
+
+        // Facing the light, wrapping leaves the diffuse term unchanged
+        let light_above = vec3::from_values(0.0, 10.0, 0.0);
+        assert_approx_eq!(1.0, ray_marcher.light_intensity(&scene, &lambert, &p, &normal, &light_above), 1.0e-5);
+        assert_approx_eq!(1.0, ray_marcher.light_intensity(&scene, &half_lambert, &p, &normal, &light_above), 1.0e-5);
+
+        // Beyond the terminator, the wrapped light still reaches the unoccluded side of the sphere
+        let light_below = vec3::from_values(10.0, -4.0, 0.0);
+        let beyond = ray_marcher.light_intensity(&scene, &half_lambert, &p, &normal, &light_below);
+        assert!(beyond > 0.0 && beyond < 0.5);
+    }
+
+    #[test]
+    fn test_diffuse_wrap_cast_shadow() {
+        let ray_marcher = ray_marcher();
+        let scene = SceneSphereOnFloor {
+            material: Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, None, true, true),
+        };
+        let normal = vec3::from_values(0.0, 1.0, 0.0);
+        let lambert = ReflectiveProperties::new(0.0, 0.0, 0.0, 1.0, 0.0, None, None, None, None);
+        let half_lambert = ReflectiveProperties { diffuse_wrap: 1.0, ..lambert };
+
+        // The sphere lies between the light and the floor point, which faces the light at 45 degrees
+        let light = vec3::from_values(-9.0, 9.0, 0.0);
+        let p_shadow = vec3::from_values(1.0, -1.0, 0.0);
+        assert_eq!(0.0, ray_marcher.light_intensity(&scene, &lambert, &p_shadow, &normal, &light));
+        assert_eq!(0.0, ray_marcher.light_intensity(&scene, &half_lambert, &p_shadow, &normal, &light));
+
+        // Out of the shadow, the wrap lifts the diffuse term
+        let p_lit = vec3::from_values(-4.0, -1.0, 0.0);
+        let lit_lambert = ray_marcher.light_intensity(&scene, &lambert, &p_lit, &normal, &light);
+        let lit_half_lambert = ray_marcher.light_intensity(&scene, &half_lambert, &p_lit, &normal, &light);
+        assert!(lit_lambert > 0.5);
+        assert!(lit_half_lambert > lit_lambert);
+    }
+
+    #[test]
+    fn test_ambient_occlusion_radius() {
+        let ray_marcher = ray_marcher();
+        let scene = SceneFloorWithWall {
+            material: Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, None, true, true),
+        };
+        let light = vec3::from_values(0.0, 10.0, 0.0);
+        let normal = vec3::from_values(0.0, 1.0, 0.0);
+        let p = vec3::from_values(0.3, 0.0, 0.0);
+        let ao_only = |ao_radius: Option<VecFloat>, ao_falloff_exponent: Option<VecFloat>| {
+            let base = ReflectiveProperties::new(0.0, 1.0, 0.0, 0.0, 0.0, None, Some(8), None, None);
+            let properties = ReflectiveProperties {
+                ao_falloff_exponent: ao_falloff_exponent.unwrap_or(base.ao_falloff_exponent),
+                ..ao_radius.map_or(base, |radius| base.with_ao_radius(radius))
+            };
+            ray_marcher.light_intensity(&scene, &properties, &p, &normal, &light)
+        };
+
+        // The default radius only captures contact occlusion and misses the wall 0.2 away
+        let small_radius = ao_only(None, None);
+        let large_radius = ao_only(Some(1.0), None);
+        assert_eq!(1.0, small_radius);
+        assert!(large_radius < 0.9);
+
+        // A flatter falloff gives the distant, occluded samples more weight
+        let flat_falloff = ao_only(Some(1.0), Some(0.1));
+        assert!(flat_falloff < large_radius);
+    }
+
+    #[test]
+    fn test_from_scene_step_size_factor() {
+        struct SceneSteepSphere {
+            material: Material,
+        }
+
+        impl Scene for SceneSteepSphere {
+            fn eval(&self, p: &Vec3) -> SdfOutput {
+                SdfOutput::new(2.0 * sdf_op::sd_sphere(p, 1.0), self.material)
+            }
+
+            fn lipschitz_bound(&self) -> f32 {
+                2.0
+            }
+        }
+
+        let material = Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, None, true, true);
+        let camera = vec3::from_values(0.0, 0.0, 5.0);
+        let origin = vec3::from_values(0.0, 0.0, 0.0);
+        let up = vec3::from_values(0.0, 1.0, 0.0);
+        let steep = RayMarcher::from_scene(&SceneSteepSphere { material }, &camera, &origin, &up, 45.0, 1.0);
+        assert_approx_eq!(0.5, steep.step_size_factor);
+        let sphere = RayMarcher::from_scene(&SceneSphere { material }, &camera, &origin, &up, 45.0, 1.0);
+        assert_eq!(1.0, sphere.step_size_factor);
+
+        // Half steps march the steep sphere as accurately as full steps march the plain one
+        let screen_center = vec2::from_values(0.0, 0.0);
+        let (p_steep, _, _) = steep.intersection_with_scene(&SceneSteepSphere { material }, &screen_center).unwrap();
+        let (p_sphere, _, _) = sphere.intersection_with_scene(&SceneSphere { material }, &screen_center).unwrap();
+        assert_approx_eq!(1.0, p_steep.2, 1.0e-3);
+        assert_approx_eq!(p_sphere.2, p_steep.2, 1.0e-3);
+    }
+
+    #[test]
+    fn test_frame_bounds() {
+        let bounds_min = vec3::from_values(-3.0, 0.0, -1.0);
+        let bounds_max = vec3::from_values(5.0, 2.0, 4.0);
+        let corners: Vec<Vec3> = (0..8)
+            .map(|i| vec3::from_values(
+                if i & 1 == 0 { bounds_min.0 } else { bounds_max.0 },
+                if i & 2 == 0 { bounds_min.1 } else { bounds_max.1 },
+                if i & 4 == 0 { bounds_min.2 } else { bounds_max.2 },
+            ))
+            .collect();
+        let up = vec3::from_values(0.0, 1.0, 0.0);
+        for (direction, aspect_ratio) in [
+            (vec3::from_values(0.0, 0.0, 1.0), 1.0),
+            (vec3::from_values(1.0, 0.5, 2.0), 1.5),
+            (vec3::from_values(-1.0, 3.0, -0.5), 0.6),
+        ] {
+            let ray_marcher = RayMarcher::frame_bounds(1.0, &bounds_min, &bounds_max, &direction, &up, 40.0, aspect_ratio);
+            assert!(vec3::len(&vec3::sub(&ray_marcher.look_at, &vec3::from_values(1.0, 1.0, 1.5))) < 1.0e-5);
+            let screen: Vec<Vec2> = corners.iter().map(|corner| ray_marcher.to_screen_coordinates(corner)).collect();
+            assert!(corners.iter().all(|corner| ray_marcher.to_camera_coordinates(corner).2 > 0.0));
+            assert!(screen.iter().all(|p| p.0.abs() <= 1.0 + 1.0e-4 && p.1.abs() <= 1.0 + 1.0e-4));
+            // Not farther than necessary: some corner touches the border of the screen
+            assert!(screen.iter().any(|p| p.0.abs().max(p.1.abs()) > 1.0 - 1.0e-4));
+        }
+    }
+
+    #[test]
+    fn test_top_down_camera_basis() {
+        let ray_marcher = RayMarcher::new(
+            1.0,
+            &vec3::from_values(0.0, 100.0, 0.0),
+            &vec3::from_values(0.0, 0.0, 0.0),
+            &vec3::from_values(0.0, 1.0, 0.0),
+            45.0,
+            1.0,
+        );
+        let (u, v, w) = (ray_marcher.u, ray_marcher.v, ray_marcher.w);
+        for axis in [u, v, w] {
+            assert!(axis.0.is_finite() && axis.1.is_finite() && axis.2.is_finite());
+            assert_approx_eq!(1.0, vec3::len(&axis));
+        }
+        assert_approx_eq!(0.0, vec3::dot(&u, &v));
+        assert_approx_eq!(0.0, vec3::dot(&v, &w));
+        assert_approx_eq!(0.0, vec3::dot(&w, &u));
+        assert_approx_eq!(-1.0, w.1);
+
+        let scene = SceneSphere {
+            material: Material::new(&vec3::from_values(0.0, 10.0, 0.0), None, None, true, true),
+        };
+        let (p, _, _) = ray_marcher.intersection_with_scene(&scene, &vec2::from_values(0.0, 0.0)).unwrap();
+        assert!((p.1 - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_sample_environment() {
+        let ray_marcher = ray_marcher();
+        let up = ray_marcher.sample_environment(&vec3::from_values(0.0, 1.0, 0.0));
+        let horizon = ray_marcher.sample_environment(&vec3::from_values(1.0, 0.0, 0.0));
+        let down = ray_marcher.sample_environment(&vec3::from_values(0.2, -1.0, 0.0));
+        assert!(up.2 > down.2);
+        assert_eq!(ray_marcher.environment.horizon_hsl, horizon);
+
+        // Looking straight down onto water shows mostly the tint; at grazing angles, it mirrors the sky
+        let water = WaterProperties::new(&vec3::from_values(190.0f32.to_radians(), 0.8, 0.3), Some(1.0), None);
+        let normal = vec3::from_values(0.0, 1.0, 0.0);
+        let p_below = vec3::from_values(0.0, 0.0, 5.0);
+        let p_far = vec3::from_values(0.0, 4.99, -1000.0);
+        let steep = ray_marcher.water_hsl(&p_below, &normal, &water);
+        let grazing = ray_marcher.water_hsl(&p_far, &normal, &water);
+        assert!((steep.2 - water.tint_hsl.2).abs() < 0.05);
+        assert!(grazing.2 > 0.8);
+    }
+}
@@ -0,0 +1,36 @@
+use crate::vector::VecFloat;
+
+// Hyperbola through the origin that approaches |x| - k away from zero. Unlike |x|, it has a continuous derivative
+// at zero; as it never exceeds |x|, it stays a bound when applied to distances.
+pub fn smooth_abs(x: VecFloat, k: VecFloat) -> VecFloat {
+    (x * x + k * k).sqrt() - k
+}
+
+// Derivative of smooth_abs, approaching sign(x) away from zero
+pub fn smooth_sign(x: VecFloat, k: VecFloat) -> VecFloat {
+    x / (x * x + k * k).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+
+    #[test]
+    fn test_smooth_abs() {
+        let k = 0.01;
+        assert_eq!(0.0, smooth_abs(0.0, k));
+        for x in [-10.0f32, -1.0, -0.1, 0.1, 1.0, 10.0] {
+            assert_approx_eq!(x.abs(), smooth_abs(x, k), k);
+            assert!(smooth_abs(x, k) <= x.abs());
+            assert_approx_eq!(x.signum(), smooth_sign(x, k), 1.0e-2);
+        }
+
+        // Finite slope at zero: the difference quotients from both sides agree
+        let h = 1.0e-3 * k;
+        let slope_left = (smooth_abs(0.0, k) - smooth_abs(-h, k)) / h;
+        let slope_right = (smooth_abs(h, k) - smooth_abs(0.0, k)) / h;
+        assert_approx_eq!(slope_left, slope_right, 1.0e-2);
+        assert_eq!(0.0, smooth_sign(0.0, k));
+    }
+}
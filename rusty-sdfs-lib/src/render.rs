@@ -1,81 +1,455 @@
-use std::collections::VecDeque;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::fmt;
+use std::ops::Range;
 use std::f32::consts::PI;
 
-use rand::RngCore;
+use rand::rngs::StdRng;
+use rand::{Rng, RngCore, SeedableRng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use tiny_skia::{FillRule, LineCap, LineJoin, PathBuilder};
 
-use crate::canvas::{Canvas, FloatCanvas, Kernel, PixelProperties, PixelPropertyCanvas, SkiaCanvas};
+use crate::canvas::{BackgroundSpec, Canvas, DirectionSource, FloatCanvas, Kernel, PixelCoord, PixelProperties, PixelPropertyCanvas, SceneRenderOptions, SkiaCanvas};
 use crate::grid::on_jittered_grid;
-use crate::streamline::{StreamlineRegistry, flow_field_streamline, streamline_d_sep_from_lightness};
+use crate::noise::{rand_2d, value_noise_1d};
+use crate::ray_marcher::RayMarcher;
+use crate::scene::Scene;
+use crate::streamline::{SeedOrder, StreamlineConfig, StreamlineRegistry, StrokePressure, flow_field_streamline};
 use crate::vector::{vec2, Vec2};
 use crate::{LinearGradient, VecFloat};
 
 
-pub fn render_flow_field_streamlines(
+fn depth_weighted_stroke_width(
     input_canvas: &PixelPropertyCanvas,
-    output_canvas: &mut SkiaCanvas,
-    rng: &mut dyn RngCore,
-    streamline_color: &[u8; 3],
+    depth_range: (f32, f32),
+    streamline: &[Vec2],
+    stroke_width: f32,
+    far_stroke_width_factor: f32,
+) -> f32 {
+    // Scale the stroke width linearly from 1 at the nearest depth of the canvas to far_stroke_width_factor at the
+    // farthest depth, based on the mean depth along the streamline
+    let (min_depth, max_depth) = depth_range;
+    let depths: Vec<f32> = streamline.iter()
+        .filter_map(|p| input_canvas.pixel_value(p.0, p.1).map(|pixel| pixel.depth))
+        .collect();
+    if depths.is_empty() || max_depth <= min_depth {
+        return stroke_width;
+    }
+    let mean_depth = depths.iter().sum::<f32>() / depths.len() as f32;
+    let t = ((mean_depth - min_depth) / (max_depth - min_depth)).clamp(0.0, 1.0);
+    stroke_width * (1.0 + t * (far_stroke_width_factor - 1.0))
+}
+
+// Dash and gap lengths for a streamline whose mean lightness exceeds lightness_threshold, or None if it is to be
+// drawn solid. The gaps open up from twice to six times the stroke width as the lightness approaches white.
+fn streamline_dash_pattern(
+    input_canvas: &PixelPropertyCanvas,
+    streamline: &[Vec2],
     stroke_width: f32,
+    lightness_threshold: f32,
+) -> Option<(f32, f32)> {
+    let lightnesses: Vec<f32> = streamline.iter()
+        .filter_map(|p| input_canvas.pixel_value(p.0, p.1).map(|pixel| pixel.lightness))
+        .collect();
+    if lightnesses.is_empty() {
+        return None;
+    }
+    let mean_lightness = lightnesses.iter().sum::<f32>() / lightnesses.len() as f32;
+    if mean_lightness <= lightness_threshold {
+        return None;
+    }
+    let t = ((mean_lightness - lightness_threshold) / (1.0 - lightness_threshold).max(1.0e-6)).clamp(0.0, 1.0);
+    Some((3.0 * stroke_width, stroke_width * (2.0 + 4.0 * t)))
+}
+
+// Split a polyline into dashes of dash_length separated by gaps of gap_length, measured along its arc length
+fn dash_polyline(points: &[Vec2], dash_length: f32, gap_length: f32) -> Vec<Vec<Vec2>> {
+    let mut dashes = Vec::new();
+    if points.len() < 2 {
+        return dashes;
+    }
+    let mut current = vec![points[0]];
+    let mut is_dash = true;
+    let mut remaining = dash_length;
+    for segment in points.windows(2) {
+        let (p0, p1) = (&segment[0], &segment[1]);
+        let segment_length = vec2::dist(p0, p1);
+        let mut position = 0.0;
+        while segment_length - position > remaining {
+            position += remaining;
+            let q = vec2::lerp(p0, p1, position / segment_length);
+            current.push(q);
+            if is_dash {
+                dashes.push(std::mem::take(&mut current));
+            }
+            is_dash = !is_dash;
+            remaining = if is_dash { dash_length } else { gap_length };
+        }
+        remaining -= segment_length - position;
+        if is_dash {
+            current.push(*p1);
+        }
+    }
+    if is_dash && current.len() > 1 {
+        dashes.push(current);
+    }
+    dashes
+}
+
+fn sobel_kernels() -> (Kernel, Kernel) {
+    let sobel_x = Kernel::new(3, vec![
+        -1.0, 0.0, 1.0,
+        -2.0, 0.0, 2.0,
+        -1.0, 0.0, 1.0,
+    ]);
+    let sobel_y = Kernel::new(3, vec![
+        -1.0, -2.0, -1.0,
+        0.0, 0.0, 0.0,
+        1.0, 2.0, 1.0,
+    ]);
+    (sobel_x, sobel_y)
+}
+
+fn direction_field_curvature(input_canvas: &PixelPropertyCanvas) -> FloatCanvas {
+    // layer 0: direction_vec.x
+    // layer 1: direction_vec.y
+    // layer 2: sobel_x(direction_vec.x)
+    // layer 3: sobel_y(direction_vec.x)
+    // layer 4: sobel_x(direction_vec.y)
+    // layer 5: sobel_y(direction_vec.y)
+    // layer 6: gradient magnitude of the direction field, normalized to [0, 1]
+    let (sobel_x, sobel_y) = sobel_kernels();
+    let mut float_canvas = FloatCanvas::new(input_canvas.width(), input_canvas.height(), 7);
+    input_canvas.to_float_canvas_layer(&mut float_canvas, 0, |pp: &PixelProperties| {
+        if pp.direction_vec.0.is_nan() { 0.0 } else { pp.direction_vec.0 }
+    });
+    input_canvas.to_float_canvas_layer(&mut float_canvas, 1, |pp: &PixelProperties| {
+        if pp.direction_vec.1.is_nan() { 0.0 } else { pp.direction_vec.1 }
+    });
+    float_canvas.convolve_layer(0, 2, &sobel_x);
+    float_canvas.convolve_layer(0, 3, &sobel_y);
+    float_canvas.convolve_layer(1, 4, &sobel_x);
+    float_canvas.convolve_layer(1, 5, &sobel_y);
+    float_canvas.for_each_pixel_mut(|_, _, values| {
+        // For a direction field turning at a rate of w radians per pixel, the Sobel magnitude is approximately 8 * w;
+        // hence, the normalized value saturates at a turning rate of 1 radian per pixel
+        let magnitude = (values[2] * values[2] + values[3] * values[3] + values[4] * values[4] + values[5] * values[5]).sqrt();
+        values[6] = (0.125 * magnitude).min(1.0);
+    });
+    float_canvas
+}
+
+fn streamline_seeds(
+    input_canvas: &PixelPropertyCanvas,
+    rng: &mut dyn RngCore,
     seed_box_size: u32,
-    d_sep_min: f32,
-    d_sep_max: f32,
-    d_test_factor: f32,
-    d_step: f32,
-    max_depth_step: f32,
-    max_accum_angle: f32,
-    max_steps: u32,
-    min_steps: u32
-) {
+    seed_jitter: f32,
+    seed_importance: Option<f32>,
+) -> Vec<Vec2> {
     let width = input_canvas.width();
     let height = input_canvas.height();
-    let mut streamline_registry = StreamlineRegistry::new(width, height, 0.5 * d_sep_max);
-    let mut streamline_queue: VecDeque<(u32, Vec<Vec2>)> = VecDeque::new();
-
+    let mut seeds = Vec::new();
     on_jittered_grid(
         width as f32,
         height as f32,
         width / seed_box_size,
         height / seed_box_size,
+        seed_jitter,
         rng,
-        |seed_x, seed_y| {
-            let seed_streamline_option = flow_field_streamline(
-                input_canvas,
-                &streamline_registry,
-                0,
-                &vec2::from_values(seed_x, seed_y),
-                d_sep_min,
-                d_sep_max,
-                d_test_factor,
-                d_step,
-                max_depth_step,
-                max_accum_angle,
-                max_steps,
-                min_steps,
-            );
-            if seed_streamline_option.is_some() {
-                let seed_streamline = seed_streamline_option.unwrap();
-                let seed_streamline_id = streamline_registry.add_streamline(&seed_streamline);
-                let path = SkiaCanvas::linear_path(&seed_streamline);
-                if path.is_some() {
-                    output_canvas.stroke_path(
-                        &path.unwrap(),
-                        stroke_width,
-                        streamline_color,
-                    );
+        |seed_x, seed_y| seeds.push(vec2::from_values(seed_x, seed_y)),
+    );
+
+    // Add up to seed_importance extra seeds per cell, scaled by the curvature of the direction field at the grid seed
+    if let Some(importance) = seed_importance {
+        let curvature = direction_field_curvature(input_canvas);
+        let box_size = seed_box_size as f32;
+        let extra_seeds: Vec<Vec2> = seeds.iter().flat_map(|seed| {
+            let x = (seed.0 as u32).min(width - 1);
+            let y = (seed.1 as u32).min(height - 1);
+            let extra_seed_count = (importance * curvature.value_at_reflected(x as i32, y as i32, 6)).round() as u32;
+            (0..extra_seed_count).map(|_| vec2::from_values(
+                (seed.0 + box_size * (rng.gen::<f32>() - 0.5)).clamp(0.0, width as f32 - 1.0e-3),
+                (seed.1 + box_size * (rng.gen::<f32>() - 0.5)).clamp(0.0, height as f32 - 1.0e-3),
+            )).collect::<Vec<Vec2>>()
+        }).collect();
+        seeds.extend(extra_seeds);
+    }
+    seeds
+}
+
+struct RadialQueueEntry {
+    distance: f32,
+    streamline_id: u32,
+    streamline: Vec<Vec2>,
+}
+
+// Order entries such that BinaryHeap, a max-heap, pops the entry nearest to the focal point first
+impl Ord for RadialQueueEntry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance)
+    }
+}
+
+impl PartialOrd for RadialQueueEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RadialQueueEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RadialQueueEntry {}
+
+enum StreamlineQueue {
+    Fifo(VecDeque<(u32, Vec<Vec2>)>),
+    Radial(Vec2, BinaryHeap<RadialQueueEntry>),
+}
+
+impl StreamlineQueue {
+    fn new(seed_order: SeedOrder) -> StreamlineQueue {
+        match seed_order {
+            SeedOrder::Fifo => StreamlineQueue::Fifo(VecDeque::new()),
+            SeedOrder::RadialFrom(focal_point) => StreamlineQueue::Radial(focal_point, BinaryHeap::new()),
+        }
+    }
+
+    fn distance_to_focal_point(focal_point: &Vec2, streamline: &[Vec2]) -> f32 {
+        streamline.iter().map(|p| vec2::dist(p, focal_point)).fold(f32::INFINITY, f32::min)
+    }
+
+    fn push(&mut self, streamline_id: u32, streamline: Vec<Vec2>) {
+        match self {
+            StreamlineQueue::Fifo(queue) => queue.push_back((streamline_id, streamline)),
+            StreamlineQueue::Radial(focal_point, heap) => heap.push(RadialQueueEntry {
+                distance: Self::distance_to_focal_point(focal_point, &streamline),
+                streamline_id,
+                streamline,
+            }),
+        }
+    }
+
+    fn pop(&mut self) -> Option<(u32, Vec<Vec2>)> {
+        match self {
+            StreamlineQueue::Fifo(queue) => queue.pop_front(),
+            StreamlineQueue::Radial(_, heap) => heap.pop().map(|entry| (entry.streamline_id, entry.streamline)),
+        }
+    }
+}
+
+fn pressure_widths(points: &[Vec2], base_width: f32, pressure: &StrokePressure, stroke_index: u64) -> Vec<f32> {
+    let seed = pressure.seed.wrapping_add(stroke_index);
+    let mut arc_length = 0.0;
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, p)| {
+            if i > 0 {
+                arc_length += vec2::dist(&points[i - 1], p);
+            }
+            base_width * (1.0 + pressure.amplitude * value_noise_1d(pressure.frequency * arc_length, seed))
+        })
+        .collect()
+}
+
+fn stroke_polyline(
+    output_canvas: &mut SkiaCanvas,
+    points: &[Vec2],
+    width: f32,
+    color: &[u8; 3],
+    pressure: Option<&StrokePressure>,
+    stroke_index: u64,
+) {
+    match pressure {
+        Some(pressure) => {
+            let widths = pressure_widths(points, width, pressure, stroke_index);
+            output_canvas.stroke_polyline_colored(points, &widths, &vec![*color; points.len()]);
+        }
+        None => {
+            if let Some(path) = SkiaCanvas::linear_path(points) {
+                output_canvas.stroke_path(&path, width, color);
+            }
+        }
+    }
+}
+
+// Like stroke_polyline, but streamlines in regions lighter than style_from_lightness are drawn dashed
+#[allow(clippy::too_many_arguments)]
+fn stroke_streamline(
+    input_canvas: &PixelPropertyCanvas,
+    output_canvas: &mut SkiaCanvas,
+    streamline: &[Vec2],
+    width: f32,
+    color: &[u8; 3],
+    pressure: Option<&StrokePressure>,
+    stroke_index: u64,
+    style_from_lightness: Option<f32>,
+) {
+    match style_from_lightness.and_then(|threshold| streamline_dash_pattern(input_canvas, streamline, width, threshold)) {
+        Some((dash_length, gap_length)) => {
+            for dash in dash_polyline(streamline, dash_length, gap_length) {
+                stroke_polyline(output_canvas, &dash, width, color, pressure, stroke_index);
+            }
+        }
+        None => stroke_polyline(output_canvas, streamline, width, color, pressure, stroke_index),
+    }
+}
+
+// Closed polygon that outlines the stroke of the polyline with the given width and butt ends, e.g., for cutters
+// that need filled shapes instead of centerlines. Miters longer than tiny-skia's default limit of 4 (relative to
+// the half width) and LineJoin::Bevel are beveled. Where consecutive segments are shorter than the half width, the
+// inner side of a corner may loop back on itself, which does not affect filling with FillRule::Winding.
+pub fn outline_stroke(points: &[Vec2], width: f32, join: LineJoin) -> Vec<Vec2> {
+    let mut points: Vec<Vec2> = points.to_vec();
+    points.dedup_by(|a, b| vec2::dist(a, b) < 1.0e-6);
+    if points.len() < 2 {
+        return Vec::new();
+    }
+    let half_width = 0.5 * width;
+    let mut outline = offset_polyline_left(&points, half_width, join);
+    points.reverse();
+    outline.extend(offset_polyline_left(&points, half_width, join));
+    outline
+}
+
+// The side of an outline to the left of the direction of travel, where left means the normal (-d.y, d.x) of the
+// direction d
+fn offset_polyline_left(points: &[Vec2], half_width: f32, join: LineJoin) -> Vec<Vec2> {
+    const MITER_LIMIT: f32 = 4.0;
+    const MAX_ARC_STEP: f32 = PI / 16.0;
+    let normals: Vec<Vec2> = points
+        .windows(2)
+        .map(|segment| {
+            let d = vec2::normalize_inplace(vec2::sub(&segment[1], &segment[0]));
+            vec2::from_values(-d.1, d.0)
+        })
+        .collect();
+
+    let mut offset = vec![vec2::scale_and_add(&points[0], &normals[0], half_width)];
+    for i in 1..points.len() - 1 {
+        let p = &points[i];
+        let (n0, n1) = (&normals[i - 1], &normals[i]);
+        let cos_turn = vec2::dot(n0, n1);
+        let sin_turn = n0.0 * n1.1 - n0.1 * n1.0;
+        let from = vec2::scale_and_add(p, n0, half_width);
+        let to = vec2::scale_and_add(p, n1, half_width);
+        // The miter point lies on the bisector of the normals at half_width / cos(turn / 2)
+        let miter = vec2::scale_and_add(p, &vec2::add(n0, n1), half_width / (1.0 + cos_turn).max(1.0e-6));
+        if sin_turn.abs() < 1.0e-6 && cos_turn > 0.0 {
+            offset.push(from);
+        } else if sin_turn > 0.0 {
+            // Turning left: the left side is the inner side of the corner
+            let inset = half_width * sin_turn.abs() / (1.0 + cos_turn);
+            let segment_len = vec2::dist(p, &points[i - 1]).min(vec2::dist(p, &points[i + 1]));
+            if inset <= segment_len {
+                offset.push(miter);
+            } else {
+                offset.extend([from, *p, to]);
+            }
+        } else {
+            match join {
+                LineJoin::Round => {
+                    let start_angle = vec2::polar_angle(n0);
+                    let sweep = sin_turn.atan2(cos_turn);
+                    let step_count = (sweep.abs() / MAX_ARC_STEP).ceil().max(1.0) as u32;
+                    offset.extend((0..=step_count).map(|step| {
+                        let angle = start_angle + sweep * step as f32 / step_count as f32;
+                        vec2::scale_and_add(p, &vec2::polar_angle_to_unit_vector(angle), half_width)
+                    }));
                 }
-                streamline_queue.push_back((seed_streamline_id, seed_streamline));
+                LineJoin::Miter | LineJoin::MiterClip if 2.0 / (1.0 + cos_turn) <= MITER_LIMIT * MITER_LIMIT => {
+                    offset.push(miter);
+                }
+                _ => offset.extend([from, to]),
             }
-        },
-    );
+        }
+    }
+    offset.push(vec2::scale_and_add(&points[points.len() - 1], &normals[normals.len() - 1], half_width));
+    offset
+}
+
+// The streamline options come from config; mask further confines the streamlines to points where it is true
+pub fn render_flow_field_streamlines(
+    input_canvas: &PixelPropertyCanvas,
+    output_canvas: &mut SkiaCanvas,
+    rng: &mut dyn RngCore,
+    config: &StreamlineConfig,
+    mask: Option<&dyn Fn(&Vec2) -> bool>,
+) -> usize {
+    render_flow_field_streamlines_progressive(input_canvas, output_canvas, rng, config, mask, |_| {})
+}
+
+// Same as render_flow_field_streamlines, but calls on_streamline with each streamline right after it has been stroked,
+// e.g., to refresh a live preview
+pub fn render_flow_field_streamlines_progressive(
+    input_canvas: &PixelPropertyCanvas,
+    output_canvas: &mut SkiaCanvas,
+    rng: &mut dyn RngCore,
+    config: &StreamlineConfig,
+    mask: Option<&dyn Fn(&Vec2) -> bool>,
+    mut on_streamline: impl FnMut(&[Vec2]),
+) -> usize {
+    let max_streamlines = config.max_streamlines.unwrap_or(usize::MAX);
+    let mut streamline_count: usize = 0;
+    let depth_range = input_canvas.depth_range();
+    let streamline_stroke_width = |streamline: &[Vec2]| match config.far_stroke_width_factor {
+        Some(factor) => depth_weighted_stroke_width(input_canvas, depth_range, streamline, config.stroke_width, factor),
+        None => config.stroke_width,
+    };
+    let width = input_canvas.width();
+    let height = input_canvas.height();
+    let mut streamline_registry = StreamlineRegistry::new(width, height, 0.5 * config.d_sep_max);
+    let mut streamline_queue = StreamlineQueue::new(config.seed_order);
+
+    let mut seeds = streamline_seeds(input_canvas, rng, config.seed_box_size, config.seed_jitter, config.seed_importance);
+    if let SeedOrder::RadialFrom(focal_point) = config.seed_order {
+        seeds.sort_by(|a, b| vec2::dist(a, &focal_point).total_cmp(&vec2::dist(b, &focal_point)));
+    }
+    for seed in seeds {
+        if streamline_count >= max_streamlines {
+            return streamline_count;
+        }
+        let seed_streamline_option = flow_field_streamline(
+            input_canvas,
+            &streamline_registry,
+            0,
+            &seed,
+            config,
+            mask,
+        );
+        if seed_streamline_option.is_some() {
+            let seed_streamline = seed_streamline_option.unwrap();
+            let seed_streamline_id = streamline_registry.add_streamline(&seed_streamline);
+            stroke_streamline(
+                input_canvas,
+                output_canvas,
+                &seed_streamline,
+                streamline_stroke_width(&seed_streamline),
+                &config.color,
+                config.pressure.as_ref(),
+                streamline_count as u64,
+                config.style_from_lightness,
+            );
+            on_streamline(&seed_streamline);
+            streamline_count += 1;
+            streamline_queue.push(seed_streamline_id, seed_streamline);
+        }
+    }
 
-    while !streamline_queue.is_empty() {
-        let (streamline_id, streamline) = streamline_queue.pop_front().unwrap();
+    while let Some((streamline_id, streamline)) = streamline_queue.pop() {
         for (p, &sign) in streamline.iter().zip([-1.0f32, 1.0f32].iter().cycle()) {
+            if streamline_count >= max_streamlines {
+                return streamline_count;
+            }
             let pixel = input_canvas.pixel_value(p.0, p.1).unwrap();
-            let d_sep = streamline_d_sep_from_lightness(d_sep_min, d_sep_max, pixel.lightness);
+            let d_sep = config.d_sep_from_lightness(pixel.lightness);
             let new_seed = vec2::scale_and_add(
                 p,
-                &vec2::polar_angle_to_unit_vector(pixel.direction + 0.5 * PI),
+                &vec2::polar_angle_to_unit_vector(pixel.direction() + 0.5 * PI),
                 sign * d_sep,
             );
             let new_streamline = flow_field_streamline(
@@ -83,26 +457,29 @@ pub fn render_flow_field_streamlines(
                 &streamline_registry,
                 streamline_id,
                 &new_seed,
-                d_sep_min,
-                d_sep_max,
-                d_test_factor,
-                d_step,
-                max_depth_step,
-                max_accum_angle,
-                max_steps,
-                min_steps,
+                config,
+                mask,
             );
             if new_streamline.is_some() {
                 let sl = new_streamline.unwrap();
                 let streamline_id = streamline_registry.add_streamline(&sl);
-                let path = SkiaCanvas::linear_path(&sl);
-                if path.is_some() {
-                    output_canvas.stroke_path(&path.unwrap(), stroke_width, streamline_color);
-                }
-                streamline_queue.push_back((streamline_id, sl));
+                stroke_streamline(
+                    input_canvas,
+                    output_canvas,
+                    &sl,
+                    streamline_stroke_width(&sl),
+                    &config.color,
+                    config.pressure.as_ref(),
+                    streamline_count as u64,
+                    config.style_from_lightness,
+                );
+                on_streamline(&sl);
+                streamline_count += 1;
+                streamline_queue.push(streamline_id, sl);
             }
         }
     }
+    streamline_count
 }
 
 pub struct DomainRegion {
@@ -137,30 +514,26 @@ impl DomainRegion {
     }
 }
 
-pub fn render_heightmap_streamlines<F>(
-    output_canvas: &mut SkiaCanvas,
+// Screen points of the height lines from the farthest to the nearest line (the order of painting); the lines are
+// computed in parallel if requested, but the result does not depend on it
+#[allow(clippy::too_many_arguments)]
+fn heightmap_line_points<F>(
+    width: VecFloat,
+    height: VecFloat,
     domain_region: &DomainRegion,
     line_count: u32,
     buffer_count_near: u32,
     buffer_count_far: u32,
     segment_count: u32,
-    line_width: f32,
-    line_rgb: &[u8; 3],
-    fill_gradient: &LinearGradient,
-    heightmap: F,
-)
+    heightmap: &F,
+    parallel: bool,
+) -> Vec<Vec<Vec2>>
 where
-    F: Fn(&Vec2, &Vec2, &Vec2) -> f32, // args: uv_domain, t_domain, t_screen
+    F: Fn(&Vec2, &Vec2, &Vec2) -> f32 + Sync,
 {
-    let width = output_canvas.width() as VecFloat;
-    let height = output_canvas.height() as VecFloat;
-    let margin = 2.0 * line_width + 1.0;
-
-    let line_idx_from = -(buffer_count_near as i32);
-    let line_idx_to = (line_count + buffer_count_far) as i32;
-    for line_idx in (line_idx_from..line_idx_to).rev() {
+    let line_points = |line_idx: i32| {
         let t_nearfar = line_idx as VecFloat / ((line_count - 1) as VecFloat);
-        let points: Vec<_> = (0..=segment_count).map(|seg_idx| {
+        (0..=segment_count).map(|seg_idx| {
                 let t_ab = seg_idx as f32 / (segment_count as f32);
                 let uv_domain = domain_region.lerp(t_ab, t_nearfar);
                 let t_domain = vec2::from_values(t_ab, t_nearfar);
@@ -177,29 +550,111 @@ where
                     height * (t_screen.1 - h)
                 )
             })
-            .collect();
+            .collect::<Vec<Vec2>>()
+    };
+
+    let line_idx_from = -(buffer_count_near as i32);
+    let line_idx_to = (line_count + buffer_count_far) as i32;
+    if parallel {
+        (line_idx_from..line_idx_to).into_par_iter().rev().map(line_points).collect()
+    } else {
+        (line_idx_from..line_idx_to).rev().map(line_points).collect()
+    }
+}
 
-        let first_point_y = points[0].1;
-        let last_point_y = points.last().unwrap().1;
+// Closes the height line into a band that reaches below the bottom of the canvas. Its sides are straight lines
+// outside of the canvas or, with side_wave, value-noise curves that lie up to side_wave pixels inside of it; the
+// height line is cut off where it crosses them. Also returns the index range of the cut height line within the outline;
+// None if nothing of the line lies between the sides.
+fn heightmap_band_outline(
+    points: &[Vec2],
+    width: VecFloat,
+    height: VecFloat,
+    margin: VecFloat,
+    side_wave: Option<f32>,
+) -> Option<(Vec<Vec2>, Range<usize>)> {
+    // Straight sides need no points in between
+    let side_step = if side_wave.is_some() { 2.0 } else { VecFloat::INFINITY };
+    // Every band uses the same sides, so that they line up to one wavy boundary
+    let side_inset = |y: VecFloat, seed: u64| match side_wave {
+        Some(amplitude) => 0.5 * amplitude * (1.0 + value_noise_1d(0.25 / amplitude.max(1.0) * y, seed)),
+        None => -margin,
+    };
+    let left_x = |y: VecFloat| side_inset(y, 0);
+    let right_x = |y: VecFloat| width - side_inset(y, 1);
+    let line: Vec<Vec2> = points.iter().copied().filter(|p| p.0 >= left_x(p.1) && p.0 <= right_x(p.1)).collect();
+    let first_point_y = line.first()?.1;
+    let last_point_y = line.last()?.1;
 
-        let points_prepend = [
-            vec2::from_values(-margin, height + margin),
-            vec2::from_values(-margin, first_point_y),
-        ];
-        let points_append = [
-            vec2::from_values(width + margin, last_point_y),
-            vec2::from_values(width + margin, height + margin),
-        ];
-        let points: Vec<_> = points_prepend.iter().copied()
-            .chain(points.iter().copied())
-            .chain(points_append)
-            .collect();
-        let path = SkiaCanvas::closed_linear_path(&points).unwrap();
-        output_canvas.fill_path(&path, &fill_gradient.rgb(1.0 - 0.5 * (first_point_y + last_point_y) / height));
-        output_canvas.stroke_path(&path, line_width, line_rgb);
+    let side = |x: &dyn Fn(VecFloat) -> VecFloat, y_from: VecFloat, y_to: VecFloat| {
+        let step_count = ((y_to - y_from).abs() / side_step).ceil().max(1.0) as u32;
+        (0..=step_count)
+            .map(|step| {
+                let y = y_from + (y_to - y_from) * step as VecFloat / step_count as VecFloat;
+                vec2::from_values(x(y), y)
+            })
+            .collect::<Vec<_>>()
+    };
+    let bottom = height + margin;
+    let left_side = side(&left_x, bottom, first_point_y);
+    let line_range = left_side.len()..left_side.len() + line.len();
+    let outline = left_side.into_iter()
+        .chain(line)
+        .chain(side(&right_x, last_point_y, bottom))
+        .collect();
+    Some((outline, line_range))
+}
+
+pub fn render_heightmap_streamlines<F>(
+    output_canvas: &mut SkiaCanvas,
+    domain_region: &DomainRegion,
+    line_count: u32,
+    buffer_count_near: u32,
+    buffer_count_far: u32,
+    segment_count: u32,
+    line_width: f32,
+    line_rgb: &[u8; 3],
+    fill_gradient: &LinearGradient,
+    side_wave: Option<f32>, // if set, the bands end in wavy sides up to this many pixels inside the canvas
+    heightmap: F,
+)
+where
+    F: Fn(&Vec2, &Vec2, &Vec2) -> f32 + Sync, // args: uv_domain, t_domain, t_screen
+{
+    let width = output_canvas.width() as VecFloat;
+    let height = output_canvas.height() as VecFloat;
+    let margin = 2.0 * line_width + 1.0;
+
+    // Evaluating the heightmap is the expensive part and happens in parallel; filling and stroking has to be serial
+    // and back to front so that nearer lines cover farther ones
+    let lines = heightmap_line_points(
+        width,
+        height,
+        domain_region,
+        line_count,
+        buffer_count_near,
+        buffer_count_far,
+        segment_count,
+        &heightmap,
+        true,
+    );
+    for points in lines {
+        let Some((outline, line_range)) = heightmap_band_outline(&points, width, height, margin, side_wave) else {
+            continue;
+        };
+        // Only the height line is stroked; the sides of the band are not part of the drawing
+        let line = &outline[line_range];
+        let first_point_y = line[0].1;
+        let last_point_y = line[line.len() - 1].1;
+        let fill_path = SkiaCanvas::closed_linear_path(&outline).unwrap();
+        output_canvas.fill_path(&fill_path, &fill_gradient.rgb(1.0 - 0.5 * (first_point_y + last_point_y) / height), FillRule::Winding);
+        if let Some(line_path) = SkiaCanvas::linear_path(line) {
+            output_canvas.stroke_path(&line_path, line_width, line_rgb);
+        }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn render_hatch_lines(
     input_canvas: &PixelPropertyCanvas,
     output_canvas: &mut SkiaCanvas,
@@ -209,60 +664,313 @@ pub fn render_hatch_lines(
     stroke_width: f32,
     line_angle: VecFloat, // in [0, Pi)
     line_sep: VecFloat,
+    line_cap: LineCap,
+    pressure: Option<&StrokePressure>,
+    dry_brush: Option<f32>,
+    parallel: bool, // sample the hatch lines in parallel; the output is the same either way
 ) {
-    let width = input_canvas.width() as VecFloat;
-    let height = input_canvas.height() as VecFloat;
-    let endpoints = hatch_line_endpoints(width, height, line_angle, line_sep);
-    for (p0, p1) in endpoints {
+    let gap_probability = dry_brush_gap_probability(lightness_threshold, dry_brush);
+    hatch_lines_where(input_canvas, output_canvas, step_size, line_color, stroke_width, line_angle, line_sep, line_cap, pressure, parallel, |pixel| {
+        pixel.lightness <= lightness_threshold
+    }, gap_probability);
+}
+
+// Dry brush texture: the probability of a gap at each step rises linearly from 0 for black to dryness at the lightness threshold
+fn dry_brush_gap_probability(lightness_threshold: f32, dryness: Option<f32>) -> impl Fn(&PixelProperties) -> f32 + Sync {
+    let dryness = dryness.unwrap_or(0.0).clamp(0.0, 1.0);
+    move |pixel: &PixelProperties| dryness * (pixel.lightness / lightness_threshold).clamp(0.0, 1.0)
+}
+
+// Separation of parallel lines of the given stroke width such that they cover the given area fraction (= stroke_width / separation)
+pub fn hatch_separation_for_coverage(stroke_width: f32, coverage: f32) -> f32 {
+    stroke_width / coverage.clamp(1.0e-3, 1.0)
+}
+
+// Hatch such that the ink coverage approximates 1 - lightness: the lightness range is split into tone_levels bands,
+// each hatched with the separation that matches the coverage at the center of its band
+#[allow(clippy::too_many_arguments)]
+pub fn render_tone_matched_hatch_lines(
+    input_canvas: &PixelPropertyCanvas,
+    output_canvas: &mut SkiaCanvas,
+    step_size: f32,
+    line_color: &[u8; 3],
+    stroke_width: f32,
+    line_angle: VecFloat, // in [0, Pi)
+    tone_levels: u32,
+    line_cap: LineCap,
+    pressure: Option<&StrokePressure>,
+) {
+    let band_of = |lightness: f32| ((lightness * tone_levels as f32).floor() as i64).clamp(0, tone_levels as i64 - 1);
+    for band in 0..tone_levels as i64 {
+        let coverage = 1.0 - (band as f32 + 0.5) / tone_levels as f32;
+        let line_sep = hatch_separation_for_coverage(stroke_width, coverage);
+        hatch_lines_where(input_canvas, output_canvas, step_size, line_color, stroke_width, line_angle, line_sep, line_cap, pressure, false, |pixel| {
+            band_of(pixel.lightness) == band
+        }, |_| 0.0);
+    }
+}
+
+// Copy of the input canvas whose directions run along the iso-depth lines, i.e., perpendicular to the screen-space
+// depth gradient; directions are NaN where the gradient vanishes or is undefined. The streamline flag is taken from
+// the hatch flag so that streamlines traced on the copy follow the hatching materials.
+fn depth_contour_direction_canvas(input_canvas: &PixelPropertyCanvas) -> PixelPropertyCanvas {
+    // layer 0: depth
+    // layer 1: sobel_x(depth)
+    // layer 2: sobel_y(depth)
+    const MIN_GRADIENT: f32 = 1.0e-6;
+    let (sobel_x, sobel_y) = sobel_kernels();
+    let width = input_canvas.width();
+    let mut float_canvas = FloatCanvas::new(width, input_canvas.height(), 3);
+    input_canvas.to_float_canvas_layer(&mut float_canvas, 0, |pp: &PixelProperties| pp.depth);
+    float_canvas.convolve_layer(0, 1, &sobel_x);
+    float_canvas.convolve_layer(0, 2, &sobel_y);
+
+    let mut contour_canvas = PixelPropertyCanvas::new(width, input_canvas.height());
+    float_canvas.for_each_pixel(|x, y, values| {
+        let index = PixelCoord::new(x, y).to_index(width);
+        let mut pixel = *input_canvas.pixel_at_reflected(x as i32, y as i32);
+        let gradient = vec2::from_values(values[1], values[2]);
+        let direction_vec = if vec2::len(&gradient) > MIN_GRADIENT {
+            vec2::normalize_inplace(vec2::from_values(-gradient.1, gradient.0))
+        } else {
+            vec2::from_values(f32::NAN, f32::NAN)
+        };
+        pixel.direction_vec = direction_vec;
+        pixel.is_streamlined = pixel.is_hatched;
+        contour_canvas.pixels_mut()[index] = pixel;
+    });
+    contour_canvas
+}
+
+// Evenly spaced hatching across slopes, as in engravings: strokes follow the iso-depth lines where the lightness is at
+// most lightness_threshold. Returns the number of strokes.
+#[allow(clippy::too_many_arguments)]
+pub fn render_cross_slope_hatch_lines(
+    input_canvas: &PixelPropertyCanvas,
+    output_canvas: &mut SkiaCanvas,
+    lightness_threshold: f32,
+    step_size: f32,
+    line_color: &[u8; 3],
+    stroke_width: f32,
+    line_sep: VecFloat,
+    pressure: Option<&StrokePressure>,
+) -> usize {
+    // Long enough for a stroke to run along the whole border of the canvas
+    let max_steps = (2.0 * (input_canvas.width() + input_canvas.height()) as f32 / step_size).ceil() as u32;
+    let contour_canvas = depth_contour_direction_canvas(input_canvas);
+    let is_dark = |p: &Vec2| {
+        contour_canvas
+            .pixel_value(p.0, p.1)
+            .is_some_and(|pixel| pixel.lightness <= lightness_threshold)
+    };
+    // Seeds sit on an unjittered grid, so the rng does not affect the result
+    let mut rng = StdRng::seed_from_u64(0);
+    let config = StreamlineConfig {
+        color: *line_color,
+        stroke_width,
+        seed_box_size: (line_sep.round() as u32).max(1),
+        seed_jitter: 0.0,
+        d_sep_min: line_sep,
+        d_sep_max: line_sep,
+        d_step: step_size,
+        max_depth_step: f32::INFINITY,
+        max_steps,
+        avoid_self_intersection: true,
+        pressure: pressure.copied(),
+        ..StreamlineConfig::default()
+    };
+    render_flow_field_streamlines(&contour_canvas, output_canvas, &mut rng, &config, Some(&is_dark))
+}
+
+// The active runs along each hatch line, sampled in step_size increments; the lines are sampled in parallel if requested,
+// but the result (and hence the stroke order) does not depend on it
+fn hatch_line_runs(
+    input_canvas: &PixelPropertyCanvas,
+    endpoints: &[(Vec2, Vec2)],
+    step_size: f32,
+    is_lightness_active: &(impl Fn(&PixelProperties) -> bool + Sync),
+    gap_probability: &(impl Fn(&PixelProperties) -> f32 + Sync),
+    parallel: bool,
+) -> Vec<Vec<(Vec2, Vec2)>> {
+    const GAP_SEED: u64 = 0x64727962;
+    let is_pixel_active = |p: &Vec2| {
+        match input_canvas.pixel_value(p.0, p.1) {
+            // Seeding by position keeps the gaps independent of the order in which the lines are sampled
+            Some(pixel) if pixel.is_hatched && is_lightness_active(&pixel) => {
+                0.5 * (rand_2d(p.0, p.1, GAP_SEED) + 1.0) >= gap_probability(&pixel)
+            }
+            _ => false,
+        }
+    };
+    let line_runs = |(p0, p1): &(Vec2, Vec2)| {
         // walk from p0 to p1 in step_size increments
-        let dir = vec2::sub(&p1, &p0);
+        let dir = vec2::sub(p1, p0);
         let dist = vec2::len(&dir);
         let step_count = (dist / step_size).ceil() as u32;
         let dir = vec2::scale(&dir, 1.0 / dist);
 
-        let is_pixel_active = |p: &Vec2| {
-            match input_canvas.pixel_value(p.0, p.1) {
-                Some(pixel) if pixel.lightness <= lightness_threshold => true,
-                _ => false,
-            }
-        };
-        let mut last_active_p = if is_pixel_active(&p0) { Some(p0) } else { None };
-
+        let mut runs = Vec::new();
+        let mut last_active_p = if is_pixel_active(p0) { Some(*p0) } else { None };
         for step in 1..step_count {
-            let p = vec2::scale_and_add(&p0, &dir, step as VecFloat * step_size);
+            let p = vec2::scale_and_add(p0, &dir, step as VecFloat * step_size);
             let p_is_active = is_pixel_active(&p);
             if last_active_p.is_none() && p_is_active {
                 last_active_p = Some(p);
             } else if last_active_p.is_some() && (!p_is_active || step == step_count - 1) {
-                let p_prev = last_active_p.unwrap();
+                runs.push((last_active_p.unwrap(), p));
                 last_active_p = None;
-                output_canvas.stroke_line(p_prev.0, p_prev.1, p.0, p.1, stroke_width, line_color);
             }
         }
+        runs
+    };
+    if parallel {
+        endpoints.par_iter().map(line_runs).collect()
+    } else {
+        endpoints.iter().map(line_runs).collect()
     }
 }
 
-fn hatch_line_endpoints(
-    width: VecFloat,
-    height: VecFloat,
+#[allow(clippy::too_many_arguments)]
+fn hatch_lines_where(
+    input_canvas: &PixelPropertyCanvas,
+    output_canvas: &mut SkiaCanvas,
+    step_size: f32,
+    line_color: &[u8; 3],
+    stroke_width: f32,
     line_angle: VecFloat, // in [0, Pi)
     line_sep: VecFloat,
-) -> Vec<(Vec2, Vec2)> {
-    let sin_a = line_angle.sin();
-    let cos_a = line_angle.cos();
-    const EPS: VecFloat = 0.0001;
-    let is_vertical = cos_a.abs() < EPS;
-    let is_horizontal = sin_a.abs() < EPS;
-
-    let mut endpoints = Vec::new();
+    line_cap: LineCap, // not applied to strokes with pressure, which are made of many short pieces
+    pressure: Option<&StrokePressure>,
+    parallel: bool,
+    is_lightness_active: impl Fn(&PixelProperties) -> bool + Sync,
+    gap_probability: impl Fn(&PixelProperties) -> f32 + Sync,
+) {
+    let width = input_canvas.width() as VecFloat;
+    let height = input_canvas.height() as VecFloat;
+    let endpoints = hatch_line_endpoints(width, height, line_angle, line_sep);
+    let runs_per_line = hatch_line_runs(input_canvas, &endpoints, step_size, &is_lightness_active, &gap_probability, parallel);
+    stroke_hatch_line_runs(output_canvas, runs_per_line, step_size, line_color, stroke_width, line_cap, pressure);
+}
 
-    if is_horizontal {
-        let line_count = (height / line_sep).ceil() as u32;
-        for i in 0..line_count {
-            let x0 = 0.0;
-            let y0 = (i as VecFloat + 0.5) * line_sep;
-            let x1 = width;
-            let y1 = y0;
+// Stroking is serial since the canvas cannot be shared between threads
+fn stroke_hatch_line_runs(
+    output_canvas: &mut SkiaCanvas,
+    runs_per_line: Vec<Vec<(Vec2, Vec2)>>,
+    step_size: f32,
+    line_color: &[u8; 3],
+    stroke_width: f32,
+    line_cap: LineCap,
+    pressure: Option<&StrokePressure>,
+) {
+    let mut run_count: u64 = 0;
+    for runs in runs_per_line {
+        // Collect all runs of a scan line as subpaths so that the line is stroked at once
+        let mut pb = PathBuilder::new();
+        for (p_prev, p) in runs {
+            if let Some(pressure) = pressure {
+                // Resample the run so that the width can vary along it
+                let piece_count = (vec2::dist(&p_prev, &p) / step_size).ceil().max(1.0) as u32;
+                let points: Vec<Vec2> = (0..=piece_count)
+                    .map(|i| vec2::lerp(&p_prev, &p, i as f32 / piece_count as f32))
+                    .collect();
+                stroke_polyline(output_canvas, &points, stroke_width, line_color, Some(pressure), run_count);
+            } else {
+                pb.move_to(p_prev.0, p_prev.1);
+                pb.line_to(p.0, p.1);
+            }
+            run_count += 1;
+        }
+        if let Some(path) = pb.finish() {
+            output_canvas.stroke_path_with_cap(&path, stroke_width, line_color, line_cap);
+        }
+    }
+}
+
+// Form-following straight hatching: the canvas is split into tiles of tile_size x tile_size pixels, each hatched with
+// straight lines along the dominant direction of its hatched pixels rotated by angle_offset (e.g., 0.5 * PI for the
+// second layer of a crosshatch). Tiles without a defined direction use fallback_angle + angle_offset.
+#[allow(clippy::too_many_arguments)]
+pub fn render_direction_tiled_hatch_lines(
+    input_canvas: &PixelPropertyCanvas,
+    output_canvas: &mut SkiaCanvas,
+    lightness_threshold: f32,
+    step_size: f32,
+    line_color: &[u8; 3],
+    stroke_width: f32,
+    angle_offset: VecFloat,
+    fallback_angle: VecFloat,
+    line_sep: VecFloat,
+    tile_size: u32,
+    line_cap: LineCap,
+    pressure: Option<&StrokePressure>,
+) {
+    let endpoints = direction_tiled_hatch_line_endpoints(input_canvas, tile_size, angle_offset, fallback_angle, line_sep);
+    let is_lightness_active = |pixel: &PixelProperties| pixel.lightness <= lightness_threshold;
+    let runs_per_line = hatch_line_runs(input_canvas, &endpoints, step_size, &is_lightness_active, &|_| 0.0, true);
+    stroke_hatch_line_runs(output_canvas, runs_per_line, step_size, line_color, stroke_width, line_cap, pressure);
+}
+
+fn direction_tiled_hatch_line_endpoints(
+    input_canvas: &PixelPropertyCanvas,
+    tile_size: u32,
+    angle_offset: VecFloat,
+    fallback_angle: VecFloat,
+    line_sep: VecFloat,
+) -> Vec<(Vec2, Vec2)> {
+    let tile_size = tile_size.max(1);
+    let mut endpoints = Vec::new();
+    for tile_y in (0..input_canvas.height()).step_by(tile_size as usize) {
+        for tile_x in (0..input_canvas.width()).step_by(tile_size as usize) {
+            let tile_width = tile_size.min(input_canvas.width() - tile_x);
+            let tile_height = tile_size.min(input_canvas.height() - tile_y);
+            // Directions are axes, i.e., d and -d are the same: average the doubled angles
+            let doubled_sum = (tile_y..tile_y + tile_height)
+                .flat_map(|y| (tile_x..tile_x + tile_width).map(move |x| (x, y)))
+                .map(|(x, y)| input_canvas.pixel_at_reflected(x as i32, y as i32))
+                .filter(|pixel| pixel.is_hatched && !pixel.direction_vec.0.is_nan() && !pixel.direction_vec.1.is_nan())
+                .fold(vec2::from_values(0.0, 0.0), |sum, pixel| {
+                    let (x, y) = pixel.direction_vec;
+                    vec2::add(&sum, &vec2::from_values(x * x - y * y, 2.0 * x * y))
+                });
+            // Line angles are measured with the y axis pointing up, canvas directions with the y axis pointing down
+            let base_angle = if vec2::len(&doubled_sum) > 1.0e-6 {
+                -0.5 * vec2::polar_angle(&doubled_sum)
+            } else {
+                fallback_angle
+            };
+            let line_angle = (base_angle + angle_offset).rem_euclid(PI);
+            let origin = vec2::from_values(tile_x as VecFloat, tile_y as VecFloat);
+            endpoints.extend(
+                hatch_line_endpoints(tile_width as VecFloat, tile_height as VecFloat, line_angle, line_sep)
+                    .iter()
+                    .map(|(p0, p1)| (vec2::add(p0, &origin), vec2::add(p1, &origin))),
+            );
+        }
+    }
+    endpoints
+}
+
+fn hatch_line_endpoints(
+    width: VecFloat,
+    height: VecFloat,
+    line_angle: VecFloat, // in [0, Pi)
+    line_sep: VecFloat,
+) -> Vec<(Vec2, Vec2)> {
+    let sin_a = line_angle.sin();
+    let cos_a = line_angle.cos();
+    const EPS: VecFloat = 0.0001;
+    let is_vertical = cos_a.abs() < EPS;
+    let is_horizontal = sin_a.abs() < EPS;
+
+    let mut endpoints = Vec::new();
+
+    if is_horizontal {
+        let line_count = (height / line_sep).ceil() as u32;
+        for i in 0..line_count {
+            let x0 = 0.0;
+            let y0 = (i as VecFloat + 0.5) * line_sep;
+            let x1 = width;
+            let y1 = y0;
             endpoints.push((vec2::from_values(x0, y0), vec2::from_values(x1, y1)));
         }
     } else if is_vertical {
@@ -306,18 +1014,135 @@ pub fn render_edges (
     output_canvas: &mut SkiaCanvas,
     edge_color: &[u8; 3],
     edge_width: f32,
+    depth_edge_weight: Option<f32>,
+    direction_edge_weight: Option<f32>,
 ) {
-    let sobel_x = Kernel::new(3, vec![
-        -1.0, 0.0, 1.0,
-        -2.0, 0.0, 2.0,
-        -1.0, 0.0, 1.0,
-    ]);
-    let sobel_y = Kernel::new(3, vec![
-        -1.0, -2.0, -1.0,
-        0.0, 0.0, 0.0,
-        1.0, 2.0, 1.0,
-    ]);
+    let width = input_canvas.width();
+    let is_edge = edge_pixels(input_canvas, depth_edge_weight, direction_edge_weight);
+    for (index, _) in is_edge.iter().enumerate().filter(|(_, &is_edge)| is_edge) {
+        let (x, y) = (index as u32 % width, index as u32 / width);
+        output_canvas.fill_point(x as f32, y as f32, 0.5 * edge_width, edge_color);
+    }
+}
+
+// Edges as polylines through pixel centers for vector output: the edge pixels of render_edges are thinned to a
+// skeleton one pixel wide, which is then traced from its end points and around its loops. Closed loops repeat their
+// first point at the end. Polylines with fewer than min_points points (2 by default) are dropped.
+pub fn extract_edges_vector(
+    input_canvas: &PixelPropertyCanvas,
+    depth_edge_weight: Option<f32>,
+    direction_edge_weight: Option<f32>,
+    min_points: Option<usize>,
+) -> Vec<Vec<Vec2>> {
+    let min_points = min_points.unwrap_or(2);
+    let width = input_canvas.width() as i64;
+    let height = input_canvas.height() as i64;
+    let mut is_edge = edge_pixels(input_canvas, depth_edge_weight, direction_edge_weight);
+    thin_mask(&mut is_edge, width, height);
 
+    // Orthogonal neighbors come first so that paths do not cut corners
+    const NEIGHBOR_OFFSETS: [(i64, i64); 8] = [(1, 0), (0, 1), (-1, 0), (0, -1), (1, 1), (-1, 1), (-1, -1), (1, -1)];
+    let neighbors = |index: usize| {
+        let (x, y) = (index as i64 % width, index as i64 / width);
+        NEIGHBOR_OFFSETS
+            .iter()
+            .map(move |(dx, dy)| (x + dx, y + dy))
+            .filter(|&(nx, ny)| nx >= 0 && ny >= 0 && nx < width && ny < height)
+            .map(|(nx, ny)| (ny * width + nx) as usize)
+    };
+    let is_end_point = |index: usize| neighbors(index).filter(|&n| is_edge[n]).count() == 1;
+
+    let is_adjacent = |a: usize, b: usize| {
+        let (ax, ay) = (a as i64 % width, a as i64 / width);
+        let (bx, by) = (b as i64 % width, b as i64 / width);
+        (ax - bx).abs() <= 1 && (ay - by).abs() <= 1
+    };
+    let pixel_center = |index: usize| vec2::from_values((index as i64 % width) as f32 + 0.5, (index as i64 / width) as f32 + 0.5);
+
+    // The unvisited pixels reachable from start, one neighbor after the other
+    let walk = |is_visited: &mut Vec<bool>, start: usize| {
+        let mut path = Vec::new();
+        let mut current = start;
+        while let Some(next) = neighbors(current).find(|&n| is_edge[n] && !is_visited[n]) {
+            is_visited[next] = true;
+            path.push(next);
+            current = next;
+        }
+        path
+    };
+
+    let mut is_visited = vec![false; is_edge.len()];
+    let mut polylines = Vec::new();
+    let end_points: Vec<usize> = (0..is_edge.len()).filter(|&index| is_edge[index] && is_end_point(index)).collect();
+    for start in end_points.into_iter().chain(0..is_edge.len()) {
+        if !is_edge[start] || is_visited[start] {
+            continue;
+        }
+        // Starting from an end point, the second walk finds nothing; starting on a loop or in the middle of a
+        // branch, it continues the path in the other direction
+        is_visited[start] = true;
+        let forward = walk(&mut is_visited, start);
+        let backward = walk(&mut is_visited, start);
+        let mut path: Vec<usize> = backward.into_iter().rev().chain([start]).chain(forward).collect();
+        if path.len() >= 4 && is_adjacent(path[0], path[path.len() - 1]) {
+            path.push(path[0]);
+        }
+        if path.len() >= min_points {
+            polylines.push(path.into_iter().map(pixel_center).collect());
+        }
+    }
+    polylines
+}
+
+// Zhang-Suen thinning of a row-major mask to a skeleton one pixel wide, cf. T. Y. Zhang and C. Y. Suen, "A fast
+// parallel algorithm for thinning digital patterns", 1984. Pixels outside of the mask count as unset.
+fn thin_mask(mask: &mut [bool], width: i64, height: i64) {
+    let is_set = |mask: &[bool], x: i64, y: i64| x >= 0 && y >= 0 && x < width && y < height && mask[(y * width + x) as usize];
+    loop {
+        let mut is_changed = false;
+        for step in 0..2 {
+            let removable: Vec<usize> = (0..mask.len())
+                .filter(|&index| {
+                    if !mask[index] {
+                        return false;
+                    }
+                    let (x, y) = (index as i64 % width, index as i64 / width);
+                    // p[0] to p[7]: the neighbors from north clockwise
+                    let p = [(0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1)]
+                        .map(|(dx, dy)| is_set(mask, x + dx, y + dy));
+                    let neighbor_count = p.iter().filter(|&&is_set| is_set).count();
+                    let transition_count = (0..8).filter(|&i| !p[i] && p[(i + 1) % 8]).count();
+                    let (north, east, south, west) = (p[0], p[2], p[4], p[6]);
+                    // The first step removes south-east boundary pixels and north-west corners, the second step the opposite
+                    let is_removable_side = if step == 0 {
+                        !(east && south && (north || west))
+                    } else {
+                        !(north && west && (east || south))
+                    };
+                    (2..=6).contains(&neighbor_count) && transition_count == 1 && is_removable_side
+                })
+                .collect();
+            is_changed |= !removable.is_empty();
+            for index in removable {
+                mask[index] = false;
+            }
+        }
+        if !is_changed {
+            break;
+        }
+    }
+}
+
+fn edge_pixels(
+    input_canvas: &PixelPropertyCanvas,
+    depth_edge_weight: Option<f32>,
+    direction_edge_weight: Option<f32>,
+) -> Vec<bool> {
+    // Depth edges are mostly silhouettes against the background or occluded surfaces; direction edges are mostly
+    // interior creases. A weight of 0 disables the respective kind of edge.
+    let depth_edge_weight = depth_edge_weight.unwrap_or(1.0);
+    let direction_edge_weight = direction_edge_weight.unwrap_or(1.0);
+    let (sobel_x, sobel_y) = sobel_kernels();
     let mut float_canvas = FloatCanvas::new(input_canvas.width(), input_canvas.height(), 9);
     // layer 0: ln(depth)
     // layer 1: sobel_x(ln(depth))
@@ -328,32 +1153,1288 @@ pub fn render_edges (
     float_canvas.convolve_layer(0, 1, &sobel_x);
     float_canvas.convolve_layer(0, 2, &sobel_y);
 
-    // layer 3: cos(direction)
-    // layer 4: sin(direction)
-    // layer 5: sobel_x(cos(direction))
-    // layer 6: sobel_y(cos(direction))
-    // layer 7: sobel_x(sin(direction))
-    // layer 8: sobel_y(sin(direction))
+    // layer 3: direction_vec.x
+    // layer 4: direction_vec.y
+    // layer 5: sobel_x(direction_vec.x)
+    // layer 6: sobel_y(direction_vec.x)
+    // layer 7: sobel_x(direction_vec.y)
+    // layer 8: sobel_y(direction_vec.y)
     input_canvas.to_float_canvas_layer(&mut float_canvas, 3, |pp: &PixelProperties| {
-        if pp.direction.is_nan() { 1.0e6 } else { pp.direction.cos() }
+        if pp.direction_vec.0.is_nan() { 1.0e6 } else { pp.direction_vec.0 }
     });
     input_canvas.to_float_canvas_layer(&mut float_canvas, 4, |pp: &PixelProperties| {
-        if pp.direction.is_nan() { 1.0e6 } else { pp.direction.sin() }
+        if pp.direction_vec.1.is_nan() { 1.0e6 } else { pp.direction_vec.1 }
     });
     float_canvas.convolve_layer(3, 5, &sobel_x);
     float_canvas.convolve_layer(3, 6, &sobel_y);
     float_canvas.convolve_layer(4, 7, &sobel_x);
     float_canvas.convolve_layer(4, 8, &sobel_y);
 
-    float_canvas.for_each_pixel(|x, y, values| {
+    let mut is_edge = Vec::with_capacity((input_canvas.width() * input_canvas.height()) as usize);
+    float_canvas.for_each_pixel(|_, _, values| {
         let d_depth = vec2::from_values(values[1], values[2]);
         let d_cos_dir = vec2::from_values(values[5], values[6]);
         let d_sin_dir = vec2::from_values(values[7], values[8]);
-        let magnitude_depth = vec2::len(&d_depth);
-        let magnitude_dir = (vec2::len_squared(&d_cos_dir) + vec2::len_squared(&d_sin_dir)).sqrt();
-        if magnitude_dir > 5.75 || magnitude_depth > 0.07 {
-            output_canvas.fill_point(x as f32, y as f32, 0.5 * edge_width, edge_color);
-            // output_canvas.fill_rect(x as f32, y as f32, edge_width, edge_width, edge_color);
+        let magnitude_depth = depth_edge_weight * vec2::len(&d_depth);
+        let magnitude_dir = direction_edge_weight * (vec2::len_squared(&d_cos_dir) + vec2::len_squared(&d_sin_dir)).sqrt();
+        is_edge.push(magnitude_dir > 5.75 || magnitude_depth > 0.07);
+    });
+    is_edge
+}
+
+// Trace the boundary between hit and miss (NaN depth) pixels into closed polylines using marching squares.
+// Vertices lie on the midpoints between neighboring pixel centers; the first point of a loop is not repeated at its end.
+pub fn extract_silhouette(input_canvas: &PixelPropertyCanvas) -> Vec<Vec<Vec2>> {
+    let width = input_canvas.width() as i64;
+    let height = input_canvas.height() as i64;
+    let is_hit = |x: i64, y: i64| {
+        x >= 0 && y >= 0 && x < width && y < height
+            && !input_canvas.pixel_at_reflected(x as i32, y as i32).depth.is_nan()
+    };
+
+    // Cells span the pixel centers (x, y) to (x + 1, y + 1); the canvas is padded by one miss pixel on each side
+    // so that every loop closes. Edges are keyed by their midpoints in doubled pixel coordinates and each boundary
+    // segment is oriented with the hit side on its left, so every edge has exactly one successor.
+    let mut next_edge: BTreeMap<(i64, i64), (i64, i64)> = BTreeMap::new();
+    for y in -1..height {
+        for x in -1..width {
+            let corners = [(x, y), (x + 1, y), (x + 1, y + 1), (x, y + 1)];
+            let inside = corners.map(|(cx, cy)| is_hit(cx, cy));
+            let edge_key = |i: usize| {
+                let (ax, ay) = corners[i];
+                let (bx, by) = corners[(i + 1) % 4];
+                (ax + bx, ay + by)
+            };
+            let is_exit = |i: usize| inside[i] && !inside[(i + 1) % 4];
+            let is_entry = |i: usize| !inside[i] && inside[(i + 1) % 4];
+            for i in (0..4).filter(|&i| is_exit(i)) {
+                // Pairing with the next entry edge keeps diagonal hit pixels connected in the saddle cases
+                let j = (1..4).map(|k| (i + k) % 4).find(|&j| is_entry(j)).unwrap();
+                next_edge.insert(edge_key(i), edge_key(j));
+            }
+        }
+    }
+
+    let mut loops = Vec::new();
+    while let Some((&start, _)) = next_edge.first_key_value() {
+        let mut polyline = Vec::new();
+        let mut edge = start;
+        while let Some(next) = next_edge.remove(&edge) {
+            polyline.push(vec2::from_values(0.5 * edge.0 as f32 + 0.5, 0.5 * edge.1 as f32 + 0.5));
+            edge = next;
+        }
+        loops.push(polyline);
+    }
+    loops
+}
+
+// Contour lines at the multiples of interval, which must be positive (a NaN interval is rejected, too)
+fn depth_contour_segments(input_canvas: &PixelPropertyCanvas, interval: f32) -> Vec<(Vec2, Vec2)> {
+    assert!(interval > 0.0, "the contour interval must be positive, got {}", interval);
+    // Marching squares on the depth, with cells spanning the pixel centers (x, y) to (x + 1, y + 1). Cells with a
+    // corner that misses the scene are skipped so that contours end at silhouettes.
+    let depth_at = |x: u32, y: u32| input_canvas.pixel_at_reflected(x as i32, y as i32).depth;
+    let mut segments = Vec::new();
+    for y in 0..input_canvas.height().saturating_sub(1) {
+        for x in 0..input_canvas.width().saturating_sub(1) {
+            let corners = [(x, y), (x + 1, y), (x + 1, y + 1), (x, y + 1)];
+            let depths = corners.map(|(cx, cy)| depth_at(cx, cy));
+            if depths.iter().any(|d| d.is_nan()) {
+                continue;
+            }
+            let min_depth = depths.iter().cloned().fold(f32::INFINITY, f32::min);
+            let max_depth = depths.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            for level_index in ((min_depth / interval).ceil() as i64)..=((max_depth / interval).floor() as i64) {
+                let level = level_index as f32 * interval;
+                let crossings: Vec<Vec2> = (0..4)
+                    .filter(|&i| (depths[i] >= level) != (depths[(i + 1) % 4] >= level))
+                    .map(|i| {
+                        let (a, b) = (corners[i], corners[(i + 1) % 4]);
+                        let t = (level - depths[i]) / (depths[(i + 1) % 4] - depths[i]);
+                        vec2::from_values(
+                            a.0 as f32 + 0.5 + t * (b.0 as f32 - a.0 as f32),
+                            a.1 as f32 + 0.5 + t * (b.1 as f32 - a.1 as f32),
+                        )
+                    })
+                    .collect();
+                match crossings.len() {
+                    2 => segments.push((crossings[0], crossings[1])),
+                    4 => {
+                        // Saddle: the mean of the corners decides whether the corners above the level are connected
+                        let is_center_above = 0.25 * depths.iter().sum::<f32>() >= level;
+                        if is_center_above == (depths[0] >= level) {
+                            segments.push((crossings[0], crossings[1]));
+                            segments.push((crossings[2], crossings[3]));
+                        } else {
+                            segments.push((crossings[1], crossings[2]));
+                            segments.push((crossings[3], crossings[0]));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    segments
+}
+
+pub fn render_depth_contours(
+    input_canvas: &PixelPropertyCanvas,
+    output_canvas: &mut SkiaCanvas,
+    interval: f32, // world depth between adjacent contour lines; panics unless positive
+    color: &[u8; 3],
+    width: f32,
+) {
+    let mut pb = PathBuilder::new();
+    for (a, b) in depth_contour_segments(input_canvas, interval) {
+        pb.move_to(a.0, a.1);
+        pb.line_to(b.0, b.1);
+    }
+    if let Some(path) = pb.finish() {
+        output_canvas.stroke_path(&path, width, color);
+    }
+}
+
+pub fn render_halftone(
+    input_canvas: &PixelPropertyCanvas,
+    output_canvas: &mut SkiaCanvas,
+    cell_size: f32,
+    angle: f32,
+    dot_color: &[u8; 3],
+) {
+    // Lay a grid of cells, rotated by angle around the canvas center, over the canvas. Per cell, draw a dot whose
+    // radius is proportional to 1 - (mean lightness of the cell); a lightness of 0 covers the cell completely.
+    const SAMPLES_PER_AXIS: u32 = 4;
+    let width = input_canvas.width() as f32;
+    let height = input_canvas.height() as f32;
+    let center = vec2::from_values(0.5 * width, 0.5 * height);
+    let (angle_sin, angle_cos) = angle.sin_cos();
+    let to_canvas = |u: f32, v: f32| vec2::add(&center, &vec2::rotate_trig_inplace(vec2::from_values(u, v), angle_cos, angle_sin));
+    let max_radius = 0.5 * std::f32::consts::SQRT_2 * cell_size;
+
+    // The rotated grid has to cover the whole canvas, i.e., the circumcircle of the canvas
+    let half_cell_count = (0.5 * (width * width + height * height).sqrt() / cell_size).ceil() as i32;
+    for j in -half_cell_count..half_cell_count {
+        for i in -half_cell_count..half_cell_count {
+            let mut lightness_sum = 0.0;
+            let mut sample_count = 0;
+            for sj in 0..SAMPLES_PER_AXIS {
+                for si in 0..SAMPLES_PER_AXIS {
+                    let p = to_canvas(
+                        cell_size * (i as f32 + (si as f32 + 0.5) / SAMPLES_PER_AXIS as f32),
+                        cell_size * (j as f32 + (sj as f32 + 0.5) / SAMPLES_PER_AXIS as f32),
+                    );
+                    if let Some(pixel) = input_canvas.pixel_value(p.0, p.1) {
+                        lightness_sum += pixel.lightness.clamp(0.0, 1.0);
+                        sample_count += 1;
+                    }
+                }
+            }
+            if sample_count == 0 {
+                continue;
+            }
+            let radius = max_radius * (1.0 - lightness_sum / sample_count as f32);
+            if radius > 0.0 {
+                let cell_center = to_canvas(cell_size * (i as f32 + 0.5), cell_size * (j as f32 + 0.5));
+                output_canvas.fill_point(cell_center.0, cell_center.1, radius, dot_color);
+            }
+        }
+    }
+}
+
+pub fn render_dog_edges(
+    input_canvas: &PixelPropertyCanvas,
+    output_canvas: &mut SkiaCanvas,
+    sigma1: f32,
+    sigma2: f32, // > sigma1
+    threshold: f32,
+    edge_color: &[u8; 3],
+    edge_width: f32,
+) {
+    // Difference of Gaussians on the lightness, cf. H. Winnemoeller et al., "XDoG: An eXtended difference-of-Gaussians
+    // compendium including advanced image stylization", 2012. The DoG is strongly negative on the dark side of
+    // lightness edges; mark those pixels as edges.
+    // layer 0: lightness (background as white)
+    // layer 1: gaussian_blur(lightness, sigma1)
+    // layer 2: gaussian_blur(lightness, sigma2)
+    let mut float_canvas = FloatCanvas::new(input_canvas.width(), input_canvas.height(), 3);
+    input_canvas.to_float_canvas_layer(&mut float_canvas, 0, |pp: &PixelProperties| {
+        if pp.lightness.is_nan() { 1.0 } else { pp.lightness }
+    });
+    float_canvas.gaussian_blur_layer(0, 1, sigma1);
+    float_canvas.gaussian_blur_layer(0, 2, sigma2);
+
+    float_canvas.for_each_pixel(|x, y, values| {
+        if values[1] - values[2] < -threshold {
+            output_canvas.fill_point(x as f32 + 0.5, y as f32 + 0.5, 0.5 * edge_width, edge_color);
         }
     });
 }
+
+fn direction_quiver_arrows(input_canvas: &PixelPropertyCanvas, spacing: f32, arrow_len: f32) -> Vec<(Vec2, Vec2)> {
+    // (tail, tip) of one arrow per grid cell, centered on the cell and pointing along the local direction
+    let column_count = (input_canvas.width() as f32 / spacing).floor() as u32;
+    let row_count = (input_canvas.height() as f32 / spacing).floor() as u32;
+    (0..row_count).flat_map(|j| (0..column_count).map(move |i| (i, j)))
+        .filter_map(|(i, j)| {
+            let center = vec2::from_values(spacing * (i as f32 + 0.5), spacing * (j as f32 + 0.5));
+            input_canvas.pixel_value(center.0, center.1).map(|pixel| {
+                let dir = vec2::polar_angle_to_unit_vector(pixel.direction());
+                (
+                    vec2::scale_and_add(&center, &dir, -0.5 * arrow_len),
+                    vec2::scale_and_add(&center, &dir, 0.5 * arrow_len),
+                )
+            })
+        })
+        .collect()
+}
+
+pub fn render_direction_quiver(
+    input_canvas: &PixelPropertyCanvas,
+    output_canvas: &mut SkiaCanvas,
+    spacing: f32,
+    arrow_len: f32,
+    color: &[u8; 3],
+) {
+    const STROKE_WIDTH: f32 = 1.0;
+    const HEAD_ANGLE: f32 = PI / 6.0;
+    let head_len = 0.3 * arrow_len;
+    for (tail, tip) in direction_quiver_arrows(input_canvas, spacing, arrow_len) {
+        let back_angle = vec2::polar_angle(&vec2::sub(&tail, &tip));
+        let head_left = vec2::scale_and_add(&tip, &vec2::polar_angle_to_unit_vector(back_angle + HEAD_ANGLE), head_len);
+        let head_right = vec2::scale_and_add(&tip, &vec2::polar_angle_to_unit_vector(back_angle - HEAD_ANGLE), head_len);
+        for points in [&[tail, tip][..], &[head_left, tip, head_right][..]] {
+            if let Some(path) = SkiaCanvas::linear_path(points) {
+                output_canvas.stroke_path(&path, STROKE_WIDTH, color);
+            }
+        }
+    }
+}
+
+// Dimensional streamline parameters in pixels, derived from physical sizes (in mm) at a given resolution
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamlineParams {
+    pub stroke_width: f32,
+    pub seed_box_size: u32,
+    pub d_sep_min: f32,
+    pub d_sep_max: f32,
+    pub d_test_factor: f32,
+    pub d_step: f32,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StreamlineParamsError {
+    NonPositive(&'static str),
+    InvalidTestFactor(f32),
+    SeparationRange { d_sep_min: f32, d_sep_max: f32 },
+    StepTooLarge { d_step: f32, d_test_min: f32 },
+}
+
+impl fmt::Display for StreamlineParamsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamlineParamsError::NonPositive(name) => write!(f, "{} must be positive", name),
+            StreamlineParamsError::InvalidTestFactor(factor) => write!(f, "d_test_factor must be in (0, 1], got {}", factor),
+            StreamlineParamsError::SeparationRange { d_sep_min, d_sep_max } =>
+                write!(f, "d_sep_min ({} px) must be smaller than d_sep_max ({} px)", d_sep_min, d_sep_max),
+            StreamlineParamsError::StepTooLarge { d_step, d_test_min } =>
+                write!(f, "d_step ({} px) must be at most half of the smallest d_test ({} px)", d_step, d_test_min),
+        }
+    }
+}
+
+impl StreamlineParams {
+    const INCH_PER_MM: f32 = 0.1 / 2.54;
+
+    pub fn from_physical(
+        dpi: f32,
+        stroke_width_in_mm: f32,
+        seed_box_size_in_mm: f32,
+        d_sep_min_in_mm: f32,
+        d_sep_max_in_mm: f32,
+        d_test_factor: f32,
+        d_step_in_mm: f32,
+    ) -> Result<StreamlineParams, StreamlineParamsError> {
+        let named_values = [
+            ("dpi", dpi),
+            ("stroke_width", stroke_width_in_mm),
+            ("seed_box_size", seed_box_size_in_mm),
+            ("d_sep_min", d_sep_min_in_mm),
+            ("d_sep_max", d_sep_max_in_mm),
+            ("d_step", d_step_in_mm),
+        ];
+        if let Some((name, _)) = named_values.iter().find(|(_, value)| value.is_nan() || *value <= 0.0) {
+            return Err(StreamlineParamsError::NonPositive(name));
+        }
+        if d_test_factor.is_nan() || d_test_factor <= 0.0 || d_test_factor > 1.0 {
+            return Err(StreamlineParamsError::InvalidTestFactor(d_test_factor));
+        }
+
+        let px_per_mm = Self::INCH_PER_MM * dpi;
+        let d_sep_min = d_sep_min_in_mm * px_per_mm;
+        let d_sep_max = d_sep_max_in_mm * px_per_mm;
+        let d_step = d_step_in_mm * px_per_mm;
+        if d_sep_min >= d_sep_max {
+            return Err(StreamlineParamsError::SeparationRange { d_sep_min, d_sep_max });
+        }
+        // d_step << d_test <= d_sep, cf. streamline.rs; otherwise, streamlines can step across their neighbors
+        let d_test_min = d_test_factor * d_sep_min;
+        if d_step > 0.5 * d_test_min {
+            return Err(StreamlineParamsError::StepTooLarge { d_step, d_test_min });
+        }
+
+        Ok(StreamlineParams {
+            stroke_width: stroke_width_in_mm * px_per_mm,
+            seed_box_size: ((seed_box_size_in_mm * px_per_mm).round() as u32).max(1),
+            d_sep_min,
+            d_sep_max,
+            d_test_factor,
+            d_step,
+        })
+    }
+}
+
+// Serde definition of tiny-skia's LineCap
+#[derive(Serialize, Deserialize)]
+#[serde(remote = "LineCap")]
+enum LineCapDef {
+    Butt,
+    Round,
+    Square,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HatchConfig {
+    pub lightness_threshold: f32,
+    pub step_size: f32,
+    pub line_color: [u8; 3],
+    pub stroke_width: f32,
+    pub direction: HatchDirection,
+    pub line_sep: VecFloat,
+    #[serde(with = "LineCapDef")]
+    pub line_cap: LineCap, // only applies to straight lines
+    pub pressure: Option<StrokePressure>,
+    pub dry_brush: Option<f32>, // only applies to straight lines, cf. render_hatch_lines
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum HatchDirection {
+    Angle(VecFloat), // straight lines at an angle in [0, Pi)
+    AcrossSlope, // strokes along the iso-depth lines, cf. render_cross_slope_hatch_lines
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EdgeConfig {
+    pub color: [u8; 3],
+    pub width: f32,
+    pub depth_weight: Option<f32>,
+    pub direction_weight: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct IllustrationConfig {
+    pub width: u32,
+    pub height: u32,
+    pub angle_in_tangent_plane: VecFloat,
+    pub direction_source: DirectionSource,
+    pub background: BackgroundSpec,
+    pub is_background_shaded: bool, // start from bg_to_skia_canvas instead of a blank canvas
+    pub streamlines: Option<StreamlineConfig>,
+    pub hatching: Vec<HatchConfig>,
+    pub edges: Option<EdgeConfig>,
+}
+
+pub fn render_scene_illustration<S>(
+    ray_marcher: &RayMarcher,
+    scene: &S,
+    config: &IllustrationConfig,
+) -> SkiaCanvas
+where
+    S: Scene + Sync,
+{
+    let options = SceneRenderOptions {
+        angle_in_tangent_plane: config.angle_in_tangent_plane,
+        normal_finite_diff_h: None,
+        direction_source: config.direction_source,
+        background: config.background,
+    };
+    let pp_canvas = PixelPropertyCanvas::from_scene(ray_marcher, scene, config.width, config.height, &options);
+
+    let mut output_canvas = if config.is_background_shaded {
+        pp_canvas.bg_to_skia_canvas(None)
+    } else {
+        SkiaCanvas::new(config.width, config.height)
+    };
+
+    if let Some(sc) = &config.streamlines {
+        let mut rng = StdRng::seed_from_u64(sc.rng_seed);
+        render_flow_field_streamlines(&pp_canvas, &mut output_canvas, &mut rng, sc, None);
+    }
+
+    for hc in config.hatching.iter() {
+        match hc.direction {
+            HatchDirection::Angle(line_angle) => render_hatch_lines(
+                &pp_canvas,
+                &mut output_canvas,
+                hc.lightness_threshold,
+                hc.step_size,
+                &hc.line_color,
+                hc.stroke_width,
+                line_angle,
+                hc.line_sep,
+                hc.line_cap,
+                hc.pressure.as_ref(),
+                hc.dry_brush,
+                false,
+            ),
+            HatchDirection::AcrossSlope => {
+                render_cross_slope_hatch_lines(
+                    &pp_canvas,
+                    &mut output_canvas,
+                    hc.lightness_threshold,
+                    hc.step_size,
+                    &hc.line_color,
+                    hc.stroke_width,
+                    hc.line_sep,
+                    hc.pressure.as_ref(),
+                );
+            }
+        }
+    }
+
+    if let Some(ec) = &config.edges {
+        render_edges(&pp_canvas, &mut output_canvas, &ec.color, ec.width, ec.depth_weight, ec.direction_weight);
+    }
+
+    output_canvas
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use assert_approx_eq::assert_approx_eq;
+    use crate::canvas::PixelCoord;
+    use crate::test_fixtures::{scene_sphere, sphere_ray_marcher, uniform_canvas};
+
+    fn is_white(rgb: u32) -> bool {
+        rgb == 0xFFFFFF
+    }
+
+    #[test]
+    fn test_streamline_params_from_physical() {
+        // At 254 dpi, 1 mm is exactly 10 px
+        let params = StreamlineParams::from_physical(254.0, 0.15, 1.96, 0.3, 1.5, 0.8, 0.1).unwrap();
+        assert_approx_eq!(1.5, params.stroke_width);
+        assert_eq!(20, params.seed_box_size); // 19.6 px is rounded, not truncated
+        assert_approx_eq!(3.0, params.d_sep_min);
+        assert_approx_eq!(15.0, params.d_sep_max);
+        assert_approx_eq!(0.8, params.d_test_factor);
+        assert_approx_eq!(1.0, params.d_step);
+
+        let params = StreamlineParams::from_physical(350.0, 0.15, 2.0, 0.27, 1.5, 0.8, 0.1).unwrap();
+        assert_eq!(28, params.seed_box_size);
+        assert_approx_eq!(0.15 * 350.0 / 25.4, params.stroke_width);
+    }
+
+    #[test]
+    fn test_streamline_params_validation() {
+        assert_eq!(
+            Err(StreamlineParamsError::NonPositive("dpi")),
+            StreamlineParams::from_physical(0.0, 0.15, 2.0, 0.3, 1.5, 0.8, 0.1)
+        );
+        assert_eq!(
+            Err(StreamlineParamsError::NonPositive("d_step")),
+            StreamlineParams::from_physical(254.0, 0.15, 2.0, 0.3, 1.5, 0.8, f32::NAN)
+        );
+        assert_eq!(
+            Err(StreamlineParamsError::InvalidTestFactor(1.5)),
+            StreamlineParams::from_physical(254.0, 0.15, 2.0, 0.3, 1.5, 1.5, 0.1)
+        );
+        assert!(matches!(
+            StreamlineParams::from_physical(254.0, 0.15, 2.0, 1.5, 1.5, 0.8, 0.1),
+            Err(StreamlineParamsError::SeparationRange { .. })
+        ));
+        assert!(matches!(
+            StreamlineParams::from_physical(254.0, 0.15, 2.0, 0.3, 1.5, 0.8, 0.2),
+            Err(StreamlineParamsError::StepTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn test_render_halftone() {
+        let width = 40;
+        let height = 20;
+        let mut input_canvas = uniform_canvas(width, height, 0.8, 0.0);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            if (index as u32 % width) < width / 2 {
+                pixel.lightness = 0.3;
+            }
+        }
+        let mut output_canvas = SkiaCanvas::new(width, height);
+        render_halftone(&input_canvas, &mut output_canvas, 5.0, 0.0, &[0, 0, 0]);
+
+        let rgb = output_canvas.to_u32_rgb();
+        let ink_count = |x_range: std::ops::Range<u32>| {
+            (0..height).flat_map(|y| x_range.clone().map(move |x| (x, y)))
+                .filter(|(x, y)| !is_white(rgb[(y * width + x) as usize]))
+                .count()
+        };
+        let dark_ink_count = ink_count(0..width / 2);
+        let light_ink_count = ink_count(width / 2..width);
+        assert!(light_ink_count > 0);
+        assert!(dark_ink_count > 2 * light_ink_count);
+    }
+
+    #[test]
+    fn test_streamline_seeds_importance() {
+        let width = 40;
+        let height = 20;
+        let mut input_canvas = uniform_canvas(width, height, 0.5, 0.0);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            // Flat field on the left, a field whose direction turns along x on the right
+            let x = index as u32 % width;
+            if x >= width / 2 {
+                pixel.set_direction(0.5 * x as f32);
+            }
+        }
+        let count_per_half = |seeds: &[Vec2]| {
+            let left = seeds.iter().filter(|p| p.0 < (width / 2) as f32).count();
+            let right = seeds.len() - left;
+            (left, right)
+        };
+
+        let mut rng = StdRng::seed_from_u64(7);
+        let (left, right) = count_per_half(&streamline_seeds(&input_canvas, &mut rng, 4, 0.0, None));
+        assert_eq!(left, right);
+
+        let (left, right) = count_per_half(&streamline_seeds(&input_canvas, &mut rng, 4, 0.0, Some(4.0)));
+        assert!(right > 2 * left);
+    }
+
+    #[test]
+    fn test_streamline_queue_order() {
+        let streamline_at = |x: f32| vec![vec2::from_values(x, 0.0), vec2::from_values(x, 1.0)];
+
+        let mut fifo = StreamlineQueue::new(SeedOrder::Fifo);
+        let mut radial = StreamlineQueue::new(SeedOrder::RadialFrom(vec2::from_values(10.0, 0.0)));
+        for (id, x) in [(0, 1.0), (1, 9.0), (2, 14.0), (3, 10.5)] {
+            fifo.push(id, streamline_at(x));
+            radial.push(id, streamline_at(x));
+        }
+        let drain = |queue: &mut StreamlineQueue| std::iter::from_fn(|| queue.pop().map(|(id, _)| id)).collect::<Vec<u32>>();
+        assert_eq!(vec![0, 1, 2, 3], drain(&mut fifo));
+        assert_eq!(vec![3, 1, 2, 0], drain(&mut radial));
+    }
+
+    #[test]
+    fn test_pressure_widths() {
+        let points: Vec<Vec2> = (0..400).map(|i| vec2::from_values(0.5 * i as f32, 10.0)).collect();
+        let pressure = StrokePressure::new(0.3, 0.05, Some(7));
+        let widths = pressure_widths(&points, 2.0, &pressure, 0);
+        assert!(widths.iter().all(|w| (1.4..=2.6).contains(w)));
+        let (min_width, max_width) = widths.iter().fold((f32::MAX, f32::MIN), |(lo, hi), w| (lo.min(*w), hi.max(*w)));
+        assert!(max_width - min_width > 0.2);
+
+        assert_eq!(widths, pressure_widths(&points, 2.0, &pressure, 0));
+        assert_ne!(widths, pressure_widths(&points, 2.0, &pressure, 1));
+        assert_ne!(widths, pressure_widths(&points, 2.0, &StrokePressure::new(0.3, 0.05, Some(8)), 0));
+    }
+
+    #[test]
+    fn test_render_flow_field_streamlines_max_streamlines() {
+        let width = 40;
+        let height = 40;
+        let input_canvas = uniform_canvas(width, height, 0.2, 0.25 * PI);
+        let render = |max_streamlines: Option<usize>| {
+            let mut output_canvas = SkiaCanvas::new(width, height);
+            let mut rng = StdRng::seed_from_u64(5);
+            let config = StreamlineConfig { d_test_factor: 0.5, max_accum_angle: 4.0, max_streamlines, ..StreamlineConfig::default() };
+            render_flow_field_streamlines(&input_canvas, &mut output_canvas, &mut rng, &config, None)
+        };
+
+        let unlimited = render(None);
+        assert!(unlimited > 5);
+        for cap in [1, 3, 5] {
+            assert_eq!(cap, render(Some(cap)));
+        }
+        assert_eq!(unlimited, render(Some(unlimited + 10)));
+    }
+
+    #[test]
+    fn test_render_flow_field_streamlines_progressive() {
+        let width = 40;
+        let height = 40;
+        let input_canvas = uniform_canvas(width, height, 0.2, 0.25 * PI);
+        let mut output_canvas = SkiaCanvas::new(width, height);
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut streamlines: Vec<Vec<Vec2>> = vec![];
+        let config = StreamlineConfig { d_test_factor: 0.5, max_accum_angle: 4.0, ..StreamlineConfig::default() };
+        let streamline_count = render_flow_field_streamlines_progressive(
+            &input_canvas,
+            &mut output_canvas,
+            &mut rng,
+            &config,
+            None,
+            |streamline| streamlines.push(streamline.to_vec()),
+        );
+        assert!(streamline_count > 0);
+        assert_eq!(streamline_count, streamlines.len());
+        assert!(streamlines.iter().all(|streamline| streamline.len() > 3));
+    }
+
+    #[test]
+    fn test_render_flow_field_streamlines_radial_order() {
+        let width = 40;
+        let height = 40;
+        let input_canvas = uniform_canvas(width, height, 0.5, 0.5 * PI);
+        let focal_point = vec2::from_values(30.0, 30.0);
+
+        // The mask sees the starting point of every streamline before any of its steps
+        let first_point = |seed_order: SeedOrder| {
+            let visited = std::cell::RefCell::new(Vec::new());
+            let mask = |p: &Vec2| {
+                visited.borrow_mut().push(*p);
+                true
+            };
+            let mut output_canvas = SkiaCanvas::new(width, height);
+            let mut rng = StdRng::seed_from_u64(3);
+            let config = StreamlineConfig {
+                seed_jitter: 0.0,
+                d_test_factor: 0.5,
+                max_accum_angle: 4.0,
+                seed_order,
+                ..StreamlineConfig::default()
+            };
+            render_flow_field_streamlines(&input_canvas, &mut output_canvas, &mut rng, &config, Some(&mask));
+            let visited = visited.into_inner();
+            assert!(!visited.is_empty());
+            visited[0]
+        };
+
+        let fifo_start = first_point(SeedOrder::Fifo);
+        let radial_start = first_point(SeedOrder::RadialFrom(focal_point));
+        assert!(vec2::dist(&radial_start, &focal_point) < 8.0);
+        assert!(vec2::dist(&radial_start, &focal_point) < vec2::dist(&fifo_start, &focal_point));
+    }
+
+    #[test]
+    fn test_render_flow_field_streamlines_style_from_lightness() {
+        // Vertical flow, dark on the left half and light on the right half
+        let width = 40;
+        let height = 40;
+        let mut input_canvas = uniform_canvas(width, height, 0.9, 0.5 * PI);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            if index as u32 % width < width / 2 {
+                pixel.lightness = 0.1;
+            }
+        }
+        let mut output_canvas = SkiaCanvas::new(width, height);
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut streamlines: Vec<Vec<Vec2>> = vec![];
+        let config = StreamlineConfig {
+            seed_jitter: 0.0,
+            d_sep_min: 8.0,
+            d_sep_max: 8.0,
+            d_test_factor: 0.5,
+            max_accum_angle: 4.0,
+            style_from_lightness: Some(0.5),
+            ..StreamlineConfig::default()
+        };
+        render_flow_field_streamlines_progressive(
+            &input_canvas,
+            &mut output_canvas,
+            &mut rng,
+            &config,
+            None,
+            |streamline| streamlines.push(streamline.to_vec()),
+        );
+
+        // Whether each point of a streamline (away from the canvas border) is covered by ink
+        let rgb = output_canvas.to_u32_rgb();
+        let inked_points = |streamline: &[Vec2]| {
+            streamline
+                .iter()
+                .filter(|p| p.1 > 3.0 && p.1 < height as f32 - 3.0)
+                .map(|p| !is_white(rgb[(p.1 as u32 * width + p.0 as u32) as usize]))
+                .collect::<Vec<bool>>()
+        };
+        let half_width = (width / 2) as f32;
+        let dark: Vec<_> = streamlines.iter().filter(|streamline| streamline.iter().all(|p| p.0 < half_width - 1.0)).collect();
+        let light: Vec<_> = streamlines.iter().filter(|streamline| streamline.iter().all(|p| p.0 > half_width + 1.0)).collect();
+        assert!(!dark.is_empty() && !light.is_empty());
+        assert!(dark.iter().all(|streamline| inked_points(streamline).iter().all(|&inked| inked)));
+        assert!(light.iter().all(|streamline| {
+            let inked = inked_points(streamline);
+            inked.iter().any(|&inked| inked) && inked.iter().any(|&inked| !inked)
+        }));
+
+        assert_eq!(
+            vec![
+                vec![vec2::from_values(0.0, 0.0), vec2::from_values(3.0, 0.0)],
+                vec![vec2::from_values(5.0, 0.0), vec2::from_values(6.0, 0.0), vec2::from_values(6.0, 2.0)],
+                vec![vec2::from_values(6.0, 4.0), vec2::from_values(6.0, 5.0)],
+            ],
+            dash_polyline(&[vec2::from_values(0.0, 0.0), vec2::from_values(6.0, 0.0), vec2::from_values(6.0, 5.0)], 3.0, 2.0)
+        );
+    }
+
+    #[test]
+    fn test_depth_weighted_stroke_width() {
+        let width = 20;
+        let height = 10;
+        let mut input_canvas = uniform_canvas(width, height, 0.5, 0.5 * PI);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            if (index as u32 % width) >= width / 2 {
+                pixel.depth = 10.0;
+            }
+        }
+        let depth_range = input_canvas.depth_range();
+        let near_streamline = [vec2::from_values(2.5, 2.0), vec2::from_values(2.5, 8.0)];
+        let far_streamline = [vec2::from_values(17.5, 2.0), vec2::from_values(17.5, 8.0)];
+
+        let near_width = depth_weighted_stroke_width(&input_canvas, depth_range, &near_streamline, 2.0, 0.25);
+        let far_width = depth_weighted_stroke_width(&input_canvas, depth_range, &far_streamline, 2.0, 0.25);
+        assert_approx_eq!(2.0, near_width);
+        assert_approx_eq!(0.5, far_width);
+        assert!(near_width > far_width);
+    }
+
+    #[test]
+    fn test_extract_silhouette() {
+        let width = 40;
+        let height = 40;
+        let radius = 10.0;
+        let mut input_canvas = PixelPropertyCanvas::new(width, height);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            let center = PixelCoord::from_index(index, width).center();
+            if vec2::dist(&center, &vec2::from_values(20.0, 20.0)) <= radius {
+                pixel.depth = 1.0;
+            }
+        }
+
+        let loops = extract_silhouette(&input_canvas);
+        assert_eq!(1, loops.len());
+        let silhouette = &loops[0];
+        let perimeter: f32 = silhouette
+            .iter()
+            .zip(silhouette.iter().cycle().skip(1))
+            .map(|(a, b)| vec2::dist(a, b))
+            .sum();
+        // The binary hit mask gives a slightly jagged contour that overestimates the perimeter
+        assert!((perimeter - 2.0 * PI * radius).abs() < 0.1 * 2.0 * PI * radius);
+        // Each step moves half a pixel diagonally or a full pixel along an axis
+        for (a, b) in silhouette.iter().zip(silhouette.iter().cycle().skip(1)) {
+            assert!(vec2::dist(a, b) <= 1.0 + 1.0e-5);
+        }
+
+        assert!(extract_silhouette(&PixelPropertyCanvas::new(width, height)).is_empty());
+    }
+
+    #[test]
+    fn test_render_hatch_lines_butt_caps() {
+        let width = 30;
+        let height = 10;
+        let mut input_canvas = uniform_canvas(width, height, 0.1, 0.0);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            // Two collinear runs with a gap in [10, 20)
+            pixel.is_hatched = !(10..20).contains(&(index as u32 % width));
+        }
+        let inked_columns = |line_cap: LineCap| {
+            let mut output_canvas = SkiaCanvas::new(width, height);
+            render_hatch_lines(&input_canvas, &mut output_canvas, 0.5, 0.5, &[0, 0, 0], 2.0, 0.0, 5.0, line_cap, None, None, false);
+            let rgb = output_canvas.to_u32_rgb();
+            (0..width).filter(|&x| (0..height).any(|y| !is_white(rgb[(y * width + x) as usize]))).collect::<Vec<u32>>()
+        };
+
+        let butt = inked_columns(LineCap::Butt);
+        assert_eq!((0..10).chain(20..30).collect::<Vec<u32>>(), butt);
+        // Round caps bulge into the gap
+        let round = inked_columns(LineCap::Round);
+        assert!(round.contains(&10) && round.contains(&19));
+    }
+
+    #[test]
+    fn test_render_tone_matched_hatch_lines() {
+        let width = 100;
+        let height = 100;
+        // The center of the band [0.3, 0.35) for 20 tone levels
+        let mut input_canvas = uniform_canvas(width, height, 0.325, 0.0);
+        for pixel in input_canvas.pixels_mut().iter_mut() {
+            pixel.is_hatched = true;
+        }
+        let mut output_canvas = SkiaCanvas::new(width, height);
+        render_tone_matched_hatch_lines(&input_canvas, &mut output_canvas, 0.5, &[0, 0, 0], 2.0, 0.0, 20, LineCap::Butt, None);
+
+        let rgb = output_canvas.to_u32_rgb();
+        let coverage = rgb.iter().map(|c| 1.0 - (c & 0xFF) as f32 / 255.0).sum::<f32>() / rgb.len() as f32;
+        assert!((coverage - (1.0 - 0.325)).abs() < 0.03);
+
+        assert_approx_eq!(4.0, hatch_separation_for_coverage(2.0, 0.5));
+        assert_approx_eq!(2.0, hatch_separation_for_coverage(2.0, 1.5));
+    }
+
+    #[test]
+    fn test_depth_contour_direction_canvas() {
+        let width = 12;
+        let height = 10;
+        let mut input_canvas = PixelPropertyCanvas::new(width, height);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            let (x, y) = (index as u32 % width, index as u32 / width);
+            pixel.depth = if x == 0 { f32::NAN } else { 1.0 + 0.1 * (x + y) as f32 };
+            pixel.lightness = 0.5;
+            pixel.is_hatched = true;
+        }
+
+        let contour_canvas = depth_contour_direction_canvas(&input_canvas);
+        let pixel = contour_canvas.pixel_value(6.5, 5.5).unwrap();
+        // Across the gradient (1, 1)
+        assert_approx_eq!(0.0, pixel.direction_vec.0 + pixel.direction_vec.1);
+        assert_approx_eq!(1.0, vec2::len(&pixel.direction_vec));
+        assert!(pixel.is_streamlined);
+        assert_approx_eq!(0.5, pixel.lightness);
+        // Next to the miss pixels, the gradient is undefined
+        assert!(contour_canvas.pixel_value(1.5, 5.5).is_none());
+    }
+
+    #[test]
+    fn test_render_cross_slope_hatch_lines() {
+        // A plane sloping along x, i.e., the strokes run vertically; the right quarter is too light to be hatched
+        let width = 40;
+        let height = 30;
+        let mut input_canvas = PixelPropertyCanvas::new(width, height);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            let x = index as u32 % width;
+            pixel.depth = 1.0 + 0.05 * x as f32;
+            pixel.lightness = if x < 30 { 0.2 } else { 0.9 };
+            pixel.is_hatched = true;
+        }
+        let mut output_canvas = SkiaCanvas::new(width, height);
+        let stroke_count = render_cross_slope_hatch_lines(&input_canvas, &mut output_canvas, 0.5, 0.5, &[0, 0, 0], 1.0, 4.0, None);
+        assert!(stroke_count > 0);
+
+        let rgb = output_canvas.to_u32_rgb();
+        let row = |y: u32| &rgb[(y * width) as usize..((y + 1) * width) as usize];
+        assert!(row(10).iter().any(|rgb| !is_white(*rgb)));
+        assert_eq!(row(10), row(20));
+        assert!((31..width).all(|x| (0..height).all(|y| is_white(rgb[(y * width + x) as usize]))));
+    }
+
+    #[test]
+    fn test_render_edges_weights() {
+        // A depth step between the left and right half and a direction reversal between the top and bottom half
+        let width = 40;
+        let height = 40;
+        let mut input_canvas = PixelPropertyCanvas::new(width, height);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            let (x, y) = (index as u32 % width, index as u32 / width);
+            pixel.depth = if x < width / 2 { 1.0 } else { 5.0 };
+            pixel.direction_vec = vec2::from_values(if y < height / 2 { 1.0 } else { -1.0 }, 0.0);
+        }
+        let inked_pixels = |depth_weight: f32, direction_weight: f32| {
+            let mut output_canvas = SkiaCanvas::new(width, height);
+            render_edges(&input_canvas, &mut output_canvas, &[0, 0, 0], 1.0, Some(depth_weight), Some(direction_weight));
+            let rgb = output_canvas.to_u32_rgb();
+            (0..width * height)
+                .filter(|&index| !is_white(rgb[index as usize]))
+                .map(|index| (index % width, index / width))
+                .collect::<Vec<(u32, u32)>>()
+        };
+        let near_depth_step = |&(x, _): &(u32, u32)| x + 2 >= width / 2 && x <= width / 2 + 1;
+        let near_direction_step = |&(_, y): &(u32, u32)| y + 2 >= height / 2 && y <= height / 2 + 1;
+
+        let both = inked_pixels(1.0, 1.0);
+        assert!(both.iter().any(|p| near_depth_step(p) && !near_direction_step(p)));
+        assert!(both.iter().any(|p| near_direction_step(p) && !near_depth_step(p)));
+
+        let silhouette_only = inked_pixels(1.0, 0.0);
+        assert!(!silhouette_only.is_empty());
+        assert!(silhouette_only.iter().all(near_depth_step));
+
+        let creases_only = inked_pixels(0.0, 1.0);
+        assert!(!creases_only.is_empty());
+        assert!(creases_only.iter().all(near_direction_step));
+
+        assert!(inked_pixels(0.0, 0.0).is_empty());
+    }
+
+    #[test]
+    fn test_extract_edges_vector() {
+        // A vertical depth step, which render_edges draws as a band of dots two pixels wide
+        let width = 30;
+        let height = 40;
+        let mut input_canvas = PixelPropertyCanvas::new(width, height);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            pixel.depth = if index as u32 % width < width / 2 { 1.0 } else { 5.0 };
+            pixel.direction_vec = vec2::from_values(1.0, 0.0);
+        }
+        assert!(edge_pixels(&input_canvas, None, None).iter().filter(|&&is_edge| is_edge).count() >= 2 * height as usize);
+
+        let polylines = extract_edges_vector(&input_canvas, None, None, None);
+        assert_eq!(1, polylines.len());
+        let polyline = &polylines[0];
+        assert!(polyline.len() as u32 >= height - 4 && polyline.len() as u32 <= height);
+        assert!(polyline.iter().all(|p| (p.0 - (width / 2) as f32).abs() <= 1.0));
+        assert!(polyline.windows(2).all(|segment| (segment[1].1 - segment[0].1).abs() == 1.0));
+
+        assert!(extract_edges_vector(&input_canvas, None, None, Some(height as usize + 1)).is_empty());
+    }
+
+    #[test]
+    fn test_extract_edges_vector_closed() {
+        // The silhouette of a sphere, i.e., a disc of hits surrounded by misses
+        let width = 40;
+        let height = 40;
+        let center = vec2::from_values(20.0, 20.0);
+        let mut input_canvas = PixelPropertyCanvas::new(width, height);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            let p = vec2::from_values((index as u32 % width) as f32 + 0.5, (index as u32 / width) as f32 + 0.5);
+            if vec2::dist(&p, &center) < 10.0 {
+                pixel.depth = 5.0;
+                pixel.direction_vec = vec2::from_values(1.0, 0.0);
+            }
+        }
+
+        let polylines = extract_edges_vector(&input_canvas, None, Some(0.0), None);
+        assert_eq!(1, polylines.len());
+        let polyline = &polylines[0];
+        assert!(polyline.len() > 40);
+        assert_eq!(polyline[0], polyline[polyline.len() - 1]);
+        assert!(polyline.windows(2).all(|segment| vec2::dist(&segment[0], &segment[1]) < 1.5));
+        assert!(polyline.iter().all(|p| (vec2::dist(p, &center) - 10.0).abs() < 2.5));
+    }
+
+    #[test]
+    fn test_render_dog_edges() {
+        let width = 40;
+        let height = 10;
+        let mut input_canvas = uniform_canvas(width, height, 0.9, 0.0);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            if (index as u32 % width) < width / 2 {
+                pixel.lightness = 0.1;
+            }
+        }
+        let mut output_canvas = SkiaCanvas::new(width, height);
+        render_dog_edges(&input_canvas, &mut output_canvas, 1.0, 1.6, 0.02, &[0, 0, 0], 1.0);
+
+        let rgb = output_canvas.to_u32_rgb();
+        let column_has_ink = |x: u32| (0..height).any(|y| !is_white(rgb[(y * width + x) as usize]));
+        let inked_columns: Vec<u32> = (0..width).filter(|&x| column_has_ink(x)).collect();
+        assert!(!inked_columns.is_empty());
+        // A thin band on the dark side of the step; nothing in the flat regions
+        assert!(inked_columns.iter().all(|&x| x >= width / 2 - 5 && x < width / 2 + 1));
+        assert!(inked_columns.len() <= 5);
+    }
+
+    #[test]
+    fn test_render_depth_contours() {
+        // A plane tilted along x: the depth grows by 0.25 per pixel, so contours at an interval of 1 are vertical
+        // lines through the pixel centers of every 4th column; the rows from 30 on miss the scene
+        let width = 40;
+        let height = 40;
+        let mut input_canvas = PixelPropertyCanvas::new(width, height);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            let (x, y) = (index as u32 % width, index as u32 / width);
+            pixel.depth = if y < 30 { 0.25 * x as f32 } else { f32::NAN };
+        }
+
+        let segments = depth_contour_segments(&input_canvas, 1.0);
+        assert!(!segments.is_empty());
+        let mut contour_xs: Vec<i32> = vec![];
+        for (a, b) in &segments {
+            assert_approx_eq!(a.0, b.0, 1.0e-4);
+            assert_approx_eq!(1.0, (b.1 - a.1).abs(), 1.0e-4);
+            assert!(a.1.max(b.1) <= 29.5);
+            contour_xs.push(a.0.floor() as i32);
+        }
+        contour_xs.sort();
+        contour_xs.dedup();
+        assert_eq!((1..10).map(|k| 4 * k).collect::<Vec<_>>(), contour_xs);
+        for x in &contour_xs {
+            assert_eq!(29, segments.iter().filter(|(a, _)| a.0.floor() as i32 == *x).count());
+        }
+
+        let mut output_canvas = SkiaCanvas::new(width, height);
+        render_depth_contours(&input_canvas, &mut output_canvas, 1.0, &[0, 0, 0], 1.0);
+        let rgb = output_canvas.to_u32_rgb();
+        assert!(!is_white(rgb[(10 * width + 8) as usize]));
+        assert!(is_white(rgb[(10 * width + 10) as usize]));
+        assert!(is_white(rgb[(35 * width + 8) as usize]));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_render_depth_contours_nan_interval() {
+        let mut output_canvas = SkiaCanvas::new(10, 10);
+        render_depth_contours(&uniform_canvas(10, 10, 0.5, 0.0), &mut output_canvas, f32::NAN, &[0, 0, 0], 1.0);
+    }
+
+    #[test]
+    fn test_render_direction_quiver() {
+        let width = 40;
+        let height = 20;
+        let mut input_canvas = PixelPropertyCanvas::new(width, height);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            // The right half is left empty (NaN)
+            if (index as u32 % width) < width / 2 {
+                pixel.lightness = 0.5;
+                pixel.set_direction(0.25 * PI);
+                pixel.depth = 1.0;
+            }
+        }
+
+        let arrows = direction_quiver_arrows(&input_canvas, 5.0, 4.0);
+        assert_eq!(4 * 4, arrows.len());
+        for (tail, tip) in arrows.iter() {
+            assert!(tip.0 < (width / 2) as f32);
+            let dir = vec2::sub(tip, tail);
+            assert_approx_eq!(0.25 * PI, vec2::polar_angle(&dir));
+            assert_approx_eq!(4.0, vec2::len(&dir), 1.0e-5);
+        }
+
+        let mut output_canvas = SkiaCanvas::new(width, height);
+        render_direction_quiver(&input_canvas, &mut output_canvas, 5.0, 4.0, &[0, 0, 0]);
+        let rgb = output_canvas.to_u32_rgb();
+        assert!(rgb.iter().any(|rgb| !is_white(*rgb)));
+        assert!((0..height).all(|y| (width / 2 + 1..width).all(|x| is_white(rgb[(y * width + x) as usize]))));
+    }
+
+    #[test]
+    fn test_render_hatch_lines_is_hatched() {
+        let width = 20;
+        let height = 10;
+        let mut input_canvas = uniform_canvas(width, height, 0.1, 0.0);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            pixel.is_hatched = (index as u32 % width) >= width / 2;
+        }
+        let mut output_canvas = SkiaCanvas::new(width, height);
+        render_hatch_lines(&input_canvas, &mut output_canvas, 0.5, 0.5, &[0, 0, 0], 1.0, 0.0, 2.0, LineCap::Round, None, None, false);
+
+        let rgb = output_canvas.to_u32_rgb();
+        let column_has_ink = |x: u32| (0..height).any(|y| !is_white(rgb[(y * width + x) as usize]));
+        assert!((0..width / 2 - 2).all(|x| !column_has_ink(x)));
+        assert!((width / 2 + 2..width).all(column_has_ink));
+    }
+
+    #[test]
+    fn test_heightmap_line_points_parallel() {
+        let domain_region = DomainRegion {
+            near_a: vec2::from_values(-1.0, 1.0),
+            near_b: vec2::from_values(1.0, 1.0),
+            far_a: vec2::from_values(-4.0, 8.0),
+            far_b: vec2::from_values(4.0, 8.0),
+        };
+        let heightmap = |uv: &Vec2, t_domain: &Vec2, _: &Vec2| 0.05 * (3.0 * uv.0).sin() * (2.0 * uv.1).cos() * t_domain.1;
+        let line_points = |parallel: bool| heightmap_line_points(200.0, 100.0, &domain_region, 20, 2, 3, 50, &heightmap, parallel);
+
+        let serial = line_points(false);
+        let parallel = line_points(true);
+        assert_eq!(25, serial.len());
+        assert_eq!(serial.len(), parallel.len());
+        for (serial_line, parallel_line) in serial.iter().zip(parallel.iter()) {
+            assert_eq!(51, serial_line.len());
+            assert!(serial_line.iter().zip(parallel_line.iter()).all(|(s, p)| s.0 == p.0 && s.1 == p.1));
+        }
+        // Back to front: the farthest line lies highest on the screen
+        assert!(serial[0][0].1 < serial[serial.len() - 1][0].1);
+    }
+
+    #[test]
+    fn test_render_heightmap_streamlines_side_wave() {
+        let domain_region = DomainRegion {
+            near_a: vec2::from_values(-1.0, 1.0),
+            near_b: vec2::from_values(1.0, 1.0),
+            far_a: vec2::from_values(-4.0, 8.0),
+            far_b: vec2::from_values(4.0, 8.0),
+        };
+        let (width, height) = (80, 60);
+        let gradient = LinearGradient::new(&[0, 0, 0], &[100, 100, 100]);
+        // Leftmost inked pixel of each row in the lower half of the canvas, which the bands cover completely
+        let left_boundary = |side_wave: Option<f32>| {
+            let mut output_canvas = SkiaCanvas::new(width, height);
+            render_heightmap_streamlines(&mut output_canvas, &domain_region, 10, 1, 1, 40, 1.0, &[0, 0, 0], &gradient, side_wave, |_, _, _| 0.0);
+            let rgb = output_canvas.to_u32_rgb();
+            (height / 2..height)
+                .map(|y| (0..width).find(|&x| !is_white(rgb[(y * width + x) as usize])).unwrap())
+                .collect::<Vec<u32>>()
+        };
+
+        assert!(left_boundary(None).iter().all(|&x| x == 0));
+        let wavy = left_boundary(Some(8.0));
+        assert!(wavy.iter().all(|&x| x <= 8));
+        assert!(wavy.iter().min() != wavy.iter().max());
+
+        // The sides are filled but not stroked: a red line only shows in the rows of the (horizontal) height lines
+        let red_rows = |side_wave: Option<f32>| {
+            let mut output_canvas = SkiaCanvas::new(width, height);
+            render_heightmap_streamlines(&mut output_canvas, &domain_region, 10, 1, 1, 40, 1.0, &[255, 0, 0], &gradient, side_wave, |_, _, _| 0.0);
+            let rgb = output_canvas.to_u32_rgb();
+            let is_red = |pixel_rgb: u32| ((pixel_rgb >> 16) & 0xFF) > ((pixel_rgb >> 8) & 0xFF) + 50;
+            (0..height).filter(|&y| (0..width).any(|x| is_red(rgb[(y * width + x) as usize]))).collect::<Vec<u32>>()
+        };
+        let straight_rows = red_rows(None);
+        assert!(!straight_rows.is_empty() && straight_rows.len() < (height / 2) as usize);
+        assert_eq!(straight_rows, red_rows(Some(8.0)));
+    }
+
+    #[test]
+    fn test_render_hatch_lines_parallel() {
+        // Concentric lightness rings yield several runs per hatch line
+        let width = 60;
+        let height = 50;
+        let mut input_canvas = uniform_canvas(width, height, 0.0, 0.0);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            let (x, y) = ((index as u32 % width) as f32, (index as u32 / width) as f32);
+            pixel.lightness = (0.3 * (x - 30.0).hypot(y - 25.0)).sin().abs();
+            pixel.is_hatched = true;
+        }
+        let endpoints = hatch_line_endpoints(width as f32, height as f32, 0.6, 3.0);
+        let is_active = |pixel: &PixelProperties| pixel.lightness <= 0.5;
+        let serial_runs = hatch_line_runs(&input_canvas, &endpoints, 0.5, &is_active, &|_| 0.0, false);
+        let parallel_runs = hatch_line_runs(&input_canvas, &endpoints, 0.5, &is_active, &|_| 0.0, true);
+        assert_eq!(serial_runs.len(), parallel_runs.len());
+        assert!(serial_runs.iter().map(Vec::len).sum::<usize>() > serial_runs.len());
+        for (serial, parallel) in serial_runs.iter().zip(parallel_runs.iter()) {
+            assert_eq!(serial.len(), parallel.len());
+            for ((s0, s1), (p0, p1)) in serial.iter().zip(parallel.iter()) {
+                assert_eq!((s0.0, s0.1, s1.0, s1.1), (p0.0, p0.1, p1.0, p1.1));
+            }
+        }
+
+        let render = |parallel: bool| {
+            let mut output_canvas = SkiaCanvas::new(width, height);
+            render_hatch_lines(&input_canvas, &mut output_canvas, 0.5, 0.5, &[0, 0, 0], 1.0, 0.6, 3.0, LineCap::Butt, None, Some(0.5), parallel);
+            output_canvas.to_u32_rgb()
+        };
+        assert_eq!(render(false), render(true));
+    }
+
+    #[test]
+    fn test_outline_stroke() {
+        // A straight segment of width w yields a w x length rectangle
+        let segment = [vec2::from_values(1.0, 2.0), vec2::from_values(1.0, 7.0)];
+        let rectangle = outline_stroke(&segment, 3.0, LineJoin::Miter);
+        let expected = [(-0.5, 2.0), (-0.5, 7.0), (2.5, 7.0), (2.5, 2.0)];
+        assert_eq!(expected.len(), rectangle.len());
+        for (p, e) in rectangle.iter().zip(expected.iter()) {
+            assert_approx_eq!(e.0, p.0, 1.0e-5);
+            assert_approx_eq!(e.1, p.1, 1.0e-5);
+        }
+
+        // A right angle: the outer corner is a single miter point or an arc, the inner corner a single point
+        let corner = vec2::from_values(10.0, 0.0);
+        let polyline = [vec2::from_values(0.0, 0.0), corner, vec2::from_values(10.0, -10.0)];
+        let miter = outline_stroke(&polyline, 2.0, LineJoin::Miter);
+        assert_eq!(6, miter.len());
+        assert_approx_eq!(2.0f32.sqrt(), vec2::dist(&corner, &miter[1]), 1.0e-5);
+        assert_approx_eq!(2.0f32.sqrt(), vec2::dist(&corner, &miter[4]), 1.0e-5);
+        let round = outline_stroke(&polyline, 2.0, LineJoin::Round);
+        assert!(round.len() > miter.len());
+        assert!(round[1..round.len() - 4].iter().all(|p| (vec2::dist(&corner, p) - 1.0).abs() < 1.0e-5));
+        let bevel = outline_stroke(&polyline, 2.0, LineJoin::Bevel);
+        assert_eq!(7, bevel.len());
+
+        assert!(outline_stroke(&[corner, corner], 2.0, LineJoin::Round).is_empty());
+    }
+
+    #[test]
+    fn test_render_direction_tiled_hatch_lines() {
+        // A consistent 30 degree direction field (in canvas coordinates, i.e., pointing down and to the right), except for an undefined direction in the last tile
+        let width = 60;
+        let height = 40;
+        let direction = 30.0f32.to_radians();
+        let mut input_canvas = uniform_canvas(width, height, 0.1, direction);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            let (x, y) = (index as u32 % width, index as u32 / width);
+            if x >= 40 && y >= 20 {
+                pixel.direction_vec = vec2::from_values(f32::NAN, f32::NAN);
+            } else if (x + y) % 2 == 1 {
+                // Flipped directions describe the same axis
+                pixel.direction_vec = vec2::scale(&pixel.direction_vec, -1.0);
+            }
+            pixel.is_hatched = true;
+        }
+        let line_angle = |(p0, p1): &(Vec2, Vec2)| vec2::polar_angle(&vec2::sub(p1, p0)).rem_euclid(PI);
+        let is_in_undefined_tile = |(p0, p1): &(Vec2, Vec2)| p0.0.min(p1.0) >= 40.0 && p0.1.min(p1.1) >= 20.0;
+
+        let fallback_angle = 0.25 * PI;
+        let endpoints = direction_tiled_hatch_line_endpoints(&input_canvas, 20, 0.0, fallback_angle, 3.0);
+        let (fallback, oriented): (Vec<_>, Vec<_>) = endpoints
+            .iter()
+            .filter(|(p0, p1)| vec2::dist(p0, p1) > 1.0)
+            .partition(|segment| is_in_undefined_tile(segment));
+        assert!(!oriented.is_empty() && !fallback.is_empty());
+        assert!(oriented.iter().all(|segment| (line_angle(segment) - direction).abs() < 1.0e-3));
+        // The fallback angle is a line angle (cf. render_hatch_lines), measured with the y axis pointing up
+        assert!(fallback.iter().all(|segment| (line_angle(segment) - (PI - fallback_angle)).abs() < 1.0e-3));
+
+        // The second layer of a crosshatch is perpendicular
+        let crossing = direction_tiled_hatch_line_endpoints(&input_canvas, 20, 0.5 * PI, fallback_angle, 3.0);
+        assert!(crossing
+            .iter()
+            .filter(|segment| vec2::dist(&segment.0, &segment.1) > 1.0 && !is_in_undefined_tile(segment))
+            .all(|segment| (line_angle(segment) - (direction + 0.5 * PI)).abs() < 1.0e-3));
+
+        let mut output_canvas = SkiaCanvas::new(width, height);
+        render_direction_tiled_hatch_lines(&input_canvas, &mut output_canvas, 0.5, 0.5, &[0, 0, 0], 1.0, 0.0, fallback_angle, 3.0, 20, LineCap::Butt, None);
+        assert!(output_canvas.to_u32_rgb().iter().any(|rgb| !is_white(*rgb)));
+    }
+
+    #[test]
+    fn test_dry_brush_hatch_line_runs() {
+        // A dark left half and a light right half, separated by an unhatched gap
+        let width = 200;
+        let height = 40;
+        let mut input_canvas = uniform_canvas(width, height, 0.45, 0.0);
+        for (index, pixel) in input_canvas.pixels_mut().iter_mut().enumerate() {
+            let x = index as u32 % width;
+            if x < width / 2 {
+                pixel.lightness = 0.1;
+            }
+            pixel.is_hatched = !(width / 2 - 2..width / 2 + 2).contains(&x);
+        }
+        let endpoints = hatch_line_endpoints(width as f32, height as f32, 0.0, 4.0);
+        let is_active = |pixel: &PixelProperties| pixel.lightness <= 0.5;
+        let runs: Vec<(Vec2, Vec2)> = hatch_line_runs(&input_canvas, &endpoints, 0.5, &is_active, &dry_brush_gap_probability(0.5, Some(0.8)), false)
+            .into_iter()
+            .flatten()
+            .collect();
+        let (dark, light): (Vec<_>, Vec<_>) = runs.iter().partition(|(p0, _)| p0.0 < width as f32 / 2.0);
+        let mean_length = |runs: &[&(Vec2, Vec2)]| runs.iter().map(|(p0, p1)| vec2::dist(p0, p1)).sum::<f32>() / runs.len() as f32;
+        // Both halves are broken up, but the light half more so
+        assert!(dark.len() > endpoints.len());
+        assert!(light.len() > dark.len());
+        assert!(mean_length(&light) < mean_length(&dark));
+
+        // Without dryness, each half is a single run per line
+        let solid_runs = hatch_line_runs(&input_canvas, &endpoints, 0.5, &is_active, &dry_brush_gap_probability(0.5, None), false);
+        assert!(solid_runs.iter().all(|runs| runs.len() == 2));
+    }
+
+    #[test]
+    fn test_render_scene_illustration() {
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let scene = scene_sphere();
+        let edge_color = [255, 0, 0];
+        let mut config = IllustrationConfig {
+            width: 32,
+            height: 32,
+            angle_in_tangent_plane: 0.0,
+            direction_source: DirectionSource::Light,
+            background: BackgroundSpec::default(),
+            is_background_shaded: true,
+            streamlines: None,
+            hatching: vec![],
+            edges: Some(EdgeConfig { color: edge_color, width: 1.0, depth_weight: None, direction_weight: None }),
+        };
+        let is_edge_colored = |rgb: &u32| ((rgb >> 16) & 0xFF) > ((rgb >> 8) & 0xFF) + 32;
+
+        let with_edges = render_scene_illustration(&ray_marcher, &scene, &config).to_u32_rgb();
+        assert!(with_edges.iter().any(|rgb| !is_white(*rgb)));
+        assert!(with_edges.iter().any(is_edge_colored));
+
+        config.edges = None;
+        let without_edges = render_scene_illustration(&ray_marcher, &scene, &config).to_u32_rgb();
+        assert!(without_edges.iter().any(|rgb| !is_white(*rgb)));
+        assert!(!without_edges.iter().any(is_edge_colored));
+    }
+}
@@ -1,6 +1,437 @@
-use crate::vector::Vec3;
-use crate::sdf::SdfOutput;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+use crate::vector::{vec3, vec4, Vec2, Vec3, Vec4, VecFloat};
+use crate::ray_marcher::UpAxis;
+use crate::sdf::{Material, SdfOutput};
 
 pub trait Scene {
     fn eval(&self, p: &Vec3) -> SdfOutput;
+
+    // Upper bound on the rate of change of the distance, i.e., the distance may overestimate the true distance by
+    // up to this factor; see RayMarcher::from_scene
+    fn lipschitz_bound(&self) -> f32 {
+        1.0
+    }
+}
+
+// A scene that changes over time, e.g., for motion-blurred stills
+pub trait AnimatedScene: Scene {
+    fn set_time(&mut self, t: VecFloat);
+}
+
+// Forwards to the inner scene while counting SDF evaluations for profiling
+pub struct CountingScene<'a, S: Scene> {
+    scene: &'a S,
+    count: AtomicUsize,
+}
+
+impl<'a, S: Scene> CountingScene<'a, S> {
+    pub fn new(scene: &'a S) -> CountingScene<'a, S> {
+        CountingScene {
+            scene,
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        self.count.store(0, Ordering::Relaxed);
+    }
+}
+
+impl<'a, S: Scene> Scene for CountingScene<'a, S> {
+    fn eval(&self, p: &Vec3) -> SdfOutput {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.scene.eval(p)
+    }
+
+    fn lipschitz_bound(&self) -> f32 {
+        self.scene.lipschitz_bound()
+    }
+}
+
+// Overlays two scenes: the nearer surface (and its material) wins
+pub struct UnionScene<'a, A: Scene, B: Scene> {
+    a: &'a A,
+    b: &'a B,
+}
+
+impl<'a, A: Scene, B: Scene> UnionScene<'a, A, B> {
+    pub fn new(a: &'a A, b: &'a B) -> UnionScene<'a, A, B> {
+        UnionScene { a, b }
+    }
+}
+
+impl<'a, A: Scene, B: Scene> Scene for UnionScene<'a, A, B> {
+    fn eval(&self, p: &Vec3) -> SdfOutput {
+        self.a.eval(p).min(&self.b.eval(p))
+    }
+
+    fn lipschitz_bound(&self) -> f32 {
+        self.a.lipschitz_bound().max(self.b.lipschitz_bound())
+    }
+}
+
+// Blends two scenes into each other within the smoothing width, mixing their materials
+pub struct SmoothUnionScene<'a, A: Scene, B: Scene> {
+    a: &'a A,
+    b: &'a B,
+    smoothing_width: VecFloat,
+}
+
+impl<'a, A: Scene, B: Scene> SmoothUnionScene<'a, A, B> {
+    pub fn new(a: &'a A, b: &'a B, smoothing_width: VecFloat) -> SmoothUnionScene<'a, A, B> {
+        SmoothUnionScene { a, b, smoothing_width }
+    }
+}
+
+impl<'a, A: Scene, B: Scene> Scene for SmoothUnionScene<'a, A, B> {
+    fn eval(&self, p: &Vec3) -> SdfOutput {
+        self.a.eval(p).smooth_union(&self.b.eval(p), self.smoothing_width)
+    }
+
+    fn lipschitz_bound(&self) -> f32 {
+        self.a.lipschitz_bound().max(self.b.lipschitz_bound())
+    }
+}
+
+// Places the inner scene rotated by the unit quaternion rotation and then shifted by translation;
+// queries are mapped back into the inner scene's frame by the inverse transform
+pub struct TransformScene<'a, S: Scene> {
+    scene: &'a S,
+    translation: Vec3,
+    inverse_rotation: Vec4,
+}
+
+impl<'a, S: Scene> TransformScene<'a, S> {
+    pub fn new(scene: &'a S, translation: &Vec3, rotation: &Vec4) -> TransformScene<'a, S> {
+        TransformScene {
+            scene,
+            translation: *translation,
+            inverse_rotation: vec4::from_values(rotation.0, -rotation.1, -rotation.2, -rotation.3), // = conjugate
+        }
+    }
+}
+
+impl<'a, S: Scene> Scene for TransformScene<'a, S> {
+    fn eval(&self, p: &Vec3) -> SdfOutput {
+        let p_local = vec4::apply_quaternion_rotation(&self.inverse_rotation, &vec3::sub(p, &self.translation));
+        self.scene.eval(&p_local)
+    }
+
+    fn lipschitz_bound(&self) -> f32 {
+        self.scene.lipschitz_bound()
+    }
+}
+
+// Surface y = height_map(x, z) (or z = height_map(x, y) for UpAxis::Z) of a single material. The vertical gap
+// p.y - h overestimates the distance to a sloped surface; scaling it by the cosine of the steepest slope,
+// 1 / sqrt(max_slope^2 + 1), makes it a lower bound, so the scene can be ray marched without reducing the step size
+// factor of the RayMarcher. max_slope bounds |grad h|.
+pub struct HeightmapScene<F: Fn(&Vec2) -> VecFloat> {
+    height_map: F,
+    material: Material,
+    gap_scale: VecFloat,
+    up_axis: UpAxis,
+}
+
+impl<F: Fn(&Vec2) -> VecFloat> HeightmapScene<F> {
+    pub fn new(height_map: F, max_slope: VecFloat, material: Material) -> HeightmapScene<F> {
+        Self::new_with_up_axis(height_map, max_slope, material, UpAxis::Y)
+    }
+
+    pub fn new_with_up_axis(height_map: F, max_slope: VecFloat, material: Material, up_axis: UpAxis) -> HeightmapScene<F> {
+        HeightmapScene {
+            height_map,
+            material,
+            gap_scale: 1.0 / (max_slope * max_slope + 1.0).sqrt(),
+            up_axis,
+        }
+    }
+}
+
+impl<F: Fn(&Vec2) -> VecFloat> Scene for HeightmapScene<F> {
+    fn eval(&self, p: &Vec3) -> SdfOutput {
+        let h = (self.height_map)(&self.up_axis.ground(p));
+        SdfOutput::new(self.gap_scale * (self.up_axis.height(p) - h), self.material)
+    }
+}
+
+// Memoizes the inner scene on a regular grid of nodes spanning [bounds_min, bounds_max] and trilinearly interpolates
+// the distance between nodes; nodes are evaluated lazily on first use. Queries outside of the bounds are forwarded.
+// Accuracy tradeoff: the interpolated distance is exact only at the nodes. Between nodes, the error grows with the
+// curvature of the SDF and the square of the cell size; features smaller than a cell are blurred or lost entirely,
+// and the interpolated distance is not guaranteed to be a lower bound, so ray marching may slightly overshoot.
+// Materials are taken from the nearest node. Choose a cell size well below the smallest feature of interest.
+pub struct CachedScene<'a, S: Scene> {
+    scene: &'a S,
+    bounds_min: Vec3,
+    cell_size: Vec3,
+    node_count: u32, // per axis
+    nodes: Vec<OnceLock<SdfOutput>>,
+}
+
+impl<'a, S: Scene> CachedScene<'a, S> {
+    pub fn new(scene: &'a S, bounds_min: &Vec3, bounds_max: &Vec3, node_count: u32) -> CachedScene<'a, S> {
+        let node_count = node_count.max(2);
+        let cell_size = vec3::scale(&vec3::sub(bounds_max, bounds_min), 1.0 / (node_count - 1) as VecFloat);
+        let total_node_count = (node_count as usize).pow(3);
+        CachedScene {
+            scene,
+            bounds_min: *bounds_min,
+            cell_size,
+            node_count,
+            nodes: (0..total_node_count).map(|_| OnceLock::new()).collect(),
+        }
+    }
+
+    fn node(&self, ix: u32, iy: u32, iz: u32) -> &SdfOutput {
+        let n = self.node_count as usize;
+        let index = (iz as usize * n + iy as usize) * n + ix as usize;
+        self.nodes[index].get_or_init(|| {
+            let p = vec3::add(
+                &self.bounds_min,
+                &vec3::mul(&self.cell_size, &vec3::from_values(ix as VecFloat, iy as VecFloat, iz as VecFloat)),
+            );
+            self.scene.eval(&p)
+        })
+    }
+}
+
+impl<'a, S: Scene> Scene for CachedScene<'a, S> {
+    fn eval(&self, p: &Vec3) -> SdfOutput {
+        // Continuous grid coordinates of p
+        let g = vec3::div(&vec3::sub(p, &self.bounds_min), &self.cell_size);
+        let max_g = (self.node_count - 1) as VecFloat;
+        if !(g.0 >= 0.0 && g.1 >= 0.0 && g.2 >= 0.0 && g.0 <= max_g && g.1 <= max_g && g.2 <= max_g) {
+            return self.scene.eval(p);
+        }
+        // Index of the cell containing p; points on the upper bounds belong to the last cell
+        let ix = (g.0 as u32).min(self.node_count - 2);
+        let iy = (g.1 as u32).min(self.node_count - 2);
+        let iz = (g.2 as u32).min(self.node_count - 2);
+        let tx = g.0 - ix as VecFloat;
+        let ty = g.1 - iy as VecFloat;
+        let tz = g.2 - iz as VecFloat;
+
+        let lerp = |a: VecFloat, b: VecFloat, t: VecFloat| a + t * (b - a);
+        let d = |dx: u32, dy: u32, dz: u32| self.node(ix + dx, iy + dy, iz + dz).distance;
+        let distance = lerp(
+            lerp(lerp(d(0, 0, 0), d(1, 0, 0), tx), lerp(d(0, 1, 0), d(1, 1, 0), tx), ty),
+            lerp(lerp(d(0, 0, 1), d(1, 0, 1), tx), lerp(d(0, 1, 1), d(1, 1, 1), tx), ty),
+            tz,
+        );
+        let material = self.node(
+            ix + tx.round() as u32,
+            iy + ty.round() as u32,
+            iz + tz.round() as u32,
+        ).material;
+        SdfOutput::new(distance, material)
+    }
+
+    // Trilinear interpolation keeps each partial derivative within the bound of the wrapped scene, but together
+    // they may make the interpolated distance up to sqrt(3) times as steep
+    fn lipschitz_bound(&self) -> f32 {
+        3.0f32.sqrt() * self.scene.lipschitz_bound()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sdf::sdf_op;
+    use crate::test_fixtures::{scene_sphere, sphere_ray_marcher};
+    use crate::vector::vec2;
+    use assert_approx_eq::assert_approx_eq;
+    use std::f32::consts::PI;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    struct SceneOffsetSphere {
+        center: Vec3,
+        material: Material,
+    }
+
+    impl Scene for SceneOffsetSphere {
+        fn eval(&self, p: &Vec3) -> SdfOutput {
+            SdfOutput::new(sdf_op::sd_sphere(&vec3::sub(p, &self.center), 1.0), self.material)
+        }
+    }
+
+    #[test]
+    fn test_union_scene() {
+        let light_source = vec3::from_values(5.0, 5.0, 5.0);
+        let left = SceneOffsetSphere {
+            center: vec3::from_values(-2.0, 0.0, 0.0),
+            material: Material::new(&light_source, None, Some(&vec3::from_values(0.0, 1.0, 0.5)), true, true),
+        };
+        let right = SceneOffsetSphere {
+            center: vec3::from_values(3.0, 0.0, 0.0),
+            material: Material::new(&light_source, None, Some(&vec3::from_values(2.0, 1.0, 0.5)), true, true),
+        };
+
+        let union = UnionScene::new(&left, &right);
+        let between = union.eval(&vec3::from_values(0.2, 0.0, 0.0));
+        assert_approx_eq!(1.2, between.distance);
+        assert_eq!(0.0, between.material.bg_hsl.0);
+        let near_right = union.eval(&vec3::from_values(1.0, 0.0, 0.0));
+        assert_approx_eq!(1.0, near_right.distance);
+        assert_eq!(2.0, near_right.material.bg_hsl.0);
+
+        // The smooth union lies below the plain union in the blend region and mixes the materials
+        let smooth_union = SmoothUnionScene::new(&left, &right, 1.0);
+        let midpoint = smooth_union.eval(&vec3::from_values(0.5, 0.0, 0.0));
+        assert!(midpoint.distance < union.eval(&vec3::from_values(0.5, 0.0, 0.0)).distance);
+        assert!(midpoint.material.bg_hsl.0 > 0.0 && midpoint.material.bg_hsl.0 < 2.0);
+        let far_left = vec3::from_values(-4.0, 0.0, 0.0);
+        assert_eq!(union.eval(&far_left).distance, smooth_union.eval(&far_left).distance);
+    }
+
+    #[test]
+    fn test_heightmap_scene() {
+        // Steep ripples with |grad h| <= 3
+        let height_map = |q: &Vec2| 0.5 * (6.0 * q.0).sin() * (2.0 * q.1).cos();
+        let material = Material::new(&vec3::from_values(5.0, 5.0, 5.0), None, None, true, true);
+        let scene = HeightmapScene::new(height_map, 3.0, material);
+
+        // Marching straight down never ends up below the surface
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..100 {
+            let (x, z) = (rng.gen_range(-2.0..2.0), rng.gen_range(-2.0..2.0));
+            let h = height_map(&vec2::from_values(x, z));
+            let mut p = vec3::from_values(x, 2.0, z);
+            for _ in 0..200 {
+                p.1 -= scene.eval(&p).distance;
+                assert!(p.1 >= h - 1.0e-5);
+            }
+            assert_approx_eq!(h, p.1, 1.0e-3);
+        }
+
+        // The distance is a lower bound: no surface point lies within it
+        for _ in 0..20 {
+            let p = vec3::from_values(rng.gen_range(-1.0..1.0), rng.gen_range(-0.2..1.0), rng.gen_range(-1.0..1.0));
+            let d = scene.eval(&p).distance;
+            for ix in -50..=50 {
+                for iz in -50..=50 {
+                    let q = vec2::from_values(p.0 + 0.02 * ix as f32, p.2 + 0.02 * iz as f32);
+                    let surface_point = vec3::from_values(q.0, height_map(&q), q.1);
+                    assert!(vec3::len(&vec3::sub(&p, &surface_point)) >= d.abs() - 1.0e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_transform_scene() {
+        let scene = SceneOffsetSphere {
+            center: vec3::from_values(0.0, 0.0, 0.0),
+            material: Material::new(&vec3::from_values(5.0, 5.0, 5.0), None, None, true, true),
+        };
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let screen_center = vec2::from_values(0.0, 0.0);
+        let translation = vec3::from_values(0.0, 0.0, -1.5);
+        let no_rotation = vec4::from_values(1.0, 0.0, 0.0, 0.0);
+        let translated = TransformScene::new(&scene, &translation, &no_rotation);
+
+        let (hit, _, _) = ray_marcher.intersection_with_scene(&scene, &screen_center).unwrap();
+        let (translated_hit, _, _) = ray_marcher.intersection_with_scene(&translated, &screen_center).unwrap();
+        let shift = vec3::sub(&translated_hit, &hit);
+        assert!(vec3::len(&vec3::sub(&shift, &translation)) < 0.01);
+
+        // A point of the inner scene maps to its rotated and translated position
+        let off_center = SceneOffsetSphere { center: vec3::from_values(1.0, 0.0, 0.0), material: scene.material };
+        let rotation = vec4::quaternion_rotation(&vec3::from_values(0.0, 0.0, 1.0), 0.5 * PI);
+        let transformed = TransformScene::new(&off_center, &vec3::from_values(2.0, 0.0, 0.0), &rotation);
+        let q_local = vec3::from_values(1.5, 0.2, -0.3);
+        let q_world = vec3::add(&vec4::apply_quaternion_rotation(&rotation, &q_local), &vec3::from_values(2.0, 0.0, 0.0));
+        assert_approx_eq!(off_center.eval(&q_local).distance, transformed.eval(&q_world).distance);
+        assert_approx_eq!(-1.0, transformed.eval(&vec3::from_values(2.0, 1.0, 0.0)).distance);
+    }
+
+    #[test]
+    fn test_counting_scene() {
+        let scene = scene_sphere();
+        let ray_marcher = sphere_ray_marcher(1.0);
+        let counting_scene = CountingScene::new(&scene);
+        let center = vec2::from_values(0.0, 0.0);
+
+        let (p, depth, _) = ray_marcher.intersection_with_scene(&scene, &center).unwrap();
+        let (p_counted, depth_counted, _) = ray_marcher.intersection_with_scene(&counting_scene, &center).unwrap();
+        assert_eq!(p, p_counted);
+        assert_eq!(depth, depth_counted);
+        assert!(counting_scene.count() >= 1);
+        assert!(counting_scene.count() <= 10);
+
+        counting_scene.reset();
+        assert_eq!(0, counting_scene.count());
+    }
+
+    #[test]
+    fn test_cached_scene() {
+        let scene = scene_sphere();
+        let counting_scene = CountingScene::new(&scene);
+        let cached_scene = CachedScene::new(
+            &counting_scene,
+            &vec3::from_values(-2.0, -2.0, -2.0),
+            &vec3::from_values(2.0, 2.0, 2.0),
+            41,
+        );
+
+        let mut rng = StdRng::seed_from_u64(42);
+        for _ in 0..1000 {
+            let p = vec3::from_values(rng.gen_range(-2.0..2.0), rng.gen_range(-2.0..2.0), rng.gen_range(-2.0..2.0));
+            if vec3::len(&p) < 0.5 {
+                continue; // The interpolation error is largest at the kink of the sphere SDF at its center
+            }
+            assert!((cached_scene.eval(&p).distance - scene.eval(&p).distance).abs() < 0.01);
+        }
+
+        // Nodes are evaluated only once; evaluations outside of the bounds are forwarded
+        let p = vec3::from_values(0.55, 0.05, 0.05);
+        cached_scene.eval(&p);
+        counting_scene.reset();
+        assert_eq!(cached_scene.eval(&p).distance, cached_scene.eval(&p).distance);
+        assert_eq!(0, counting_scene.count());
+        let p_outside = vec3::from_values(3.0, 0.0, 0.0);
+        assert_eq!(scene.eval(&p_outside).distance, cached_scene.eval(&p_outside).distance);
+        assert_eq!(1, counting_scene.count());
+    }
+
+    #[test]
+    fn test_cached_scene_lipschitz_bound() {
+        struct SceneSteepSphere {
+            material: Material,
+        }
+
+        impl Scene for SceneSteepSphere {
+            fn eval(&self, p: &Vec3) -> SdfOutput {
+                SdfOutput::new(2.0 * sdf_op::sd_sphere(p, 1.0), self.material)
+            }
+
+            fn lipschitz_bound(&self) -> f32 {
+                2.0
+            }
+        }
+
+        let bounds_min = vec3::from_values(-2.0, -2.0, -2.0);
+        let bounds_max = vec3::from_values(2.0, 2.0, 2.0);
+        let scene = scene_sphere();
+        let steep_scene = SceneSteepSphere { material: scene.material };
+        assert_approx_eq!(3.0f32.sqrt(), CachedScene::new(&scene, &bounds_min, &bounds_max, 5).lipschitz_bound());
+        let cached_scene = CachedScene::new(&steep_scene, &bounds_min, &bounds_max, 5);
+        assert_approx_eq!(2.0 * 3.0f32.sqrt(), cached_scene.lipschitz_bound());
+
+        // Differences of the interpolated distance stay within the bound
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..1000 {
+            let p = vec3::from_values(rng.gen_range(-1.9..1.9), rng.gen_range(-1.9..1.9), rng.gen_range(-1.9..1.9));
+            let q = vec3::add(&p, &vec3::from_values(rng.gen_range(-0.1..0.1), rng.gen_range(-0.1..0.1), rng.gen_range(-0.1..0.1)));
+            let change = (cached_scene.eval(&p).distance - cached_scene.eval(&q).distance).abs();
+            assert!(change <= cached_scene.lipschitz_bound() * vec3::len(&vec3::sub(&p, &q)) + 1.0e-4);
+        }
+    }
 }
@@ -1,4 +1,13 @@
-use crate::vector::{vec2, vec3, vec4, Vec2, Vec3, Vec4, VecFloat};
+use crate::remapping::smooth_abs;
+use crate::vector::{vec2, vec3, vec4, Vec2, Vec3, Vec4, VecFloat, EPSILON};
+#[cfg(feature = "sdf-text")]
+use crate::font;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShadowMode {
+    Soft, // penumbra-based, fractional visibility near shadow boundaries
+    Hard, // binary visibility
+}
 
 #[derive(Clone, Copy)]
 pub struct ReflectiveProperties {
@@ -6,11 +15,14 @@ pub struct ReflectiveProperties {
     pub ao_weight: VecFloat,
     pub visibility_weight: VecFloat,
     pub diffuse_weight: VecFloat,
+    pub diffuse_wrap: VecFloat, // 0 for Lambert, 1 for half-Lambert
     pub specular_weight: VecFloat,
     pub specular_exponent: VecFloat,
     pub ao_steps: u32,
     pub ao_step_size: VecFloat,
+    pub ao_falloff_exponent: VecFloat,
     pub penumbra: VecFloat,
+    pub shadow_mode: ShadowMode,
 }
 
 impl ReflectiveProperties {
@@ -30,16 +42,23 @@ impl ReflectiveProperties {
             ao_weight,
             visibility_weight,
             diffuse_weight,
+            diffuse_wrap: 0.0,
             specular_weight,
             specular_exponent: specular_exponent.unwrap_or(32.0),
             ao_steps: ao_steps.unwrap_or(5),
             ao_step_size: ao_step_size.unwrap_or(0.01),
+            ao_falloff_exponent: 1.0,
             penumbra: penumbra.unwrap_or(48.0),
+            shadow_mode: ShadowMode::Soft,
         }
     }
 
-    pub fn default() -> ReflectiveProperties {
-        Self::new(0.1, 0.1, 0.0, 0.8, 1.0, None, None, None, None)
+    // Spreads the AO samples evenly up to an outer radius, i.e., sets the step size from the current ao_steps
+    pub fn with_ao_radius(self, ao_radius: VecFloat) -> ReflectiveProperties {
+        ReflectiveProperties {
+            ao_step_size: ao_radius / self.ao_steps.max(1) as VecFloat,
+            ..self
+        }
     }
 
     pub fn lerp(&self, other: &ReflectiveProperties, t: VecFloat) -> ReflectiveProperties {
@@ -51,12 +70,42 @@ impl ReflectiveProperties {
             ao_weight: float_lerp(self.ao_weight, other.ao_weight, t),
             visibility_weight: float_lerp(self.visibility_weight, other.visibility_weight, t),
             diffuse_weight: float_lerp(self.diffuse_weight, other.diffuse_weight, t),
+            diffuse_wrap: float_lerp(self.diffuse_wrap, other.diffuse_wrap, t),
             specular_weight: float_lerp(self.specular_weight, other.specular_weight, t),
             specular_exponent: float_lerp(self.specular_exponent, other.specular_exponent, t),
             ao_steps: float_lerp(self.ao_steps as VecFloat, other.ao_steps as VecFloat, t).round()
                 as u32,
             ao_step_size: float_lerp(self.ao_step_size, other.ao_step_size, t),
+            ao_falloff_exponent: float_lerp(self.ao_falloff_exponent, other.ao_falloff_exponent, t),
             penumbra: float_lerp(self.penumbra, other.penumbra, t),
+            shadow_mode: if t < 0.5 { self.shadow_mode } else { other.shadow_mode },
+        }
+    }
+}
+
+// The remaining options (diffuse_wrap, ao_falloff_exponent, shadow_mode) are set via struct update syntax, e.g.,
+// ReflectiveProperties { shadow_mode: ShadowMode::Hard, ..ReflectiveProperties::default() }
+impl Default for ReflectiveProperties {
+    fn default() -> ReflectiveProperties {
+        Self::new(0.1, 0.1, 0.0, 0.8, 1.0, None, None, None, None)
+    }
+}
+
+// Stylized water: the surface color mixes the reflected environment with a tinted view of the environment through
+// the surface, weighted by the Fresnel term
+#[derive(Clone, Copy)]
+pub struct WaterProperties {
+    pub tint_hsl: Vec3,
+    pub tint_strength: VecFloat, // in [0, 1]
+    pub index_of_refraction: VecFloat,
+}
+
+impl WaterProperties {
+    pub fn new(tint_hsl: &Vec3, tint_strength: Option<VecFloat>, index_of_refraction: Option<VecFloat>) -> WaterProperties {
+        WaterProperties {
+            tint_hsl: *tint_hsl,
+            tint_strength: tint_strength.unwrap_or(0.6),
+            index_of_refraction: index_of_refraction.unwrap_or(1.33),
         }
     }
 }
@@ -68,9 +117,15 @@ pub struct Material {
     pub bg_hsl: Vec3,
     pub is_shaded: bool,
     pub is_hatched: bool,
+    pub is_streamlined: bool,
+    pub specular_hsl: Option<Vec3>, // tint of specular highlights; if None, highlights brighten bg_hsl
+    pub water: Option<WaterProperties>, // if set, replaces bg_hsl by the color of the environment seen via the water surface
+    pub id: u32, // identifies the material in per-material statistics, cf. PixelPropertyCanvas::material_histogram; 0 by default
 }
 
 impl Material {
+    // Streamlined, without specular tint or water, and with id 0; set other values via struct update syntax, e.g.,
+    // Material { water: Some(water), ..Material::new(&light_source, None, None, true, true) }
     pub fn new(
         light_source: &Vec3,
         reflective_properties: Option<&ReflectiveProperties>,
@@ -85,6 +140,10 @@ impl Material {
             bg_hsl: *bg_hsl.unwrap_or(&vec3::from_values(0.0, 0.0, 1.0)),
             is_shaded,
             is_hatched,
+            is_streamlined: true,
+            specular_hsl: None,
+            water: None,
+            id: 0,
         }
     }
 
@@ -105,6 +164,25 @@ impl Material {
             } else {
                 other.is_hatched
             },
+            is_streamlined: if t < 0.5 {
+                self.is_streamlined
+            } else {
+                other.is_streamlined
+            },
+            specular_hsl: match (self.specular_hsl, other.specular_hsl) {
+                (Some(a), Some(b)) => Some(vec3::lerp_hsl(&a, &b, t)),
+                (a, b) => if t < 0.5 { a } else { b },
+            },
+            water: if t < 0.5 {
+                self.water
+            } else {
+                other.water
+            },
+            id: if t < 0.5 {
+                self.id
+            } else {
+                other.id
+            },
         }
     }
 }
@@ -130,6 +208,22 @@ impl SdfOutput {
             *other
         }
     }
+
+    // Smooth boolean operations on the distances; the materials are blended by the mixing factor of the operation
+    pub fn smooth_union(&self, other: &SdfOutput, smoothing_width: VecFloat) -> SdfOutput {
+        let (distance, t) = sdf_op::op_smooth_union(self.distance, other.distance, smoothing_width);
+        SdfOutput::new(distance, self.material.lerp(&other.material, t))
+    }
+
+    pub fn smooth_difference(&self, other: &SdfOutput, smoothing_width: VecFloat) -> SdfOutput {
+        let (distance, t) = sdf_op::op_smooth_difference(self.distance, other.distance, smoothing_width);
+        SdfOutput::new(distance, self.material.lerp(&other.material, t))
+    }
+
+    pub fn smooth_intersection(&self, other: &SdfOutput, smoothing_width: VecFloat) -> SdfOutput {
+        let (distance, t) = sdf_op::op_smooth_intersection(self.distance, other.distance, smoothing_width);
+        SdfOutput::new(distance, self.material.lerp(&other.material, t))
+    }
 }
 
 pub mod sdf_op {
@@ -139,6 +233,11 @@ pub mod sdf_op {
         d.abs() - thickness
     }
 
+    // Like op_onion, but the shell does not have a crease in its distance field at d = 0 (cf. smooth_abs)
+    pub fn op_onion_smooth(d: VecFloat, thickness: VecFloat, smoothing_width: VecFloat) -> VecFloat {
+        smooth_abs(d, smoothing_width) - thickness
+    }
+
     // See https://iquilezles.org/articles/smin/
     pub fn op_smooth_union(
         dist1: VecFloat,
@@ -170,6 +269,21 @@ pub mod sdf_op {
         }
     }
 
+    pub fn op_smooth_intersection(
+        dist1: VecFloat,
+        dist2: VecFloat,
+        smoothing_width: VecFloat,
+    ) -> (VecFloat, VecFloat) {
+        let h = (smoothing_width - (dist1 - dist2).abs()).max(0.0) / smoothing_width;
+        let mixing = 0.5 * h * h * h;
+        let smoothing = (1.0 / 3.0) * mixing * smoothing_width;
+        if dist1 > dist2 {
+            (dist1 + smoothing, mixing)
+        } else {
+            (dist2 + smoothing, 1.0 - mixing)
+        }
+    }
+
     pub fn op_shift(p: &Vec3, offset: &Vec3) -> Vec3 {
         vec3::sub(p, offset)
     }
@@ -280,6 +394,109 @@ pub mod sdf_op {
         ) // = p - s * clamp(round(p/s), lim_a, lim_b)
     }
 
+    pub fn op_extrude(p: &Vec3, sd_xy: VecFloat, half_height: VecFloat) -> VecFloat {
+        // Extrude the 2D distance sd_xy (evaluated at (p.x, p.y)) along the z-axis
+        let w = vec2::from_values(sd_xy, p.2.abs() - half_height);
+        w.0.max(w.1).min(0.0) + vec2::len(&vec2::from_values(w.0.max(0.0), w.1.max(0.0)))
+    }
+
+    #[cfg(feature = "sdf-text")]
+    fn sd_glyph_2d(p: &Vec2, glyph: char, cell_size: VecFloat) -> VecFloat {
+        // Union of the filled cells of the bitmap glyph, which spans [0, 5] x [0, 7] cells with its origin at the
+        // bottom left; exact outside of the glyph and a bound inside of it
+        let sd_cell = |column: u32, row: u32| {
+            let center = vec2::from_values(column as VecFloat + 0.5, (font::GLYPH_ROWS - 1 - row) as VecFloat + 0.5);
+            let q = vec2::from_values((p.0 / cell_size - center.0).abs() - 0.5, (p.1 / cell_size - center.1).abs() - 0.5);
+            cell_size * (vec2::len(&vec2::from_values(q.0.max(0.0), q.1.max(0.0))) + q.0.max(q.1).min(0.0))
+        };
+        let rows = font::glyph_rows(glyph);
+        (0..font::GLYPH_ROWS)
+            .flat_map(|row| (0..font::GLYPH_COLUMNS).map(move |column| (column, row)))
+            .filter(|(column, row)| (rows[*row as usize] >> (font::GLYPH_COLUMNS - 1 - column)) & 1 == 1)
+            .map(|(column, row)| sd_cell(column, row))
+            .fold(VecFloat::INFINITY, VecFloat::min)
+    }
+
+    // Glyph of the given height in the xy-plane (bottom left at the origin), extruded to the given depth centered on z = 0;
+    // blank glyphs yield infinity
+    #[cfg(feature = "sdf-text")]
+    pub fn sd_glyph(p: &Vec3, glyph: char, size: VecFloat, depth: VecFloat) -> VecFloat {
+        let cell_size = size / font::GLYPH_ROWS as VecFloat;
+        op_extrude(p, sd_glyph_2d(&vec2::from_values(p.0, p.1), glyph, cell_size), 0.5 * depth)
+    }
+
+    // Line of glyphs as in sd_glyph with one blank column between neighboring glyphs
+    #[cfg(feature = "sdf-text")]
+    pub fn sd_text(p: &Vec3, text: &str, size: VecFloat, depth: VecFloat) -> VecFloat {
+        let cell_size = size / font::GLYPH_ROWS as VecFloat;
+        let advance = (font::GLYPH_COLUMNS + 1) as VecFloat * cell_size;
+        let half_glyph = vec3::from_values(0.5 * font::GLYPH_COLUMNS as VecFloat * cell_size, 0.5 * size, 0.5 * depth);
+        text.chars().enumerate().fold(VecFloat::INFINITY, |d, (index, glyph)| {
+            let glyph_p = vec3::from_values(p.0 - index as VecFloat * advance, p.1, p.2);
+            // The bounding box of a glyph is closer than the glyph itself, which lets us skip the distant glyphs
+            let box_p = vec3::from_values(glyph_p.0 - half_glyph.0, glyph_p.1 - half_glyph.1, glyph_p.2);
+            if sd_box(&box_p, &half_glyph) >= d {
+                d
+            } else {
+                d.min(sd_glyph(&glyph_p, glyph, size, depth))
+            }
+        })
+    }
+
+    pub fn sd_bezier_2d(p: &Vec2, a: &Vec2, b: &Vec2, c: &Vec2) -> VecFloat {
+        // Unsigned distance to the quadratic Bezier curve with control points a, b, c.
+        // See https://iquilezles.org/articles/distfunctions2d/
+        // The closest point on the curve is found by solving the cubic equation
+        // dot(B(t) - p, B'(t)) = 0 analytically (Cardano for one real root, trigonometric solution for three).
+        let ab = vec2::sub(b, a);
+        let curvature = vec2::add(&vec2::sub(a, &vec2::scale(b, 2.0)), c); // = a - 2b + c
+        let ab2 = vec2::scale(&ab, 2.0);
+        let pa = vec2::sub(a, p);
+        let curvature_len_squared = vec2::len_squared(&curvature);
+
+        // If b is (close to) the midpoint of a and c, the curve degenerates to the line segment ac and the
+        // coefficients of the normalized cubic blow up. Fall back to the segment distance in this case.
+        if curvature_len_squared < 1.0e-6 * vec2::len_squared(&ab).max(EPSILON) {
+            return vec2::dist_to_segment(p, a, c);
+        }
+
+        let kk = 1.0 / curvature_len_squared;
+        let kx = kk * vec2::dot(&ab, &curvature);
+        let ky = kk * (2.0 * vec2::dot(&ab, &ab) + vec2::dot(&pa, &curvature)) / 3.0;
+        let kz = kk * vec2::dot(&pa, &ab);
+        let pp = ky - kx * kx;
+        let pp3 = pp * pp * pp;
+        let q = kx * (2.0 * kx * kx - 3.0 * ky) + kz;
+        let h = q * q + 4.0 * pp3;
+        let dist_squared_at = |t: VecFloat| {
+            // = |pa + (ab2 + curvature * t) * t|^2
+            vec2::len_squared(&vec2::scale_and_add(
+                &pa,
+                &vec2::scale_and_add(&ab2, &curvature, t),
+                t,
+            ))
+        };
+
+        let dist_squared = if h >= 0.0 {
+            // One real root
+            let h = h.sqrt();
+            let x = vec2::from_values(0.5 * (h - q), 0.5 * (-h - q));
+            let uv = vec2::from_values(x.0.signum() * x.0.abs().cbrt(), x.1.signum() * x.1.abs().cbrt());
+            let t = (uv.0 + uv.1 - kx).clamp(0.0, 1.0);
+            dist_squared_at(t)
+        } else {
+            // Three real roots; the third one can never be the closest
+            let z = (-pp).sqrt();
+            let v = (q / (2.0 * pp * z)).clamp(-1.0, 1.0).acos() / 3.0;
+            let m = v.cos();
+            let n = v.sin() * 3.0f32.sqrt();
+            let t0 = ((m + m) * z - kx).clamp(0.0, 1.0);
+            let t1 = ((-n - m) * z - kx).clamp(0.0, 1.0);
+            dist_squared_at(t0).min(dist_squared_at(t1))
+        };
+        dist_squared.sqrt()
+    }
+
     pub fn sd_plane(p: &Vec3, normal: &Vec3, offset: VecFloat) -> VecFloat {
         vec3::dot(p, normal) - offset
     }
@@ -289,11 +506,7 @@ pub mod sdf_op {
     }
 
     pub fn sd_box(p: &Vec3, sides: &Vec3) -> VecFloat {
-        let q = vec3::from_values(
-            p.0.abs() - sides.0,
-            p.1.abs() - sides.1,
-            p.2.abs() - sides.2,
-        ); // q = abs(p) - s
+        let q = vec3::sub(&vec3::abs(p), sides); // q = abs(p) - s
         vec3::len(&vec3::max_float(&q, 0.0)) + q.0.max(q.1).max(q.2).min(0.0) // = length(max(q, 0)) + min(max(q.x, q.y, q.z), 0);
     }
 
@@ -453,5 +666,96 @@ pub mod sdf_op {
                 sd_triangle(&vec3::from_values(-1.25, 0.0, -1.0), &a, &b, &c)
             );
         }
+
+        #[test]
+        fn test_sd_bezier_2d_straight() {
+            let a = vec2::from_values(0.0, 0.0);
+            let b = vec2::from_values(1.0, 0.0);
+            let c = vec2::from_values(2.0, 0.0);
+            assert_approx_eq!(1.0, sd_bezier_2d(&vec2::from_values(1.0, 1.0), &a, &b, &c));
+            assert_approx_eq!(1.0, sd_bezier_2d(&vec2::from_values(3.0, 0.0), &a, &b, &c));
+            assert_approx_eq!(2.0f32.sqrt(), sd_bezier_2d(&vec2::from_values(-1.0, -1.0), &a, &b, &c));
+
+            let b_off_center = vec2::from_values(0.5, 0.0);
+            assert_approx_eq!(1.0, sd_bezier_2d(&vec2::from_values(1.0, 1.0), &a, &b_off_center, &c));
+        }
+
+        #[test]
+        fn test_sd_bezier_2d_curved() {
+            // The curve traces the parabola y = 1 - x^2 for x in [-1, 1]
+            let a = vec2::from_values(-1.0, 0.0);
+            let b = vec2::from_values(0.0, 2.0);
+            let c = vec2::from_values(1.0, 0.0);
+            assert_approx_eq!(1.0, sd_bezier_2d(&vec2::from_values(0.0, 2.0), &a, &b, &c));
+            assert_approx_eq!(0.5, sd_bezier_2d(&vec2::from_values(0.0, 0.5), &a, &b, &c));
+            assert_approx_eq!(1.0, sd_bezier_2d(&vec2::from_values(2.0, 0.0), &a, &b, &c));
+            assert_approx_eq!(0.0, sd_bezier_2d(&vec2::from_values(0.5, 0.75), &a, &b, &c), 1.0e-3);
+        }
+
+        #[test]
+        fn test_sdf_output_smooth_union() {
+            let light_source = vec3::from_values(0.0, 0.0, 0.0);
+            let red = Material { is_streamlined: false, ..Material::new(&light_source, None, Some(&vec3::from_values(0.0, 1.0, 0.5)), true, false) };
+            let blue = Material::new(&light_source, None, Some(&vec3::from_values(0.0, 1.0, 0.1)), true, true);
+            let k = 1.0;
+
+            // In the transition band, the distance is pulled below both inputs and the material is blended
+            let a = SdfOutput::new(0.2, red);
+            let b = SdfOutput::new(0.3, blue);
+            let blended = a.smooth_union(&b, k);
+            assert!(blended.distance < 0.2);
+            assert!(blended.material.bg_hsl.2 < 0.5 && blended.material.bg_hsl.2 > 0.1);
+            assert!(!blended.material.is_hatched);
+
+            // Outside of the band, the result equals the plain union
+            let far = SdfOutput::new(2.0, blue);
+            let unblended = a.smooth_union(&far, k);
+            assert_approx_eq!(0.2, unblended.distance);
+            assert_approx_eq!(0.5, unblended.material.bg_hsl.2);
+
+            let intersected = a.smooth_intersection(&b, k);
+            assert!(intersected.distance > 0.3);
+            assert!(intersected.material.bg_hsl.2 < 0.5 && intersected.material.bg_hsl.2 > 0.1);
+            let intersected_far = a.smooth_intersection(&far, k);
+            assert_approx_eq!(2.0, intersected_far.distance);
+            assert_approx_eq!(0.1, intersected_far.material.bg_hsl.2);
+
+            let subtracted = a.smooth_difference(&SdfOutput::new(-0.1, blue), k);
+            assert!(subtracted.distance > 0.2);
+        }
+
+        #[cfg(feature = "sdf-text")]
+        #[test]
+        fn test_sd_glyph() {
+            // 'O' with a cell size of 1: the ring of cells around the hole spanning [1, 4] x [1, 6]
+            let size = 7.0;
+            let depth = 0.5;
+            assert_approx_eq!(0.0, sd_glyph(&vec3::from_values(0.5, 3.5, 0.25), 'O', size, depth));
+            assert_approx_eq!(0.0, sd_glyph(&vec3::from_values(2.5, 6.5, -0.25), 'o', size, depth));
+            assert_approx_eq!(1.5, sd_glyph(&vec3::from_values(2.5, 3.5, 0.0), 'O', size, depth));
+            assert_approx_eq!(-0.25, sd_glyph(&vec3::from_values(4.5, 3.5, 0.0), 'O', size, depth));
+            assert_approx_eq!(1.0, sd_glyph(&vec3::from_values(6.0, 3.5, 0.0), 'O', size, depth));
+            assert!(sd_glyph(&vec3::from_values(2.5, 3.5, 0.0), ' ', size, depth).is_infinite());
+
+            // The second glyph of a text starts 6 cells to the right
+            assert_approx_eq!(1.5, sd_text(&vec3::from_values(8.5, 3.5, 0.0), "IO", size, depth));
+            assert_approx_eq!(0.0, sd_text(&vec3::from_values(6.5, 3.5, 0.25), "IO", size, depth));
+            assert_approx_eq!(0.5, sd_text(&vec3::from_values(2.5, 3.5, 0.75), "I O", size, depth));
+        }
+
+        #[test]
+        fn test_op_onion_smooth() {
+            assert_approx_eq!(-0.1, op_onion_smooth(0.0, 0.1, 0.01));
+            assert_approx_eq!(op_onion(0.5, 0.1), op_onion_smooth(0.5, 0.1, 0.01), 0.01);
+            assert_approx_eq!(op_onion(-0.5, 0.1), op_onion_smooth(-0.5, 0.1, 0.01), 0.01);
+        }
+
+        #[test]
+        fn test_op_extrude() {
+            let p = vec3::from_values(0.0, 1.5, 0.0);
+            let sd_xy = sd_bezier_2d(&vec2::from_values(p.0, p.1), &vec2::from_values(-1.0, 0.0), &vec2::from_values(0.0, 2.0), &vec2::from_values(1.0, 0.0)) - 0.1;
+            assert_approx_eq!(0.4, op_extrude(&p, sd_xy, 1.0));
+            assert_approx_eq!(0.5, op_extrude(&vec3::from_values(p.0, p.1, 1.3), sd_xy, 1.0));
+        }
     }
 }
@@ -1,4 +1,8 @@
-use crate::canvas::PixelPropertyCanvas;
+use std::f32::consts::PI;
+
+use serde::{Deserialize, Serialize};
+
+use crate::canvas::{PixelProperties, PixelPropertyCanvas};
 use crate::ray_marcher::RayMarcher;
 use crate::scene::Scene;
 use crate::vector::{vec2, vec3, Vec2, Vec3};
@@ -102,14 +106,21 @@ impl StreamlineRegistry {
         streamline_id
     }
 
+    // The separation test is elliptical: offsets along the unit flow direction are stretched by the anisotropy, so
+    // for anisotropy > 1 points may come closer along the flow (down to d_sep / anisotropy) than across it (d_sep).
+    // Panics unless the anisotropy is positive and finite.
     pub fn is_point_allowed(
         &self,
         p: &Vec2,
         d_sep: f32,
         d_sep_relaxed: f32,
         relaxed_streamline_id: u32,
+        flow_direction: &Vec2,
+        anisotropy: f32,
     ) -> bool {
-        let cell_radius = (d_sep / self.cell_size).ceil() as u32;
+        assert!(anisotropy > 0.0 && anisotropy.is_finite(), "anisotropy must be positive and finite, got {}", anisotropy);
+        let search_radius = d_sep.max(d_sep_relaxed) / anisotropy.min(1.0);
+        let cell_radius = (search_radius / self.cell_size).ceil() as u32;
         let (i_x_cell, i_y_cell) = self.cell_coordinates(p);
         let i_x_min = i_x_cell.saturating_sub(cell_radius);
         let i_x_max = (i_x_cell + cell_radius).min(self.cells_x - 1);
@@ -125,7 +136,10 @@ impl StreamlineRegistry {
                     } else {
                         d_sep
                     };
-                    if vec2::dist(p, &candidate.point) < min_dist {
+                    let offset = vec2::sub(p, &candidate.point);
+                    let along = anisotropy * vec2::dot(&offset, flow_direction);
+                    let across = offset.0 * flow_direction.1 - offset.1 * flow_direction.0;
+                    if along * along + across * across < min_dist * min_dist {
                         return false;
                     }
                 }
@@ -135,8 +149,99 @@ impl StreamlineRegistry {
     }
 }
 
-pub fn streamline_d_sep_from_lightness(d_sep_min: f32, d_sep_max: f32, lightness: f32) -> f32 {
-    (d_sep_max - d_sep_min) * lightness * lightness * lightness + d_sep_min
+// d_sep_gamma controls the tonal response: the larger it is, the more densely streamlines are packed in mid-tones
+pub fn streamline_d_sep_from_lightness(d_sep_min: f32, d_sep_max: f32, d_sep_gamma: f32, lightness: f32) -> f32 {
+    (d_sep_max - d_sep_min) * lightness.powf(d_sep_gamma) + d_sep_min
+}
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub enum SeedOrder {
+    Fifo,
+    RadialFrom(Vec2), // grow streamlines outward from a focal point (in canvas coordinates)
+}
+
+// Pencil-like line weight: the width of a stroke breathes along its arc length following a 1D value noise. Unlike
+// lightness- or depth-driven widths, this is texture only; each stroke gets its own noise.
+#[derive(Clone, Copy, Serialize, Deserialize)]
+pub struct StrokePressure {
+    pub amplitude: f32, // relative variation; widths stay within base * [1 - amplitude, 1 + amplitude]
+    pub frequency: f32, // noise cycles per pixel of arc length
+    pub seed: u64,
+}
+
+impl StrokePressure {
+    pub fn new(amplitude: f32, frequency: f32, seed: Option<u64>) -> StrokePressure {
+        StrokePressure {
+            amplitude,
+            frequency,
+            seed: seed.unwrap_or(0),
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct StreamlineConfig {
+    pub color: [u8; 3],
+    pub stroke_width: f32,
+    pub far_stroke_width_factor: Option<f32>,
+    pub seed_box_size: u32,
+    pub seed_jitter: f32, // in [0, 1]
+    pub seed_importance: Option<f32>,
+    pub d_sep_min: f32,
+    pub d_sep_max: f32,
+    pub d_sep_gamma: Option<f32>, // defaults to 3, cf. streamline_d_sep_from_lightness
+    pub d_test_factor: f32,
+    pub d_step: f32,
+    pub max_depth_step: f32,
+    pub max_accum_angle: f32,
+    pub max_steps: u32,
+    pub min_steps: u32,
+    pub lightness_min: Option<f32>, // streamlines are confined to the lightness band [lightness_min, lightness_max]
+    pub lightness_max: Option<f32>,
+    pub seed_order: SeedOrder,
+    pub avoid_self_intersection: bool, // also keep d_test away from earlier points of the streamline being grown
+    pub max_streamlines: Option<usize>, // budget; once reached, no further streamlines are started
+    pub anisotropy: f32, // > 0; > 1 lets streamline points come closer along the flow than across it; 1 is isotropic
+    pub pressure: Option<StrokePressure>,
+    pub style_from_lightness: Option<f32>, // streamlines lighter than this on average are drawn dashed
+    pub rng_seed: u64, // used by render_scene_illustration; the other renderers take the rng as an argument
+}
+
+impl StreamlineConfig {
+    pub fn d_sep_from_lightness(&self, lightness: f32) -> f32 {
+        streamline_d_sep_from_lightness(self.d_sep_min, self.d_sep_max, self.d_sep_gamma.unwrap_or(3.0), lightness)
+    }
+}
+
+impl Default for StreamlineConfig {
+    fn default() -> StreamlineConfig {
+        StreamlineConfig {
+            color: [0, 0, 0],
+            stroke_width: 1.0,
+            far_stroke_width_factor: None,
+            seed_box_size: 8,
+            seed_jitter: 0.5,
+            seed_importance: None,
+            d_sep_min: 2.0,
+            d_sep_max: 4.0,
+            d_sep_gamma: None,
+            d_test_factor: 0.8,
+            d_step: 0.5,
+            max_depth_step: 1.0,
+            max_accum_angle: PI,
+            max_steps: 100,
+            min_steps: 2,
+            lightness_min: None,
+            lightness_max: None,
+            seed_order: SeedOrder::Fifo,
+            avoid_self_intersection: false,
+            max_streamlines: None,
+            anisotropy: 1.0,
+            pressure: None,
+            style_from_lightness: None,
+            rng_seed: 0,
+        }
+    }
 }
 
 pub fn flow_field_streamline(
@@ -144,82 +249,97 @@ pub fn flow_field_streamline(
     streamline_registry: &StreamlineRegistry,
     start_from_streamline_id: u32,
     p_start: &Vec2,
-    d_sep_min: f32,
-    d_sep_max: f32,
-    d_test_factor: f32,
-    d_step: f32,
-    max_depth_step: f32,
-    max_accum_angle: f32,
-    max_steps: u32,
-    min_steps: u32,
+    config: &StreamlineConfig,
+    mask: Option<&dyn Fn(&Vec2) -> bool>, // streamlines are confined to points where the mask is true
 ) -> Option<Vec<Vec2>> {
+    let is_in_mask = |p: &Vec2| mask.is_none_or(|m| m(p));
+    if !is_in_mask(p_start) {
+        return None;
+    }
+    let is_in_lightness_band = |pv: &PixelProperties| {
+        config.lightness_min.is_none_or(|min| pv.lightness >= min) && config.lightness_max.is_none_or(|max| pv.lightness <= max)
+    };
     let pv_start = canvas.pixel_value(p_start.0, p_start.1);
     if pv_start.is_none() {
         return None;
     }
 
     let pv_start = pv_start.unwrap();
-    if !pv_start.is_hatched {
+    if !pv_start.is_streamlined || !is_in_lightness_band(&pv_start) {
         return None;
     }
 
-    let d_sep = streamline_d_sep_from_lightness(d_sep_min, d_sep_max, pv_start.lightness);
+    let d_sep = config.d_sep_from_lightness(pv_start.lightness);
     if !streamline_registry.is_point_allowed(
         p_start,
         d_sep,
-        d_test_factor * d_sep,
+        config.d_test_factor * d_sep,
         start_from_streamline_id,
+        &vec2::polar_angle_to_unit_vector(pv_start.direction()),
+        config.anisotropy,
     ) {
         return None;
     }
 
+    // Each half of the line gets half of the step and turning budgets; d_step is negative to grow against the direction
+    #[allow(clippy::too_many_arguments)]
     fn continue_line(
         canvas: &PixelPropertyCanvas,
         streamline_registry: &StreamlineRegistry,
         p_start: &Vec2,
-        direction_start: f32,
-        depth_start: f32,
-        d_sep_min: f32,
-        d_sep_max: f32,
-        d_test_factor: f32,
+        pv_start: &PixelProperties,
+        config: &StreamlineConfig,
         d_step: f32,
-        max_depth_step: f32,
-        max_accum_angle: f32,
-        max_steps: u32,
+        is_in_mask: &dyn Fn(&Vec2) -> bool,
+        is_in_lightness_band: &dyn Fn(&PixelProperties) -> bool,
+        earlier_points: Option<&[Vec2]>, // if set, the points preceding p_start in arc order, to test for self-intersections
     ) -> Vec<Vec2> {
         let mut line: Vec<Vec2> = Vec::new();
+        let mut history: Vec<Vec2> = earlier_points.map(|points| [points, &[*p_start]].concat()).unwrap_or_default();
         let mut p_last = *p_start;
-        let mut next_direction = direction_start;
-        let mut last_depth = depth_start;
+        let mut next_direction = pv_start.direction();
+        let mut last_depth = pv_start.depth;
         let mut accum_angle = 0.0f32;
+        let max_accum_angle = 0.5 * config.max_accum_angle;
 
-        for _ in 0..max_steps {
+        for _ in 0..config.max_steps / 2 {
             let next_dir_uv = vec2::polar_angle_to_unit_vector(next_direction);
             let p_new = vec2::scale_and_add(&p_last, &next_dir_uv, d_step);
+            if !is_in_mask(&p_new) {
+                break;
+            }
             let pv_new = canvas.pixel_value(p_new.0, p_new.1);
             if pv_new.is_none() {
                 break;
             }
 
             let pv_new = pv_new.unwrap();
-            if !pv_new.is_hatched {
+            if !pv_new.is_streamlined || !is_in_lightness_band(&pv_new) {
                 break;
             }
 
-            let new_dir_uv = vec2::polar_angle_to_unit_vector(pv_new.direction);
+            let new_dir_uv = vec2::polar_angle_to_unit_vector(pv_new.direction());
             accum_angle += vec2::dot(&next_dir_uv, &new_dir_uv).clamp(-1.0, 1.0).acos();
-            let d_sep = d_test_factor
-                * streamline_d_sep_from_lightness(d_sep_min, d_sep_max, pv_new.lightness);
+            let d_sep = config.d_test_factor * config.d_sep_from_lightness(pv_new.lightness);
             if accum_angle > max_accum_angle
-                || (pv_new.depth - last_depth).abs() > max_depth_step
-                || !streamline_registry.is_point_allowed(&p_new, d_sep, d_sep, 0)
+                || (pv_new.depth - last_depth).abs() > config.max_depth_step
+                || !streamline_registry.is_point_allowed(&p_new, d_sep, d_sep, 0, &new_dir_uv, config.anisotropy)
             {
                 break;
             }
+            if earlier_points.is_some() {
+                // Skip the most recent points, which are close to p_new merely by being its neighbors along the line
+                let lookback = (2.0 * d_sep / d_step.abs()).ceil() as usize;
+                let distant_history = &history[..history.len().saturating_sub(lookback)];
+                if distant_history.iter().any(|p| vec2::dist(p, &p_new) < d_sep) {
+                    break;
+                }
+                history.push(p_new);
+            }
 
             line.push(p_new);
             p_last = p_new;
-            next_direction = pv_new.direction;
+            next_direction = pv_new.direction();
             last_depth = pv_new.depth;
         }
         line
@@ -229,29 +349,25 @@ pub fn flow_field_streamline(
         canvas,
         streamline_registry,
         p_start,
-        pv_start.direction,
-        pv_start.depth,
-        d_sep_min,
-        d_sep_max,
-        d_test_factor,
-        d_step,
-        max_depth_step,
-        0.5 * max_accum_angle,
-        max_steps / 2,
+        &pv_start,
+        config,
+        config.d_step,
+        &is_in_mask,
+        &is_in_lightness_band,
+        config.avoid_self_intersection.then_some(&[]),
     );
+    // Growing against the direction continues the line beyond p_start, i.e., after the reversed first half
+    let reversed_line_with_direction: Vec<Vec2> = line_with_direction.iter().rev().cloned().collect();
     let line_against_direction = continue_line(
         canvas,
         streamline_registry,
         p_start,
-        pv_start.direction,
-        pv_start.depth,
-        d_sep_min,
-        d_sep_max,
-        d_test_factor,
-        -d_step,
-        max_depth_step,
-        0.5 * max_accum_angle,
-        max_steps / 2,
+        &pv_start,
+        config,
+        -config.d_step,
+        &is_in_mask,
+        &is_in_lightness_band,
+        config.avoid_self_intersection.then_some(&reversed_line_with_direction),
     );
     let line_midpoint = [*p_start];
 
@@ -263,13 +379,26 @@ pub fn flow_field_streamline(
         .cloned()
         .collect();
 
-    if line.len() > (min_steps + 1) as usize {
+    if line.len() > (config.min_steps + 1) as usize {
         Some(line)
     } else {
         None
     }
 }
 
+// Drops streamlines that are geometrically tiny although they may have taken many steps, e.g., because they fold
+// back on themselves: a streamline is kept only if both its arc length and the diagonal of its bounding box reach
+// the given minimums
+pub fn prune_streamlines(paths: &mut Vec<Vec<Vec2>>, min_arc_length: f32, min_bbox_diagonal: f32) {
+    paths.retain(|path| {
+        let arc_length: f32 = path.windows(2).map(|segment| vec2::dist(&segment[0], &segment[1])).sum();
+        let (min, max) = path.iter().fold((vec2::inf(), vec2::scale(&vec2::inf(), -1.0)), |(min, max), p| {
+            (vec2::from_values(min.0.min(p.0), min.1.min(p.1)), vec2::from_values(max.0.max(p.0), max.1.max(p.1)))
+        });
+        !path.is_empty() && arc_length >= min_arc_length && vec2::dist(&min, &max) >= min_bbox_diagonal
+    });
+}
+
 // *** World Space Streamlines
 
 pub fn gradient_streamline_segments(
@@ -320,3 +449,150 @@ pub fn gradient_streamline_segments(
     }
     segments
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::canvas::Canvas;
+    use crate::test_fixtures::uniform_canvas;
+
+    fn streamline_config() -> StreamlineConfig {
+        StreamlineConfig { d_sep_min: 1.0, d_sep_max: 4.0, ..StreamlineConfig::default() }
+    }
+
+    fn streamline_from(canvas: &PixelPropertyCanvas, p_start: &Vec2) -> Option<Vec<Vec2>> {
+        let registry = StreamlineRegistry::new(canvas.width(), canvas.height(), 2.0);
+        flow_field_streamline(canvas, &registry, 0, p_start, &streamline_config(), None)
+    }
+
+    #[test]
+    fn test_is_point_allowed_anisotropy() {
+        let mut registry = StreamlineRegistry::new(20, 20, 2.0);
+        let center = vec2::from_values(10.0, 10.0);
+        registry.add_streamline(&[center]);
+        let flow_direction = vec2::from_values(1.0, 0.0);
+        let along = vec2::from_values(11.5, 10.0);
+        let across = vec2::from_values(10.0, 11.5);
+
+        assert!(!registry.is_point_allowed(&along, 2.0, 2.0, 0, &flow_direction, 1.0));
+        assert!(!registry.is_point_allowed(&across, 2.0, 2.0, 0, &flow_direction, 1.0));
+        // With d_sep = 2 and anisotropy 2, the ellipse reaches 1 along the flow but still 2 across it
+        assert!(registry.is_point_allowed(&along, 2.0, 2.0, 0, &flow_direction, 2.0));
+        assert!(!registry.is_point_allowed(&across, 2.0, 2.0, 0, &flow_direction, 2.0));
+        assert!(registry.is_point_allowed(&vec2::from_values(10.0, 12.5), 2.0, 2.0, 0, &flow_direction, 2.0));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_is_point_allowed_zero_anisotropy() {
+        let registry = StreamlineRegistry::new(20, 20, 2.0);
+        registry.is_point_allowed(&vec2::from_values(10.0, 10.0), 2.0, 2.0, 0, &vec2::from_values(1.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_flow_field_streamline_is_streamlined() {
+        let mut canvas = uniform_canvas(20, 20, 0.5, 0.0);
+        let p_start = vec2::from_values(10.0, 10.0);
+        assert!(streamline_from(&canvas, &p_start).is_some());
+
+        for pixel in canvas.pixels_mut().iter_mut() {
+            pixel.is_streamlined = false;
+        }
+        assert!(streamline_from(&canvas, &p_start).is_none());
+    }
+
+    #[test]
+    fn test_prune_streamlines() {
+        let long: Vec<Vec2> = (0..50).map(|i| vec2::from_values(i as f32, 0.5 * i as f32)).collect();
+        // Many steps back and forth within half a pixel: a long arc length, but a tiny bounding box
+        let folded: Vec<Vec2> = (0..50).map(|i| vec2::from_values(10.0 + 0.5 * (i % 2) as f32, 10.0)).collect();
+        let short = vec![vec2::from_values(0.0, 0.0), vec2::from_values(1.0, 1.0)];
+        let mut paths = vec![folded, long.clone(), short, vec![]];
+        prune_streamlines(&mut paths, 5.0, 3.0);
+        assert_eq!(1, paths.len());
+        assert_eq!(long, paths[0]);
+    }
+
+    #[test]
+    fn test_streamline_d_sep_from_lightness_gamma() {
+        let d_sep = |gamma: f32, lightness: f32| streamline_d_sep_from_lightness(1.0, 5.0, gamma, lightness);
+        assert_eq!(1.0, d_sep(1.0, 0.0));
+        assert_eq!(1.0, d_sep(3.0, 0.0));
+        assert_eq!(5.0, d_sep(1.0, 1.0));
+        assert_eq!(5.0, d_sep(3.0, 1.0));
+        // A higher gamma packs mid-tones more densely, i.e., with smaller separations
+        assert_eq!(3.0, d_sep(1.0, 0.5));
+        assert_eq!(1.5, d_sep(3.0, 0.5));
+        assert!(d_sep(5.0, 0.5) < d_sep(3.0, 0.5));
+    }
+
+    #[test]
+    fn test_flow_field_streamline_mask() {
+        let canvas = uniform_canvas(20, 20, 0.5, 0.0);
+        let registry = StreamlineRegistry::new(canvas.width(), canvas.height(), 2.0);
+        let left_half = |p: &Vec2| p.0 < 10.0;
+        let config = streamline_config();
+        let streamline_masked = |p_start: &Vec2| flow_field_streamline(&canvas, &registry, 0, p_start, &config, Some(&left_half));
+
+        let unmasked = streamline_from(&canvas, &vec2::from_values(5.0, 10.0)).unwrap();
+        assert!(unmasked.iter().any(|p| p.0 >= 10.0));
+        let masked = streamline_masked(&vec2::from_values(5.0, 10.0)).unwrap();
+        assert!(masked.iter().all(|p| p.0 < 10.0));
+        assert!(streamline_masked(&vec2::from_values(15.0, 10.0)).is_none());
+    }
+
+    #[test]
+    fn test_flow_field_streamline_lightness_band() {
+        // Horizontal flow; dark on the left half, light on the right half
+        let mut canvas = uniform_canvas(20, 20, 0.2, 0.0);
+        for (index, pixel) in canvas.pixels_mut().iter_mut().enumerate() {
+            if index % 20 >= 10 {
+                pixel.lightness = 0.9;
+            }
+        }
+        let registry = StreamlineRegistry::new(canvas.width(), canvas.height(), 2.0);
+        let config = StreamlineConfig { lightness_max: Some(0.5), ..streamline_config() };
+        let shadows_only = |p_start: &Vec2| flow_field_streamline(&canvas, &registry, 0, p_start, &config, None);
+
+        assert!(streamline_from(&canvas, &vec2::from_values(5.0, 10.0)).unwrap().iter().any(|p| p.0 >= 10.0));
+        let streamline = shadows_only(&vec2::from_values(5.0, 10.0)).unwrap();
+        assert!(streamline.iter().all(|p| p.0 < 10.0));
+        assert!(streamline.iter().any(|p| p.0 > 9.0));
+        assert!(shadows_only(&vec2::from_values(15.0, 10.0)).is_none());
+        assert!(streamline_from(&canvas, &vec2::from_values(15.0, 10.0)).is_some());
+    }
+
+    #[test]
+    fn test_flow_field_streamline_self_intersection() {
+        // A tight spiral around the canvas center
+        let width = 40;
+        let mut canvas = uniform_canvas(width, width, 0.5, 0.0);
+        for (index, pixel) in canvas.pixels_mut().iter_mut().enumerate() {
+            let p = vec2::from_values((index as u32 % width) as f32 + 0.5, (index as u32 / width) as f32 + 0.5);
+            let to_center = vec2::sub(&vec2::from_values(20.0, 20.0), &p);
+            pixel.set_direction(to_center.1.atan2(to_center.0) + 0.5 * std::f32::consts::PI + 0.05);
+        }
+        let registry = StreamlineRegistry::new(canvas.width(), canvas.height(), 2.0);
+        let spiral = |avoid_self_intersection: bool| {
+            let config = StreamlineConfig {
+                d_step: 0.2,
+                max_accum_angle: 100.0,
+                max_steps: 2000,
+                avoid_self_intersection,
+                ..streamline_config()
+            };
+            flow_field_streamline(&canvas, &registry, 0, &vec2::from_values(28.5, 20.5), &config, None).unwrap()
+        };
+        // d_test = 0.8 * d_sep(0.5) = 1.1; points further apart along the line than the lookback must keep that distance
+        let overlaps = |line: &[Vec2]| {
+            let lookback = (2.0 * 1.1 / 0.2f32).ceil() as usize;
+            line.iter().enumerate().any(|(i, p)| line.iter().skip(i + lookback).any(|q| vec2::dist(p, q) < 1.1 - 1.0e-3))
+        };
+
+        let unguarded = spiral(false);
+        let guarded = spiral(true);
+        assert!(overlaps(&unguarded));
+        assert!(!overlaps(&guarded));
+        assert!(guarded.len() < unguarded.len());
+    }
+}
@@ -0,0 +1,49 @@
+// Scenes, cameras, and canvases shared by the unit tests of the other modules
+
+use crate::canvas::PixelPropertyCanvas;
+use crate::ray_marcher::RayMarcher;
+use crate::scene::Scene;
+use crate::sdf::{sdf_op, Material, SdfOutput};
+use crate::vector::{vec3, Vec3};
+
+// Unit sphere centered at the origin
+pub(crate) struct SceneSphere {
+    pub(crate) material: Material,
+}
+
+impl Scene for SceneSphere {
+    fn eval(&self, p: &Vec3) -> SdfOutput {
+        SdfOutput::new(sdf_op::sd_sphere(p, 1.0), self.material)
+    }
+}
+
+// Unit sphere lit from the upper right front, shaded and hatched
+pub(crate) fn scene_sphere() -> SceneSphere {
+    SceneSphere {
+        material: Material::new(&vec3::from_values(5.0, 5.0, 5.0), None, None, true, true),
+    }
+}
+
+// Camera on the positive z axis looking at the origin (and, thus, at the sphere of SceneSphere) with y up
+pub(crate) fn sphere_ray_marcher(aspect_ratio: f32) -> RayMarcher {
+    RayMarcher::new(
+        1.0,
+        &vec3::from_values(0.0, 0.0, 5.0),
+        &vec3::from_values(0.0, 0.0, 0.0),
+        &vec3::from_values(0.0, 1.0, 0.0),
+        45.0,
+        aspect_ratio,
+    )
+}
+
+// Canvas of constant lightness and direction at depth 1 whose pixels are all streamlined
+pub(crate) fn uniform_canvas(width: u32, height: u32, lightness: f32, direction: f32) -> PixelPropertyCanvas {
+    let mut canvas = PixelPropertyCanvas::new(width, height);
+    for pixel in canvas.pixels_mut().iter_mut() {
+        pixel.lightness = lightness;
+        pixel.set_direction(direction);
+        pixel.depth = 1.0;
+        pixel.is_streamlined = true;
+    }
+    canvas
+}
@@ -101,6 +101,17 @@ pub mod vec2 {
         )
     }
 
+    pub fn closest_point_on_segment(p: &Vec2, a: &Vec2, b: &Vec2) -> Vec2 {
+        // Clamp the projection of p onto the line through a and b to the segment; degenerate segments yield a
+        let ab = sub(b, a);
+        let t = (dot(&sub(p, a), &ab) / len_squared(&ab).max(EPSILON)).clamp(0.0, 1.0);
+        scale_and_add(a, &ab, t)
+    }
+
+    pub fn dist_to_segment(p: &Vec2, a: &Vec2, b: &Vec2) -> VecFloat {
+        dist(p, &closest_point_on_segment(p, a, b))
+    }
+
     #[cfg(test)]
     mod tests {
         use super::*;
@@ -235,6 +246,23 @@ pub mod vec2 {
             assert_eq!((0.5, 1.5), lerp(&a, &b, 0.5));
             assert_eq!((1.25, 1.75), lerp(&a, &b, 0.75));
         }
+
+        #[test]
+        fn test_vec2_dist_to_segment() {
+            let a = from_values(1.0, 1.0);
+            let b = from_values(5.0, 1.0);
+            // Beyond the endpoints
+            assert_approx_eq!(2.0f32.sqrt(), dist_to_segment(&from_values(0.0, 0.0), &a, &b));
+            assert_eq!(a, closest_point_on_segment(&from_values(0.0, 0.0), &a, &b));
+            assert_approx_eq!(3.0, dist_to_segment(&from_values(8.0, 1.0), &a, &b));
+            assert_eq!(b, closest_point_on_segment(&from_values(8.0, 1.0), &a, &b));
+            // Beside the segment
+            assert_approx_eq!(2.0, dist_to_segment(&from_values(2.5, -1.0), &a, &b));
+            assert_eq!((2.5, 1.0), closest_point_on_segment(&from_values(2.5, -1.0), &a, &b));
+            assert_approx_eq!(0.0, dist_to_segment(&from_values(4.0, 1.0), &a, &b));
+            // Degenerate segment
+            assert_approx_eq!(5.0, dist_to_segment(&from_values(4.0, 5.0), &a, &a));
+        }
     }
 }
 
@@ -292,10 +320,30 @@ pub mod vec3 {
         a.0 * b.0 + a.1 * b.1 + a.2 * b.2
     }
 
+    pub fn abs(a: &Vec3) -> Vec3 {
+        (a.0.abs(), a.1.abs(), a.2.abs())
+    }
+
+    pub fn max(a: &Vec3, b: &Vec3) -> Vec3 {
+        (a.0.max(b.0), a.1.max(b.1), a.2.max(b.2))
+    }
+
+    pub fn min(a: &Vec3, b: &Vec3) -> Vec3 {
+        (a.0.min(b.0), a.1.min(b.1), a.2.min(b.2))
+    }
+
     pub fn max_float(a: &Vec3, b: VecFloat) -> Vec3 {
         (a.0.max(b), a.1.max(b), a.2.max(b))
     }
 
+    pub fn min_float(a: &Vec3, b: VecFloat) -> Vec3 {
+        (a.0.min(b), a.1.min(b), a.2.min(b))
+    }
+
+    pub fn clamp(a: &Vec3, lo: VecFloat, hi: VecFloat) -> Vec3 {
+        (a.0.clamp(lo, hi), a.1.clamp(lo, hi), a.2.clamp(lo, hi))
+    }
+
     pub fn cross(a: &Vec3, b: &Vec3) -> Vec3 {
         (
             a.1 * b.2 - a.2 * b.1,
@@ -339,6 +387,16 @@ pub mod vec3 {
         scale_and_add(incident, normal, -2.0 * dot(incident, normal))
     }
 
+    // eta = ratio of the indices of refraction (outside / inside); returns the zero vector on total internal reflection
+    pub fn refract(incident: &Vec3, normal: &Vec3, eta: VecFloat) -> Vec3 {
+        let cos_incident = dot(normal, incident);
+        let k = 1.0 - eta * eta * (1.0 - cos_incident * cos_incident);
+        if k < 0.0 {
+            return (0.0, 0.0, 0.0);
+        }
+        scale_and_add_inplace(scale(incident, eta), normal, -(eta * cos_incident + k.sqrt())) // = eta * i - (eta * dot(n, i) + sqrt(k)) * n
+    }
+
     pub fn lerp(a: &Vec3, b: &Vec3, t: VecFloat) -> Vec3 {
         (
             a.0 + t * (b.0 - a.0),
@@ -400,6 +458,40 @@ pub mod vec3 {
         )
     }
 
+    // Inverse of hsl_to_rgb: RGB components in [0, 1], hue in radians
+    pub fn rgb_to_hsl(rgb: &Vec3) -> Vec3 {
+        let (r, g, b) = *rgb;
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let chroma = max - min;
+        let lightness = 0.5 * (max + min);
+        if chroma <= 0.0 {
+            return (0.0, 0.0, lightness);
+        }
+
+        let hue_bucket = if max == r {
+            ((g - b) / chroma).rem_euclid(6.0)
+        } else if max == g {
+            (b - r) / chroma + 2.0
+        } else {
+            (r - g) / chroma + 4.0
+        };
+        let saturation = chroma / (1.0 - (2.0 * lightness - 1.0).abs());
+        (
+            hue_bucket * (60.0 * PI / 180.0),
+            saturation,
+            lightness,
+        )
+    }
+
+    pub fn rgb_u8_to_hsl(rgb: &[u8; 3]) -> Vec3 {
+        rgb_to_hsl(&from_values(
+            rgb[0] as VecFloat / 255.0,
+            rgb[1] as VecFloat / 255.0,
+            rgb[2] as VecFloat / 255.0,
+        ))
+    }
+
     pub fn lerp_hsl(hsl_a: &Vec3, hsl_b: &Vec3, t: VecFloat) -> Vec3 {
         let mut hue_delta = hsl_b.0 - hsl_a.0;
         if hue_delta > PI {
@@ -561,12 +653,85 @@ pub mod vec3 {
             assert_approx_eq!(expected.2, r.2);
         }
 
+        #[test]
+        fn test_vec3_abs() {
+            assert_eq!((1.5, 0.0, 2.0), abs(&from_values(-1.5, -0.0, 2.0)));
+        }
+
+        #[test]
+        fn test_vec3_min_max() {
+            let a = from_values(-1.0, 2.0, 3.0);
+            let b = from_values(0.5, -4.0, 3.0);
+            assert_eq!((0.5, 2.0, 3.0), max(&a, &b));
+            assert_eq!((-1.0, -4.0, 3.0), min(&a, &b));
+            assert_eq!((0.0, 2.0, 3.0), max_float(&a, 0.0));
+            assert_eq!((-1.0, 0.0, 0.0), min_float(&a, 0.0));
+        }
+
+        #[test]
+        fn test_vec3_clamp() {
+            let a = from_values(-2.0, 0.25, 7.0);
+            assert_eq!((-1.0, 0.25, 1.0), clamp(&a, -1.0, 1.0));
+        }
+
+        #[test]
+        fn test_vec3_refract() {
+            let normal = from_values(0.0, 1.0, 0.0);
+            // Straight through at normal incidence, bent toward the normal when entering a denser medium
+            assert_eq!((0.0, -1.0, 0.0), refract(&from_values(0.0, -1.0, 0.0), &normal, 0.75));
+            let incident = normalize(&from_values(1.0, -1.0, 0.0));
+            let r = refract(&incident, &normal, 0.75);
+            assert_approx_eq!(1.0, len(&r));
+            assert_approx_eq!(0.75 * incident.0, r.0);
+            // Total internal reflection when leaving the denser medium at a grazing angle
+            assert_eq!((0.0, 0.0, 0.0), refract(&normalize(&from_values(1.0, -0.2, 0.0)), &normal, 1.33));
+        }
+
         #[test]
         fn test_vec3_round_inplace() {
             let a = from_values(-3.51, -2.1, 3.5);
             assert_eq!((-4.0, -2.0, 4.0), round_inplace(a));
         }
 
+        #[test]
+        fn test_rgb_to_hsl() {
+            let red = rgb_to_hsl(&from_values(1.0, 0.0, 0.0));
+            assert_approx_eq!(0.0, red.0);
+            assert_approx_eq!(1.0, red.1);
+            assert_approx_eq!(0.5, red.2);
+
+            let gray = rgb_to_hsl(&from_values(0.25, 0.25, 0.25));
+            assert_eq!((0.0, 0.0, 0.25), gray);
+
+            for rgb in [from_values(0.2, 0.6, 0.9), from_values(0.9, 0.1, 0.4), from_values(0.3, 0.8, 0.1)] {
+                let round_trip = hsl_to_rgb(&rgb_to_hsl(&rgb));
+                assert_approx_eq!(rgb.0, round_trip.0);
+                assert_approx_eq!(rgb.1, round_trip.1);
+                assert_approx_eq!(rgb.2, round_trip.2);
+            }
+        }
+
+        #[test]
+        fn test_rgb_to_hsl_primaries() {
+            let expected_hues = [
+                ([255, 0, 0], 0.0),
+                ([255, 255, 0], 60.0),
+                ([0, 255, 0], 120.0),
+                ([0, 255, 255], 180.0),
+                ([0, 0, 255], 240.0),
+                ([255, 0, 255], 300.0),
+            ];
+            for (rgb, hue_degrees) in expected_hues {
+                let hsl = rgb_u8_to_hsl(&rgb);
+                assert_approx_eq!(hue_degrees * PI / 180.0, hsl.0);
+                assert_approx_eq!(1.0, hsl.1);
+                assert_approx_eq!(0.5, hsl.2);
+                assert_eq!(rgb, hsl_to_rgb_u8(&hsl));
+            }
+            assert_eq!((0.0, 0.0, 1.0), rgb_u8_to_hsl(&[255, 255, 255]));
+            assert_eq!((0.0, 0.0, 0.0), rgb_u8_to_hsl(&[0, 0, 0]));
+        }
+
         #[test]
         fn test_vec3_orthonormal_basis_of_plane() {
             let n = from_values(0.0, 1.0, 0.0);
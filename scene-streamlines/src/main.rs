@@ -11,7 +11,10 @@ use rand_xoshiro::Xoshiro256PlusPlus;
 
 use rusty_sdfs_lib::PixelPropertyCanvas;
 use rusty_sdfs_lib::RayMarcher;
+use rusty_sdfs_lib::SceneRenderOptions;
 use rusty_sdfs_lib::render_flow_field_streamlines;
+use rusty_sdfs_lib::StreamlineConfig;
+use rusty_sdfs_lib::StreamlineParams;
 use rusty_sdfs_lib::vec3;
 use scene::SceneMeadow;
 
@@ -30,15 +33,19 @@ fn main() {
     const MAX_STEPS: u32 = 450;
     const MIN_STEPS: u32 = 4;
     const SEED_BOX_SIZE_IN_MM: f32 = 2.0;
+    const SEED_JITTER: f32 = 1.0;
     const DPI: f32 = 350.0;
 
     const INCH_PER_CM: f32 = 1.0 / 2.54;
-    const INCH_PER_MM: f32 = 0.1 / 2.54;
-    const SEED_BOX_SIZE: u32 = (SEED_BOX_SIZE_IN_MM * INCH_PER_MM * DPI) as u32;
-    const STROKE_WIDTH: f32 = STROKE_WIDTH_IN_MM * INCH_PER_MM * DPI;
-    const D_SEP_MIN: f32 = D_SEP_MIN_IN_MM * INCH_PER_MM * DPI;
-    const D_SEP_MAX: f32 = D_SEP_MAX_IN_MM * INCH_PER_MM * DPI;
-    const D_STEP: f32 = D_STEP_IN_MM * INCH_PER_MM * DPI;
+    let params = StreamlineParams::from_physical(
+        DPI,
+        STROKE_WIDTH_IN_MM,
+        SEED_BOX_SIZE_IN_MM,
+        D_SEP_MIN_IN_MM,
+        D_SEP_MAX_IN_MM,
+        D_TEST_FACTOR,
+        D_STEP_IN_MM,
+    ).unwrap();
     let width = (WIDTH_IN_CM * INCH_PER_CM * DPI).round() as u32;
     let height = (HEIGHT_IN_CM * INCH_PER_CM * DPI).round() as u32;
 
@@ -47,9 +54,8 @@ fn main() {
     let look_at = scene.look_at();
     let up = vec3::from_values(0.0, 1.0, 0.0);
     let fov = scene.fov();
-    const MAX_CHANGE_RATE: f32 = 2.0;
-    let ray_marcher = RayMarcher::new(
-        1.0,
+    let ray_marcher = RayMarcher::from_scene(
+        &scene,
         &camera,
         &look_at,
         &up,
@@ -61,14 +67,14 @@ fn main() {
 
     println!(
         "Rendering on canvas of size {} px x {} px using a stroke width of {} px...",
-        width, height, STROKE_WIDTH
+        width, height, params.stroke_width
     );
     println!(
         "Using a minimum separation of streamlines of {} px, a maximum of {} px, a test factor of {}, a step of {} px, and an initial seed box size of {} px...",
-        D_SEP_MIN, D_SEP_MAX, D_TEST_FACTOR, D_STEP, SEED_BOX_SIZE
+        params.d_sep_min, params.d_sep_max, params.d_test_factor, params.d_step, params.seed_box_size
     );
     let start_instant = Instant::now();
-    let pp_canvas = PixelPropertyCanvas::from_scene(&ray_marcher, &scene, width, height, 0.0);
+    let pp_canvas = PixelPropertyCanvas::from_scene(&ray_marcher, &scene, width, height, &SceneRenderOptions::default());
     pp_canvas.to_file("meadow.ppc").unwrap();
     let duration_ldd = start_instant.elapsed();
     println!(
@@ -77,24 +83,24 @@ fn main() {
     );
 
     let start_instant = Instant::now();
-    let mut output_canvas = pp_canvas.bg_to_skia_canvas();
-    let streamline_color = vec3::hsl_to_rgb_u8(&scene.hsl_streamlines());
-    render_flow_field_streamlines(
-        &pp_canvas,
-        &mut output_canvas,
-        &mut rng,
-        &streamline_color,
-        STROKE_WIDTH,
-        SEED_BOX_SIZE,
-        D_SEP_MIN,
-        D_SEP_MAX,
-        D_TEST_FACTOR,
-        D_STEP,
-        MAX_DEPTH_STEP,
-        MAX_ACCUM_ANGLE,
-        MAX_STEPS,
-        MIN_STEPS
-    );
+    let mut output_canvas = pp_canvas.bg_to_skia_canvas(None);
+    let config = StreamlineConfig {
+        color: vec3::hsl_to_rgb_u8(&scene.hsl_streamlines()),
+        stroke_width: params.stroke_width,
+        seed_box_size: params.seed_box_size,
+        seed_jitter: SEED_JITTER,
+        d_sep_min: params.d_sep_min,
+        d_sep_max: params.d_sep_max,
+        d_test_factor: params.d_test_factor,
+        d_step: params.d_step,
+        max_depth_step: MAX_DEPTH_STEP,
+        max_accum_angle: MAX_ACCUM_ANGLE,
+        max_steps: MAX_STEPS,
+        min_steps: MIN_STEPS,
+        rng_seed: RNG_SEED,
+        ..StreamlineConfig::default()
+    };
+    render_flow_field_streamlines(&pp_canvas, &mut output_canvas, &mut rng, &config, None);
 
 
     let duraction_flow = start_instant.elapsed();
@@ -104,7 +110,7 @@ fn main() {
     );
 
     println!("Outputting image(s) to disk/display...");
-    // output_canvas.save_png(Path::new("output.png"));
+    // output_canvas.save_png(Path::new("output.png")).expect("failed to save output.png");
     output_canvas.display_in_window("scene streamlines");
     println!("Done");
 }
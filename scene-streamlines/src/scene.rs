@@ -2,7 +2,8 @@ use std::f32::consts::PI;
 
 use rusty_sdfs_lib::{vec2, vec3, Vec2, Vec3, VecFloat};
 use rusty_sdfs_lib::Scene;
-use rusty_sdfs_lib::{Material, ReflectiveProperties, SdfOutput};
+use rusty_sdfs_lib::smooth_abs;
+use rusty_sdfs_lib::{Material, ReflectiveProperties, SdfOutput, WaterProperties};
 use rusty_sdfs_lib::sdf_op::{
     op_elongate_y, op_elongate_z, op_onion, op_repeat_finite, op_repeat_xz, op_rotate_y,
     op_rotate_z, op_shift, op_smooth_difference, op_smooth_union, sd_box, sd_cylinder,
@@ -22,13 +23,12 @@ impl SceneOcean {
 
         let surface_hsl = vec3::from_values(0.0f32.to_radians(), 0.0, 1.0);
         let surface_reflective_props = ReflectiveProperties::new(0.1, 0.0, 0.0, 0.8, 0.1, None, None, None, None);
-        let material_surface = Material::new(
-            &light,
-            Some(&surface_reflective_props),
-            Some(&surface_hsl),
-            true,
-            false,
-        );
+        let surface_water = WaterProperties::new(&vec3::from_values(195.0f32.to_radians(), 0.7, 0.35), None, None);
+        let material_surface = Material {
+            is_streamlined: false,
+            water: Some(surface_water),
+            ..Material::new(&light, Some(&surface_reflective_props), Some(&surface_hsl), true, false)
+        };
 
         SceneOcean {
             light,
@@ -71,9 +71,11 @@ impl SceneOcean {
 
 impl Scene for SceneOcean {
     fn eval(&self, p: &Vec3) -> SdfOutput {
+        // The smoothed absolute value avoids a crease in the distance field at the surface, which yields noisy normals
+        const SURFACE_SMOOTHING: VecFloat = 0.01;
         let h = SceneOcean::height_map(p);
         SdfOutput {
-            distance: (h - p.1).abs(),
+            distance: smooth_abs(h - p.1, SURFACE_SMOOTHING),
             material: self.material_surface,
         }
     }
@@ -167,10 +169,10 @@ impl SceneMeadow {
             stem_radius,
         );
 
-        let (bulb, bulb_t) = op_smooth_union(core, shell, shell_core_k);
-        let material_flower = self.material_core.lerp(&self.material_shell, bulb_t);
-        let (flower, _) = op_smooth_union(bulb, stem, stem_k);
-        SdfOutput::new(flower, material_flower)
+        let bulb = SdfOutput::new(core, self.material_core)
+            .smooth_union(&SdfOutput::new(shell, self.material_shell), shell_core_k);
+        let (flower, _) = op_smooth_union(bulb.distance, stem, stem_k);
+        SdfOutput::new(flower, bulb.material)
     }
 }
 
@@ -5,7 +5,9 @@ use std::vec;
 use minifb::WindowOptions;
 use rand::{Rng, SeedableRng};
 use rand_xoshiro::Xoshiro256StarStar;
+use rusty_sdfs_lib::advect_field;
 use rusty_sdfs_lib::noise_2d;
+use rusty_sdfs_lib::FillRule;
 use rusty_sdfs_lib::vec2;
 use rusty_sdfs_lib::Animation;
 use rusty_sdfs_lib::SkiaCanvas;
@@ -23,6 +25,8 @@ struct WaveAnimation {
     _rng: Xoshiro256StarStar,
     noise_x: Vec<f32>,
     noise_y: Vec<f32>,
+    flow: Vec<Vec2>,
+    time: f32,
 }
 
 impl WaveAnimation {
@@ -37,6 +41,7 @@ impl WaveAnimation {
         let v: Vec<_> = (0..CENTROID_COUNT).map(|_| vec2::from_values(rng.gen_range(0.1..MAX_FLOW_SPEED), rng.gen_range(0.1..((Self::HEIGHT as f32 / Self::WIDTH as f32) * MAX_FLOW_SPEED)))).collect();
         let mut noise_x= vec![0.0f32; Self::WIDTH as usize * Self::HEIGHT as usize];
         let mut noise_y = vec![0.0f32; Self::WIDTH as usize * Self::HEIGHT as usize];
+        let mut flow = vec![vec2::from_values(0.0, 0.0); Self::WIDTH as usize * Self::HEIGHT as usize];
         for iy in 0..(Self::HEIGHT as usize) {
             let yf = iy as f32;
             for ix in 0..(Self::WIDTH as usize) {
@@ -47,8 +52,15 @@ impl WaveAnimation {
                 const NOISE_OCTAVES: u32 = 4;
                 const YX_OFFSET: VecFloat = 1000.0;
                 const YY_OFFSET: VecFloat = 889.0;
-                noise_x[idx] = NOISE_SCALE * noise_2d(NOISE_INPUT_SCALE * xf, NOISE_INPUT_SCALE * yf, NOISE_OCTAVES);
-                noise_y[idx] = NOISE_SCALE * noise_2d(NOISE_INPUT_SCALE * xf + YX_OFFSET, NOISE_INPUT_SCALE * yf + YY_OFFSET, NOISE_OCTAVES);
+                noise_x[idx] = NOISE_SCALE * Self::tileable(xf, yf, |x, y| noise_2d(NOISE_INPUT_SCALE * x, NOISE_INPUT_SCALE * y, NOISE_OCTAVES));
+                noise_y[idx] = NOISE_SCALE * Self::tileable(xf, yf, |x, y| noise_2d(NOISE_INPUT_SCALE * x + YX_OFFSET, NOISE_INPUT_SCALE * y + YY_OFFSET, NOISE_OCTAVES));
+
+                // Slow large-scale current (in pixels per second) that carries the displacement noise along
+                const FLOW_INPUT_SCALE: VecFloat = 0.004;
+                const FLOW_SPEED: VecFloat = 20.0;
+                const FLOW_OFFSET: VecFloat = 500.0;
+                let flow_angle = PI * noise_2d(FLOW_INPUT_SCALE * xf + FLOW_OFFSET, FLOW_INPUT_SCALE * yf, 2);
+                flow[idx] = vec2::scale(&vec2::polar_angle_to_unit_vector(flow_angle), FLOW_SPEED);
             }
         }
 
@@ -58,8 +70,22 @@ impl WaveAnimation {
             _rng: rng,
             noise_x,
             noise_y,
+            flow,
+            time: 0.0,
         }
     }
+
+    // Blend f with its copies shifted by the canvas size so that the result wraps around seamlessly at the borders,
+    // as advect_field assumes
+    fn tileable(x: f32, y: f32, f: impl Fn(f32, f32) -> f32) -> f32 {
+        let w = Self::WIDTH as f32;
+        let h = Self::HEIGHT as f32;
+        let (tx, ty) = (x / w, y / h);
+        (1.0 - tx) * (1.0 - ty) * f(x, y)
+            + tx * (1.0 - ty) * f(x - w, y)
+            + (1.0 - tx) * ty * f(x, y - h)
+            + tx * ty * f(x - w, y - h)
+    }
 }
 
 impl Animation for WaveAnimation {
@@ -128,17 +154,23 @@ impl Animation for WaveAnimation {
                 .unzip();
 
             let path = SkiaCanvas::closed_cubic_curve_path(&ray_endpoints, &ray_left_ctrl, &ray_right_ctrl).unwrap();
-            canvas.fill_path(&path, &[10, 140, 255]);
+            canvas.fill_path(&path, &[10, 140, 255], FillRule::Winding);
             canvas.stroke_path(&path, 3.0, &[50, 175, 255]);
         }
 
+        // Advect the original noise over the whole elapsed time; resampling the previous frame instead would blur the
+        // noise a bit more with every frame
+        self.time += 1.0 / Self::FPS;
+        let noise_x = advect_field(&self.noise_x, &self.flow, Self::WIDTH, Self::HEIGHT, self.time);
+        let noise_y = advect_field(&self.noise_y, &self.flow, Self::WIDTH, Self::HEIGHT, self.time);
+
         let mut noisy_canvas = SkiaCanvas::new(Self::WIDTH, Self::HEIGHT);
         noisy_canvas.iter_mut_rgba_with_coordinates(|x, y, rgba| {
             let xf = x as f32;
             let yf = y as f32;
             let idx = y as usize * Self::WIDTH as usize + x as usize;
-            let x_shift = self.noise_x[idx];
-            let y_shift = self.noise_y[idx];
+            let x_shift = noise_x[idx];
+            let y_shift = noise_y[idx];
             let color = canvas.sample_bilinear(xf + x_shift, yf + y_shift);
             rgba[0] = color.red();
             rgba[1] = color.green();